@@ -0,0 +1,344 @@
+use oh_my_kiwi_engine::aof::AppendFsync;
+use oh_my_kiwi_engine::config::{EngineKind, KiwiConfig};
+use oh_my_kiwi_engine::eviction::MaxmemoryPolicy;
+use oh_my_kiwi_engine::snapshot::{SaveRule, SnapshotFormat};
+use oh_my_kiwi_tcp::config::TcpConfig;
+
+/// Parses `--name value` command line options into the server configurations.
+pub(crate) fn parse_args(
+    args: impl IntoIterator<Item = String>,
+) -> Result<(TcpConfig, KiwiConfig), String> {
+    let mut tcp_config = TcpConfig::default();
+    let mut kiwi_config = KiwiConfig::default();
+
+    let mut args = args.into_iter();
+    while let Some(name) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for option '{name}'"))?;
+        match name.as_str() {
+            "--bind" => tcp_config.set_host(value),
+            "--port" => tcp_config.set_port(parse_number(&name, &value)?),
+            "--databases" => kiwi_config.set_databases(parse_number(&name, &value)?),
+            "--requirepass" => kiwi_config.set_requirepass(Some(value)),
+            "--aclfile" => kiwi_config.set_aclfile(Some(value.into())),
+            "--acllog-max-len" => kiwi_config.set_acllog_max_len(parse_number(&name, &value)?),
+            "--dir" => kiwi_config.set_dir(value),
+            "--dbfilename" => kiwi_config.set_dbfilename(value),
+            "--snapshot-format" => {
+                kiwi_config.set_snapshot_format(value.parse::<SnapshotFormat>()?)
+            }
+            "--save" => kiwi_config.set_save_rules(parse_save_rules(&value)?),
+            "--appendonly" => kiwi_config.set_appendonly(parse_yes_no(&name, &value)?),
+            "--appendfilename" => kiwi_config.set_appendfilename(value),
+            "--appendfsync" => kiwi_config.set_appendfsync(value.parse::<AppendFsync>()?),
+            "--replicaof" => kiwi_config.set_replicaof(Some(parse_leader(&value)?)),
+            "--masteruser" => kiwi_config.set_masteruser(Some(value)),
+            "--masterauth" => kiwi_config.set_masterauth(Some(value)),
+            "--replica-read-only" => {
+                kiwi_config.set_replica_read_only(parse_yes_no(&name, &value)?)
+            }
+            "--repl-backlog-size" => {
+                kiwi_config.set_repl_backlog_size(parse_number(&name, &value)?)
+            }
+            "--replica-output-buffer-limit" => {
+                kiwi_config.set_replica_output_buffer_limit(parse_number(&name, &value)?)
+            }
+            "--cluster-enabled" => kiwi_config.set_cluster(parse_yes_no(&name, &value)?),
+            "--cluster-port" => kiwi_config.set_cluster_port(parse_number(&name, &value)?),
+            "--cluster-config-file" => kiwi_config.set_cluster_config_file(value),
+            "--cluster-node-timeout" => {
+                kiwi_config.set_cluster_node_timeout(parse_number(&name, &value)?)
+            }
+            "--maxmemory" => kiwi_config.set_maxmemory(parse_memory(&name, &value)?),
+            "--maxmemory-policy" => {
+                kiwi_config.set_maxmemory_policy(value.parse::<MaxmemoryPolicy>()?)
+            }
+            "--maxmemory-samples" => {
+                kiwi_config.set_maxmemory_samples(parse_number(&name, &value)?)
+            }
+            "--engine" => kiwi_config.set_engine(value.parse::<EngineKind>()?),
+            "--engine-shards" => kiwi_config.set_engine_shards(parse_number(&name, &value)?),
+            "--engine-threads" => kiwi_config.set_engine_threads(parse_number(&name, &value)?),
+            "--lsm-dir" => kiwi_config.set_lsm_dir(value),
+            "--lsm-memtable-size" => {
+                kiwi_config.set_lsm_memtable_size(parse_memory(&name, &value)?)
+            }
+            "--lsm-wal-fsync" => kiwi_config.set_lsm_wal_fsync(value.parse::<AppendFsync>()?),
+            "--tiered-file" => kiwi_config.set_tiered_file(value),
+            "--compression-threshold" => {
+                kiwi_config.set_compression_threshold(parse_memory(&name, &value)?)
+            }
+            "--list-max-listpack-size" => {
+                kiwi_config.set_list_max_listpack_size(parse_number(&name, &value)?)
+            }
+            "--hash-max-listpack-entries" => {
+                kiwi_config.set_hash_max_listpack_entries(parse_number(&name, &value)?)
+            }
+            "--hash-max-listpack-value" => {
+                kiwi_config.set_hash_max_listpack_value(parse_number(&name, &value)?)
+            }
+            "--set-max-intset-entries" => {
+                kiwi_config.set_set_max_intset_entries(parse_number(&name, &value)?)
+            }
+            "--set-max-listpack-entries" => {
+                kiwi_config.set_set_max_listpack_entries(parse_number(&name, &value)?)
+            }
+            "--set-max-listpack-value" => {
+                kiwi_config.set_set_max_listpack_value(parse_number(&name, &value)?)
+            }
+            "--zset-max-listpack-entries" => {
+                kiwi_config.set_zset_max_listpack_entries(parse_number(&name, &value)?)
+            }
+            "--zset-max-listpack-value" => {
+                kiwi_config.set_zset_max_listpack_value(parse_number(&name, &value)?)
+            }
+            "--tracking-table-max-keys" => {
+                kiwi_config.set_tracking_table_max_keys(parse_number(&name, &value)?)
+            }
+            _ => return Err(format!("unknown option '{name}'")),
+        }
+    }
+
+    Ok((tcp_config, kiwi_config))
+}
+
+/// Parses `"<seconds> <changes> ..."` save rules; an empty string disables saving.
+fn parse_save_rules(value: &str) -> Result<Vec<SaveRule>, String> {
+    let numbers = value
+        .split_whitespace()
+        .map(|number| parse_number("--save", number))
+        .collect::<Result<Vec<u64>, String>>()?;
+    if numbers.len() % 2 != 0 {
+        return Err(format!("invalid save rules '{value}'"));
+    }
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SaveRule::new(pair[0], pair[1]))
+        .collect())
+}
+
+/// Parses a `"<host> <port>"` leader address.
+fn parse_leader(value: &str) -> Result<(String, u16), String> {
+    match value.split_whitespace().collect::<Vec<_>>()[..] {
+        [host, port] => Ok((host.to_string(), parse_number("--replicaof", port)?)),
+        _ => Err(format!("invalid value '{value}' for option '--replicaof'")),
+    }
+}
+
+/// Parses a size in bytes with an optional unit, like `100mb` or `1g`:
+/// `k`, `m` and `g` are powers of 1000, `kb`, `mb` and `gb` of 1024.
+fn parse_memory(name: &str, value: &str) -> Result<usize, String> {
+    let lower = value.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid value '{value}' for option '{name}'")),
+    };
+    parse_number::<usize>(name, digits)?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("invalid value '{value}' for option '{name}'"))
+}
+
+fn parse_yes_no(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("invalid value '{value}' for option '{name}'")),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{value}' for option '{name}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let (tcp_config, kiwi_config) = parse_args(args(&[])).unwrap();
+        assert_eq!(tcp_config, TcpConfig::default());
+        assert_eq!(kiwi_config, KiwiConfig::default());
+    }
+
+    #[test]
+    fn test_options() {
+        let (tcp_config, kiwi_config) = parse_args(args(&[
+            "--bind",
+            "0.0.0.0",
+            "--port",
+            "7000",
+            "--databases",
+            "4",
+            "--requirepass",
+            "secret",
+            "--aclfile",
+            "users.acl",
+            "--acllog-max-len",
+            "16",
+            "--dir",
+            "/tmp",
+            "--dbfilename",
+            "kiwi.snap",
+            "--snapshot-format",
+            "rdb",
+            "--save",
+            "900 1 300 10",
+            "--appendonly",
+            "yes",
+            "--appendfsync",
+            "always",
+            "--replicaof",
+            "10.0.0.1 6380",
+            "--masteruser",
+            "replica",
+            "--masterauth",
+            "secret",
+            "--replica-read-only",
+            "no",
+            "--repl-backlog-size",
+            "4096",
+            "--replica-output-buffer-limit",
+            "8192",
+            "--cluster-enabled",
+            "yes",
+            "--cluster-port",
+            "17000",
+            "--cluster-config-file",
+            "nodes-7000.conf",
+            "--cluster-node-timeout",
+            "5000",
+            "--maxmemory",
+            "100mb",
+            "--maxmemory-policy",
+            "allkeys-lru",
+            "--maxmemory-samples",
+            "10",
+            "--engine",
+            "sharded",
+            "--engine-shards",
+            "32",
+            "--engine-threads",
+            "4",
+            "--lsm-dir",
+            "segments",
+            "--lsm-memtable-size",
+            "1mb",
+            "--lsm-wal-fsync",
+            "no",
+            "--tiered-file",
+            "spill.kiwi",
+            "--compression-threshold",
+            "1kb",
+            "--list-max-listpack-size",
+            "1",
+            "--hash-max-listpack-entries",
+            "2",
+            "--hash-max-listpack-value",
+            "3",
+            "--set-max-intset-entries",
+            "4",
+            "--set-max-listpack-entries",
+            "5",
+            "--set-max-listpack-value",
+            "6",
+            "--zset-max-listpack-entries",
+            "7",
+            "--zset-max-listpack-value",
+            "8",
+            "--tracking-table-max-keys",
+            "100",
+        ]))
+        .unwrap();
+        assert_eq!(tcp_config, TcpConfig::new().host("0.0.0.0").port(7000));
+        assert_eq!(
+            kiwi_config,
+            KiwiConfig::new()
+                .with_databases(4)
+                .with_requirepass("secret")
+                .with_aclfile("users.acl")
+                .with_acllog_max_len(16)
+                .with_dir("/tmp")
+                .with_dbfilename("kiwi.snap")
+                .with_snapshot_format(SnapshotFormat::Rdb)
+                .with_save_rules(vec![SaveRule::new(900, 1), SaveRule::new(300, 10)])
+                .with_appendonly(true)
+                .with_appendfsync(AppendFsync::Always)
+                .with_replicaof("10.0.0.1", 6380)
+                .with_masteruser("replica")
+                .with_masterauth("secret")
+                .with_replica_read_only(false)
+                .with_repl_backlog_size(4096)
+                .with_replica_output_buffer_limit(8192)
+                .with_cluster(true)
+                .with_cluster_port(17000)
+                .with_cluster_config_file("nodes-7000.conf")
+                .with_cluster_node_timeout(5000)
+                .with_maxmemory(100 * 1024 * 1024)
+                .with_maxmemory_policy(MaxmemoryPolicy::AllKeysLru)
+                .with_maxmemory_samples(10)
+                .with_engine(EngineKind::Sharded)
+                .with_engine_shards(32)
+                .with_engine_threads(4)
+                .with_lsm_dir("segments")
+                .with_lsm_memtable_size(1024 * 1024)
+                .with_lsm_wal_fsync(AppendFsync::No)
+                .with_tiered_file("spill.kiwi")
+                .with_compression_threshold(1024)
+                .with_list_max_listpack_size(1)
+                .with_hash_max_listpack_entries(2)
+                .with_hash_max_listpack_value(3)
+                .with_set_max_intset_entries(4)
+                .with_set_max_listpack_entries(5)
+                .with_set_max_listpack_value(6)
+                .with_zset_max_listpack_entries(7)
+                .with_zset_max_listpack_value(8)
+                .with_tracking_table_max_keys(100)
+        );
+    }
+
+    #[test]
+    fn test_save_rules() {
+        let (_, kiwi_config) = parse_args(args(&["--save", ""])).unwrap();
+        assert!(kiwi_config.save_rules().is_empty());
+        assert!(parse_args(args(&["--save", "900"])).is_err());
+        assert!(parse_args(args(&["--save", "900 soon"])).is_err());
+    }
+
+    #[test]
+    fn test_memory_units() {
+        assert_eq!(parse_memory("--maxmemory", "1024"), Ok(1024));
+        assert_eq!(parse_memory("--maxmemory", "2k"), Ok(2000));
+        assert_eq!(parse_memory("--maxmemory", "2KB"), Ok(2048));
+        assert_eq!(parse_memory("--maxmemory", "1gb"), Ok(1 << 30));
+        assert!(parse_memory("--maxmemory", "1tb").is_err());
+        assert!(parse_memory("--maxmemory", "mb").is_err());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(parse_args(args(&["--port", "http"])).is_err());
+        assert!(parse_args(args(&["--replicaof", "10.0.0.1"])).is_err());
+        assert!(parse_args(args(&["--port"])).is_err());
+        assert!(parse_args(args(&["--verbose", "yes"])).is_err());
+        assert!(parse_args(args(&["--appendonly", "maybe"])).is_err());
+        assert!(parse_args(args(&["--appendfsync", "sometimes"])).is_err());
+        assert!(parse_args(args(&["--snapshot-format", "json"])).is_err());
+        assert!(parse_args(args(&["--maxmemory-policy", "lru"])).is_err());
+        assert!(parse_args(args(&["--engine", "btree"])).is_err());
+    }
+}
@@ -1,27 +1,181 @@
+mod args;
+
+use crate::args::parse_args;
+use oh_my_kiwi_domain::Engine;
 use oh_my_kiwi_domain::error::KiwiErrorHandler;
+use oh_my_kiwi_engine::acl::Acl;
+use oh_my_kiwi_engine::aof::AppendOnlyFile;
+use oh_my_kiwi_engine::cluster::Cluster;
 use oh_my_kiwi_engine::command_processor::KiwiCommandProcessor;
+use oh_my_kiwi_engine::config::{EngineKind, KiwiConfig};
 use oh_my_kiwi_engine::in_memory::InMemoryEngine;
+use oh_my_kiwi_engine::lsm::LsmEngine;
+use oh_my_kiwi_engine::propagation::WriteBarrier;
+use oh_my_kiwi_engine::replication::{ReplicaTarget, Replication};
 use oh_my_kiwi_engine::response_writer::KiwiResponseWriter;
+use oh_my_kiwi_engine::shared_nothing::SharedNothingEngine;
+use oh_my_kiwi_engine::sharded::ShardedEngine;
+use oh_my_kiwi_engine::snapshot::Snapshots;
+use oh_my_kiwi_engine::tiered::TieredEngine;
+use oh_my_kiwi_engine::tracking::TrackingTable;
 use oh_my_kiwi_parser::KiwiCommandParser;
-use oh_my_kiwi_tcp::start_tcp_server;
+use oh_my_kiwi_tcp::config::TcpConfig;
+use oh_my_kiwi_tcp::{start_tcp_server, start_tcp_server_on_cores};
 use std::sync::Arc;
+use tokio::runtime::Handle;
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let engine = Arc::new(InMemoryEngine::new());
+    let (tcp_config, kiwi_config) = parse_args(std::env::args().skip(1))
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    match kiwi_config.engine() {
+        EngineKind::InMemory => {
+            let engine = InMemoryEngine::from_config(&kiwi_config);
+            serve(Arc::new(engine), None, tcp_config, kiwi_config).await
+        }
+        EngineKind::Sharded => {
+            let engine = ShardedEngine::from_config(&kiwi_config);
+            info!("Storing keys in {} shards", engine.shard_count());
+            serve(Arc::new(engine), None, tcp_config, kiwi_config).await
+        }
+        EngineKind::SharedNothing => {
+            let engine = SharedNothingEngine::from_config(&kiwi_config)?;
+            let cores = engine.runtimes();
+            info!("Serving keys and connections on {} cores", cores.len());
+            serve(Arc::new(engine), Some(cores), tcp_config, kiwi_config).await
+        }
+        EngineKind::Lsm => {
+            let engine = LsmEngine::from_config(&kiwi_config)?;
+            info!("Storing keys in {}", kiwi_config.lsm_path().display());
+            serve(Arc::new(engine), None, tcp_config, kiwi_config).await
+        }
+        EngineKind::Tiered => {
+            let engine = TieredEngine::from_config(&kiwi_config)?;
+            let spill = kiwi_config.tiered_path();
+            info!("Spilling cold values to {}", spill.display());
+            serve(Arc::new(engine), None, tcp_config, kiwi_config).await
+        }
+    }
+}
+
+/// Runs the server on top of `engine`, serving connections on the `cores`
+/// runtimes when given.
+async fn serve<E>(
+    engine: Arc<E>,
+    cores: Option<Vec<Handle>>,
+    tcp_config: TcpConfig,
+    kiwi_config: KiwiConfig,
+) -> std::io::Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+{
+    let tracking = Arc::new(TrackingTable::new(&kiwi_config));
+    let acl = Arc::new(Acl::new(&kiwi_config)?);
+
+    let barrier = Arc::new(WriteBarrier::default());
+    let snapshots = Arc::new(Snapshots::new(&kiwi_config));
+    // A persistent engine comes back with its keys, which loading the data
+    // set again would only apply twice.
+    let mut recovered = 0;
+    for db in 0..engine.databases() {
+        recovered += engine.size(db).await;
+    }
+    if recovered > 0 {
+        info!("Kept {recovered} keys the engine recovered");
+    }
+    let aof = if kiwi_config.appendonly() {
+        let aof = Arc::new(AppendOnlyFile::open(&kiwi_config, barrier.clone())?);
+        if recovered == 0 {
+            let commands = aof.load(engine.as_ref()).await?;
+            info!("Replayed {commands} commands from {}", aof.path().display());
+        }
+        tokio::spawn(aof.clone().run_fsync());
+        Some(aof)
+    } else {
+        if recovered == 0 {
+            let keys = snapshots.load(engine.as_ref()).await?;
+            info!("Loaded {keys} keys from {}", snapshots.path().display());
+        }
+        None
+    };
+    tokio::spawn(snapshots.clone().run_save_rules(engine.clone()));
+
+    let replication = Arc::new(Replication::new(
+        &kiwi_config,
+        tcp_config.port_u16(),
+        barrier,
+        aof.clone(),
+    ));
+    let target = ReplicaTarget {
+        engine: engine.clone(),
+        tracking: tracking.clone(),
+        snapshots: snapshots.clone(),
+    };
+    tokio::spawn(replication.clone().run_active_expire(target.clone()));
+    if let Some((host, port)) = kiwi_config.replicaof() {
+        replication.follow(host.to_string(), port, target.clone());
+    }
+
+    let cluster = if kiwi_config.cluster() {
+        let cluster = Arc::new(Cluster::new(
+            &kiwi_config,
+            tcp_config.host_str(),
+            tcp_config.port_u16(),
+        )?);
+        // The cluster decides whom this node replicates, and promotes it on failover.
+        let replication = replication.clone();
+        cluster
+            .start(move |leader| match leader {
+                Some((host, port)) => replication.follow(host, port, target.clone()),
+                None => replication.promote(),
+            })
+            .await?;
+        Some(cluster)
+    } else {
+        None
+    };
 
-    let processor_factory = move || KiwiCommandProcessor::new(engine.clone());
+    let processor_factory = move |client_addr| {
+        KiwiCommandProcessor::new(
+            engine.clone(),
+            tracking.clone(),
+            acl.clone(),
+            snapshots.clone(),
+            aof.clone(),
+            replication.clone(),
+            cluster.clone(),
+            client_addr,
+        )
+    };
     let parser_factory = move |byte_reader| KiwiCommandParser::new(byte_reader);
     let response_writer_factory = move |byte_writer| KiwiResponseWriter::new(byte_writer);
     let error_handler_factory = move || KiwiErrorHandler::new();
 
-    start_tcp_server(
-        processor_factory,
-        parser_factory,
-        response_writer_factory,
-        error_handler_factory,
-    )
-    .await
+    match cores {
+        Some(cores) => {
+            start_tcp_server_on_cores(
+                tcp_config,
+                cores,
+                processor_factory,
+                parser_factory,
+                response_writer_factory,
+                error_handler_factory,
+            )
+            .await
+        }
+        None => {
+            start_tcp_server(
+                tcp_config,
+                processor_factory,
+                parser_factory,
+                response_writer_factory,
+                error_handler_factory,
+            )
+            .await
+        }
+    }
 }
@@ -1,13 +1,191 @@
+use crate::command::acl::AclCommand;
+use crate::command::cluster::ClusterCommand;
 use crate::error::CommandError;
 use crate::types::Types;
 
+pub mod acl;
+pub mod cluster;
+
 #[derive(Debug)]
 pub enum KiwiCommand {
     None,
     Ping,
     Command(String),
-    Set { key: Types, value: Types },
-    Get { key: Types },
+    Set {
+        key: Types,
+        value: Types,
+    },
+    Get {
+        key: Types,
+    },
+    MGet {
+        keys: Vec<Types>,
+    },
+    MSet {
+        pairs: Vec<(Types, Types)>,
+    },
+    Del {
+        keys: Vec<Types>,
+    },
+    /// DEL freeing large values in the background.
+    Unlink {
+        keys: Vec<Types>,
+    },
+    Client(ClientCommand),
+    Select { index: usize },
+    SwapDb { first: usize, second: usize },
+    Move { key: Types, db: usize },
+    DbSize,
+    FlushDb {
+        lazy: bool,
+    },
+    FlushAll {
+        lazy: bool,
+    },
+    Auth {
+        username: Option<String>,
+        password: String,
+    },
+    Hello(HelloOptions),
+    Quit,
+    Acl(AclCommand),
+    Expire {
+        key: Types,
+        seconds: i64,
+    },
+    PExpire {
+        key: Types,
+        milliseconds: i64,
+    },
+    ExpireAt {
+        key: Types,
+        timestamp: i64,
+    },
+    PExpireAt {
+        key: Types,
+        timestamp_ms: i64,
+    },
+    Ttl {
+        key: Types,
+    },
+    PTtl {
+        key: Types,
+    },
+    Persist {
+        key: Types,
+    },
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    Debug(DebugCommand),
+    Object(ObjectCommand),
+    Memory(MemoryCommand),
+    Dump {
+        key: Types,
+    },
+    Restore {
+        key: Types,
+        ttl: i64,
+        payload: Vec<u8>,
+        options: RestoreOptions,
+    },
+    /// Follow the leader at `(host, port)`, or stop following with `None`.
+    ReplicaOf(Option<(String, u16)>),
+    ReplConf(Vec<(String, String)>),
+    Psync {
+        replid: String,
+        offset: i64,
+    },
+    Sync,
+    Info(Vec<String>),
+    Wait {
+        replicas: i64,
+        timeout_ms: u64,
+    },
+    WaitAof {
+        local: i64,
+        replicas: i64,
+        timeout_ms: u64,
+    },
+    Cluster(ClusterCommand),
+    /// Lets the next command access a slot this cluster node is importing.
+    Asking,
+    Migrate {
+        host: String,
+        port: u16,
+        keys: Vec<Types>,
+        db: usize,
+        timeout_ms: u64,
+        options: MigrateOptions,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientCommand {
+    Id,
+    Tracking(TrackingOptions),
+    Caching(bool),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DebugCommand {
+    /// Save the snapshot, empty the databases and load it back.
+    Reload,
+}
+
+#[derive(Debug)]
+pub enum ObjectCommand {
+    /// How the value of `key` is stored.
+    Encoding { key: Types },
+}
+
+#[derive(Debug)]
+pub enum MemoryCommand {
+    /// Bytes `key` takes. Values are sized exactly, so `samples`, how many
+    /// nested elements Redis looks at to estimate, is only validated.
+    Usage {
+        key: Types,
+        samples: Option<usize>,
+    },
+    Stats,
+    Doctor,
+    MallocStats,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RestoreOptions {
+    pub replace: bool,
+    /// The TTL is a unix time in milliseconds rather than a duration.
+    pub absttl: bool,
+    pub idle_time: Option<u64>,
+    pub freq: Option<u8>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MigrateOptions {
+    /// Keep the keys on this instance.
+    pub copy: bool,
+    pub replace: bool,
+    /// Credentials for the target, with the username unless it is the default user.
+    pub auth: Option<(Option<String>, String)>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HelloOptions {
+    pub protocol: Option<i64>,
+    pub auth: Option<(String, String)>,
+    pub client_name: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrackingOptions {
+    pub enabled: bool,
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    pub optin: bool,
+    pub optout: bool,
+    pub noloop: bool,
 }
 
 impl KiwiCommand {
@@ -19,10 +197,200 @@ impl KiwiCommand {
             "COMMAND" => Self::create_command(args),
             "GET" => Self::create_get(args),
             "SET" => Self::create_set(args),
+            "MGET" => match args.is_empty() {
+                true => Err(CommandError::WrongNumberOfArguments),
+                false => Ok(KiwiCommand::MGet { keys: args }),
+            },
+            "MSET" => Self::create_mset(args),
+            "DEL" => match args.is_empty() {
+                true => Err(CommandError::WrongNumberOfArguments),
+                false => Ok(KiwiCommand::Del { keys: args }),
+            },
+            "UNLINK" => match args.is_empty() {
+                true => Err(CommandError::WrongNumberOfArguments),
+                false => Ok(KiwiCommand::Unlink { keys: args }),
+            },
+            "CLIENT" => Self::create_client(args),
+            "SELECT" => Self::create_select(args),
+            "SWAPDB" => Self::create_swapdb(args),
+            "MOVE" => Self::create_move(args),
+            "DBSIZE" => Self::create_dbsize(args),
+            "FLUSHDB" => Ok(KiwiCommand::FlushDb {
+                lazy: Self::create_flush_mode(args)?,
+            }),
+            "FLUSHALL" => Ok(KiwiCommand::FlushAll {
+                lazy: Self::create_flush_mode(args)?,
+            }),
+            "AUTH" => Self::create_auth(args),
+            "HELLO" => Self::create_hello(args),
+            "QUIT" => Ok(KiwiCommand::Quit),
+            "ACL" => acl::create_acl(args),
+            "EXPIRE" => Self::create_expire(args, false),
+            "PEXPIRE" => Self::create_expire(args, true),
+            "EXPIREAT" => Self::create_expire_at(args, false),
+            "PEXPIREAT" => Self::create_expire_at(args, true),
+            "TTL" => Ok(KiwiCommand::Ttl {
+                key: Self::single_key(args)?,
+            }),
+            "PTTL" => Ok(KiwiCommand::PTtl {
+                key: Self::single_key(args)?,
+            }),
+            "PERSIST" => Ok(KiwiCommand::Persist {
+                key: Self::single_key(args)?,
+            }),
+            "SAVE" => Self::create_no_args(args, KiwiCommand::Save),
+            "BGSAVE" => Self::create_no_args(args, KiwiCommand::BgSave),
+            "LASTSAVE" => Self::create_no_args(args, KiwiCommand::LastSave),
+            "BGREWRITEAOF" => Self::create_no_args(args, KiwiCommand::BgRewriteAof),
+            "DEBUG" => Self::create_debug(args),
+            "OBJECT" => Self::create_object(args),
+            "MEMORY" => Self::create_memory(args),
+            "DUMP" => Ok(KiwiCommand::Dump {
+                key: Self::single_key(args)?,
+            }),
+            "RESTORE" => Self::create_restore(args),
+            "REPLICAOF" | "SLAVEOF" => Self::create_replicaof(args),
+            "REPLCONF" => Self::create_replconf(args),
+            "PSYNC" => Self::create_psync(args),
+            "SYNC" => Self::create_no_args(args, KiwiCommand::Sync),
+            "WAIT" => {
+                let [replicas, timeout] = <[Types; 2]>::try_from(args)
+                    .map_err(|_| CommandError::WrongNumberOfArguments)?;
+                Ok(KiwiCommand::Wait {
+                    replicas: int_arg(&replicas)?,
+                    timeout_ms: timeout_arg(&timeout)?,
+                })
+            }
+            "WAITAOF" => {
+                let [local, replicas, timeout] = <[Types; 3]>::try_from(args)
+                    .map_err(|_| CommandError::WrongNumberOfArguments)?;
+                Ok(KiwiCommand::WaitAof {
+                    local: int_arg(&local)?,
+                    replicas: int_arg(&replicas)?,
+                    timeout_ms: timeout_arg(&timeout)?,
+                })
+            }
+            "CLUSTER" => cluster::create_cluster(args),
+            "ASKING" => Self::create_no_args(args, KiwiCommand::Asking),
+            "MIGRATE" => Self::create_migrate(args),
+            "INFO" => Ok(KiwiCommand::Info(
+                args.iter()
+                    .map(|arg| string_arg(arg).map(|section| section.to_lowercase()))
+                    .collect::<Result<_, _>>()?,
+            )),
             _ => Err(CommandError::UnsupportedCommand),
         }
     }
 
+    /// Lowercase command name, as used by ACL rules.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KiwiCommand::None => "none",
+            KiwiCommand::Ping => "ping",
+            KiwiCommand::Command(_) => "command",
+            KiwiCommand::Set { .. } => "set",
+            KiwiCommand::Get { .. } => "get",
+            KiwiCommand::MGet { .. } => "mget",
+            KiwiCommand::MSet { .. } => "mset",
+            KiwiCommand::Del { .. } => "del",
+            KiwiCommand::Unlink { .. } => "unlink",
+            KiwiCommand::Client(_) => "client",
+            KiwiCommand::Select { .. } => "select",
+            KiwiCommand::SwapDb { .. } => "swapdb",
+            KiwiCommand::Move { .. } => "move",
+            KiwiCommand::DbSize => "dbsize",
+            KiwiCommand::FlushDb { .. } => "flushdb",
+            KiwiCommand::FlushAll { .. } => "flushall",
+            KiwiCommand::Auth { .. } => "auth",
+            KiwiCommand::Hello(_) => "hello",
+            KiwiCommand::Quit => "quit",
+            KiwiCommand::Acl(_) => "acl",
+            KiwiCommand::Expire { .. } => "expire",
+            KiwiCommand::PExpire { .. } => "pexpire",
+            KiwiCommand::ExpireAt { .. } => "expireat",
+            KiwiCommand::PExpireAt { .. } => "pexpireat",
+            KiwiCommand::Ttl { .. } => "ttl",
+            KiwiCommand::PTtl { .. } => "pttl",
+            KiwiCommand::Persist { .. } => "persist",
+            KiwiCommand::Save => "save",
+            KiwiCommand::BgSave => "bgsave",
+            KiwiCommand::LastSave => "lastsave",
+            KiwiCommand::BgRewriteAof => "bgrewriteaof",
+            KiwiCommand::Debug(_) => "debug",
+            KiwiCommand::Object(_) => "object",
+            KiwiCommand::Memory(_) => "memory",
+            KiwiCommand::Dump { .. } => "dump",
+            KiwiCommand::Restore { .. } => "restore",
+            KiwiCommand::ReplicaOf(_) => "replicaof",
+            KiwiCommand::ReplConf(_) => "replconf",
+            KiwiCommand::Psync { .. } => "psync",
+            KiwiCommand::Sync => "sync",
+            KiwiCommand::Info(_) => "info",
+            KiwiCommand::Wait { .. } => "wait",
+            KiwiCommand::WaitAof { .. } => "waitaof",
+            KiwiCommand::Cluster(_) => "cluster",
+            KiwiCommand::Asking => "asking",
+            KiwiCommand::Migrate { .. } => "migrate",
+        }
+    }
+
+    /// Lowercase subcommand name for container commands like CLIENT and ACL.
+    pub fn subcommand_name(&self) -> Option<&'static str> {
+        match self {
+            KiwiCommand::Client(command) => Some(match command {
+                ClientCommand::Id => "id",
+                ClientCommand::Tracking(_) => "tracking",
+                ClientCommand::Caching(_) => "caching",
+            }),
+            KiwiCommand::Acl(command) => Some(command.name()),
+            KiwiCommand::Debug(DebugCommand::Reload) => Some("reload"),
+            KiwiCommand::Object(ObjectCommand::Encoding { .. }) => Some("encoding"),
+            KiwiCommand::Memory(command) => Some(match command {
+                MemoryCommand::Usage { .. } => "usage",
+                MemoryCommand::Stats => "stats",
+                MemoryCommand::Doctor => "doctor",
+                MemoryCommand::MallocStats => "malloc-stats",
+            }),
+            KiwiCommand::Cluster(command) => Some(command.name()),
+            _ => None,
+        }
+    }
+
+    /// Keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&Types> {
+        match self {
+            KiwiCommand::Set { key, .. }
+            | KiwiCommand::Get { key }
+            | KiwiCommand::Move { key, .. }
+            | KiwiCommand::Expire { key, .. }
+            | KiwiCommand::PExpire { key, .. }
+            | KiwiCommand::ExpireAt { key, .. }
+            | KiwiCommand::PExpireAt { key, .. }
+            | KiwiCommand::Ttl { key }
+            | KiwiCommand::PTtl { key }
+            | KiwiCommand::Persist { key }
+            | KiwiCommand::Dump { key }
+            | KiwiCommand::Restore { key, .. }
+            | KiwiCommand::Object(ObjectCommand::Encoding { key })
+            | KiwiCommand::Memory(MemoryCommand::Usage { key, .. }) => {
+                vec![key]
+            }
+            KiwiCommand::MGet { keys }
+            | KiwiCommand::Del { keys }
+            | KiwiCommand::Unlink { keys }
+            | KiwiCommand::Migrate { keys, .. } => keys.iter().collect(),
+            KiwiCommand::MSet { pairs } => pairs.iter().map(|(key, _)| key).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Pub/sub channels the command publishes or subscribes to. No command
+    /// names a channel yet; pub/sub commands list theirs here so the ACL
+    /// checks them against the user's channel patterns.
+    pub fn channels(&self) -> Vec<&Types> {
+        vec![]
+    }
+
     fn create_command(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
         if args.is_empty() || args.len() > 1 {
             Err(CommandError::WrongNumberOfArguments)
@@ -53,4 +421,848 @@ impl KiwiCommand {
             })
         }
     }
+
+    fn create_mset(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Err(CommandError::WrongNumberOfArguments);
+        }
+        let mut args = args.into_iter();
+        let mut pairs = Vec::new();
+        while let (Some(key), Some(value)) = (args.next(), args.next()) {
+            pairs.push((key, value));
+        }
+        Ok(KiwiCommand::MSet { pairs })
+    }
+
+    fn create_expire(args: Vec<Types>, milliseconds: bool) -> Result<KiwiCommand, CommandError> {
+        let [key, ttl] =
+            <[Types; 2]>::try_from(args).map_err(|_| CommandError::WrongNumberOfArguments)?;
+        let ttl = int_arg(&ttl)?;
+        Ok(match milliseconds {
+            false => KiwiCommand::Expire { key, seconds: ttl },
+            true => KiwiCommand::PExpire {
+                key,
+                milliseconds: ttl,
+            },
+        })
+    }
+
+    fn create_expire_at(args: Vec<Types>, milliseconds: bool) -> Result<KiwiCommand, CommandError> {
+        let [key, timestamp] =
+            <[Types; 2]>::try_from(args).map_err(|_| CommandError::WrongNumberOfArguments)?;
+        let timestamp = int_arg(&timestamp)?;
+        Ok(match milliseconds {
+            false => KiwiCommand::ExpireAt { key, timestamp },
+            true => KiwiCommand::PExpireAt {
+                key,
+                timestamp_ms: timestamp,
+            },
+        })
+    }
+
+    fn single_key(args: Vec<Types>) -> Result<Types, CommandError> {
+        let [key] =
+            <[Types; 1]>::try_from(args).map_err(|_| CommandError::WrongNumberOfArguments)?;
+        Ok(key)
+    }
+
+    fn create_no_args(args: Vec<Types>, command: KiwiCommand) -> Result<KiwiCommand, CommandError> {
+        if !args.is_empty() {
+            return Err(CommandError::WrongNumberOfArguments);
+        }
+        Ok(command)
+    }
+
+    fn create_restore(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let mut args = args.into_iter();
+        let (Some(key), Some(ttl), Some(payload)) = (args.next(), args.next(), args.next()) else {
+            return Err(CommandError::WrongNumberOfArguments);
+        };
+        let ttl = int_arg(&ttl)?;
+        if ttl < 0 {
+            return Err(CommandError::InvalidTtl);
+        }
+        let payload = bytes_arg(&payload)?;
+
+        let mut options = RestoreOptions::default();
+        while let Some(arg) = args.next() {
+            match string_arg(&arg)?.to_uppercase().as_str() {
+                "REPLACE" => options.replace = true,
+                "ABSTTL" => options.absttl = true,
+                "IDLETIME" if options.freq.is_none() => {
+                    let idle_time = args.next().ok_or(CommandError::SyntaxError)?;
+                    let idle_time = u64::try_from(int_arg(&idle_time)?)
+                        .map_err(|_| CommandError::InvalidIdleTime)?;
+                    options.idle_time = Some(idle_time);
+                }
+                "FREQ" if options.idle_time.is_none() => {
+                    let freq = args.next().ok_or(CommandError::SyntaxError)?;
+                    let freq =
+                        u8::try_from(int_arg(&freq)?).map_err(|_| CommandError::InvalidFreq)?;
+                    options.freq = Some(freq);
+                }
+                _ => return Err(CommandError::SyntaxError),
+            }
+        }
+
+        Ok(KiwiCommand::Restore {
+            key,
+            ttl,
+            payload,
+            options,
+        })
+    }
+
+    fn create_migrate(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let mut args = args.into_iter();
+        let (Some(host), Some(port), Some(key), Some(db), Some(timeout)) = (
+            args.next(),
+            args.next(),
+            args.next(),
+            args.next(),
+            args.next(),
+        ) else {
+            return Err(CommandError::WrongNumberOfArguments);
+        };
+        let host = string_arg(&host)?;
+        let port = string_arg(&port)?
+            .parse()
+            .map_err(|_| CommandError::NotAnInteger)?;
+        let db = db_index_arg(&db)?;
+        let timeout_ms = timeout_arg(&timeout)?;
+
+        let mut options = MigrateOptions::default();
+        let mut keys = None;
+        while let Some(arg) = args.next() {
+            match string_arg(&arg)?.to_uppercase().as_str() {
+                "COPY" => options.copy = true,
+                "REPLACE" => options.replace = true,
+                "AUTH" => {
+                    let password = args.next().ok_or(CommandError::SyntaxError)?;
+                    options.auth = Some((None, string_arg(&password)?));
+                }
+                "AUTH2" => match (args.next(), args.next()) {
+                    (Some(username), Some(password)) => {
+                        options.auth = Some((Some(string_arg(&username)?), string_arg(&password)?))
+                    }
+                    _ => return Err(CommandError::SyntaxError),
+                },
+                "KEYS" => {
+                    if !bytes_arg(&key)?.is_empty() {
+                        return Err(CommandError::MigrateKeysWithKey);
+                    }
+                    keys = Some(args.by_ref().collect());
+                }
+                _ => return Err(CommandError::SyntaxError),
+            }
+        }
+
+        Ok(KiwiCommand::Migrate {
+            host,
+            port,
+            keys: keys.unwrap_or(vec![key]),
+            db,
+            timeout_ms,
+            options,
+        })
+    }
+
+    fn create_replicaof(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let [host, port] =
+            <[Types; 2]>::try_from(args).map_err(|_| CommandError::WrongNumberOfArguments)?;
+        let host = string_arg(&host)?;
+        let port = string_arg(&port)?;
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            return Ok(KiwiCommand::ReplicaOf(None));
+        }
+        let port = port.parse().map_err(|_| CommandError::NotAnInteger)?;
+        Ok(KiwiCommand::ReplicaOf(Some((host, port))))
+    }
+
+    fn create_replconf(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        if !args.len().is_multiple_of(2) {
+            return Err(CommandError::SyntaxError);
+        }
+        let options = args
+            .chunks(2)
+            .map(|pair| Ok((string_arg(&pair[0])?.to_lowercase(), string_arg(&pair[1])?)))
+            .collect::<Result<_, CommandError>>()?;
+        Ok(KiwiCommand::ReplConf(options))
+    }
+
+    fn create_psync(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let [replid, offset] =
+            <[Types; 2]>::try_from(args).map_err(|_| CommandError::WrongNumberOfArguments)?;
+        Ok(KiwiCommand::Psync {
+            replid: string_arg(&replid)?,
+            offset: int_arg(&offset)?,
+        })
+    }
+
+    fn create_debug(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let mut args = args.into_iter();
+        let subcommand = match args.next() {
+            Some(arg) => string_arg(&arg)?.to_uppercase(),
+            None => return Err(CommandError::WrongNumberOfArguments),
+        };
+
+        match subcommand.as_str() {
+            "RELOAD" => {
+                Self::create_no_args(args.collect(), KiwiCommand::Debug(DebugCommand::Reload))
+            }
+            _ => Err(CommandError::UnknownSubcommand(subcommand)),
+        }
+    }
+
+    fn create_object(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let mut args = args.into_iter();
+        let subcommand = match args.next() {
+            Some(arg) => string_arg(&arg)?.to_uppercase(),
+            None => return Err(CommandError::WrongNumberOfArguments),
+        };
+
+        match subcommand.as_str() {
+            "ENCODING" => Ok(KiwiCommand::Object(ObjectCommand::Encoding {
+                key: Self::single_key(args.collect())?,
+            })),
+            _ => Err(CommandError::UnknownSubcommand(subcommand)),
+        }
+    }
+
+    fn create_memory(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let mut args = args.into_iter();
+        let subcommand = match args.next() {
+            Some(arg) => string_arg(&arg)?.to_uppercase(),
+            None => return Err(CommandError::WrongNumberOfArguments),
+        };
+        let args: Vec<Types> = args.collect();
+
+        let command = match subcommand.as_str() {
+            "USAGE" => Self::create_memory_usage(args)?,
+            "STATS" | "DOCTOR" | "MALLOC-STATS" if !args.is_empty() => {
+                return Err(CommandError::WrongNumberOfArguments);
+            }
+            "STATS" => MemoryCommand::Stats,
+            "DOCTOR" => MemoryCommand::Doctor,
+            "MALLOC-STATS" => MemoryCommand::MallocStats,
+            _ => return Err(CommandError::UnknownSubcommand(subcommand)),
+        };
+        Ok(KiwiCommand::Memory(command))
+    }
+
+    fn create_memory_usage(args: Vec<Types>) -> Result<MemoryCommand, CommandError> {
+        let mut args = args.into_iter();
+        let key = args.next().ok_or(CommandError::WrongNumberOfArguments)?;
+        let mut samples = None;
+        while let Some(arg) = args.next() {
+            match string_arg(&arg)?.to_uppercase().as_str() {
+                "SAMPLES" => {
+                    let count = args.next().ok_or(CommandError::SyntaxError)?;
+                    let count =
+                        usize::try_from(int_arg(&count)?).map_err(|_| CommandError::SyntaxError)?;
+                    samples = Some(count);
+                }
+                _ => return Err(CommandError::SyntaxError),
+            }
+        }
+        Ok(MemoryCommand::Usage { key, samples })
+    }
+
+    fn create_client(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let mut args = args.into_iter();
+        let subcommand = match args.next() {
+            Some(arg) => string_arg(&arg)?.to_uppercase(),
+            None => return Err(CommandError::WrongNumberOfArguments),
+        };
+        let args: Vec<Types> = args.collect();
+
+        let command = match subcommand.as_str() {
+            "ID" if args.is_empty() => ClientCommand::Id,
+            "ID" => return Err(CommandError::WrongNumberOfArguments),
+            "TRACKING" => ClientCommand::Tracking(Self::create_tracking_options(args)?),
+            "CACHING" => {
+                if args.len() != 1 {
+                    return Err(CommandError::WrongNumberOfArguments);
+                }
+                match string_arg(&args[0])?.to_uppercase().as_str() {
+                    "YES" => ClientCommand::Caching(true),
+                    "NO" => ClientCommand::Caching(false),
+                    _ => return Err(CommandError::SyntaxError),
+                }
+            }
+            _ => return Err(CommandError::UnknownSubcommand(subcommand)),
+        };
+        Ok(KiwiCommand::Client(command))
+    }
+
+    fn create_tracking_options(args: Vec<Types>) -> Result<TrackingOptions, CommandError> {
+        let mut args = args.into_iter();
+        let enabled = match args.next() {
+            Some(arg) => match string_arg(&arg)?.to_uppercase().as_str() {
+                "ON" => true,
+                "OFF" => false,
+                _ => return Err(CommandError::SyntaxError),
+            },
+            None => return Err(CommandError::WrongNumberOfArguments),
+        };
+        let mut options = TrackingOptions {
+            enabled,
+            ..TrackingOptions::default()
+        };
+
+        while let Some(arg) = args.next() {
+            match string_arg(&arg)?.to_uppercase().as_str() {
+                "BCAST" => options.bcast = true,
+                "OPTIN" => options.optin = true,
+                "OPTOUT" => options.optout = true,
+                "NOLOOP" => options.noloop = true,
+                "PREFIX" => match args.next() {
+                    Some(prefix) => options.prefixes.push(string_arg(&prefix)?),
+                    None => return Err(CommandError::SyntaxError),
+                },
+                "REDIRECT" => return Err(CommandError::TrackingRedirectUnsupported),
+                _ => return Err(CommandError::SyntaxError),
+            }
+        }
+
+        if !options.prefixes.is_empty() && !options.bcast {
+            return Err(CommandError::TrackingPrefixRequiresBcast);
+        }
+        if options.bcast && (options.optin || options.optout) {
+            return Err(CommandError::TrackingBcastWithOptInOut);
+        }
+        if options.optin && options.optout {
+            return Err(CommandError::TrackingOptInWithOptOut);
+        }
+        Ok(options)
+    }
+
+    fn create_select(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        if args.len() != 1 {
+            return Err(CommandError::WrongNumberOfArguments);
+        }
+        Ok(KiwiCommand::Select {
+            index: db_index_arg(&args[0])?,
+        })
+    }
+
+    fn create_swapdb(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        if args.len() != 2 {
+            return Err(CommandError::WrongNumberOfArguments);
+        }
+        Ok(KiwiCommand::SwapDb {
+            first: db_index_arg(&args[0])?,
+            second: db_index_arg(&args[1])?,
+        })
+    }
+
+    fn create_move(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        if args.len() != 2 {
+            return Err(CommandError::WrongNumberOfArguments);
+        }
+        Ok(KiwiCommand::Move {
+            key: args[0].clone(),
+            db: db_index_arg(&args[1])?,
+        })
+    }
+
+    fn create_dbsize(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        if !args.is_empty() {
+            return Err(CommandError::WrongNumberOfArguments);
+        }
+        Ok(KiwiCommand::DbSize)
+    }
+
+    /// Parses the optional `ASYNC | SYNC` argument of FLUSHDB and FLUSHALL.
+    fn create_flush_mode(args: Vec<Types>) -> Result<bool, CommandError> {
+        match args.as_slice() {
+            [] => Ok(false),
+            [mode] => match string_arg(mode)?.to_uppercase().as_str() {
+                "ASYNC" => Ok(true),
+                "SYNC" => Ok(false),
+                _ => Err(CommandError::SyntaxError),
+            },
+            _ => Err(CommandError::SyntaxError),
+        }
+    }
+
+    fn create_auth(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        match args.as_slice() {
+            [password] => Ok(KiwiCommand::Auth {
+                username: None,
+                password: string_arg(password)?,
+            }),
+            [username, password] => Ok(KiwiCommand::Auth {
+                username: Some(string_arg(username)?),
+                password: string_arg(password)?,
+            }),
+            _ => Err(CommandError::WrongNumberOfArguments),
+        }
+    }
+
+    fn create_hello(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+        let mut args = args.into_iter();
+        let mut options = HelloOptions {
+            protocol: match args.next() {
+                Some(protocol) => Some(int_arg(&protocol).map_err(|_| CommandError::NoProto)?),
+                None => None,
+            },
+            ..HelloOptions::default()
+        };
+
+        while let Some(arg) = args.next() {
+            match string_arg(&arg)?.to_uppercase().as_str() {
+                "AUTH" => match (args.next(), args.next()) {
+                    (Some(username), Some(password)) => {
+                        options.auth = Some((string_arg(&username)?, string_arg(&password)?))
+                    }
+                    _ => return Err(CommandError::SyntaxError),
+                },
+                "SETNAME" => match args.next() {
+                    Some(name) => options.client_name = Some(string_arg(&name)?),
+                    None => return Err(CommandError::SyntaxError),
+                },
+                _ => return Err(CommandError::SyntaxError),
+            }
+        }
+        Ok(KiwiCommand::Hello(options))
+    }
+}
+
+fn db_index_arg(arg: &Types) -> Result<usize, CommandError> {
+    usize::try_from(int_arg(arg)?).map_err(|_| CommandError::DbIndexOutOfRange)
+}
+
+fn int_arg(arg: &Types) -> Result<i64, CommandError> {
+    match arg {
+        Types::Integer(value) => Ok(*value),
+        _ => string_arg(arg)?
+            .parse()
+            .map_err(|_| CommandError::NotAnInteger),
+    }
+}
+
+fn timeout_arg(arg: &Types) -> Result<u64, CommandError> {
+    u64::try_from(int_arg(arg)?).map_err(|_| CommandError::NegativeTimeout)
+}
+
+fn bytes_arg(arg: &Types) -> Result<Vec<u8>, CommandError> {
+    match arg {
+        Types::BulkBytes(value) => Ok(value.clone()),
+        _ => Ok(string_arg(arg)?.into_bytes()),
+    }
+}
+
+fn string_arg(arg: &Types) -> Result<String, CommandError> {
+    match arg {
+        Types::BulkString(value) | Types::SimpleString(value) => Ok(value.clone()),
+        _ => Err(CommandError::WrongArgumentType),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<Types> {
+        values
+            .iter()
+            .map(|value| Types::BulkString(value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_client_id() {
+        let command = KiwiCommand::parse_command("client", args(&["id"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Client(ClientCommand::Id)));
+    }
+
+    #[test]
+    fn test_client_tracking_bcast_with_prefixes() {
+        let command = KiwiCommand::parse_command(
+            "CLIENT",
+            args(&[
+                "TRACKING", "on", "BCAST", "PREFIX", "user:", "PREFIX", "session:", "NOLOOP",
+            ]),
+        )
+        .unwrap();
+
+        let KiwiCommand::Client(ClientCommand::Tracking(options)) = command else {
+            panic!("expected CLIENT TRACKING, got {:?}", command);
+        };
+        assert!(options.enabled);
+        assert!(options.bcast);
+        assert!(options.noloop);
+        assert_eq!(
+            options.prefixes,
+            vec!["user:".to_string(), "session:".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_client_tracking_off() {
+        let command = KiwiCommand::parse_command("CLIENT", args(&["TRACKING", "off"])).unwrap();
+        let KiwiCommand::Client(ClientCommand::Tracking(options)) = command else {
+            panic!("expected CLIENT TRACKING, got {:?}", command);
+        };
+        assert!(!options.enabled);
+    }
+
+    #[test]
+    fn test_client_tracking_prefix_requires_bcast() {
+        let result = KiwiCommand::parse_command("CLIENT", args(&["TRACKING", "on", "PREFIX", "a"]));
+        assert!(matches!(
+            result,
+            Err(CommandError::TrackingPrefixRequiresBcast)
+        ));
+    }
+
+    #[test]
+    fn test_client_tracking_incompatible_modes() {
+        let result =
+            KiwiCommand::parse_command("CLIENT", args(&["TRACKING", "on", "BCAST", "OPTIN"]));
+        assert!(matches!(
+            result,
+            Err(CommandError::TrackingBcastWithOptInOut)
+        ));
+
+        let result =
+            KiwiCommand::parse_command("CLIENT", args(&["TRACKING", "on", "OPTIN", "OPTOUT"]));
+        assert!(matches!(result, Err(CommandError::TrackingOptInWithOptOut)));
+    }
+
+    #[test]
+    fn test_client_caching() {
+        let command = KiwiCommand::parse_command("CLIENT", args(&["CACHING", "yes"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Client(ClientCommand::Caching(true))
+        ));
+
+        let result = KiwiCommand::parse_command("CLIENT", args(&["CACHING", "maybe"]));
+        assert!(matches!(result, Err(CommandError::SyntaxError)));
+    }
+
+    #[test]
+    fn test_select() {
+        let command = KiwiCommand::parse_command("SELECT", args(&["3"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Select { index: 3 }));
+
+        let result = KiwiCommand::parse_command("SELECT", args(&["-1"]));
+        assert!(matches!(result, Err(CommandError::DbIndexOutOfRange)));
+
+        let result = KiwiCommand::parse_command("SELECT", args(&["one"]));
+        assert!(matches!(result, Err(CommandError::NotAnInteger)));
+    }
+
+    #[test]
+    fn test_flush_modes() {
+        let command = KiwiCommand::parse_command("FLUSHALL", args(&["async"])).unwrap();
+        assert!(matches!(command, KiwiCommand::FlushAll { lazy: true }));
+
+        let command = KiwiCommand::parse_command("FLUSHDB", args(&[])).unwrap();
+        assert!(matches!(command, KiwiCommand::FlushDb { lazy: false }));
+
+        let result = KiwiCommand::parse_command("FLUSHDB", args(&["LATER"]));
+        assert!(matches!(result, Err(CommandError::SyntaxError)));
+    }
+
+    #[test]
+    fn test_auth() {
+        let command = KiwiCommand::parse_command("AUTH", args(&["secret"])).unwrap();
+        assert!(
+            matches!(command, KiwiCommand::Auth { username: None, password } if password == "secret")
+        );
+
+        let command = KiwiCommand::parse_command("AUTH", args(&["alice", "secret"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Auth { username: Some(username), .. } if username == "alice"
+        ));
+
+        let result = KiwiCommand::parse_command("AUTH", args(&[]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+    }
+
+    #[test]
+    fn test_hello() {
+        let command = KiwiCommand::parse_command(
+            "HELLO",
+            args(&["3", "AUTH", "default", "secret", "SETNAME", "worker"]),
+        )
+        .unwrap();
+        let KiwiCommand::Hello(options) = command else {
+            panic!("expected HELLO, got {:?}", command);
+        };
+        assert_eq!(options.protocol, Some(3));
+        assert_eq!(
+            options.auth,
+            Some(("default".to_string(), "secret".to_string()))
+        );
+        assert_eq!(options.client_name, Some("worker".to_string()));
+
+        let result = KiwiCommand::parse_command("HELLO", args(&["3", "AUTH", "default"]));
+        assert!(matches!(result, Err(CommandError::SyntaxError)));
+    }
+
+    #[test]
+    fn test_client_unknown_subcommand() {
+        let result = KiwiCommand::parse_command("CLIENT", args(&["frobnicate"]));
+        assert!(
+            matches!(result, Err(CommandError::UnknownSubcommand(name)) if name == "FROBNICATE")
+        );
+    }
+
+    #[test]
+    fn test_expire() {
+        let command = KiwiCommand::parse_command("EXPIRE", args(&["key", "10"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Expire { seconds: 10, .. }));
+
+        let command = KiwiCommand::parse_command("pexpire", args(&["key", "1500"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::PExpire {
+                milliseconds: 1500,
+                ..
+            }
+        ));
+
+        let command =
+            KiwiCommand::parse_command("PEXPIREAT", args(&["key", "1700000000000"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::PExpireAt {
+                timestamp_ms: 1_700_000_000_000,
+                ..
+            }
+        ));
+
+        let result = KiwiCommand::parse_command("EXPIRE", args(&["key", "soon"]));
+        assert!(matches!(result, Err(CommandError::NotAnInteger)));
+        let result = KiwiCommand::parse_command("TTL", args(&[]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+    }
+
+    #[test]
+    fn test_persistence_commands() {
+        let command = KiwiCommand::parse_command("bgsave", args(&[])).unwrap();
+        assert!(matches!(command, KiwiCommand::BgSave));
+
+        let result = KiwiCommand::parse_command("SAVE", args(&["now"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+
+        let command = KiwiCommand::parse_command("DEBUG", args(&["reload"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Debug(DebugCommand::Reload)));
+        assert_eq!(command.subcommand_name(), Some("reload"));
+
+        let result = KiwiCommand::parse_command("DEBUG", args(&["segfault"]));
+        assert!(matches!(result, Err(CommandError::UnknownSubcommand(_))));
+    }
+
+    #[test]
+    fn test_object() {
+        let command = KiwiCommand::parse_command("OBJECT", args(&["encoding", "key"])).unwrap();
+        assert_eq!(command.name(), "object");
+        assert_eq!(command.subcommand_name(), Some("encoding"));
+        assert_eq!(command.keys(), vec![&Types::BulkString("key".to_string())]);
+
+        let result = KiwiCommand::parse_command("OBJECT", args(&["encoding"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+        let result = KiwiCommand::parse_command("OBJECT", args(&["refcount", "key"]));
+        assert!(matches!(result, Err(CommandError::UnknownSubcommand(_))));
+    }
+
+    #[test]
+    fn test_memory() {
+        let command = KiwiCommand::parse_command("MEMORY", args(&["usage", "key"])).unwrap();
+        assert_eq!(command.name(), "memory");
+        assert_eq!(command.subcommand_name(), Some("usage"));
+        assert_eq!(command.keys(), vec![&Types::BulkString("key".to_string())]);
+        let command =
+            KiwiCommand::parse_command("MEMORY", args(&["USAGE", "key", "samples", "0"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Memory(MemoryCommand::Usage {
+                samples: Some(0),
+                ..
+            })
+        ));
+        let command = KiwiCommand::parse_command("MEMORY", args(&["malloc-stats"])).unwrap();
+        assert_eq!(command.subcommand_name(), Some("malloc-stats"));
+        assert!(command.keys().is_empty());
+
+        let result = KiwiCommand::parse_command("MEMORY", args(&["usage"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+        let result = KiwiCommand::parse_command("MEMORY", args(&["usage", "key", "samples", "-1"]));
+        assert!(matches!(result, Err(CommandError::SyntaxError)));
+        let result = KiwiCommand::parse_command("MEMORY", args(&["usage", "key", "samples"]));
+        assert!(matches!(result, Err(CommandError::SyntaxError)));
+        let result = KiwiCommand::parse_command("MEMORY", args(&["stats", "extra"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+        let result = KiwiCommand::parse_command("MEMORY", args(&["purge"]));
+        assert!(matches!(result, Err(CommandError::UnknownSubcommand(_))));
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut restore_args = args(&["key", "0"]);
+        restore_args.push(Types::BulkBytes(vec![0xFF]));
+        restore_args.extend(args(&["replace", "IDLETIME", "10"]));
+        let command = KiwiCommand::parse_command("RESTORE", restore_args).unwrap();
+        let KiwiCommand::Restore {
+            payload, options, ..
+        } = command
+        else {
+            panic!("expected RESTORE, got {:?}", command);
+        };
+        assert_eq!(payload, vec![0xFF]);
+        assert_eq!(
+            options,
+            RestoreOptions {
+                replace: true,
+                idle_time: Some(10),
+                ..RestoreOptions::default()
+            }
+        );
+
+        let result = KiwiCommand::parse_command("RESTORE", args(&["key", "-1", "x"]));
+        assert!(matches!(result, Err(CommandError::InvalidTtl)));
+        let result = KiwiCommand::parse_command("RESTORE", args(&["key", "0", "x", "FREQ", "256"]));
+        assert!(matches!(result, Err(CommandError::InvalidFreq)));
+        let result = KiwiCommand::parse_command(
+            "RESTORE",
+            args(&["key", "0", "x", "FREQ", "1", "IDLETIME", "1"]),
+        );
+        assert!(matches!(result, Err(CommandError::SyntaxError)));
+        let result = KiwiCommand::parse_command("RESTORE", args(&["key", "0"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+    }
+
+    #[test]
+    fn test_replication_commands() {
+        let command =
+            KiwiCommand::parse_command("REPLICAOF", args(&["localhost", "6380"])).unwrap();
+        assert!(
+            matches!(command, KiwiCommand::ReplicaOf(Some((host, 6380))) if host == "localhost")
+        );
+        let command = KiwiCommand::parse_command("slaveof", args(&["no", "one"])).unwrap();
+        assert!(matches!(command, KiwiCommand::ReplicaOf(None)));
+        let result = KiwiCommand::parse_command("REPLICAOF", args(&["localhost", "70000"]));
+        assert!(matches!(result, Err(CommandError::NotAnInteger)));
+
+        let command =
+            KiwiCommand::parse_command("REPLCONF", args(&["Listening-Port", "6380"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::ReplConf(options) if options == vec![("listening-port".to_string(), "6380".to_string())]
+        ));
+        let result = KiwiCommand::parse_command("REPLCONF", args(&["capa"]));
+        assert!(matches!(result, Err(CommandError::SyntaxError)));
+
+        let command = KiwiCommand::parse_command("PSYNC", args(&["?", "-1"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Psync { offset: -1, .. }));
+
+        let command = KiwiCommand::parse_command("WAIT", args(&["1", "100"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Wait {
+                replicas: 1,
+                timeout_ms: 100
+            }
+        ));
+        let command = KiwiCommand::parse_command("WAITAOF", args(&["1", "0", "0"])).unwrap();
+        assert!(matches!(command, KiwiCommand::WaitAof { local: 1, .. }));
+        let result = KiwiCommand::parse_command("WAIT", args(&["1", "-1"]));
+        assert!(matches!(result, Err(CommandError::NegativeTimeout)));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let command = KiwiCommand::parse_command(
+            "MIGRATE",
+            args(&[
+                "127.0.0.1",
+                "7001",
+                "foo",
+                "0",
+                "1000",
+                "COPY",
+                "AUTH2",
+                "u",
+                "p",
+            ]),
+        )
+        .unwrap();
+        let KiwiCommand::Migrate {
+            host,
+            port: 7001,
+            keys,
+            db: 0,
+            timeout_ms: 1000,
+            options,
+        } = command
+        else {
+            panic!("expected MIGRATE, got {:?}", command);
+        };
+        assert_eq!(host, "127.0.0.1");
+        assert_eq!(keys, args(&["foo"]));
+        assert!(options.copy && !options.replace);
+        assert_eq!(options.auth, Some((Some("u".to_string()), "p".to_string())));
+
+        let command = KiwiCommand::parse_command(
+            "MIGRATE",
+            args(&[
+                "127.0.0.1",
+                "7001",
+                "",
+                "0",
+                "0",
+                "REPLACE",
+                "KEYS",
+                "a",
+                "b",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(command.keys().len(), 2);
+
+        let result = KiwiCommand::parse_command(
+            "MIGRATE",
+            args(&["127.0.0.1", "7001", "foo", "0", "0", "KEYS", "a"]),
+        );
+        assert!(matches!(result, Err(CommandError::MigrateKeysWithKey)));
+        let result = KiwiCommand::parse_command("MIGRATE", args(&["127.0.0.1", "7001", "foo"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+    }
+
+    #[test]
+    fn test_multi_key_commands() {
+        let command = KiwiCommand::parse_command("MGET", args(&["a", "b"])).unwrap();
+        assert_eq!(command.keys().len(), 2);
+        let result = KiwiCommand::parse_command("MGET", args(&[]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+
+        let command = KiwiCommand::parse_command("MSET", args(&["a", "1", "b", "2"])).unwrap();
+        assert!(matches!(&command, KiwiCommand::MSet { pairs } if pairs.len() == 2));
+        assert_eq!(
+            command.keys(),
+            vec![
+                &Types::BulkString("a".to_string()),
+                &Types::BulkString("b".to_string())
+            ]
+        );
+        let result = KiwiCommand::parse_command("MSET", args(&["a", "1", "b"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+
+        let command = KiwiCommand::parse_command("DEL", args(&["a", "b", "c"])).unwrap();
+        assert_eq!(command.keys().len(), 3);
+        let result = KiwiCommand::parse_command("DEL", args(&[]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+
+        let command = KiwiCommand::parse_command("unlink", args(&["a", "b"])).unwrap();
+        assert_eq!(command.name(), "unlink");
+        assert_eq!(command.keys().len(), 2);
+        let result = KiwiCommand::parse_command("UNLINK", args(&[]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+    }
 }
@@ -0,0 +1,152 @@
+use crate::command::{KiwiCommand, string_arg};
+use crate::error::CommandError;
+use crate::types::Types;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AclCommand {
+    SetUser {
+        username: String,
+        rules: Vec<String>,
+    },
+    GetUser(String),
+    DelUser(Vec<String>),
+    List,
+    Users,
+    WhoAmI,
+    Cat(Option<String>),
+    Load,
+    Save,
+    Log(Option<usize>),
+    LogReset,
+}
+
+impl AclCommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AclCommand::SetUser { .. } => "setuser",
+            AclCommand::GetUser(_) => "getuser",
+            AclCommand::DelUser(_) => "deluser",
+            AclCommand::List => "list",
+            AclCommand::Users => "users",
+            AclCommand::WhoAmI => "whoami",
+            AclCommand::Cat(_) => "cat",
+            AclCommand::Load => "load",
+            AclCommand::Save => "save",
+            AclCommand::Log(_) | AclCommand::LogReset => "log",
+        }
+    }
+}
+
+pub(super) fn create_acl(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+    let mut args = args.into_iter();
+    let subcommand = match args.next() {
+        Some(arg) => string_arg(&arg)?.to_uppercase(),
+        None => return Err(CommandError::WrongNumberOfArguments),
+    };
+    let args = args
+        .map(|arg| string_arg(&arg))
+        .collect::<Result<Vec<String>, CommandError>>()?;
+
+    let command = match (subcommand.as_str(), args.len()) {
+        ("SETUSER", 1..) => {
+            let mut args = args.into_iter();
+            AclCommand::SetUser {
+                username: args.next().unwrap_or_default(),
+                rules: args.collect(),
+            }
+        }
+        ("GETUSER", 1) => AclCommand::GetUser(args.into_iter().next().unwrap_or_default()),
+        ("DELUSER", 1..) => AclCommand::DelUser(args),
+        ("LIST", 0) => AclCommand::List,
+        ("USERS", 0) => AclCommand::Users,
+        ("WHOAMI", 0) => AclCommand::WhoAmI,
+        ("CAT", 0..=1) => AclCommand::Cat(args.into_iter().next()),
+        ("LOAD", 0) => AclCommand::Load,
+        ("SAVE", 0) => AclCommand::Save,
+        ("LOG", 0) => AclCommand::Log(None),
+        ("LOG", 1) if args[0].eq_ignore_ascii_case("RESET") => AclCommand::LogReset,
+        ("LOG", 1) => match args[0].parse() {
+            Ok(count) => AclCommand::Log(Some(count)),
+            Err(_) => return Err(CommandError::NotAnInteger),
+        },
+        (
+            "SETUSER" | "GETUSER" | "DELUSER" | "LIST" | "USERS" | "WHOAMI" | "CAT" | "LOAD"
+            | "SAVE" | "LOG",
+            _,
+        ) => {
+            return Err(CommandError::WrongNumberOfArguments);
+        }
+        _ => return Err(CommandError::UnknownSubcommand(subcommand)),
+    };
+    Ok(KiwiCommand::Acl(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<Types> {
+        values
+            .iter()
+            .map(|value| Types::BulkString(value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_setuser() {
+        let command = KiwiCommand::parse_command(
+            "ACL",
+            args(&["setuser", "alice", "on", ">pass", "~cache:*"]),
+        )
+        .unwrap();
+        let KiwiCommand::Acl(AclCommand::SetUser { username, rules }) = command else {
+            panic!("expected ACL SETUSER, got {:?}", command);
+        };
+        assert_eq!(username, "alice");
+        assert_eq!(rules, vec!["on", ">pass", "~cache:*"]);
+    }
+
+    #[test]
+    fn test_cat() {
+        let command = KiwiCommand::parse_command("ACL", args(&["CAT"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Acl(AclCommand::Cat(None))));
+
+        let command = KiwiCommand::parse_command("ACL", args(&["CAT", "read"])).unwrap();
+        assert!(
+            matches!(command, KiwiCommand::Acl(AclCommand::Cat(Some(category))) if category == "read")
+        );
+    }
+
+    #[test]
+    fn test_log() {
+        let command = KiwiCommand::parse_command("ACL", args(&["LOG"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Acl(AclCommand::Log(None))));
+
+        let command = KiwiCommand::parse_command("ACL", args(&["LOG", "5"])).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Acl(AclCommand::Log(Some(5)))
+        ));
+
+        let command = KiwiCommand::parse_command("ACL", args(&["log", "reset"])).unwrap();
+        assert!(matches!(command, KiwiCommand::Acl(AclCommand::LogReset)));
+
+        let result = KiwiCommand::parse_command("ACL", args(&["LOG", "-1"]));
+        assert!(matches!(result, Err(CommandError::NotAnInteger)));
+    }
+
+    #[test]
+    fn test_wrong_arity() {
+        let result = KiwiCommand::parse_command("ACL", args(&["WHOAMI", "extra"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+
+        let result = KiwiCommand::parse_command("ACL", args(&["GETUSER"]));
+        assert!(matches!(result, Err(CommandError::WrongNumberOfArguments)));
+    }
+
+    #[test]
+    fn test_unknown_subcommand() {
+        let result = KiwiCommand::parse_command("ACL", args(&["DRYRUN"]));
+        assert!(matches!(result, Err(CommandError::UnknownSubcommand(name)) if name == "DRYRUN"));
+    }
+}
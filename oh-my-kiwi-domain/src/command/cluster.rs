@@ -0,0 +1,316 @@
+use crate::command::{KiwiCommand, int_arg, string_arg};
+use crate::error::CommandError;
+use crate::types::Types;
+
+/// Number of hash slots the key space is split into.
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClusterCommand {
+    Info,
+    MyId,
+    Nodes,
+    Slots,
+    Shards,
+    KeySlot(Types),
+    CountKeysInSlot(u16),
+    GetKeysInSlot {
+        slot: u16,
+        count: usize,
+    },
+    /// Join the cluster of the node at `host:port`, whose bus listens on
+    /// `bus_port` when given.
+    Meet {
+        host: String,
+        port: u16,
+        bus_port: Option<u16>,
+    },
+    AddSlots(Vec<u16>),
+    AddSlotsRange(Vec<(u16, u16)>),
+    DelSlots(Vec<u16>),
+    DelSlotsRange(Vec<(u16, u16)>),
+    SetSlot {
+        slot: u16,
+        state: SlotState,
+    },
+    /// Become a replica of the node with this id.
+    Replicate(String),
+}
+
+/// What CLUSTER SETSLOT does with a slot.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SlotState {
+    /// Start moving the slot's keys to the node with this id.
+    Migrating(String),
+    /// Start receiving the slot's keys from the node with this id.
+    Importing(String),
+    /// Stop migrating or importing the slot.
+    Stable,
+    /// Assign the slot to the node with this id.
+    Node(String),
+}
+
+impl ClusterCommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClusterCommand::Info => "info",
+            ClusterCommand::MyId => "myid",
+            ClusterCommand::Nodes => "nodes",
+            ClusterCommand::Slots => "slots",
+            ClusterCommand::Shards => "shards",
+            ClusterCommand::KeySlot(_) => "keyslot",
+            ClusterCommand::CountKeysInSlot(_) => "countkeysinslot",
+            ClusterCommand::GetKeysInSlot { .. } => "getkeysinslot",
+            ClusterCommand::Meet { .. } => "meet",
+            ClusterCommand::AddSlots(_) => "addslots",
+            ClusterCommand::AddSlotsRange(_) => "addslotsrange",
+            ClusterCommand::DelSlots(_) => "delslots",
+            ClusterCommand::DelSlotsRange(_) => "delslotsrange",
+            ClusterCommand::SetSlot { .. } => "setslot",
+            ClusterCommand::Replicate(_) => "replicate",
+        }
+    }
+}
+
+pub(super) fn create_cluster(args: Vec<Types>) -> Result<KiwiCommand, CommandError> {
+    let mut args = args.into_iter();
+    let subcommand = match args.next() {
+        Some(arg) => string_arg(&arg)?.to_uppercase(),
+        None => return Err(CommandError::WrongNumberOfArguments),
+    };
+    let args: Vec<Types> = args.collect();
+
+    let command = match (subcommand.as_str(), args.as_slice()) {
+        ("INFO", []) => ClusterCommand::Info,
+        ("MYID", []) => ClusterCommand::MyId,
+        ("NODES", []) => ClusterCommand::Nodes,
+        ("SLOTS", []) => ClusterCommand::Slots,
+        ("SHARDS", []) => ClusterCommand::Shards,
+        ("KEYSLOT", [key]) => ClusterCommand::KeySlot(key.clone()),
+        ("COUNTKEYSINSLOT", [slot]) => ClusterCommand::CountKeysInSlot(slot_arg(slot)?),
+        ("GETKEYSINSLOT", [slot, count]) => ClusterCommand::GetKeysInSlot {
+            slot: slot_arg(slot)?,
+            count: usize::try_from(int_arg(count)?).map_err(|_| CommandError::InvalidKeyCount)?,
+        },
+        ("MEET", [host, port, bus_port @ ..]) if bus_port.len() <= 1 => {
+            let host = string_arg(host)?;
+            let port_arg = |arg: &Types| {
+                let port = string_arg(arg)?;
+                port.parse()
+                    .map_err(|_| CommandError::InvalidNodeAddress(format!("{host}:{port}")))
+            };
+            ClusterCommand::Meet {
+                port: port_arg(port)?,
+                bus_port: bus_port.first().map(port_arg).transpose()?,
+                host,
+            }
+        }
+        ("ADDSLOTS", [_, ..]) => ClusterCommand::AddSlots(slots_arg(&args)?),
+        ("DELSLOTS", [_, ..]) => ClusterCommand::DelSlots(slots_arg(&args)?),
+        ("ADDSLOTSRANGE", [_, ..]) => ClusterCommand::AddSlotsRange(slot_ranges_arg(&args)?),
+        ("DELSLOTSRANGE", [_, ..]) => ClusterCommand::DelSlotsRange(slot_ranges_arg(&args)?),
+        ("SETSLOT", [slot, state, node @ ..]) => {
+            let state = match (string_arg(state)?.to_uppercase().as_str(), node) {
+                ("MIGRATING", [node]) => SlotState::Migrating(string_arg(node)?),
+                ("IMPORTING", [node]) => SlotState::Importing(string_arg(node)?),
+                ("NODE", [node]) => SlotState::Node(string_arg(node)?),
+                ("STABLE", []) => SlotState::Stable,
+                _ => return Err(CommandError::SyntaxError),
+            };
+            ClusterCommand::SetSlot {
+                slot: slot_arg(slot)?,
+                state,
+            }
+        }
+        ("REPLICATE", [node]) => ClusterCommand::Replicate(string_arg(node)?),
+        (
+            "INFO" | "MYID" | "NODES" | "SLOTS" | "SHARDS" | "KEYSLOT" | "COUNTKEYSINSLOT"
+            | "GETKEYSINSLOT" | "MEET" | "ADDSLOTS" | "DELSLOTS" | "ADDSLOTSRANGE"
+            | "DELSLOTSRANGE" | "SETSLOT" | "REPLICATE",
+            _,
+        ) => return Err(CommandError::WrongNumberOfArguments),
+        _ => return Err(CommandError::UnknownSubcommand(subcommand)),
+    };
+    Ok(KiwiCommand::Cluster(command))
+}
+
+fn slot_arg(arg: &Types) -> Result<u16, CommandError> {
+    int_arg(arg)
+        .ok()
+        .and_then(|slot| u16::try_from(slot).ok())
+        .filter(|slot| *slot < CLUSTER_SLOTS)
+        .ok_or(CommandError::InvalidSlot)
+}
+
+fn slots_arg(args: &[Types]) -> Result<Vec<u16>, CommandError> {
+    args.iter().map(slot_arg).collect()
+}
+
+fn slot_ranges_arg(args: &[Types]) -> Result<Vec<(u16, u16)>, CommandError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(CommandError::WrongNumberOfArguments);
+    }
+    args.chunks(2)
+        .map(|pair| {
+            let (start, end) = (slot_arg(&pair[0])?, slot_arg(&pair[1])?);
+            if start > end {
+                return Err(CommandError::InvalidSlotRange { start, end });
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<Types> {
+        values
+            .iter()
+            .map(|value| Types::BulkString(value.to_string()))
+            .collect()
+    }
+
+    fn parse(values: &[&str]) -> Result<KiwiCommand, CommandError> {
+        KiwiCommand::parse_command("CLUSTER", args(values))
+    }
+
+    #[test]
+    fn test_slots() {
+        let command = parse(&["addslots", "0", "1", "16383"]).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Cluster(ClusterCommand::AddSlots(slots)) if slots == [0, 1, 16383]
+        ));
+
+        let command = parse(&["DELSLOTSRANGE", "0", "10", "20", "20"]).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Cluster(ClusterCommand::DelSlotsRange(ranges)) if ranges == [(0, 10), (20, 20)]
+        ));
+
+        assert!(matches!(
+            parse(&["ADDSLOTS", "16384"]),
+            Err(CommandError::InvalidSlot)
+        ));
+        assert!(matches!(
+            parse(&["ADDSLOTS", "-1"]),
+            Err(CommandError::InvalidSlot)
+        ));
+        assert!(matches!(
+            parse(&["ADDSLOTSRANGE", "10", "5"]),
+            Err(CommandError::InvalidSlotRange { start: 10, end: 5 })
+        ));
+        assert!(matches!(
+            parse(&["ADDSLOTSRANGE", "10"]),
+            Err(CommandError::WrongNumberOfArguments)
+        ));
+        assert!(matches!(
+            parse(&["ADDSLOTS"]),
+            Err(CommandError::WrongNumberOfArguments)
+        ));
+    }
+
+    #[test]
+    fn test_meet() {
+        let command = parse(&["MEET", "127.0.0.1", "7001"]).unwrap();
+        assert_eq!(command.subcommand_name(), Some("meet"));
+        assert!(matches!(
+            command,
+            KiwiCommand::Cluster(ClusterCommand::Meet { host, port: 7001, bus_port: None }) if host == "127.0.0.1"
+        ));
+
+        let command = parse(&["meet", "10.0.0.1", "7001", "17002"]).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Cluster(ClusterCommand::Meet {
+                port: 7001,
+                bus_port: Some(17002),
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            parse(&["MEET", "10.0.0.1", "port"]),
+            Err(CommandError::InvalidNodeAddress(address)) if address == "10.0.0.1:port"
+        ));
+    }
+
+    #[test]
+    fn test_keys_in_slot() {
+        let command = parse(&["GETKEYSINSLOT", "42", "10"]).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Cluster(ClusterCommand::GetKeysInSlot {
+                slot: 42,
+                count: 10
+            })
+        ));
+        assert!(matches!(
+            parse(&["GETKEYSINSLOT", "42", "-1"]),
+            Err(CommandError::InvalidKeyCount)
+        ));
+        assert!(matches!(
+            parse(&["COUNTKEYSINSLOT", "a"]),
+            Err(CommandError::InvalidSlot)
+        ));
+    }
+
+    #[test]
+    fn test_setslot() {
+        let command = parse(&["SETSLOT", "42", "migrating", "abc"]).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Cluster(ClusterCommand::SetSlot {
+                slot: 42,
+                state: SlotState::Migrating(node),
+            }) if node == "abc"
+        ));
+        let command = parse(&["SETSLOT", "42", "STABLE"]).unwrap();
+        assert!(matches!(
+            command,
+            KiwiCommand::Cluster(ClusterCommand::SetSlot {
+                state: SlotState::Stable,
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            parse(&["SETSLOT", "42", "NODE"]),
+            Err(CommandError::SyntaxError)
+        ));
+        assert!(matches!(
+            parse(&["SETSLOT", "42", "STABLE", "abc"]),
+            Err(CommandError::SyntaxError)
+        ));
+        assert!(matches!(
+            parse(&["SETSLOT", "42"]),
+            Err(CommandError::WrongNumberOfArguments)
+        ));
+    }
+
+    #[test]
+    fn test_replicate() {
+        assert!(matches!(
+            parse(&["REPLICATE", "abc"]).unwrap(),
+            KiwiCommand::Cluster(ClusterCommand::Replicate(node)) if node == "abc"
+        ));
+        assert!(matches!(
+            parse(&["REPLICATE"]),
+            Err(CommandError::WrongNumberOfArguments)
+        ));
+    }
+
+    #[test]
+    fn test_unknown_subcommand() {
+        assert!(matches!(
+            parse(&["RESET"]),
+            Err(CommandError::UnknownSubcommand(name)) if name == "RESET"
+        ));
+        assert!(matches!(
+            parse(&["INFO", "extra"]),
+            Err(CommandError::WrongNumberOfArguments)
+        ));
+    }
+}
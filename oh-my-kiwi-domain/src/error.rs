@@ -4,7 +4,7 @@ use async_trait::async_trait;
 use std::num::{ParseFloatError, ParseIntError};
 use std::string::FromUtf8Error;
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::info;
 
 #[derive(Error, Debug)]
 pub enum KiwiError {
@@ -31,6 +31,215 @@ pub enum CommandError {
 
     #[error("Wrong argument type")]
     WrongArgumentType,
+
+    #[error("Syntax error")]
+    SyntaxError,
+
+    #[error("Unknown subcommand '{0}'")]
+    UnknownSubcommand(String),
+
+    #[error("Value is not an integer or out of range")]
+    NotAnInteger,
+
+    #[error("DB index is out of range")]
+    DbIndexOutOfRange,
+
+    #[error("Source and destination objects are the same")]
+    SameObject,
+
+    #[error("NOAUTH Authentication required")]
+    NoAuth,
+
+    #[error("WRONGPASS invalid username-password pair or user is disabled")]
+    WrongPass,
+
+    #[error("AUTH called without any password configured for the default user")]
+    AuthNotConfigured,
+
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+
+    #[error("NOPERM User {user} has no permissions to run the '{command}' command")]
+    NoCommandPermission { user: String, command: String },
+
+    #[error("NOPERM No permissions to access a key")]
+    NoKeyPermission,
+
+    #[error("NOPERM No permissions to access a channel")]
+    NoChannelPermission,
+
+    #[error("Error in ACL SETUSER modifier '{rule}': {reason}")]
+    InvalidAclRule { rule: String, reason: String },
+
+    #[error("The 'default' user cannot be removed")]
+    DefaultUserRemoval,
+
+    #[error("Unknown category '{0}'")]
+    UnknownAclCategory(String),
+
+    #[error("This instance is not configured to use an ACL file")]
+    AclFileNotConfigured,
+
+    #[error("Error loading ACL file: {0}")]
+    AclFile(String),
+
+    #[error("invalid expire time in '{0}' command")]
+    InvalidExpireTime(&'static str),
+
+    #[error("Background save already in progress")]
+    BackgroundSaveInProgress,
+
+    #[error("Error saving the snapshot: {0}")]
+    Persistence(String),
+
+    #[error("Error reading the data set: {0}")]
+    Storage(String),
+
+    #[error("Background append only file rewriting already in progress")]
+    AofRewriteInProgress,
+
+    #[error("Append only file is not enabled")]
+    AofDisabled,
+
+    #[error("DUMP payload version or checksum are wrong")]
+    BadDumpPayload,
+
+    #[error("Bad data format")]
+    BadDataFormat,
+
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+
+    #[error("Invalid TTL value, must be >= 0")]
+    InvalidTtl,
+
+    #[error("Invalid IDLETIME value, must be >= 0")]
+    InvalidIdleTime,
+
+    #[error("Invalid FREQ value, must be >= 0 and <= 255")]
+    InvalidFreq,
+
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnlyReplica,
+
+    #[error("Unrecognized REPLCONF option: {0}")]
+    UnknownReplConfOption(String),
+
+    #[error("timeout is negative")]
+    NegativeTimeout,
+
+    #[error(
+        "{0} cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated."
+    )]
+    WaitOnReplica(&'static str),
+
+    #[error("WAITAOF cannot be used when numlocal is set but appendonly is disabled.")]
+    WaitAofWithoutAof,
+
+    #[error("This instance has cluster support disabled")]
+    ClusterDisabled,
+
+    #[error("Invalid or out of range slot")]
+    InvalidSlot,
+
+    #[error("start slot number {start} is greater than end slot number {end}")]
+    InvalidSlotRange { start: u16, end: u16 },
+
+    #[error("Slot {0} specified multiple times")]
+    SlotSpecifiedTwice(u16),
+
+    #[error("Slot {0} is already busy")]
+    SlotBusy(u16),
+
+    #[error("Slot {0} is already unassigned")]
+    SlotUnassigned(u16),
+
+    #[error("Invalid number of keys")]
+    InvalidKeyCount,
+
+    #[error("Invalid node address specified: {0}")]
+    InvalidNodeAddress(String),
+
+    #[error("MOVED {slot} {address}")]
+    Moved { slot: u16, address: String },
+
+    #[error("CROSSSLOT Keys in request don't hash to the same slot")]
+    CrossSlot,
+
+    #[error("CLUSTERDOWN Hash slot not served")]
+    ClusterDown,
+
+    #[error("ASK {slot} {address}")]
+    Ask { slot: u16, address: String },
+
+    #[error("TRYAGAIN Multiple keys request during rehashing of slot")]
+    TryAgain,
+
+    #[error("I don't know about node {0}")]
+    UnknownNode(String),
+
+    #[error("I'm not the owner of hash slot {0}")]
+    NotSlotOwner(u16),
+
+    #[error("I'm already the owner of hash slot {0}")]
+    AlreadySlotOwner(u16),
+
+    #[error(
+        "Can't assign hashslot {0} to a different node while I still hold keys for this hash slot."
+    )]
+    SlotNotEmpty(u16),
+
+    #[error("When using MIGRATE KEYS option, the key argument must be set to the empty string")]
+    MigrateKeysWithKey,
+
+    #[error("IOERR error or timeout {0} target instance")]
+    MigrateIo(&'static str),
+
+    #[error("Target instance replied with error: {0}")]
+    MigrateTarget(String),
+
+    #[error("Can't replicate myself")]
+    ReplicateMyself,
+
+    #[error("I can only replicate a master, not a replica.")]
+    ReplicateReplica,
+
+    #[error("To set a master the node must be empty and without assigned slots.")]
+    ReplicateNotEmpty,
+
+    #[error("REPLICAOF not allowed in cluster mode.")]
+    ReplicaOfInClusterMode,
+
+    #[error("OOM command not allowed when used memory > 'maxmemory'.")]
+    OutOfMemory,
+
+    #[error("SELECT is not allowed in cluster mode")]
+    SelectInClusterMode,
+
+    #[error("PREFIX option requires BCAST mode to be enabled")]
+    TrackingPrefixRequiresBcast,
+
+    #[error("You can't use BCAST mode together with OPTIN or OPTOUT")]
+    TrackingBcastWithOptInOut,
+
+    #[error("You can't use OPTIN and OPTOUT at the same time")]
+    TrackingOptInWithOptOut,
+
+    #[error("REDIRECT is not supported, invalidation messages are sent as RESP3 pushes")]
+    TrackingRedirectUnsupported,
+
+    #[error("Client tracking requires RESP3 without REDIRECT, switch with HELLO 3")]
+    TrackingRequiresResp3,
+
+    #[error(
+        "You can't switch BCAST or OPTIN/OPTOUT mode before disabling tracking for this client"
+    )]
+    TrackingModeSwitch,
+
+    #[error(
+        "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"
+    )]
+    TrackingCachingWithoutOptInOut,
 }
 
 #[derive(Error, Debug)]
@@ -63,6 +272,7 @@ pub enum ParseError {
     ConnectionError(#[from] std::io::Error),
 }
 
+#[derive(Default)]
 pub struct KiwiErrorHandler;
 
 impl KiwiErrorHandler {
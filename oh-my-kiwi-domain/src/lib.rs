@@ -11,8 +11,14 @@ pub mod error;
 
 
 #[async_trait]
-pub trait CommandProcessor {
+pub trait CommandProcessor: Send {
     async fn process(&mut self, command: KiwiCommand) -> Result<Response, KiwiError>;
+
+    /// Waits for the next out-of-band message (e.g. a client tracking invalidation)
+    /// that must be pushed to the client between regular responses.
+    async fn next_push(&mut self) -> Option<Response> {
+        std::future::pending().await
+    }
 }
 
 #[async_trait]
@@ -20,6 +26,9 @@ pub trait ResponseWriter {
     async fn write(&mut self, response: Response) -> Result<(), KiwiError>;
 }
 
+/// Bytes the reader of every client connection buffers.
+pub const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
 #[async_trait]
 pub trait BytesReader {
     async fn read_line(&mut self) -> Result<Vec<u8>, ParseError>;
@@ -36,8 +45,198 @@ pub trait ErrorHandler<RW> {
     async fn handle_error(&self, response_writer: &mut RW, error: KiwiError) -> Option<KiwiError>;
 }
 
+/// A key together with its encoded value and optional expiry time, in unix
+/// milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub expires_at: Option<u64>,
+    pub value_type: ValueType,
+}
+
+/// The Redis type a value stands for. Most follow from the RESP type the value
+/// is encoded as, but a map is a sorted set only when it was created as one,
+/// which the RESP doesn't tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    List,
+    Set,
+    Hash,
+    SortedSet,
+}
+
+impl ValueType {
+    /// The type of a value encoded as `resp`, taking maps for hashes.
+    pub fn of(resp: &[u8]) -> Self {
+        match resp.first() {
+            Some(b'*') => ValueType::List,
+            Some(b'~') => ValueType::Set,
+            Some(b'%') => ValueType::Hash,
+            _ => ValueType::String,
+        }
+    }
+}
+
+/// How a key was used where it came from, as RESTORE's IDLETIME and FREQ give
+/// it. Unset parts start afresh.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessHistory {
+    /// Milliseconds since the key was last used.
+    pub idle_ms: Option<u64>,
+    /// The key's LFU counter.
+    pub frequency: Option<u8>,
+}
+
+/// A point-in-time copy of every database of an engine, for persistence to
+/// read from a blocking thread while the engine keeps serving writes.
+pub trait Snapshot: Send {
+    fn databases(&self) -> usize;
+
+    /// Keys in `db` and how many of them have an expiry, as a sizing hint:
+    /// keys that expired before the snapshot was taken may be counted.
+    fn size_hint(&self, db: usize) -> (usize, usize);
+
+    /// The live entries of `db`, read one at a time. Engines reading them from
+    /// disk report a failed read as an error item.
+    fn entries(&self, db: usize) -> Box<dyn Iterator<Item = std::io::Result<Entry>> + '_>;
+}
+
+/// A snapshot of entries copied out of the engine up front, for engines that
+/// can't share their data with one.
+pub struct CollectedSnapshot {
+    databases: Vec<Vec<Entry>>,
+}
+
+impl CollectedSnapshot {
+    /// A snapshot holding the entries of each database, by index.
+    pub fn new(databases: Vec<Vec<Entry>>) -> Self {
+        Self { databases }
+    }
+}
+
+impl Snapshot for CollectedSnapshot {
+    fn databases(&self) -> usize {
+        self.databases.len()
+    }
+
+    fn size_hint(&self, db: usize) -> (usize, usize) {
+        let entries = &self.databases[db];
+        let volatile = entries.iter().filter(|entry| entry.expires_at.is_some());
+        (entries.len(), volatile.count())
+    }
+
+    fn entries(&self, db: usize) -> Box<dyn Iterator<Item = std::io::Result<Entry>> + '_> {
+        Box::new(self.databases[db].iter().cloned().map(Ok))
+    }
+}
+
+/// How much memory an engine's data set takes, and the limit eviction keeps
+/// it under.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Approximate bytes taken by the keys, their values and bookkeeping.
+    pub used: usize,
+    /// The part of `used` taken by bookkeeping rather than keys and values.
+    pub overhead: usize,
+    /// The limit, 0 for none.
+    pub max: usize,
+    /// Name of the eviction policy.
+    pub policy: &'static str,
+    /// Keys evicted since startup.
+    pub evicted_keys: u64,
+    pub compression: CompressionStats,
+}
+
+/// The values an engine keeps compressed, and their size before and after.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub values: usize,
+    pub original_bytes: usize,
+    pub compressed_bytes: usize,
+}
+
+/// Where the reads of an engine that keeps cold keys on disk were served
+/// from, and how the keys are spread over the tiers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TierStats {
+    /// Reads of keys held in memory.
+    pub memory_hits: u64,
+    /// Reads of keys that had to be brought back from disk.
+    pub disk_hits: u64,
+    /// Reads of missing keys.
+    pub misses: u64,
+    pub memory_keys: usize,
+    pub disk_keys: usize,
+    /// Bytes of the file the cold values are kept in, garbage included.
+    pub disk_bytes: u64,
+}
+
 #[async_trait]
 pub trait Engine {
-    async fn get(&self, key: &Vec<u8>) -> Option<Vec<u8>>;
-    async fn set(&self, key: Vec<u8>, value: Vec<u8>);
+    /// Number of logical databases, addressed by indexes `0..databases()`.
+    fn databases(&self) -> usize;
+
+    async fn get(&self, db: usize, key: &[u8]) -> Option<Vec<u8>>;
+    async fn set(&self, db: usize, key: Vec<u8>, value: Vec<u8>);
+
+    /// Removes `key` from `db`. Returns `false` when the key is missing. With
+    /// `lazy` a large value is freed in the background.
+    async fn delete(&self, db: usize, key: &[u8], lazy: bool) -> bool;
+
+    /// Moves `key` from `db` to `target`. Returns `false` when the key is missing
+    /// in `db` or already exists in `target`.
+    async fn move_key(&self, db: usize, target: usize, key: &[u8]) -> bool;
+    async fn swap(&self, first: usize, second: usize);
+    async fn size(&self, db: usize) -> usize;
+
+    /// Removes every key of `db`. With `lazy` the memory is released in the background.
+    async fn flush(&self, db: usize, lazy: bool);
+    async fn flush_all(&self, lazy: bool);
+
+    /// Sets or clears the expiry of `key`, in unix milliseconds. An expiry in the
+    /// past deletes the key. Returns `false` when the key is missing.
+    async fn set_expiry(&self, db: usize, key: &[u8], expires_at: Option<u64>) -> bool;
+
+    /// Expiry of `key`: `None` when the key is missing, `Some(None)` when it has none.
+    async fn expiry(&self, db: usize, key: &[u8]) -> Option<Option<u64>>;
+
+    /// How the value of `key` is stored, named like Redis's OBJECT ENCODING.
+    /// `None` when the key is missing.
+    async fn encoding(&self, db: usize, key: &[u8]) -> Option<&'static str>;
+
+    /// Approximate bytes of memory `key` takes with its value and
+    /// bookkeeping, for MEMORY USAGE. `None` when the key is missing.
+    async fn memory_usage(&self, db: usize, key: &[u8]) -> Option<usize>;
+
+    /// A point-in-time copy of every database. Taking it holds writers up
+    /// only as long as sharing the data with the snapshot takes.
+    async fn snapshot(&self) -> Box<dyn Snapshot>;
+
+    /// Creates `entry` in `db` with its expiry and access history in one step,
+    /// as RESTORE does. Without `replace` an existing key is left alone and
+    /// `false` returned. An expiry in the past only removes the replaced key.
+    async fn restore(&self, db: usize, entry: Entry, history: AccessHistory, replace: bool)
+    -> bool;
+
+    /// Inserts `entries` into `db`, replacing existing keys.
+    async fn insert_entries(&self, db: usize, entries: Vec<Entry>);
+
+    fn memory(&self) -> MemoryStats;
+
+    /// Evicts keys by the eviction policy until the used memory is back under
+    /// the limit or nothing more may be evicted. Returns the evicted keys
+    /// with their database.
+    async fn evict(&self) -> Vec<(usize, Vec<u8>)>;
+
+    /// Removes some of the keys whose expiry passed, so that values nobody
+    /// reads again are freed as well, large ones in the background. Called
+    /// periodically; returns the removed keys with their database.
+    async fn expire_cycle(&self) -> Vec<(usize, Vec<u8>)>;
+
+    /// Statistics of the memory and disk tiers, for engines that have them.
+    fn tiers(&self) -> Option<TierStats> {
+        None
+    }
 }
@@ -5,7 +5,15 @@ pub enum Response {
     Pong,
     Value(Types),
     Error(String),
-    Null
+    Null,
+    /// Acknowledges QUIT; the connection is closed once it has been written.
+    Quit,
+    /// Bytes written to the connection as they are, such as the replication
+    /// stream sent to a follower.
+    Raw(Vec<u8>),
+    /// Closes the connection without writing anything, such as that of a
+    /// follower that fell too far behind.
+    Close,
 }
 
 impl Response {
@@ -15,7 +23,10 @@ impl Response {
             Response::Pong => Types::SimpleString("PONG".to_string()),
             Response::Error(message) => Types::SimpleError(message.to_string()),
             Response::Value(types) => types.clone(),
-            Response::Null => Types::Null
+            Response::Null => Types::Null,
+            Response::Quit => Types::SimpleString("OK".to_string()),
+            Response::Raw(bytes) => Types::BulkBytes(bytes.clone()),
+            Response::Close => Types::BulkBytes(Vec::new()),
         }
     }
 }
@@ -2,6 +2,8 @@ use async_trait::async_trait;
 use num_bigint::BigInt;
 use ordered_float::OrderedFloat;
 use std::collections::BTreeMap;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
 use crate::BytesReader;
 use crate::error::ParseError;
 
@@ -19,6 +21,9 @@ pub enum Types {
     BulkError(String),
     Map(BTreeMap<Types, Types>),
     Set(Vec<Types>),
+    Push(Vec<Types>),
+    /// A bulk string that is not valid UTF-8, such as a DUMP payload.
+    BulkBytes(Vec<u8>),
 }
 
 impl Types {
@@ -27,7 +32,7 @@ impl Types {
             Types::SimpleString(payload) => simple_string_to_bytes(payload),
             Types::SimpleError(payload) => simple_error_to_bytes(payload),
             Types::Integer(value) => integer_to_bytes(value),
-            Types::BulkString(payload) => bulk_string_to_bytes(payload),
+            Types::BulkString(payload) => bulk_string_to_bytes(payload.as_bytes()),
             Types::Array(array) => array_to_bytes(array),
             Types::Null => null_to_bytes(),
             Types::Boolean(value) => boolean_to_bytes(value),
@@ -36,6 +41,8 @@ impl Types {
             Types::BulkError(payload) => bulk_error_to_bytes(payload),
             Types::Map(map) => map_to_bytes(map),
             Types::Set(set) => set_to_bytes(set),
+            Types::Push(push) => push_to_bytes(push),
+            Types::BulkBytes(payload) => bulk_string_to_bytes(payload),
         }
     }
 
@@ -44,6 +51,16 @@ impl Types {
         Self::from_bytes(&mut cursor).await
     }
 
+    /// Like [`Types::from_slice`], outside of an async context: reading from
+    /// a slice never has to wait.
+    pub fn parse_slice(data: &[u8]) -> Result<Self, ParseError> {
+        let mut parse = pin!(Self::from_slice(data));
+        match parse.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(result) => result,
+            Poll::Pending => unreachable!("reading from a slice never waits"),
+        }
+    }
+
     pub async fn from_bytes<R: BytesReader + Send>(
         reader: &mut R,
     ) -> Result<Self, ParseError> {
@@ -109,9 +126,12 @@ impl Types {
                 if &data_with_crlf[len as usize..] != CRLF {
                     return Err(ParseError::MissingSeparator);
                 }
-                Ok(Types::BulkString(String::from_utf8(
-                    data_with_crlf[..len as usize].to_vec(),
-                )?))
+                let mut data = data_with_crlf;
+                data.truncate(len as usize);
+                Ok(match String::from_utf8(data) {
+                    Ok(payload) => Types::BulkString(payload),
+                    Err(err) => Types::BulkBytes(err.into_bytes()),
+                })
             }
 
             b'!' => {
@@ -161,6 +181,16 @@ impl Types {
                 Ok(Types::Set(set))
             }
 
+            b'>' => {
+                let len_str = String::from_utf8(rest_of_line.to_vec())?;
+                let len = len_str.parse::<usize>()?;
+                let mut push = Vec::with_capacity(len);
+                for _ in 0..len {
+                    push.push(Box::pin(Self::from_bytes(reader)).await?);
+                }
+                Ok(Types::Push(push))
+            }
+
             _ => {
                 let type_symbol = String::from_utf8(vec![type_prefix])?;
                 Err(ParseError::UnsupportedDataType(type_symbol))
@@ -196,18 +226,18 @@ fn integer_to_bytes(value: &i64) -> Vec<u8> {
     result
 }
 
-fn bulk_string_to_bytes(payload: &str) -> Vec<u8> {
+fn bulk_string_to_bytes(payload: &[u8]) -> Vec<u8> {
     // 2CRLF + len + size number
     let mut result = Vec::with_capacity(payload.len() + CRLF_LEN * 2 + 1 + 13);
     result.extend_from_slice(b"$");
     result.extend_from_slice(payload.len().to_string().as_bytes());
     result.extend_from_slice(CRLF);
-    result.extend_from_slice(payload.as_bytes());
+    result.extend_from_slice(payload);
     result.extend_from_slice(CRLF);
     result
 }
 
-fn array_to_bytes(arr: &Vec<Types>) -> Vec<u8> {
+fn array_to_bytes(arr: &[Types]) -> Vec<u8> {
     let mut result = Vec::with_capacity(CRLF_LEN + 1 + 13);
     result.extend_from_slice(b"*");
     if arr.is_empty() {
@@ -304,7 +334,7 @@ fn map_to_bytes(map: &BTreeMap<Types, Types>) -> Vec<u8> {
     result
 }
 
-fn set_to_bytes(set: &Vec<Types>) -> Vec<u8> {
+fn set_to_bytes(set: &[Types]) -> Vec<u8> {
     let mut result = Vec::with_capacity(32);
 
     result.extend_from_slice(b"~");
@@ -318,6 +348,20 @@ fn set_to_bytes(set: &Vec<Types>) -> Vec<u8> {
     result
 }
 
+fn push_to_bytes(push: &[Types]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(32);
+
+    result.extend_from_slice(b">");
+    result.extend_from_slice(push.len().to_string().as_bytes());
+    result.extend_from_slice(CRLF);
+
+    for elem in push {
+        result.extend_from_slice(&elem.to_bytes());
+    }
+
+    result
+}
+
 struct BytesCursor<'a> {
     data: &'a [u8],
     pos: usize,
@@ -448,6 +492,18 @@ mod tests {
         assert_eq!(val.to_bytes(), b"~2\r\n:1\r\n:2\r\n");
     }
 
+    #[test]
+    fn test_push() {
+        let val = Types::Push(vec![
+            Types::BulkString("invalidate".to_string()),
+            Types::Array(vec![Types::BulkString("foo".to_string())]),
+        ]);
+        assert_eq!(
+            val.to_bytes(),
+            b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n"
+        );
+    }
+
     #[test]
     fn test_empty_array() {
         let val = Types::Array(vec![]);
@@ -546,6 +602,15 @@ mod tests {
         assert_eq!(result, Types::BulkString("foobar".to_string()));
     }
 
+    #[tokio::test]
+    async fn test_parse_binary_bulk_string() {
+        let input = b"$3\r\n\xFF\x00a\r\n";
+        let mut reader = MockReader::new(input);
+        let result = Types::from_bytes(&mut reader).await.unwrap();
+        assert_eq!(result, Types::BulkBytes(vec![0xFF, 0x00, b'a']));
+        assert_eq!(result.to_bytes(), input);
+    }
+
     #[tokio::test]
     async fn test_parse_empty_bulk_string() {
         let input = b"$0\r\n\r\n";
@@ -685,6 +750,18 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[tokio::test]
+    async fn test_parse_push() {
+        let input = b">2\r\n$10\r\ninvalidate\r\n_\r\n";
+        let mut reader = MockReader::new(input);
+        let result = Types::from_bytes(&mut reader).await.unwrap();
+        let expected = Types::Push(vec![
+            Types::BulkString("invalidate".to_string()),
+            Types::Null,
+        ]);
+        assert_eq!(result, expected);
+    }
+
     #[tokio::test]
     async fn test_from_slice_complex() {
         let original_value = Types::Array(vec![
@@ -707,4 +784,11 @@ mod tests {
 
         assert_eq!(original_value, parsed_value);
     }
+
+    #[test]
+    fn test_parse_slice() {
+        let value = Types::Array(vec![Types::BulkString("a".to_string()), Types::Integer(1)]);
+        assert_eq!(Types::parse_slice(&value.to_bytes()).unwrap(), value);
+        assert!(Types::parse_slice(b"*2\r\n:1\r\n").is_err());
+    }
 }
@@ -0,0 +1,85 @@
+//! SET/GET throughput of the engines as threads are added. Run with
+//! `cargo bench -p oh-my-kiwi-engine`; the sharded and shared-nothing engines
+//! should scale with the cores, the in-memory one stays flat on its lock.
+//! The shared-nothing engine runs its tasks on its own core threads, as it
+//! does connections.
+
+use oh_my_kiwi_domain::Engine;
+use oh_my_kiwi_engine::config::KiwiConfig;
+use oh_my_kiwi_engine::in_memory::InMemoryEngine;
+use oh_my_kiwi_engine::shared_nothing::SharedNothingEngine;
+use oh_my_kiwi_engine::sharded::ShardedEngine;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+const DURATION: Duration = Duration::from_secs(1);
+const TASKS_PER_THREAD: usize = 4;
+const KEYS: usize = 10_000;
+
+fn main() {
+    let cores = std::thread::available_parallelism().map_or(1, usize::from);
+    println!("{cores} cores available");
+    println!("{:<15} {:>8} {:>14}", "engine", "threads", "ops/sec");
+
+    for threads in [1, 2, 4, 8] {
+        let config = KiwiConfig::new().with_engine_threads(threads);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .enable_time()
+            .build()
+            .unwrap();
+        let workers = vec![runtime.handle().clone(); threads];
+
+        let engine = Arc::new(InMemoryEngine::from_config(&config));
+        let ops = runtime.block_on(run(engine, &workers));
+        println!("{:<15} {threads:>8} {ops:>14.0}", "in-memory");
+        let engine = Arc::new(ShardedEngine::from_config(&config));
+        let ops = runtime.block_on(run(engine, &workers));
+        println!("{:<15} {threads:>8} {ops:>14.0}", "sharded");
+        let engine = Arc::new(SharedNothingEngine::from_config(&config).unwrap());
+        let cores = engine.runtimes();
+        let ops = runtime.block_on(run(engine, &cores));
+        println!("{:<15} {threads:>8} {ops:>14.0}", "shared-nothing");
+    }
+}
+
+/// Operations per second of tasks alternating SET and GET, spread over the
+/// `workers` runtimes.
+async fn run<E>(engine: Arc<E>, workers: &[Handle]) -> f64
+where
+    E: Engine + Send + Sync + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let ops = Arc::new(AtomicU64::new(0));
+
+    let tasks: Vec<_> = (0..workers.len() * TASKS_PER_THREAD)
+        .map(|task| {
+            let (engine, stop, ops) = (engine.clone(), stop.clone(), ops.clone());
+            workers[task % workers.len()].spawn(async move {
+                let mut done = 0;
+                let mut i = task;
+                while !stop.load(Ordering::Relaxed) {
+                    let key = format!("key:{}", i % KEYS).into_bytes();
+                    engine.set(0, key.clone(), b"value".to_vec()).await;
+                    engine.get(0, &key).await;
+                    done += 2;
+                    i += 7;
+                    if done % 256 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+                ops.fetch_add(done, Ordering::Relaxed);
+            })
+        })
+        .collect();
+
+    let started = Instant::now();
+    tokio::time::sleep(DURATION).await;
+    stop.store(true, Ordering::Relaxed);
+    for task in tasks {
+        task.await.unwrap();
+    }
+    ops.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+}
@@ -0,0 +1,54 @@
+//! SET latency while the keyspace grows. Run with
+//! `cargo bench -p oh-my-kiwi-engine --bench set_latency`; the in-memory
+//! engine's worst SET should stay close to its typical one as the
+//! dictionary resizes, where a `HashMap` stalls on the insert that
+//! rehashes every key.
+
+use oh_my_kiwi_domain::Engine;
+use oh_my_kiwi_engine::in_memory::InMemoryEngine;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 2_000_000;
+
+fn main() {
+    println!("{:<10} {:>12} {:>12} {:>12}", "map", "p50", "p99.9", "max");
+
+    let mut map = HashMap::new();
+    let latencies = (0..KEYS).map(|i| {
+        let started = Instant::now();
+        map.insert(key(i), b"value".to_vec());
+        started.elapsed()
+    });
+    report("HashMap", latencies.collect());
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let engine = InMemoryEngine::new();
+    let latencies = runtime.block_on(async {
+        let mut latencies = Vec::with_capacity(KEYS);
+        for i in 0..KEYS {
+            let started = Instant::now();
+            engine.set(0, key(i), b"value".to_vec()).await;
+            latencies.push(started.elapsed());
+        }
+        latencies
+    });
+    report("engine", latencies);
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key:{i}").into_bytes()
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort_unstable();
+    let at = |quantile: f64| latencies[((latencies.len() - 1) as f64 * quantile) as usize];
+    println!(
+        "{name:<10} {:>12?} {:>12?} {:>12?}",
+        at(0.5),
+        at(0.999),
+        at(1.0)
+    );
+}
@@ -0,0 +1,407 @@
+use crate::acl::categories::{
+    CATEGORIES, command_categories, commands_in_category, is_known_category,
+};
+use crate::acl::log::{AclLog, DenialReason};
+use crate::acl::user::User;
+use crate::command_processor::key_bytes;
+use crate::config::KiwiConfig;
+use oh_my_kiwi_domain::command::KiwiCommand;
+use oh_my_kiwi_domain::error::CommandError;
+use oh_my_kiwi_domain::types::Types;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub mod categories;
+pub mod log;
+pub mod user;
+
+pub const DEFAULT_USER: &str = "default";
+
+/// Server-wide access control list: the users, their permissions, and the
+/// optional file they are persisted to with ACL SAVE and ACL LOAD.
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+    file: Option<PathBuf>,
+    log: AclLog,
+}
+
+impl Acl {
+    /// Creates the default user, protected by `requirepass` when it is set, and
+    /// loads the ACL file if one is configured and exists.
+    pub fn new(config: &KiwiConfig) -> std::io::Result<Self> {
+        let acl = Self {
+            users: RwLock::new(BTreeMap::new()),
+            file: config.aclfile().map(Path::to_path_buf),
+            log: AclLog::new(config.acllog_max_len()),
+        };
+
+        let mut default_user = default_user();
+        if let Some(password) = config.requirepass() {
+            default_user.apply_rule(&format!(">{password}")).ok();
+        }
+        acl.users
+            .write()
+            .unwrap()
+            .insert(DEFAULT_USER.to_string(), default_user);
+
+        if acl.file.as_deref().is_some_and(Path::exists) {
+            acl.load().map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+            })?;
+        }
+        Ok(acl)
+    }
+
+    /// Whether new connections have to AUTH before running commands, which is
+    /// the case unless the default user is enabled and has no password.
+    pub fn requires_auth(&self) -> bool {
+        let users = self.users.read().unwrap();
+        !users
+            .get(DEFAULT_USER)
+            .is_some_and(|user| user.is_enabled() && user.is_nopass())
+    }
+
+    /// Checks the credentials of `username`, logging the attempt made by
+    /// `client_info` if they are wrong.
+    pub fn authenticate(&self, username: &str, password: &str, client_info: &str) -> bool {
+        let users = self.users.read().unwrap();
+        let authenticated = users
+            .get(username)
+            .is_some_and(|user| user.is_enabled() && user.check_password(password));
+        if !authenticated {
+            self.log
+                .push(DenialReason::Auth, "AUTH", username, client_info);
+        }
+        authenticated
+    }
+
+    /// Checks that `username` may run `command` on all of the keys and
+    /// channels it touches.
+    /// Denials are logged along with `client_info`.
+    pub fn check(
+        &self,
+        username: &str,
+        command: &KiwiCommand,
+        client_info: &str,
+    ) -> Result<(), CommandError> {
+        let users = self.users.read().unwrap();
+        let name = command.name();
+        let subcommand = command.subcommand_name();
+
+        let user = users.get(username).filter(|user| user.is_enabled());
+        let Some(user) = user.filter(|user| user.can_run(name, subcommand)) else {
+            let command = match subcommand {
+                Some(subcommand) => format!("{name}|{subcommand}"),
+                None => name.to_string(),
+            };
+            self.log
+                .push(DenialReason::Command, &command, username, client_info);
+            return Err(CommandError::NoCommandPermission {
+                user: username.to_string(),
+                command,
+            });
+        };
+
+        let categories = match subcommand {
+            Some(subcommand) => command_categories(&format!("{name}|{subcommand}")),
+            None => command_categories(name),
+        };
+        let read = categories.contains(&"read");
+        let write = categories.contains(&"write");
+        for key in command.keys() {
+            let key = key_bytes(key);
+            if !user.can_access_key(&key, read, write) {
+                let key = String::from_utf8_lossy(&key);
+                self.log
+                    .push(DenialReason::Key, &key, username, client_info);
+                return Err(CommandError::NoKeyPermission);
+            }
+        }
+        for channel in command.channels() {
+            let channel = key_bytes(channel);
+            if !user.can_access_channel(&channel) {
+                let channel = String::from_utf8_lossy(&channel);
+                self.log
+                    .push(DenialReason::Channel, &channel, username, client_info);
+                return Err(CommandError::NoChannelPermission);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn log(&self) -> &AclLog {
+        &self.log
+    }
+
+    /// Creates or modifies a user. The rules are applied atomically: if one of
+    /// them is invalid the user is left untouched.
+    pub fn set_user(&self, username: &str, rules: &[String]) -> Result<(), CommandError> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users
+            .get(username)
+            .cloned()
+            .unwrap_or_else(|| User::new(username));
+        apply_rules(&mut user, rules.iter().map(String::as_str))?;
+
+        users.insert(username.to_string(), user);
+        Ok(())
+    }
+
+    pub fn get_user(&self, username: &str) -> Option<Types> {
+        let users = self.users.read().unwrap();
+        users.get(username).map(User::to_types)
+    }
+
+    /// Deletes the given users, returning how many of them existed.
+    pub fn delete_users(&self, usernames: &[String]) -> Result<usize, CommandError> {
+        if usernames.iter().any(|username| username == DEFAULT_USER) {
+            return Err(CommandError::DefaultUserRemoval);
+        }
+
+        let mut users = self.users.write().unwrap();
+        Ok(usernames
+            .iter()
+            .filter(|username| users.remove(username.as_str()).is_some())
+            .count())
+    }
+
+    /// Describes every user as an ACL file line.
+    pub fn list(&self) -> Vec<String> {
+        let users = self.users.read().unwrap();
+        users.values().map(User::to_string).collect()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        let users = self.users.read().unwrap();
+        users.keys().cloned().collect()
+    }
+
+    /// Lists all categories, or the commands of `category` when it is given.
+    pub fn categories(category: Option<&str>) -> Result<Vec<&'static str>, CommandError> {
+        match category.map(str::to_lowercase) {
+            None => Ok(CATEGORIES.to_vec()),
+            Some(category) if is_known_category(&category) => Ok(commands_in_category(&category)),
+            Some(category) => Err(CommandError::UnknownAclCategory(category)),
+        }
+    }
+
+    /// Replaces all users with the ones defined in the ACL file. Nothing changes
+    /// if the file has an error.
+    pub fn load(&self) -> Result<(), CommandError> {
+        let path = self
+            .file
+            .as_ref()
+            .ok_or(CommandError::AclFileNotConfigured)?;
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| CommandError::AclFile(format!("{}: {err}", path.display())))?;
+
+        let mut loaded = BTreeMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let location = format!("{}:{}", path.display(), number + 1);
+            let mut parts = line.split_whitespace();
+            if parts.next() != Some("user") {
+                return Err(CommandError::AclFile(format!(
+                    "{location}: line should start with user keyword"
+                )));
+            }
+            let Some(username) = parts.next() else {
+                return Err(CommandError::AclFile(format!(
+                    "{location}: missing username"
+                )));
+            };
+            if loaded.contains_key(username) {
+                return Err(CommandError::AclFile(format!(
+                    "{location}: duplicate user '{username}'"
+                )));
+            }
+
+            let mut user = User::new(username);
+            apply_rules(&mut user, parts)
+                .map_err(|err| CommandError::AclFile(format!("{location}: {err}")))?;
+            loaded.insert(username.to_string(), user);
+        }
+        loaded
+            .entry(DEFAULT_USER.to_string())
+            .or_insert_with(default_user);
+
+        *self.users.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    /// Writes all users to the ACL file, replacing it atomically.
+    pub fn save(&self) -> Result<(), CommandError> {
+        let path = self
+            .file
+            .as_ref()
+            .ok_or(CommandError::AclFileNotConfigured)?;
+        let mut content = self.list().join("\n");
+        content.push('\n');
+
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, content)
+            .and_then(|_| std::fs::rename(&temp_path, path))
+            .map_err(|err| CommandError::AclFile(format!("{}: {err}", path.display())))
+    }
+}
+
+fn default_user() -> User {
+    let mut user = User::new(DEFAULT_USER);
+    for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+        user.apply_rule(rule).ok();
+    }
+    user
+}
+
+fn apply_rules<'a>(
+    user: &mut User,
+    rules: impl IntoIterator<Item = &'a str>,
+) -> Result<(), CommandError> {
+    for rule in rules {
+        user.apply_rule(rule)
+            .map_err(|reason| CommandError::InvalidAclRule {
+                rule: rule.to_string(),
+                reason: reason.to_string(),
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "id=1 addr=127.0.0.1:50000";
+
+    fn rules(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn get(key: &str) -> KiwiCommand {
+        KiwiCommand::Get {
+            key: Types::BulkString(key.to_string()),
+        }
+    }
+
+    fn set(key: &str) -> KiwiCommand {
+        KiwiCommand::Set {
+            key: Types::BulkString(key.to_string()),
+            value: Types::BulkString("value".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_default_user() {
+        let acl = Acl::new(&KiwiConfig::default()).unwrap();
+        assert!(!acl.requires_auth());
+        assert!(acl.check(DEFAULT_USER, &set("key"), CLIENT).is_ok());
+
+        let acl = Acl::new(&KiwiConfig::new().with_requirepass("secret")).unwrap();
+        assert!(acl.requires_auth());
+        assert!(acl.authenticate(DEFAULT_USER, "secret", CLIENT));
+        assert!(!acl.authenticate(DEFAULT_USER, "wrong", CLIENT));
+    }
+
+    #[test]
+    fn test_check_commands_and_keys() {
+        let acl = Acl::new(&KiwiConfig::default()).unwrap();
+        acl.set_user("reader", &rules(&["on", ">pass", "~cache:*", "+@read"]))
+            .unwrap();
+
+        assert!(acl.authenticate("reader", "pass", CLIENT));
+        assert!(acl.check("reader", &get("cache:1"), CLIENT).is_ok());
+        assert!(matches!(
+            acl.check("reader", &get("secret"), CLIENT),
+            Err(CommandError::NoKeyPermission)
+        ));
+        assert!(matches!(
+            acl.check("reader", &set("cache:1"), CLIENT),
+            Err(CommandError::NoCommandPermission { command, .. }) if command == "set"
+        ));
+    }
+
+    #[test]
+    fn test_denials_are_logged() {
+        let acl = Acl::new(&KiwiConfig::default()).unwrap();
+        acl.set_user("reader", &rules(&["on", ">pass", "~cache:*", "+get"]))
+            .unwrap();
+
+        assert!(!acl.authenticate("reader", "wrong", CLIENT));
+        assert!(acl.check("reader", &set("cache:1"), CLIENT).is_err());
+        assert!(acl.check("reader", &get("secret"), CLIENT).is_err());
+        assert!(acl.check("reader", &get("cache:1"), CLIENT).is_ok());
+
+        let objects: Vec<Types> = acl
+            .log()
+            .entries(None)
+            .iter()
+            .map(|entry| match entry {
+                Types::Map(map) => map[&Types::BulkString("object".to_string())].clone(),
+                other => panic!("expected a map, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            objects,
+            vec![
+                Types::BulkString("secret".to_string()),
+                Types::BulkString("set".to_string()),
+                Types::BulkString("AUTH".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled_and_deleted_users() {
+        let acl = Acl::new(&KiwiConfig::default()).unwrap();
+        acl.set_user("alice", &rules(&[">pass", "allcommands", "allkeys"]))
+            .unwrap();
+        assert!(!acl.authenticate("alice", "pass", CLIENT));
+        assert!(acl.check("alice", &get("key"), CLIENT).is_err());
+
+        acl.set_user("alice", &rules(&["on"])).unwrap();
+        assert!(acl.authenticate("alice", "pass", CLIENT));
+
+        assert_eq!(acl.delete_users(&rules(&["alice", "bob"])).unwrap(), 1);
+        assert!(acl.check("alice", &get("key"), CLIENT).is_err());
+        assert!(acl.delete_users(&rules(&["default"])).is_err());
+    }
+
+    #[test]
+    fn test_set_user_is_atomic() {
+        let acl = Acl::new(&KiwiConfig::default()).unwrap();
+        let result = acl.set_user("alice", &rules(&["on", "+nosuchcommand"]));
+        assert!(
+            matches!(result, Err(CommandError::InvalidAclRule { rule, .. }) if rule == "+nosuchcommand")
+        );
+        assert!(acl.get_user("alice").is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("kiwi-acl-{}.acl", std::process::id()));
+        let config = KiwiConfig::new().with_aclfile(&path);
+
+        let acl = Acl::new(&config).unwrap();
+        acl.set_user(
+            "alice",
+            &rules(&["on", ">pass", "~app:*", "&app.*", "+get"]),
+        )
+        .unwrap();
+        acl.save().unwrap();
+
+        let restored = Acl::new(&config).unwrap();
+        assert_eq!(restored.list(), acl.list());
+        assert!(restored.authenticate("alice", "pass", CLIENT));
+
+        std::fs::write(&path, "user alice on +nosuchcommand\n").unwrap();
+        assert!(restored.load().is_err());
+        assert_eq!(restored.list(), acl.list());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
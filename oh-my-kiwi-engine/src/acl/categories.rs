@@ -0,0 +1,146 @@
+/// Every ACL category, in the order ACL CAT lists them.
+pub(crate) const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "set",
+    "sortedset",
+    "list",
+    "hash",
+    "string",
+    "bitmap",
+    "hyperloglog",
+    "geo",
+    "stream",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+/// ACL categories of every command, with container commands like ACL and
+/// CLIENT listed per subcommand as `command|subcommand`.
+pub(crate) const COMMANDS: &[(&str, &[&str])] = &[
+    ("ping", &["fast", "connection"]),
+    ("command", &["slow", "connection"]),
+    ("get", &["read", "string", "fast"]),
+    ("set", &["write", "string", "slow"]),
+    ("mget", &["read", "string", "fast"]),
+    ("mset", &["write", "string", "slow"]),
+    ("del", &["keyspace", "write", "slow"]),
+    ("unlink", &["keyspace", "write", "fast"]),
+    ("client|id", &["slow", "connection"]),
+    ("client|tracking", &["slow", "connection"]),
+    ("client|caching", &["slow", "connection"]),
+    ("select", &["fast", "connection"]),
+    ("swapdb", &["keyspace", "write", "fast", "dangerous"]),
+    ("move", &["keyspace", "write", "fast"]),
+    ("dbsize", &["keyspace", "read", "fast"]),
+    ("flushdb", &["keyspace", "write", "slow", "dangerous"]),
+    ("flushall", &["keyspace", "write", "slow", "dangerous"]),
+    ("auth", &["fast", "connection"]),
+    ("hello", &["fast", "connection"]),
+    ("quit", &["fast", "connection"]),
+    ("acl|setuser", &["admin", "slow", "dangerous"]),
+    ("acl|getuser", &["admin", "slow", "dangerous"]),
+    ("acl|deluser", &["admin", "slow", "dangerous"]),
+    ("acl|list", &["admin", "slow", "dangerous"]),
+    ("acl|users", &["admin", "slow", "dangerous"]),
+    ("acl|whoami", &["slow"]),
+    ("acl|cat", &["slow"]),
+    ("acl|log", &["admin", "slow", "dangerous"]),
+    ("acl|load", &["admin", "slow", "dangerous"]),
+    ("acl|save", &["admin", "slow", "dangerous"]),
+    ("expire", &["keyspace", "write", "fast"]),
+    ("pexpire", &["keyspace", "write", "fast"]),
+    ("expireat", &["keyspace", "write", "fast"]),
+    ("pexpireat", &["keyspace", "write", "fast"]),
+    ("ttl", &["keyspace", "read", "fast"]),
+    ("pttl", &["keyspace", "read", "fast"]),
+    ("persist", &["keyspace", "write", "fast"]),
+    ("save", &["admin", "slow", "dangerous"]),
+    ("bgsave", &["admin", "slow", "dangerous"]),
+    ("lastsave", &["fast", "dangerous"]),
+    ("bgrewriteaof", &["admin", "slow", "dangerous"]),
+    ("debug|reload", &["admin", "slow", "dangerous"]),
+    ("object|encoding", &["keyspace", "read", "slow"]),
+    ("memory|usage", &["read", "slow"]),
+    ("memory|stats", &["slow"]),
+    ("memory|doctor", &["slow"]),
+    ("memory|malloc-stats", &["slow"]),
+    ("dump", &["keyspace", "read", "slow"]),
+    ("restore", &["keyspace", "write", "slow", "dangerous"]),
+    ("migrate", &["keyspace", "write", "slow", "dangerous"]),
+    ("replicaof", &["admin", "slow", "dangerous"]),
+    ("replconf", &["admin", "slow", "dangerous"]),
+    ("psync", &["admin", "slow", "dangerous"]),
+    ("sync", &["admin", "slow", "dangerous"]),
+    ("info", &["slow", "dangerous"]),
+    ("wait", &["slow", "connection"]),
+    ("waitaof", &["slow", "connection"]),
+    ("cluster|info", &["slow"]),
+    ("cluster|myid", &["slow"]),
+    ("cluster|nodes", &["slow"]),
+    ("cluster|slots", &["slow"]),
+    ("cluster|shards", &["slow"]),
+    ("cluster|keyslot", &["slow"]),
+    ("cluster|countkeysinslot", &["slow"]),
+    ("cluster|getkeysinslot", &["slow"]),
+    ("cluster|meet", &["admin", "slow", "dangerous"]),
+    ("cluster|addslots", &["admin", "slow", "dangerous"]),
+    ("cluster|addslotsrange", &["admin", "slow", "dangerous"]),
+    ("cluster|delslots", &["admin", "slow", "dangerous"]),
+    ("cluster|delslotsrange", &["admin", "slow", "dangerous"]),
+    ("cluster|setslot", &["admin", "slow", "dangerous"]),
+    ("cluster|replicate", &["admin", "slow", "dangerous"]),
+    ("asking", &["fast", "connection"]),
+];
+
+/// Categories of `name`, which is either a plain command or `command|subcommand`.
+pub(crate) fn command_categories(name: &str) -> &'static [&'static str] {
+    COMMANDS
+        .iter()
+        .find(|(command, _)| *command == name)
+        .map_or(&[], |(_, categories)| categories)
+}
+
+/// Whether an ACL rule may reference `name`, either a command, a container
+/// command like `client`, or one of its subcommands like `client|id`.
+pub(crate) fn is_known_command(name: &str) -> bool {
+    COMMANDS.iter().any(|(command, _)| {
+        *command == name
+            || command
+                .split_once('|')
+                .is_some_and(|(base, _)| base == name)
+    })
+}
+
+/// Whether `name` may modify the data set.
+pub(crate) fn is_write_command(name: &str) -> bool {
+    command_categories(name).contains(&"write")
+}
+
+/// Write commands that may add data, refused while the data set is over
+/// maxmemory and nothing more can be evicted.
+const DENY_OOM_COMMANDS: &[&str] = &["set", "mset", "restore"];
+
+pub(crate) fn is_deny_oom_command(name: &str) -> bool {
+    DENY_OOM_COMMANDS.contains(&name)
+}
+
+pub(crate) fn is_known_category(category: &str) -> bool {
+    CATEGORIES.contains(&category)
+}
+
+pub(crate) fn commands_in_category(category: &str) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .filter(|(_, categories)| categories.contains(&category))
+        .map(|(command, _)| *command)
+        .collect()
+}
@@ -0,0 +1,212 @@
+use crate::time::now_ms;
+use oh_my_kiwi_domain::types::Types;
+use ordered_float::OrderedFloat;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+pub const DEFAULT_ACLLOG_MAX_LEN: usize = 128;
+
+/// Repeated denials within this window are folded into a single entry.
+const GROUPING_WINDOW_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenialReason {
+    Command,
+    Key,
+    Channel,
+    Auth,
+}
+
+impl DenialReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DenialReason::Command => "command",
+            DenialReason::Key => "key",
+            DenialReason::Channel => "channel",
+            DenialReason::Auth => "auth",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AclLogEntry {
+    id: u64,
+    count: u64,
+    reason: DenialReason,
+    object: String,
+    username: String,
+    client_info: String,
+    created_ms: u64,
+    updated_ms: u64,
+}
+
+/// Bounded log of denied commands and failed authentications, newest first.
+pub struct AclLog {
+    state: Mutex<AclLogState>,
+    max_len: usize,
+}
+
+#[derive(Default)]
+struct AclLogState {
+    entries: VecDeque<AclLogEntry>,
+    next_id: u64,
+}
+
+impl AclLog {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            state: Mutex::new(AclLogState::default()),
+            max_len,
+        }
+    }
+
+    /// Records a denial. A denial with the same reason, object and user as a
+    /// recent entry bumps that entry's count instead of adding a new one.
+    pub fn push(&self, reason: DenialReason, object: &str, username: &str, client_info: &str) {
+        self.push_at(reason, object, username, client_info, now_ms());
+    }
+
+    fn push_at(
+        &self,
+        reason: DenialReason,
+        object: &str,
+        username: &str,
+        client_info: &str,
+        now: u64,
+    ) {
+        if self.max_len == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let similar = state.entries.iter().position(|entry| {
+            entry.reason == reason
+                && entry.object == object
+                && entry.username == username
+                && now.saturating_sub(entry.updated_ms) < GROUPING_WINDOW_MS
+        });
+
+        let entry = match similar.and_then(|index| state.entries.remove(index)) {
+            Some(mut entry) => {
+                entry.count += 1;
+                entry.client_info = client_info.to_string();
+                entry.updated_ms = now;
+                entry
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                AclLogEntry {
+                    id,
+                    count: 1,
+                    reason,
+                    object: object.to_string(),
+                    username: username.to_string(),
+                    client_info: client_info.to_string(),
+                    created_ms: now,
+                    updated_ms: now,
+                }
+            }
+        };
+        state.entries.push_front(entry);
+        state.entries.truncate(self.max_len);
+    }
+
+    /// Returns up to `count` of the most recent entries, all of them by default.
+    pub fn entries(&self, count: Option<usize>) -> Vec<Types> {
+        let now = now_ms();
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .iter()
+            .take(count.unwrap_or(usize::MAX))
+            .map(|entry| entry.to_types(now))
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.state.lock().unwrap().entries.clear();
+    }
+}
+
+impl AclLogEntry {
+    fn to_types(&self, now: u64) -> Types {
+        let mut map = BTreeMap::new();
+        let mut field = |name: &str, value: Types| {
+            map.insert(Types::BulkString(name.to_string()), value);
+        };
+        field("count", Types::Integer(self.count as i64));
+        field(
+            "reason",
+            Types::BulkString(self.reason.as_str().to_string()),
+        );
+        field("context", Types::BulkString("toplevel".to_string()));
+        field("object", Types::BulkString(self.object.clone()));
+        field("username", Types::BulkString(self.username.clone()));
+        let age = now.saturating_sub(self.created_ms) as f64 / 1000.0;
+        field("age-seconds", Types::Double(OrderedFloat(age)));
+        field("client-info", Types::BulkString(self.client_info.clone()));
+        field("entry-id", Types::Integer(self.id as i64));
+        field("timestamp-created", Types::Integer(self.created_ms as i64));
+        field(
+            "timestamp-last-updated",
+            Types::Integer(self.updated_ms as i64),
+        );
+        Types::Map(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field<'a>(entry: &'a Types, name: &str) -> &'a Types {
+        let Types::Map(map) = entry else {
+            panic!("expected a map, got {:?}", entry);
+        };
+        &map[&Types::BulkString(name.to_string())]
+    }
+
+    #[test]
+    fn test_newest_first_and_bounded() {
+        let log = AclLog::new(2);
+        log.push(DenialReason::Command, "set", "alice", "addr=a");
+        log.push(DenialReason::Key, "secret", "alice", "addr=a");
+        log.push(DenialReason::Auth, "AUTH", "bob", "addr=b");
+
+        let entries = log.entries(None);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            field(&entries[0], "reason"),
+            &Types::BulkString("auth".to_string())
+        );
+        assert_eq!(
+            field(&entries[1], "object"),
+            &Types::BulkString("secret".to_string())
+        );
+        assert_eq!(log.entries(Some(1)).len(), 1);
+
+        log.reset();
+        assert!(log.entries(None).is_empty());
+    }
+
+    #[test]
+    fn test_similar_denials_are_grouped() {
+        let log = AclLog::new(DEFAULT_ACLLOG_MAX_LEN);
+        log.push_at(DenialReason::Command, "set", "alice", "addr=a", 1_000);
+        log.push_at(DenialReason::Command, "get", "alice", "addr=a", 2_000);
+        log.push_at(DenialReason::Command, "set", "alice", "addr=c", 3_000);
+
+        let entries = log.entries(None);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(field(&entries[0], "count"), &Types::Integer(2));
+        assert_eq!(field(&entries[0], "entry-id"), &Types::Integer(0));
+        assert_eq!(
+            field(&entries[0], "client-info"),
+            &Types::BulkString("addr=c".to_string())
+        );
+
+        log.push_at(DenialReason::Command, "set", "alice", "addr=a", 70_000);
+        assert_eq!(log.entries(None).len(), 3);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::acl::categories::{command_categories, is_known_category, is_known_command};
+use crate::auth::constant_time_eq;
+use crate::glob::glob_match;
+use oh_my_kiwi_domain::types::Types;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+
+/// A user with its passwords and permissions.
+///
+/// Permissions are kept as the ordered list of command rules that produced them:
+/// a command is allowed when the last rule matching it starts with `+`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    name: String,
+    enabled: bool,
+    nopass: bool,
+    passwords: BTreeSet<String>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+    commands: Vec<CommandRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CommandRule {
+    allow: bool,
+    target: RuleTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RuleTarget {
+    All,
+    Category(String),
+    Command(String),
+}
+
+impl User {
+    /// Creates a disabled user without passwords or permissions.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Applies one ACL SETUSER rule, returning the reason when it is invalid.
+    pub fn apply_rule(&mut self, rule: &str) -> Result<(), &'static str> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec![KeyPattern::read_write("*")],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec!["*".to_string()],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.commands = vec![CommandRule::all(true)],
+            "nocommands" => self.commands.clear(),
+            "reset" => *self = Self::new(std::mem::take(&mut self.name)),
+            _ => return self.apply_pattern_rule(rule),
+        }
+        Ok(())
+    }
+
+    fn apply_pattern_rule(&mut self, rule: &str) -> Result<(), &'static str> {
+        if let Some(password) = rule.strip_prefix('>') {
+            self.nopass = false;
+            self.passwords.insert(hash_password(password));
+        } else if let Some(password) = rule.strip_prefix('<') {
+            if !self.passwords.remove(&hash_password(password)) {
+                return Err("The password you are trying to remove from the user does not exist");
+            }
+        } else if let Some(hash) = rule.strip_prefix('#') {
+            if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return Err(
+                    "The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters",
+                );
+            }
+            self.nopass = false;
+            self.passwords.insert(hash.to_lowercase());
+        } else if let Some(hash) = rule.strip_prefix('!') {
+            if !self.passwords.remove(&hash.to_lowercase()) {
+                return Err("The password you are trying to remove from the user does not exist");
+            }
+        } else if let Some(pattern) = rule.strip_prefix('~') {
+            self.keys.push(KeyPattern::read_write(pattern));
+        } else if let Some((access, pattern)) =
+            rule.strip_prefix('%').and_then(|rule| rule.split_once('~'))
+        {
+            let access = access.to_uppercase();
+            if access.is_empty() || !access.chars().all(|c| c == 'R' || c == 'W') {
+                return Err("Syntax error");
+            }
+            self.keys.push(KeyPattern {
+                pattern: pattern.to_string(),
+                read: access.contains('R'),
+                write: access.contains('W'),
+            });
+        } else if let Some(pattern) = rule.strip_prefix('&') {
+            self.channels.push(pattern.to_string());
+        } else if let Some(target) = rule.strip_prefix('+') {
+            self.add_command_rule(true, target)?;
+        } else if let Some(target) = rule.strip_prefix('-') {
+            self.add_command_rule(false, target)?;
+        } else {
+            return Err("Syntax error");
+        }
+        Ok(())
+    }
+
+    fn add_command_rule(&mut self, allow: bool, target: &str) -> Result<(), &'static str> {
+        let target = target.to_lowercase();
+        let target = match target.strip_prefix('@') {
+            Some("all") => RuleTarget::All,
+            Some(category) if is_known_category(category) => {
+                RuleTarget::Category(category.to_string())
+            }
+            None if is_known_command(&target) => RuleTarget::Command(target),
+            _ => return Err("Unknown command or category name in ACL"),
+        };
+
+        // A rule on every command makes all the previous ones irrelevant, and
+        // denying everything is the same as having no rules at all.
+        if target == RuleTarget::All {
+            self.commands.clear();
+            if !allow {
+                return Ok(());
+            }
+        }
+        self.commands.push(CommandRule { allow, target });
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_nopass(&self) -> bool {
+        self.nopass
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        if self.nopass {
+            return true;
+        }
+
+        let hash = hash_password(password);
+        self.passwords.iter().fold(false, |matched, candidate| {
+            constant_time_eq(candidate.as_bytes(), hash.as_bytes()) | matched
+        })
+    }
+
+    /// Whether the user may run `command`, optionally narrowed to a `subcommand`.
+    pub fn can_run(&self, command: &str, subcommand: Option<&str>) -> bool {
+        let full_name = subcommand.map(|subcommand| format!("{command}|{subcommand}"));
+        let categories = command_categories(full_name.as_deref().unwrap_or(command));
+
+        self.commands
+            .iter()
+            .rfind(|rule| match &rule.target {
+                RuleTarget::All => true,
+                RuleTarget::Category(category) => categories.contains(&category.as_str()),
+                RuleTarget::Command(name) => name == command || Some(name) == full_name.as_ref(),
+            })
+            .is_some_and(|rule| rule.allow)
+    }
+
+    /// Whether the user may access `key` with the given kind of access.
+    pub fn can_access_key(&self, key: &[u8], read: bool, write: bool) -> bool {
+        self.keys.iter().any(|pattern| {
+            (!read || pattern.read)
+                && (!write || pattern.write)
+                && glob_match(pattern.pattern.as_bytes(), key)
+        })
+    }
+
+    /// Whether the user may access the pub/sub `channel`.
+    pub fn can_access_channel(&self, channel: &[u8]) -> bool {
+        self.channels
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), channel))
+    }
+
+    /// Serializes the user as ACL GETUSER does.
+    pub fn to_types(&self) -> Types {
+        let mut flags = vec![Types::BulkString(
+            if self.enabled { "on" } else { "off" }.to_string(),
+        )];
+        if self.nopass {
+            flags.push(Types::BulkString("nopass".to_string()));
+        }
+
+        let passwords = self
+            .passwords
+            .iter()
+            .map(|hash| Types::BulkString(hash.clone()))
+            .collect();
+
+        let mut info = BTreeMap::new();
+        let mut field = |name: &str, value: Types| {
+            info.insert(Types::BulkString(name.to_string()), value);
+        };
+        field("flags", Types::Array(flags));
+        field("passwords", Types::Array(passwords));
+        field("commands", Types::BulkString(self.describe_commands()));
+        field("keys", Types::BulkString(self.describe_keys()));
+        field("channels", Types::BulkString(self.describe_channels()));
+        Types::Map(info)
+    }
+
+    fn describe_commands(&self) -> String {
+        if self.commands.is_empty() {
+            return CommandRule::all(false).to_string();
+        }
+        join(self.commands.iter().map(|rule| rule.to_string()))
+    }
+
+    fn describe_keys(&self) -> String {
+        join(self.keys.iter().map(|pattern| pattern.to_string()))
+    }
+
+    fn describe_channels(&self) -> String {
+        join(self.channels.iter().map(|pattern| format!("&{pattern}")))
+    }
+}
+
+/// Describes the user as the rules that recreate it, the way ACL LIST and the
+/// ACL file show it.
+impl Display for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "user {} {}",
+            self.name,
+            if self.enabled { "on" } else { "off" }
+        )?;
+        if self.nopass {
+            write!(f, " nopass")?;
+        }
+        for hash in &self.passwords {
+            write!(f, " #{hash}")?;
+        }
+        if !self.keys.is_empty() {
+            write!(f, " {}", self.describe_keys())?;
+        }
+        write!(f, " resetchannels")?;
+        if !self.channels.is_empty() {
+            write!(f, " {}", self.describe_channels())?;
+        }
+        write!(f, " {}", self.describe_commands())
+    }
+}
+
+impl KeyPattern {
+    fn read_write(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            read: true,
+            write: true,
+        }
+    }
+}
+
+impl Display for KeyPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.read, self.write) {
+            (true, true) => write!(f, "~{}", self.pattern),
+            (true, false) => write!(f, "%R~{}", self.pattern),
+            _ => write!(f, "%W~{}", self.pattern),
+        }
+    }
+}
+
+impl CommandRule {
+    fn all(allow: bool) -> Self {
+        Self {
+            allow,
+            target: RuleTarget::All,
+        }
+    }
+}
+
+impl Display for CommandRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.allow { '+' } else { '-' };
+        match &self.target {
+            RuleTarget::All => write!(f, "{sign}@all"),
+            RuleTarget::Category(category) => write!(f, "{sign}@{category}"),
+            RuleTarget::Command(command) => write!(f, "{sign}{command}"),
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn join(parts: impl Iterator<Item = String>) -> String {
+    parts.collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::new("alice");
+        for rule in rules {
+            user.apply_rule(rule).unwrap();
+        }
+        user
+    }
+
+    #[test]
+    fn test_new_user_has_no_permissions() {
+        let user = User::new("alice");
+        assert!(!user.is_enabled());
+        assert!(!user.check_password(""));
+        assert!(!user.can_run("get", None));
+        assert!(!user.can_access_key(b"key", true, false));
+        assert_eq!(user.to_string(), "user alice off resetchannels -@all");
+    }
+
+    #[test]
+    fn test_passwords() {
+        let mut user = user(&["on", ">first", ">second"]);
+        assert!(user.check_password("first"));
+        assert!(user.check_password("second"));
+        assert!(!user.check_password("third"));
+
+        user.apply_rule("<first").unwrap();
+        assert!(!user.check_password("first"));
+        assert!(user.apply_rule("<first").is_err());
+
+        user.apply_rule(&format!("#{}", hash_password("third")))
+            .unwrap();
+        assert!(user.check_password("third"));
+
+        user.apply_rule("nopass").unwrap();
+        assert!(user.check_password("anything"));
+    }
+
+    #[test]
+    fn test_command_rules_last_match_wins() {
+        let user = user(&["+@all", "-@dangerous", "+flushdb"]);
+        assert!(user.can_run("get", None));
+        assert!(user.can_run("flushdb", None));
+        assert!(!user.can_run("flushall", None));
+        assert!(!user.can_run("acl", Some("setuser")));
+        assert!(user.can_run("acl", Some("whoami")));
+    }
+
+    #[test]
+    fn test_subcommand_rules() {
+        let user = user(&["+@read", "+client|id"]);
+        assert!(user.can_run("get", None));
+        assert!(!user.can_run("set", None));
+        assert!(user.can_run("client", Some("id")));
+        assert!(!user.can_run("client", Some("tracking")));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let mut user = User::new("alice");
+        assert!(user.apply_rule("+nosuchcommand").is_err());
+        assert!(user.apply_rule("+@nosuchcategory").is_err());
+        assert!(user.apply_rule("%X~key").is_err());
+        assert!(user.apply_rule("#nothex").is_err());
+        assert!(user.apply_rule("whatever").is_err());
+    }
+
+    #[test]
+    fn test_key_patterns() {
+        let user = user(&["~cache:*", "%R~config:*"]);
+        assert!(user.can_access_key(b"cache:1", true, true));
+        assert!(user.can_access_key(b"config:1", true, false));
+        assert!(!user.can_access_key(b"config:1", false, true));
+        assert!(!user.can_access_key(b"other", true, false));
+    }
+
+    #[test]
+    fn test_channel_patterns() {
+        let mut user = user(&["&news.*"]);
+        assert!(user.can_access_channel(b"news.tech"));
+        assert!(!user.can_access_channel(b"sports"));
+
+        user.apply_rule("resetchannels").unwrap();
+        assert!(!user.can_access_channel(b"news.tech"));
+        user.apply_rule("allchannels").unwrap();
+        assert!(user.can_access_channel(b"sports"));
+    }
+
+    #[test]
+    fn test_describe_round_trip() {
+        let user = user(&[
+            "on", ">secret", "~cache:*", "%W~log:*", "&news.*", "+@read", "-dbsize",
+        ]);
+        let description = user.to_string();
+        assert_eq!(
+            description,
+            format!(
+                "user alice on #{} ~cache:* %W~log:* resetchannels &news.* +@read -dbsize",
+                hash_password("secret")
+            )
+        );
+
+        let mut restored = User::new("alice");
+        for rule in description.split(' ').skip(2) {
+            restored.apply_rule(rule).unwrap();
+        }
+        assert_eq!(restored, user);
+    }
+}
@@ -0,0 +1,585 @@
+use crate::config::KiwiConfig;
+use crate::propagation::{
+    Replay, WriteBarrier, bulk_bytes, command_bytes, parse_command, select_bytes,
+};
+use async_trait::async_trait;
+use oh_my_kiwi_domain::command::KiwiCommand;
+use oh_my_kiwi_domain::error::{CommandError, ParseError};
+use oh_my_kiwi_domain::types::Types;
+use oh_my_kiwi_domain::{BytesReader, Engine, Snapshot};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+pub const DEFAULT_APPENDFILENAME: &str = "appendonly.aof";
+
+/// A rewrite stops draining its buffer into the new file once a drain takes
+/// less than this, and hands the rest over along with the file.
+const REWRITE_HANDOVER_SIZE: usize = 64 * 1024;
+
+/// When the log is flushed to disk with fsync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write command.
+    Always,
+    /// Once per second, in the background.
+    EverySec,
+    /// Never explicitly; the operating system decides.
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(format!("invalid appendfsync policy '{value}'")),
+        }
+    }
+}
+
+/// Append-only log of every write command, in RESP format.
+///
+/// Replaying the log from the start rebuilds the data set. BGREWRITEAOF
+/// compacts it: the current data set is written to a new file while new writes
+/// keep going to the old one and to a buffer, which is drained into the new
+/// file before it replaces the old one.
+pub struct AppendOnlyFile {
+    path: PathBuf,
+    fsync: AppendFsync,
+    state: Mutex<AofState>,
+    rewriting: AtomicBool,
+    barrier: Arc<WriteBarrier>,
+    /// Replication offset of the last write known to be on disk.
+    fsynced: watch::Sender<u64>,
+}
+
+struct AofState {
+    file: File,
+    db: Option<usize>,
+    unsynced: bool,
+    /// Replication offset of the last write appended.
+    offset: u64,
+    rewrite: Option<RewriteBuffer>,
+    /// Set while a rewritten log replaces the old one. Writes made meanwhile
+    /// only count as on disk once the new file is fsynced in its place.
+    replacing: bool,
+}
+
+#[derive(Default)]
+struct RewriteBuffer {
+    bytes: Vec<u8>,
+    db: Option<usize>,
+}
+
+impl AppendOnlyFile {
+    /// Opens the log for appending, creating it if needed. Write commands must
+    /// hold `barrier` while they change the engine and append.
+    pub fn open(config: &KiwiConfig, barrier: Arc<WriteBarrier>) -> std::io::Result<Self> {
+        let path = config.aof_path();
+        Ok(Self {
+            state: Mutex::new(AofState {
+                file: open_for_append(&path)?,
+                db: None,
+                unsynced: false,
+                offset: 0,
+                rewrite: None,
+                replacing: false,
+            }),
+            path,
+            fsync: config.appendfsync(),
+            rewriting: AtomicBool::new(false),
+            barrier,
+            fsynced: watch::Sender::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replays the log into `engine`, returning the number of commands applied.
+    ///
+    /// A command cut off at the end of the file, as left by a crash in the
+    /// middle of a write, is dropped and the file truncated before it.
+    pub async fn load<E: Engine>(&self, engine: &E) -> std::io::Result<usize> {
+        let data = std::fs::read(&self.path)?;
+        let mut cursor = LogCursor {
+            data: &data,
+            pos: 0,
+        };
+        let mut replay = Replay::default();
+
+        while cursor.pos < data.len() {
+            let start = cursor.pos;
+            let command = match Types::from_bytes(&mut cursor).await {
+                Ok(types) => parse_command(types),
+                Err(ParseError::ConnectionClosed | ParseError::MissingSeparator) => {
+                    warn!(
+                        "Truncating {} at offset {start}, the last command is incomplete",
+                        self.path.display()
+                    );
+                    let file = OpenOptions::new().write(true).open(&self.path)?;
+                    file.set_len(start as u64)?;
+                    file.sync_all()?;
+                    break;
+                }
+                Err(err) => Err(err.to_string()),
+            };
+
+            let result = match command {
+                Ok(command) => replay.apply(engine, command).await,
+                Err(err) => Err(err),
+            };
+            result.map_err(|err| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} at offset {start}: {err}", self.path.display()),
+                )
+            })?;
+        }
+        Ok(replay.commands)
+    }
+
+    /// Appends a write command executed against `db`, which brought the
+    /// replication stream to `offset`.
+    pub fn append(&self, db: usize, command: &KiwiCommand, offset: u64) {
+        let Some(command) = command_bytes(command) else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let mut bytes = Vec::new();
+        if state.db != Some(db) {
+            bytes.extend(select_bytes(db));
+            state.db = Some(db);
+        }
+        bytes.extend_from_slice(&command);
+
+        if let Some(rewrite) = &mut state.rewrite {
+            if rewrite.db != Some(db) {
+                rewrite.bytes.extend(select_bytes(db));
+                rewrite.db = Some(db);
+            }
+            rewrite.bytes.extend_from_slice(&command);
+        }
+
+        let result = state.file.write_all(&bytes).and_then(|_| match self.fsync {
+            AppendFsync::Always => state.file.sync_data(),
+            _ => Ok(()),
+        });
+        match result {
+            Ok(()) => {
+                state.offset = offset;
+                match self.fsync {
+                    AppendFsync::EverySec => state.unsynced = true,
+                    // Without fsyncs, a write is as durable as it gets once written.
+                    AppendFsync::Always | AppendFsync::No if !state.replacing => {
+                        self.mark_fsynced(offset)
+                    }
+                    AppendFsync::Always | AppendFsync::No => {}
+                }
+            }
+            Err(err) => warn!("Writing to {} failed: {err}", self.path.display()),
+        }
+    }
+
+    /// Follows the replication offset up to which the log is on disk.
+    pub fn fsynced_offset(&self) -> watch::Receiver<u64> {
+        self.fsynced.subscribe()
+    }
+
+    fn mark_fsynced(&self, offset: u64) {
+        self.fsynced.send_if_modified(|fsynced| {
+            let advanced = offset > *fsynced;
+            *fsynced = (*fsynced).max(offset);
+            advanced
+        });
+    }
+
+    /// Flushes the log to disk once per second with the `everysec` policy.
+    pub async fn run_fsync(self: Arc<Self>) {
+        if self.fsync != AppendFsync::EverySec {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let (file, offset) = {
+                let mut state = self.state.lock().unwrap();
+                if !state.unsynced || state.replacing {
+                    continue;
+                }
+                state.unsynced = false;
+                (state.file.try_clone(), state.offset)
+            };
+
+            let result = match file {
+                Ok(file) => tokio::task::spawn_blocking(move || file.sync_data())
+                    .await
+                    .unwrap_or_else(|err| Err(std::io::Error::other(err))),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => self.mark_fsynced(offset),
+                Err(err) => warn!("Syncing {} failed: {err}", self.path.display()),
+            }
+        }
+    }
+
+    /// Starts compacting the log from the current contents of `engine`.
+    pub async fn background_rewrite<E>(self: &Arc<Self>, engine: Arc<E>) -> Result<(), CommandError>
+    where
+        E: Engine + Send + Sync + 'static,
+    {
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return Err(CommandError::AofRewriteInProgress);
+        }
+
+        // Writers are only paused while the snapshot is taken, which shares the
+        // data set rather than copying it; the writes after it go to the
+        // rewrite buffer.
+        let barrier = self.barrier.exclusive().await;
+        self.state.lock().unwrap().rewrite = Some(RewriteBuffer::default());
+        let snapshot = engine.snapshot().await;
+        drop(barrier);
+
+        let aof = self.clone();
+        tokio::spawn(async move {
+            let temp_path = temp_path(&aof.path);
+            let path = temp_path.clone();
+            let result = match blocking(move || write_rewrite(&path, &*snapshot)).await {
+                Ok(file) => aof.finish_rewrite(file, &temp_path).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => info!("Background append only file rewriting terminated with success"),
+                Err(err) => {
+                    warn!("Background append only file rewriting failed: {err}");
+                    aof.state.lock().unwrap().rewrite = None;
+                    std::fs::remove_file(&temp_path).ok();
+                }
+            }
+            aof.rewriting.store(false, Ordering::Release);
+        });
+        Ok(())
+    }
+
+    /// Appends the writes buffered during the rewrite and swaps the new file in.
+    /// The buffer is drained into the new file, which is fsynced and renamed
+    /// over the log, on blocking threads while writers carry on; they only
+    /// wait while the last few buffered writes are handed over with the file.
+    async fn finish_rewrite(&self, mut file: File, temp_path: &Path) -> std::io::Result<()> {
+        loop {
+            let bytes = {
+                let mut state = self.state.lock().unwrap();
+                let rewrite = state.rewrite.get_or_insert_with(RewriteBuffer::default);
+                std::mem::take(&mut rewrite.bytes)
+            };
+            let handover = bytes.len() < REWRITE_HANDOVER_SIZE;
+            file = blocking(move || {
+                file.write_all(&bytes)?;
+                file.sync_data()?;
+                Ok(file)
+            })
+            .await?;
+            if handover {
+                break;
+            }
+        }
+
+        // From here on the old file is no longer the log, so what is written
+        // to it does not count as on disk.
+        self.state.lock().unwrap().replacing = true;
+        let (from, to) = (temp_path.to_path_buf(), self.path.clone());
+        let renamed = blocking(move || {
+            std::fs::rename(&from, &to)?;
+            sync_parent(&to)
+        })
+        .await;
+        if let Err(err) = renamed {
+            self.state.lock().unwrap().replacing = false;
+            return Err(err);
+        }
+
+        let synced = file.try_clone();
+        let (written, offset) = {
+            let mut state = self.state.lock().unwrap();
+            let rewrite = state.rewrite.take().unwrap_or_default();
+            let written = file.write_all(&rewrite.bytes);
+            state.file = file;
+            state.db = rewrite.db;
+            state.unsynced = true;
+            (written, state.offset)
+        };
+        let synced = match synced {
+            Ok(file) => blocking(move || file.sync_data()).await,
+            Err(err) => Err(err),
+        };
+        self.state.lock().unwrap().replacing = false;
+        written.and(synced)?;
+        self.mark_fsynced(offset);
+        Ok(())
+    }
+}
+
+/// Writes the compacted data set, returning the file for the buffered writes to
+/// be appended to.
+fn write_rewrite(path: &Path, snapshot: &dyn Snapshot) -> std::io::Result<File> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for index in 0..snapshot.databases() {
+        let mut entries = snapshot.entries(index).peekable();
+        if entries.peek().is_none() {
+            continue;
+        }
+        writer.write_all(&select_bytes(index))?;
+
+        for entry in entries {
+            let entry = entry?;
+            // Values are stored RESP encoded, so they are written as they are.
+            writer.write_all(b"*3\r\n")?;
+            writer.write_all(&bulk_bytes(b"SET"))?;
+            writer.write_all(&bulk_bytes(&entry.key))?;
+            writer.write_all(&entry.value)?;
+
+            if let Some(expires_at) = entry.expires_at {
+                writer.write_all(b"*3\r\n")?;
+                writer.write_all(&bulk_bytes(b"PEXPIREAT"))?;
+                writer.write_all(&bulk_bytes(&entry.key))?;
+                writer.write_all(&bulk_bytes(expires_at.to_string().as_bytes()))?;
+            }
+        }
+    }
+    writer.into_inner().map_err(|err| err.into_error())
+}
+
+struct LogCursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+#[async_trait]
+impl BytesReader for LogCursor<'_> {
+    async fn read_line(&mut self) -> Result<Vec<u8>, ParseError> {
+        let rest = &self.data[self.pos..];
+        if rest.is_empty() {
+            return Err(ParseError::ConnectionClosed);
+        }
+        match rest.windows(2).position(|window| window == b"\r\n") {
+            Some(end) => {
+                self.pos += end + 2;
+                Ok(rest[..end].to_vec())
+            }
+            None => Err(ParseError::MissingSeparator),
+        }
+    }
+
+    async fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, ParseError> {
+        let end = self.pos + n;
+        if end > self.data.len() {
+            return Err(ParseError::ConnectionClosed);
+        }
+        let bytes = self.data[self.pos..end].to_vec();
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+/// Runs `f` on a blocking thread.
+async fn blocking<T, F>(f: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+}
+
+/// Fsyncs the directory holding `path`, so a rename to it survives a crash.
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+fn open_for_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".rewrite");
+    PathBuf::from(temp_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_memory::InMemoryEngine;
+    use crate::snapshot::rdb::{RdbValue, dump_payload};
+    use crate::time::now_ms;
+    use oh_my_kiwi_domain::command::RestoreOptions;
+
+    fn config(name: &str) -> KiwiConfig {
+        let file = format!("kiwi-aof-{name}-{}.aof", std::process::id());
+        KiwiConfig::new()
+            .with_dir(std::env::temp_dir())
+            .with_appendfilename(file)
+    }
+
+    fn bulk(value: &str) -> Types {
+        Types::BulkString(value.to_string())
+    }
+
+    fn set(key: &str, value: &str) -> KiwiCommand {
+        KiwiCommand::Set {
+            key: bulk(key),
+            value: bulk(value),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_append_and_replay() {
+        let config = config("replay");
+        let aof = AppendOnlyFile::open(&config, Arc::default()).unwrap();
+        let expires_at = now_ms() as i64 + 60_000;
+        aof.append(0, &set("a", "1"), 0);
+        aof.append(2, &set("b", "2"), 0);
+        aof.append(
+            2,
+            &KiwiCommand::PExpireAt {
+                key: bulk("b"),
+                timestamp_ms: expires_at,
+            },
+            0,
+        );
+        aof.append(
+            2,
+            &KiwiCommand::Move {
+                key: bulk("b"),
+                db: 3,
+            },
+            0,
+        );
+        aof.append(0, &KiwiCommand::Get { key: bulk("a") }, 0);
+
+        let engine = InMemoryEngine::new();
+        assert_eq!(aof.load(&engine).await.unwrap(), 6);
+        assert_eq!(engine.get(0, b"a").await, Some(bulk("1").to_bytes()));
+        assert_eq!(engine.get(2, b"b").await, None);
+        assert_eq!(engine.expiry(3, b"b").await, Some(Some(expires_at as u64)));
+
+        std::fs::remove_file(aof.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_is_replayed() {
+        let config = config("restore");
+        let aof = AppendOnlyFile::open(&config, Arc::default()).unwrap();
+        let expires_at = now_ms() as i64 + 60_000;
+        let restore = KiwiCommand::Restore {
+            key: bulk("r"),
+            ttl: expires_at,
+            payload: dump_payload(&RdbValue::String(b"x".to_vec())),
+            options: RestoreOptions {
+                replace: true,
+                absttl: true,
+                ..RestoreOptions::default()
+            },
+        };
+        aof.append(1, &set("r", "old"), 0);
+        aof.append(1, &restore, 0);
+
+        let engine = InMemoryEngine::new();
+        assert_eq!(aof.load(&engine).await.unwrap(), 3);
+        assert_eq!(engine.get(1, b"r").await, Some(bulk("x").to_bytes()));
+        assert_eq!(engine.expiry(1, b"r").await, Some(Some(expires_at as u64)));
+
+        std::fs::remove_file(aof.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncated_tail_is_dropped() {
+        let config = config("truncated");
+        let aof = AppendOnlyFile::open(&config, Arc::default()).unwrap();
+        aof.append(0, &set("a", "1"), 0);
+        let valid_len = std::fs::metadata(aof.path()).unwrap().len();
+        aof.append(0, &set("b", "2"), 0);
+        let file = OpenOptions::new().write(true).open(aof.path()).unwrap();
+        file.set_len(valid_len + 10).unwrap();
+
+        let engine = InMemoryEngine::new();
+        assert_eq!(aof.load(&engine).await.unwrap(), 2);
+        assert_eq!(engine.get(0, b"b").await, None);
+        assert_eq!(std::fs::metadata(aof.path()).unwrap().len(), valid_len);
+
+        std::fs::write(aof.path(), b"*1\r\n$3\r\nGET\r\n").unwrap();
+        assert!(aof.load(&engine).await.is_err());
+
+        std::fs::remove_file(aof.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fsynced_offset() {
+        let config = config("fsynced").with_appendfsync(AppendFsync::Always);
+        let aof = AppendOnlyFile::open(&config, Arc::default()).unwrap();
+        let fsynced = aof.fsynced_offset();
+        aof.append(0, &set("a", "1"), 42);
+        assert_eq!(*fsynced.borrow(), 42);
+        aof.append(0, &KiwiCommand::Get { key: bulk("a") }, 50);
+        assert_eq!(*fsynced.borrow(), 42);
+
+        // Not while a rewritten log replaces this one.
+        aof.state.lock().unwrap().replacing = true;
+        aof.append(0, &set("b", "2"), 60);
+        assert_eq!(*fsynced.borrow(), 42);
+
+        std::fs::remove_file(aof.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_background_rewrite() {
+        let config = config("rewrite");
+        let aof = Arc::new(AppendOnlyFile::open(&config, Arc::default()).unwrap());
+        let engine = Arc::new(InMemoryEngine::new());
+        for value in ["1", "2", "3"] {
+            engine.set(1, b"a".to_vec(), bulk(value).to_bytes()).await;
+            aof.append(1, &set("a", value), 0);
+        }
+
+        aof.background_rewrite(engine.clone()).await.unwrap();
+        aof.append(1, &set("b", "4"), 0);
+        // More than is handed over with the file, so the buffer takes two drains.
+        let large = "x".repeat(REWRITE_HANDOVER_SIZE);
+        aof.append(2, &set("large", &large), 0);
+        while aof.rewriting.load(Ordering::Acquire) {
+            tokio::task::yield_now().await;
+        }
+        aof.append(1, &set("c", "5"), 0);
+
+        let restored = InMemoryEngine::new();
+        assert_eq!(aof.load(&restored).await.unwrap(), 8);
+        assert_eq!(restored.get(1, b"a").await, Some(bulk("3").to_bytes()));
+        assert_eq!(restored.get(1, b"b").await, Some(bulk("4").to_bytes()));
+        assert_eq!(restored.get(1, b"c").await, Some(bulk("5").to_bytes()));
+        assert_eq!(
+            restored.get(2, b"large").await,
+            Some(bulk(&large).to_bytes())
+        );
+
+        std::fs::remove_file(aof.path()).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+/// Compares two secrets in time that depends only on their lengths, so the
+/// position of the first mismatching byte can't be learned by timing AUTH calls.
+pub fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    let len = left.len().max(right.len());
+    let mut diff = left.len() ^ right.len();
+    for i in 0..len {
+        let l = left.get(i).copied().unwrap_or(0);
+        let r = right.get(i).copied().unwrap_or(0);
+        diff |= (l ^ r) as usize;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equal() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_different() {
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret1"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,1142 @@
+//! Cluster mode: the key space is split into 16384 hash slots and every node
+//! serves some of them, redirecting commands for the others with MOVED. Nodes
+//! learn about each other and about who serves which slot over the cluster
+//! bus, see [`bus`].
+//!
+//! A slot moves between nodes while both keep serving it: the target marks it
+//! importing, the source migrating, and MIGRATE copies its keys over. In the
+//! meantime the source answers for the keys it still holds and sends clients
+//! to the target with ASK for the others.
+//!
+//! A node that stops answering pings is suspected to be down (PFAIL), and
+//! marked as failed (FAIL) once a majority of the masters serving slots
+//! report it, see [`failover`]. A replica of a failed master then asks the
+//! masters for their votes and takes over its slots. Each node keeps the
+//! cluster configuration in nodes.conf to rejoin with it after a restart.
+
+mod bus;
+mod failover;
+pub(crate) mod migrate;
+mod nodes_conf;
+pub mod slot;
+
+use crate::command_processor::key_bytes;
+use crate::config::KiwiConfig;
+use crate::replication::new_replid;
+use crate::time::now_ms;
+use bus::{Gossip, Message, MessageKind};
+use oh_my_kiwi_domain::command::KiwiCommand;
+use oh_my_kiwi_domain::command::cluster::{CLUSTER_SLOTS, SlotState};
+use oh_my_kiwi_domain::error::CommandError;
+use oh_my_kiwi_domain::types::Types;
+use slot::{format_slot_ranges, key_hash_slot, slot_ranges};
+use failover::Election;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::net::TcpListener;
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+
+/// Unless configured otherwise, the bus listens on the client port plus this.
+pub const CLUSTER_PORT_OFFSET: u16 = 10000;
+pub const DEFAULT_CLUSTER_CONFIG_FILE: &str = "nodes.conf";
+pub const DEFAULT_CLUSTER_NODE_TIMEOUT: u64 = 15000;
+
+/// Called with the master to replicate when this node becomes a replica, and
+/// with `None` when it becomes a master.
+type FollowHook = Box<dyn Fn(Option<(String, u16)>) + Send + Sync>;
+
+pub struct Cluster {
+    myself: String,
+    bind: String,
+    node_timeout_ms: u64,
+    config_path: PathBuf,
+    state: Mutex<ClusterState>,
+    /// Held while nodes.conf is being saved.
+    saving: tokio::sync::Mutex<()>,
+    follow: OnceLock<FollowHook>,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+}
+
+struct ClusterState {
+    current_epoch: u64,
+    /// Epoch of the last failover election this node voted in.
+    last_vote_epoch: u64,
+    /// Every known node by id, including this one.
+    nodes: BTreeMap<String, Node>,
+    /// Id of the node serving each slot.
+    slots: Vec<Option<String>>,
+    /// Slots of this node being moved, with the node they move to.
+    migrating: BTreeMap<u16, String>,
+    /// Slots being moved to this node, with the node they come from.
+    importing: BTreeMap<u16, String>,
+    /// The election this replica runs to replace its failed master.
+    election: Option<Election>,
+    /// What nodes.conf was last written with.
+    saved_config: String,
+}
+
+/// How a node serving keys of a slot in migration handles a command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Routing {
+    Serve,
+    /// The slot is moving to the node at `address`, which serves the keys
+    /// this node no longer has.
+    Migrating {
+        slot: u16,
+        address: String,
+    },
+    /// The slot is moving to this node and the client asked for it with ASKING.
+    Importing,
+}
+
+struct Node {
+    host: String,
+    port: u16,
+    bus_port: u16,
+    config_epoch: u64,
+    /// Id of the master the node replicates, `None` for a master.
+    master: Option<String>,
+    /// Met but yet to answer, so the node is known by a placeholder id.
+    handshake: bool,
+    /// Suspected to be down, as it stopped answering this node's pings.
+    pfail: bool,
+    /// Down, as a majority of the masters agreed.
+    fail: bool,
+    fail_time_ms: u64,
+    /// Masters that reported the node as down, with when they last did.
+    fail_reports: BTreeMap<String, u64>,
+    ping_sent_ms: u64,
+    pong_received_ms: u64,
+    connected: bool,
+    /// The task keeping the bus connection to the node.
+    link: Option<AbortHandle>,
+}
+
+impl Node {
+    fn new(host: String, port: u16, bus_port: u16, handshake: bool) -> Self {
+        Self {
+            host,
+            port,
+            bus_port,
+            config_epoch: 0,
+            master: None,
+            handshake,
+            pfail: false,
+            fail: false,
+            fail_time_ms: 0,
+            fail_reports: BTreeMap::new(),
+            ping_sent_ms: 0,
+            // Gives a new node the node timeout to answer before it is suspected.
+            pong_received_ms: now_ms(),
+            connected: false,
+            link: None,
+        }
+    }
+
+    fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+impl ClusterState {
+    fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        slot_ranges(
+            (0..CLUSTER_SLOTS).filter(|slot| self.slots[*slot as usize].as_deref() == Some(id)),
+        )
+    }
+
+    /// Records the slots node `id` claims to serve. A claim on a slot served
+    /// by another node only wins with a higher config epoch, and slots the
+    /// node no longer claims become unassigned. Returns the nodes that lost
+    /// slots to `id`.
+    fn claim_slots(&mut self, id: &str, config_epoch: u64, ranges: &[(u16, u16)]) -> Vec<String> {
+        let mut claimed = vec![false; CLUSTER_SLOTS as usize];
+        for (start, end) in ranges {
+            claimed[*start as usize..=*end as usize].fill(true);
+        }
+
+        let mut losers = Vec::new();
+        for (slot, claimed) in claimed.into_iter().enumerate() {
+            let owner = self.slots[slot].as_deref();
+            let update = match (owner, claimed) {
+                (Some(owner), true) if owner != id => self
+                    .nodes
+                    .get(owner)
+                    .is_none_or(|node| node.config_epoch < config_epoch),
+                (None, true) => true,
+                (Some(owner), false) => owner == id,
+                _ => false,
+            };
+            if update {
+                let previous =
+                    std::mem::replace(&mut self.slots[slot], claimed.then(|| id.to_string()));
+                if let Some(previous) = previous.filter(|previous| previous != id)
+                    && !losers.contains(&previous)
+                {
+                    losers.push(previous);
+                }
+            }
+        }
+        losers
+    }
+
+    /// Masters serving slots, the nodes that vote on failures and failovers.
+    fn voters(&self) -> HashSet<String> {
+        self.slots.iter().flatten().cloned().collect()
+    }
+
+    /// Master `master` followed by its replicas.
+    fn shard<'a>(&'a self, master: &'a str) -> impl Iterator<Item = (&'a String, &'a Node)> {
+        let master = self.nodes.get_key_value(master);
+        let replicas = self.nodes.iter().filter(move |(_, node)| {
+            !node.handshake
+                && master.is_some_and(|(id, _)| node.master.as_deref() == Some(id.as_str()))
+        });
+        master.into_iter().chain(replicas)
+    }
+
+    fn known_node(&self, id: &str) -> Result<(), CommandError> {
+        match self.nodes.get(id) {
+            Some(node) if !node.handshake => Ok(()),
+            _ => Err(CommandError::UnknownNode(id.to_string())),
+        }
+    }
+
+    fn knows_address(&self, host: &str, port: u16) -> bool {
+        self.nodes
+            .values()
+            .any(|node| node.host == host && node.port == port)
+    }
+}
+
+impl Cluster {
+    /// A cluster of just this node, serving no slots yet. Its bus listens
+    /// next to the client port on `bind`.
+    pub fn new(config: &KiwiConfig, bind: &str, port: u16) -> std::io::Result<Self> {
+        let bus_port = match config.cluster_port() {
+            0 => port.checked_add(CLUSTER_PORT_OFFSET).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("port {port} leaves no room for the cluster bus port"),
+                )
+            })?,
+            bus_port => bus_port,
+        };
+        // Until a MEET shows the address other nodes reach us on.
+        let host = match bind.parse::<IpAddr>() {
+            Ok(ip) if !ip.is_unspecified() => ip.to_string(),
+            _ => "127.0.0.1".to_string(),
+        };
+
+        let config_path = config.cluster_config_path();
+        let saved = nodes_conf::load(&config_path)?;
+        let (myself, mut state) = match saved {
+            Some(saved) => {
+                info!(
+                    "Loaded the cluster configuration from {}",
+                    config_path.display()
+                );
+                (saved.myself, saved.state)
+            }
+            None => {
+                let myself = new_replid();
+                let mut nodes = BTreeMap::new();
+                nodes.insert(myself.clone(), Node::new(host, port, bus_port, false));
+                let state = ClusterState {
+                    current_epoch: 0,
+                    last_vote_epoch: 0,
+                    nodes,
+                    slots: vec![None; CLUSTER_SLOTS as usize],
+                    migrating: BTreeMap::new(),
+                    importing: BTreeMap::new(),
+                    election: None,
+                    saved_config: String::new(),
+                };
+                (myself, state)
+            }
+        };
+        if let Some(node) = state.nodes.get_mut(&myself) {
+            node.port = port;
+            node.bus_port = bus_port;
+            node.pong_received_ms = 0;
+            node.connected = true;
+        }
+
+        Ok(Self {
+            myself,
+            bind: bind.to_string(),
+            node_timeout_ms: config.cluster_node_timeout(),
+            config_path,
+            state: Mutex::new(state),
+            saving: tokio::sync::Mutex::new(()),
+            follow: OnceLock::new(),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+        })
+    }
+
+    /// Starts listening on the cluster bus and connects to the nodes known
+    /// from nodes.conf. `follow` is how this node starts replicating a master,
+    /// or stops to become one.
+    pub async fn start(
+        self: &Arc<Self>,
+        follow: impl Fn(Option<(String, u16)>) + Send + Sync + 'static,
+    ) -> std::io::Result<()> {
+        let _ = self.follow.set(Box::new(follow));
+        let bus_port = self.state.lock().unwrap().nodes[&self.myself].bus_port;
+        let listener = TcpListener::bind((self.bind.as_str(), bus_port)).await?;
+        info!(
+            "Cluster bus listening on port {bus_port}, node id {}",
+            self.myself
+        );
+        tokio::spawn(bus::listen(self.clone(), listener));
+
+        let mut state = self.state.lock().unwrap();
+        let peers: Vec<String> = state
+            .nodes
+            .keys()
+            .filter(|id| **id != self.myself)
+            .cloned()
+            .collect();
+        for id in peers {
+            if let Some(node) = state.nodes.remove(&id) {
+                self.add_node(&mut state, id, node);
+            }
+        }
+        let master = state.nodes[&self.myself].master.clone();
+        if let Some(node) = master.and_then(|master| state.nodes.get(&master)) {
+            self.follow_leader(Some((node.host.clone(), node.port)));
+        }
+        drop(state);
+
+        tokio::spawn(failover::run_cron(self.clone()));
+        Ok(())
+    }
+
+    pub fn myself(&self) -> &str {
+        &self.myself
+    }
+
+    /// Checks that this node serves the keys of `command`, which must all hash
+    /// to the same slot. `asking` is set when the client sent ASKING just
+    /// before the command.
+    pub fn route(&self, command: &KiwiCommand, asking: bool) -> Result<Routing, CommandError> {
+        let mut slots = command
+            .keys()
+            .into_iter()
+            .map(|key| key_hash_slot(&key_bytes(key)));
+        let Some(slot) = slots.next() else {
+            return Ok(Routing::Serve);
+        };
+        if slots.any(|other| other != slot) {
+            return Err(CommandError::CrossSlot);
+        }
+
+        let state = self.state.lock().unwrap();
+        match state.slots[slot as usize].as_deref() {
+            Some(owner) if owner == self.myself => {
+                // MIGRATE itself has to reach the keys left to move.
+                let target = state
+                    .migrating
+                    .get(&slot)
+                    .and_then(|id| state.nodes.get(id));
+                match target {
+                    Some(target) if !matches!(command, KiwiCommand::Migrate { .. }) => {
+                        Ok(Routing::Migrating {
+                            slot,
+                            address: target.address(),
+                        })
+                    }
+                    _ => Ok(Routing::Serve),
+                }
+            }
+            _ if asking && state.importing.contains_key(&slot) => Ok(Routing::Importing),
+            Some(owner) => Err(CommandError::Moved {
+                slot,
+                address: state.nodes[owner].address(),
+            }),
+            None => Err(CommandError::ClusterDown),
+        }
+    }
+
+    /// Starts a handshake with the node at `host:port`, which joins the two
+    /// clusters once it answers.
+    pub fn meet(
+        self: &Arc<Self>,
+        host: &str,
+        port: u16,
+        bus_port: Option<u16>,
+    ) -> Result<(), CommandError> {
+        let invalid = || CommandError::InvalidNodeAddress(format!("{host}:{port}"));
+        let ip = host.parse::<IpAddr>().map_err(|_| invalid())?;
+        let bus_port = match bus_port {
+            Some(bus_port) => bus_port,
+            None => port.checked_add(CLUSTER_PORT_OFFSET).ok_or_else(invalid)?,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if !state.knows_address(&ip.to_string(), port) {
+            let node = Node::new(ip.to_string(), port, bus_port, true);
+            self.add_node(&mut state, new_replid(), node);
+        }
+        Ok(())
+    }
+
+    pub fn add_slots(&self, slots: &[u16]) -> Result<(), CommandError> {
+        self.assign_slots(slots, true)
+    }
+
+    pub fn del_slots(&self, slots: &[u16]) -> Result<(), CommandError> {
+        self.assign_slots(slots, false)
+    }
+
+    /// Assigns the slots to this node or unassigns them, changing none of
+    /// them when any is taken, or already free, respectively.
+    fn assign_slots(&self, slots: &[u16], assign: bool) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        let mut seen = HashSet::new();
+        for slot in slots.iter().copied() {
+            if !seen.insert(slot) {
+                return Err(CommandError::SlotSpecifiedTwice(slot));
+            }
+            match (assign, state.slots[slot as usize].is_some()) {
+                (true, true) => return Err(CommandError::SlotBusy(slot)),
+                (false, false) => return Err(CommandError::SlotUnassigned(slot)),
+                _ => {}
+            }
+        }
+        for slot in slots.iter().copied() {
+            state.slots[slot as usize] = assign.then(|| self.myself.clone());
+        }
+        Ok(())
+    }
+
+    /// CLUSTER REPLICATE: turns this node into a replica of master `id`.
+    /// `empty` tells whether this node holds no keys, as it must.
+    pub fn replicate(&self, id: &str, empty: bool) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if id == self.myself {
+            return Err(CommandError::ReplicateMyself);
+        }
+        state.known_node(id)?;
+        let node = &state.nodes[id];
+        if node.master.is_some() {
+            return Err(CommandError::ReplicateReplica);
+        }
+        if !empty || !state.slot_ranges(&self.myself).is_empty() {
+            return Err(CommandError::ReplicateNotEmpty);
+        }
+
+        let leader = (node.host.clone(), node.port);
+        info!("Replicating node {id}");
+        if let Some(myself) = state.nodes.get_mut(&self.myself) {
+            myself.master = Some(id.to_string());
+        }
+        state.election = None;
+        self.follow_leader(Some(leader));
+        Ok(())
+    }
+
+    /// CLUSTER SETSLOT. `has_keys` tells whether this node still holds keys
+    /// of the slot, which it can't give away then.
+    pub fn set_slot(
+        &self,
+        slot: u16,
+        slot_state: SlotState,
+        has_keys: bool,
+    ) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        let mine = state.slots[slot as usize].as_deref() == Some(self.myself.as_str());
+        match slot_state {
+            SlotState::Migrating(id) => {
+                if !mine {
+                    return Err(CommandError::NotSlotOwner(slot));
+                }
+                state.known_node(&id)?;
+                state.migrating.insert(slot, id);
+            }
+            SlotState::Importing(id) => {
+                if mine {
+                    return Err(CommandError::AlreadySlotOwner(slot));
+                }
+                state.known_node(&id)?;
+                state.importing.insert(slot, id);
+            }
+            SlotState::Stable => {
+                state.migrating.remove(&slot);
+                state.importing.remove(&slot);
+            }
+            SlotState::Node(id) => {
+                state.known_node(&id)?;
+                if id == self.myself {
+                    // A new config epoch makes the other nodes accept the
+                    // claim over the one of the source.
+                    if state.importing.remove(&slot).is_some() {
+                        state.current_epoch += 1;
+                        let epoch = state.current_epoch;
+                        if let Some(myself) = state.nodes.get_mut(&self.myself) {
+                            myself.config_epoch = epoch;
+                        }
+                        info!("Imported slot {slot}, config epoch is now {epoch}");
+                    }
+                } else {
+                    if mine && has_keys {
+                        return Err(CommandError::SlotNotEmpty(slot));
+                    }
+                    state.migrating.remove(&slot);
+                }
+                state.slots[slot as usize] = Some(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// CLUSTER INFO.
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let assigned = state.slots.iter().filter(|owner| owner.is_some()).count();
+        let failing = |check: fn(&Node) -> bool| {
+            state
+                .slots
+                .iter()
+                .flatten()
+                .filter(|owner| state.nodes.get(*owner).is_some_and(check))
+                .count()
+        };
+        let (pfail, fail) = (
+            failing(|node| node.pfail && !node.fail),
+            failing(|node| node.fail),
+        );
+        let cluster_state = match assigned == CLUSTER_SLOTS as usize && fail == 0 {
+            true => "ok",
+            false => "fail",
+        };
+
+        [
+            format!("cluster_state:{cluster_state}"),
+            format!("cluster_slots_assigned:{assigned}"),
+            format!("cluster_slots_ok:{}", assigned - pfail - fail),
+            format!("cluster_slots_pfail:{pfail}"),
+            format!("cluster_slots_fail:{fail}"),
+            format!("cluster_known_nodes:{}", state.nodes.len()),
+            format!("cluster_size:{}", state.voters().len()),
+            format!("cluster_current_epoch:{}", state.current_epoch),
+            format!(
+                "cluster_my_epoch:{}",
+                state.nodes[&self.myself].config_epoch
+            ),
+            format!(
+                "cluster_stats_messages_sent:{}",
+                self.messages_sent.load(Ordering::Relaxed)
+            ),
+            format!(
+                "cluster_stats_messages_received:{}",
+                self.messages_received.load(Ordering::Relaxed)
+            ),
+        ]
+        .iter()
+        .map(|line| format!("{line}\r\n"))
+        .collect()
+    }
+
+    /// CLUSTER NODES: a line per node with its address, flags, master, epoch,
+    /// link state and slots.
+    pub fn nodes(&self) -> String {
+        self.node_lines(&self.state.lock().unwrap(), false)
+    }
+
+    /// The lines of CLUSTER NODES. For nodes.conf, ping times and link
+    /// states are left out so the file only changes with the configuration.
+    fn node_lines(&self, state: &ClusterState, config: bool) -> String {
+        let mut lines = String::new();
+        for (id, node) in &state.nodes {
+            if config && node.handshake {
+                continue;
+            }
+            let myself = *id == self.myself;
+            let mut flags = Vec::new();
+            if myself {
+                flags.push("myself");
+            }
+            flags.push(match (node.handshake, &node.master) {
+                (true, _) => "handshake",
+                (false, Some(_)) => "slave",
+                (false, None) => "master",
+            });
+            if node.fail {
+                flags.push("fail");
+            } else if node.pfail {
+                flags.push("fail?");
+            }
+            let (ping_sent_ms, pong_received_ms) = match config {
+                true => (0, 0),
+                false => (node.ping_sent_ms, node.pong_received_ms),
+            };
+            let link = match node.connected && (myself || !config) {
+                true => "connected",
+                false => "disconnected",
+            };
+            lines.push_str(&format!(
+                "{id} {}@{} {} {} {ping_sent_ms} {pong_received_ms} {} {link}",
+                node.address(),
+                node.bus_port,
+                flags.join(","),
+                node.master.as_deref().unwrap_or("-"),
+                node.config_epoch,
+            ));
+            let slots = format_slot_ranges(&state.slot_ranges(id));
+            if !slots.is_empty() {
+                lines.push(' ');
+                lines.push_str(&slots);
+            }
+            if myself {
+                for (slot, target) in &state.migrating {
+                    lines.push_str(&format!(" [{slot}->-{target}]"));
+                }
+                for (slot, source) in &state.importing {
+                    lines.push_str(&format!(" [{slot}-<-{source}]"));
+                }
+            }
+            lines.push('\n');
+        }
+        lines
+    }
+
+    /// CLUSTER SLOTS: every range of slots served by the same node, with the
+    /// addresses of the node and of its replicas.
+    pub fn slots(&self) -> Types {
+        let state = self.state.lock().unwrap();
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < CLUSTER_SLOTS {
+            let owner = state.slots[start as usize].as_deref();
+            let end = (start..CLUSTER_SLOTS)
+                .take_while(|slot| state.slots[*slot as usize].as_deref() == owner)
+                .last()
+                .unwrap_or(start);
+            if let Some(owner) = owner {
+                let mut range = vec![Types::Integer(start as i64), Types::Integer(end as i64)];
+                for (id, node) in state.shard(owner) {
+                    range.push(Types::Array(vec![
+                        Types::BulkString(node.host.clone()),
+                        Types::Integer(node.port as i64),
+                        Types::BulkString(id.clone()),
+                    ]));
+                }
+                ranges.push(Types::Array(range));
+            }
+            start = end + 1;
+        }
+        Types::Array(ranges)
+    }
+
+    /// CLUSTER SHARDS: every master with the slots it serves and its replicas.
+    pub fn shards(&self) -> Types {
+        let state = self.state.lock().unwrap();
+        let shards = state
+            .nodes
+            .iter()
+            .filter(|(_, node)| !node.handshake && node.master.is_none())
+            .map(|(id, _)| {
+                let slots = state
+                    .slot_ranges(id)
+                    .into_iter()
+                    .flat_map(|(start, end)| [start, end])
+                    .map(|slot| Types::Integer(slot as i64))
+                    .collect();
+                let nodes = state
+                    .shard(id)
+                    .map(|(id, node)| {
+                        let role = match node.master {
+                            Some(_) => "replica",
+                            None => "master",
+                        };
+                        let health = match node.fail {
+                            true => "fail",
+                            false => "online",
+                        };
+                        map([
+                            ("id", Types::BulkString(id.clone())),
+                            ("port", Types::Integer(node.port as i64)),
+                            ("ip", Types::BulkString(node.host.clone())),
+                            ("endpoint", Types::BulkString(node.host.clone())),
+                            ("role", Types::BulkString(role.to_string())),
+                            ("health", Types::BulkString(health.to_string())),
+                        ])
+                    })
+                    .collect();
+                map([
+                    ("slots", Types::Array(slots)),
+                    ("nodes", Types::Array(nodes)),
+                ])
+            })
+            .collect();
+        Types::Array(shards)
+    }
+
+    fn add_node(self: &Arc<Self>, state: &mut ClusterState, id: String, mut node: Node) {
+        info!("Meeting node {id} at {}", node.address());
+        node.link = Some(tokio::spawn(bus::link(self.clone(), id.clone())).abort_handle());
+        state.nodes.insert(id, node);
+    }
+
+    fn follow_leader(&self, leader: Option<(String, u16)>) {
+        if let Some(follow) = self.follow.get() {
+            follow(leader);
+        }
+    }
+
+    /// The message for the link to node `id`: a MEET until the node answers,
+    /// a PING after that.
+    fn ping(&self, id: &str) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        let node = state.nodes.get_mut(id)?;
+        node.ping_sent_ms = now_ms();
+        let kind = match node.handshake {
+            true => MessageKind::Meet,
+            false => MessageKind::Ping,
+        };
+        Some(self.message(&state, kind, id))
+    }
+
+    /// Handles the PONG node `id` answered with. A node answering its MEET is
+    /// renamed to its real id, unless that id is already known. Returns
+    /// whether the link is still needed.
+    fn pong(self: &Arc<Self>, id: &mut String, message: Message, host: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(node) = state.nodes.get(id.as_str()) else {
+            return false;
+        };
+        if node.handshake {
+            let Some(mut node) = state.nodes.remove(id.as_str()) else {
+                return false;
+            };
+            if message.sender == self.myself || state.nodes.contains_key(&message.sender) {
+                return false;
+            }
+            node.handshake = false;
+            state.nodes.insert(message.sender.clone(), node);
+            *id = message.sender.clone();
+        }
+
+        if let Some(node) = state.nodes.get_mut(id.as_str()) {
+            node.pong_received_ms = now_ms();
+            node.connected = true;
+            node.pfail = false;
+        }
+        self.update(&mut state, host, &message);
+        self.clear_failure(&mut state, id);
+        true
+    }
+
+    /// Handles a message from another node, returning the reply: an AUTH_ACK
+    /// to a granted vote request, a PONG otherwise. A MEET from an unknown
+    /// node adds it to the cluster.
+    async fn receive(self: &Arc<Self>, message: Message, peer: IpAddr, local: IpAddr) -> Message {
+        let mut voted = false;
+        {
+            let mut state = self.state.lock().unwrap();
+            if message.kind == MessageKind::Meet {
+                if let Some(myself) = state.nodes.get_mut(&self.myself) {
+                    myself.host = local.to_string();
+                }
+                if message.sender != self.myself && !state.nodes.contains_key(&message.sender) {
+                    let node = Node::new(peer.to_string(), message.port, message.bus_port, false);
+                    self.add_node(&mut state, message.sender.clone(), node);
+                }
+            }
+            if state.nodes.contains_key(&message.sender) {
+                self.update(&mut state, &peer.to_string(), &message);
+                match message.kind {
+                    MessageKind::Fail => self.mark_failed(&mut state, &message.about),
+                    MessageKind::AuthRequest => voted = self.grant_vote(&mut state, &message),
+                    _ => {}
+                }
+            }
+        }
+
+        // The vote is on disk before it goes out, so a restart cannot vote
+        // twice in the same epoch.
+        let mut kind = MessageKind::Pong;
+        if voted {
+            match self.save_config().await {
+                Ok(()) => kind = MessageKind::AuthAck,
+                Err(err) => warn!(
+                    "Not voting in epoch {}, saving the cluster config failed: {err}",
+                    message.current_epoch
+                ),
+            }
+        }
+        let state = self.state.lock().unwrap();
+        self.message(&state, kind, &message.sender)
+    }
+
+    /// Handles the reply to a message sent on a connection of its own.
+    fn reply(self: &Arc<Self>, message: Message, host: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.nodes.contains_key(&message.sender) {
+            self.update(&mut state, host, &message);
+            if message.kind == MessageKind::AuthAck {
+                self.count_vote(&mut state, &message);
+            }
+        }
+    }
+
+    /// Applies what a known node says about itself and the nodes it knows,
+    /// meeting the ones this node has not heard of.
+    fn update(self: &Arc<Self>, state: &mut ClusterState, host: &str, message: &Message) {
+        if let Some(node) = state.nodes.get_mut(&message.sender) {
+            node.host = host.to_string();
+            node.port = message.port;
+            node.bus_port = message.bus_port;
+            node.config_epoch = message.config_epoch;
+            node.master = message.master.clone();
+        }
+        state.current_epoch = state.current_epoch.max(message.current_epoch);
+        let losers = state.claim_slots(&message.sender, message.config_epoch, &message.slots);
+        // A migration ends once the slot changed hands.
+        let ClusterState {
+            slots,
+            migrating,
+            importing,
+            ..
+        } = state;
+        migrating.retain(|slot, _| slots[*slot as usize].as_deref() == Some(&self.myself));
+        importing.retain(|slot, _| slots[*slot as usize].as_deref() != Some(&self.myself));
+        self.follow_new_owner(state, &message.sender, &losers);
+
+        // Only masters serving slots get a say on failures.
+        let voter = state.voters().contains(&message.sender);
+        let now = now_ms();
+        for gossip in &message.gossip {
+            if gossip.id == self.myself {
+                continue;
+            }
+            if let Some(node) = state.nodes.get_mut(&gossip.id) {
+                match voter && gossip.failing {
+                    true => node.fail_reports.insert(message.sender.clone(), now),
+                    false => node.fail_reports.remove(&message.sender),
+                };
+            } else if !state.knows_address(&gossip.host, gossip.port) {
+                let node = Node::new(gossip.host.clone(), gossip.port, gossip.bus_port, true);
+                self.add_node(state, new_replid(), node);
+            }
+        }
+    }
+
+    /// After node `owner` took slots from `losers`: when this node's master,
+    /// or this node itself, lost its last slot that way, this node follows
+    /// the new owner, as a failed master does once it is back.
+    fn follow_new_owner(&self, state: &mut ClusterState, owner: &str, losers: &[String]) {
+        let master = state.nodes[&self.myself]
+            .master
+            .clone()
+            .unwrap_or_else(|| self.myself.clone());
+        if !losers.contains(&master) || !state.slot_ranges(&master).is_empty() {
+            return;
+        }
+        let Some(node) = state.nodes.get(owner) else {
+            return;
+        };
+        let leader = (node.host.clone(), node.port);
+        info!("Node {owner} took over the slots of {master}, replicating it");
+        if let Some(myself) = state.nodes.get_mut(&self.myself) {
+            myself.master = Some(owner.to_string());
+        }
+        state.election = None;
+        self.follow_leader(Some(leader));
+    }
+
+    /// This node's header, plus gossip about every node other than the recipient.
+    fn message(&self, state: &ClusterState, kind: MessageKind, recipient: &str) -> Message {
+        let myself = &state.nodes[&self.myself];
+        let gossip = state
+            .nodes
+            .iter()
+            .filter(|(id, node)| !node.handshake && **id != self.myself && *id != recipient)
+            .map(|(id, node)| Gossip {
+                id: id.clone(),
+                host: node.host.clone(),
+                port: node.port,
+                bus_port: node.bus_port,
+                failing: node.pfail || node.fail,
+            })
+            .collect();
+        Message {
+            kind,
+            sender: self.myself.clone(),
+            port: myself.port,
+            bus_port: myself.bus_port,
+            current_epoch: state.current_epoch,
+            config_epoch: myself.config_epoch,
+            master: myself.master.clone(),
+            slots: state.slot_ranges(&self.myself),
+            gossip,
+            about: String::new(),
+        }
+    }
+
+    /// Where the link to node `id` connects, and whether the node is still
+    /// in its handshake.
+    fn link_target(&self, id: &str) -> Option<(String, u16)> {
+        let state = self.state.lock().unwrap();
+        let node = state.nodes.get(id)?;
+        Some((node.host.clone(), node.bus_port))
+    }
+
+    fn disconnected(&self, id: &str) {
+        if let Some(node) = self.state.lock().unwrap().nodes.get_mut(id) {
+            node.connected = false;
+        }
+    }
+
+    /// Gives up on node `id` if it never answered its MEET, returning
+    /// whether it did.
+    fn forget_handshake(&self, id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let handshake = state.nodes.get(id).is_some_and(|node| node.handshake);
+        if handshake {
+            state.nodes.remove(id);
+        }
+        handshake
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        for link in state.nodes.values().filter_map(|node| node.link.as_ref()) {
+            link.abort();
+        }
+    }
+}
+
+fn map<const N: usize>(fields: [(&str, Types); N]) -> Types {
+    Types::Map(
+        fields
+            .into_iter()
+            .map(|(name, value)| (Types::BulkString(name.to_string()), value))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(port: u16) -> Arc<Cluster> {
+        Arc::new(Cluster::new(&KiwiConfig::default(), "127.0.0.1", port).unwrap())
+    }
+
+    fn get(key: &str) -> KiwiCommand {
+        KiwiCommand::Get {
+            key: Types::BulkString(key.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let cluster = node(7000);
+        let slot = key_hash_slot(b"foo");
+        assert!(matches!(
+            cluster.route(&get("foo"), false),
+            Err(CommandError::ClusterDown)
+        ));
+
+        cluster.add_slots(&[slot]).unwrap();
+        cluster.route(&get("foo"), false).unwrap();
+        cluster.route(&KiwiCommand::Ping, false).unwrap();
+
+        let mget = KiwiCommand::MGet {
+            keys: vec![
+                Types::BulkString("{foo}.a".to_string()),
+                Types::BulkString("{foo}.b".to_string()),
+            ],
+        };
+        cluster.route(&mget, false).unwrap();
+        let mget = KiwiCommand::MGet {
+            keys: vec![
+                Types::BulkString("foo".to_string()),
+                Types::BulkString("bar".to_string()),
+            ],
+        };
+        assert!(matches!(
+            cluster.route(&mget, false),
+            Err(CommandError::CrossSlot)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_moved_after_claim() {
+        let cluster = node(7000);
+        let other = node(7001);
+        let slot = key_hash_slot(b"foo");
+        other.add_slots(&[slot]).unwrap();
+
+        let message = {
+            let state = other.state.lock().unwrap();
+            other.message(&state, MessageKind::Meet, cluster.myself())
+        };
+        let local = "127.0.0.1".parse().unwrap();
+        let pong = cluster.receive(message, local, local).await;
+        assert_eq!(pong.sender, cluster.myself());
+
+        assert!(matches!(
+            cluster.route(&get("foo"), false),
+            Err(CommandError::Moved { slot: moved, address }) if moved == slot && address == "127.0.0.1:7001"
+        ));
+        assert!(
+            cluster
+                .nodes()
+                .contains(&format!("{} 127.0.0.1:7001@17001 master", other.myself()))
+        );
+        assert!(matches!(
+            cluster.add_slots(&[slot]),
+            Err(CommandError::SlotBusy(busy)) if busy == slot
+        ));
+    }
+
+    #[test]
+    fn test_assign_slots() {
+        let cluster = node(7000);
+        cluster.add_slots(&[0, 1, 2, 5]).unwrap();
+        assert!(matches!(
+            cluster.add_slots(&[3, 3]),
+            Err(CommandError::SlotSpecifiedTwice(3))
+        ));
+        assert!(matches!(
+            cluster.add_slots(&[3, 2]),
+            Err(CommandError::SlotBusy(2))
+        ));
+        assert!(matches!(
+            cluster.del_slots(&[1, 4]),
+            Err(CommandError::SlotUnassigned(4))
+        ));
+        cluster.del_slots(&[1]).unwrap();
+
+        let nodes = cluster.nodes();
+        assert!(nodes.starts_with(cluster.myself()));
+        assert!(nodes.ends_with(" myself,master - 0 0 0 connected 0 2 5\n"));
+        assert!(cluster.info().contains("cluster_slots_assigned:3\r\n"));
+        assert!(cluster.info().starts_with("cluster_state:fail\r\n"));
+
+        let Types::Array(slots) = cluster.slots() else {
+            panic!("expected an array");
+        };
+        assert_eq!(slots.len(), 3);
+        assert_eq!(
+            slots[2],
+            Types::Array(vec![
+                Types::Integer(5),
+                Types::Integer(5),
+                Types::Array(vec![
+                    Types::BulkString("127.0.0.1".to_string()),
+                    Types::Integer(7000),
+                    Types::BulkString(cluster.myself().to_string()),
+                ]),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_slot() {
+        let source = node(7000);
+        let target = node(7001);
+        let slot = key_hash_slot(b"foo");
+        source.add_slots(&[slot]).unwrap();
+        let meet = async |from: &Arc<Cluster>, to: &Arc<Cluster>| {
+            let message = {
+                let state = from.state.lock().unwrap();
+                from.message(&state, MessageKind::Meet, to.myself())
+            };
+            let local = "127.0.0.1".parse().unwrap();
+            to.receive(message, local, local).await;
+        };
+        meet(&source, &target).await;
+        meet(&target, &source).await;
+        let (source_id, target_id) = (source.myself().to_string(), target.myself().to_string());
+
+        assert!(matches!(
+            source.set_slot(slot, SlotState::Migrating("unknown".to_string()), false),
+            Err(CommandError::UnknownNode(_))
+        ));
+        assert!(matches!(
+            target.set_slot(slot, SlotState::Migrating(source_id.clone()), false),
+            Err(CommandError::NotSlotOwner(_))
+        ));
+        source
+            .set_slot(slot, SlotState::Migrating(target_id.clone()), false)
+            .unwrap();
+        target
+            .set_slot(slot, SlotState::Importing(source_id.clone()), false)
+            .unwrap();
+        assert!(
+            source
+                .nodes()
+                .contains(&format!(" [{slot}->-{target_id}]\n"))
+        );
+
+        assert_eq!(
+            source.route(&get("foo"), false).unwrap(),
+            Routing::Migrating {
+                slot,
+                address: "127.0.0.1:7001".to_string()
+            }
+        );
+        assert!(matches!(
+            target.route(&get("foo"), false),
+            Err(CommandError::Moved { .. })
+        ));
+        assert_eq!(target.route(&get("foo"), true).unwrap(), Routing::Importing);
+
+        assert!(matches!(
+            source.set_slot(slot, SlotState::Node(target_id.clone()), true),
+            Err(CommandError::SlotNotEmpty(_))
+        ));
+        target
+            .set_slot(slot, SlotState::Node(target_id.clone()), false)
+            .unwrap();
+        assert_eq!(target.route(&get("foo"), false).unwrap(), Routing::Serve);
+
+        // The source learns about the new owner from the target's higher epoch.
+        meet(&target, &source).await;
+        assert!(matches!(
+            source.route(&get("foo"), false),
+            Err(CommandError::Moved { address, .. }) if address == "127.0.0.1:7001"
+        ));
+        assert!(!source.nodes().contains("->-"));
+    }
+
+    #[test]
+    fn test_claim_slots() {
+        let cluster = node(7000);
+        let mut state = cluster.state.lock().unwrap();
+        state.nodes.insert(
+            "a".to_string(),
+            Node::new("127.0.0.1".to_string(), 7001, 17001, false),
+        );
+        state.nodes.insert(
+            "b".to_string(),
+            Node::new("127.0.0.1".to_string(), 7002, 17002, false),
+        );
+
+        state.claim_slots("a", 0, &[(0, 10)]);
+        assert_eq!(state.slot_ranges("a"), vec![(0, 10)]);
+        // Taking over slots needs a higher config epoch.
+        state.claim_slots("b", 0, &[(5, 15)]);
+        assert_eq!(state.slot_ranges("b"), vec![(11, 15)]);
+        state.claim_slots("b", 1, &[(5, 15)]);
+        assert_eq!(state.slot_ranges("a"), vec![(0, 4)]);
+        assert_eq!(state.slot_ranges("b"), vec![(5, 15)]);
+
+        state.claim_slots("b", 1, &[(5, 6)]);
+        assert_eq!(state.slot_ranges("b"), vec![(5, 6)]);
+        assert_eq!(state.slots[7], None);
+    }
+}
@@ -0,0 +1,377 @@
+//! The cluster bus. Every node keeps a connection to every other node it
+//! knows and pings it once a second; the pong that answers carries the same
+//! information as the ping: the sender's id, ports, epochs, master and
+//! slots, plus gossip about the other nodes it knows and whether it suspects
+//! them to be down. FAIL broadcasts and failover vote requests go out on
+//! connections of their own. Messages are RESP arrays.
+
+use super::Cluster;
+use super::slot::{format_slot_ranges, parse_slot_ranges};
+use async_trait::async_trait;
+use oh_my_kiwi_domain::BytesReader;
+use oh_my_kiwi_domain::error::ParseError;
+use oh_my_kiwi_domain::types::Types;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MessageKind {
+    Meet,
+    Ping,
+    Pong,
+    /// The node in `about` failed.
+    Fail,
+    /// A replica asks for a vote to replace its failed master, in `about`.
+    AuthRequest,
+    /// The vote answering an AUTH_REQUEST.
+    AuthAck,
+}
+
+impl MessageKind {
+    fn name(self) -> &'static str {
+        match self {
+            MessageKind::Meet => "MEET",
+            MessageKind::Ping => "PING",
+            MessageKind::Pong => "PONG",
+            MessageKind::Fail => "FAIL",
+            MessageKind::AuthRequest => "AUTH_REQUEST",
+            MessageKind::AuthAck => "AUTH_ACK",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Message {
+    pub(super) kind: MessageKind,
+    pub(super) sender: String,
+    pub(super) port: u16,
+    pub(super) bus_port: u16,
+    pub(super) current_epoch: u64,
+    pub(super) config_epoch: u64,
+    pub(super) master: Option<String>,
+    pub(super) slots: Vec<(u16, u16)>,
+    pub(super) gossip: Vec<Gossip>,
+    /// The node a FAIL or AUTH_REQUEST is about, empty otherwise.
+    pub(super) about: String,
+}
+
+/// What the sender knows about another node.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Gossip {
+    pub(super) id: String,
+    pub(super) host: String,
+    pub(super) port: u16,
+    pub(super) bus_port: u16,
+    /// The sender suspects the node to be down, or knows it is.
+    pub(super) failing: bool,
+}
+
+impl Message {
+    fn to_types(&self) -> Types {
+        let gossip = self
+            .gossip
+            .iter()
+            .map(|gossip| {
+                Types::Array(vec![
+                    Types::BulkString(gossip.id.clone()),
+                    Types::BulkString(gossip.host.clone()),
+                    Types::Integer(gossip.port as i64),
+                    Types::Integer(gossip.bus_port as i64),
+                    Types::Integer(gossip.failing as i64),
+                ])
+            })
+            .collect();
+        Types::Array(vec![
+            Types::BulkString(self.kind.name().to_string()),
+            Types::BulkString(self.sender.clone()),
+            Types::Integer(self.port as i64),
+            Types::Integer(self.bus_port as i64),
+            Types::Integer(self.current_epoch as i64),
+            Types::Integer(self.config_epoch as i64),
+            Types::BulkString(self.master.clone().unwrap_or_default()),
+            Types::BulkString(format_slot_ranges(&self.slots)),
+            Types::Array(gossip),
+            Types::BulkString(self.about.clone()),
+        ])
+    }
+
+    fn from_types(types: Types) -> Option<Self> {
+        let Types::Array(fields) = types else {
+            return None;
+        };
+        let [
+            kind,
+            sender,
+            port,
+            bus_port,
+            current_epoch,
+            config_epoch,
+            master,
+            slots,
+            Types::Array(gossip),
+            about,
+        ] = <[Types; 10]>::try_from(fields).ok()?
+        else {
+            return None;
+        };
+
+        let kind = match string(kind)?.as_str() {
+            "MEET" => MessageKind::Meet,
+            "PING" => MessageKind::Ping,
+            "PONG" => MessageKind::Pong,
+            "FAIL" => MessageKind::Fail,
+            "AUTH_REQUEST" => MessageKind::AuthRequest,
+            "AUTH_ACK" => MessageKind::AuthAck,
+            _ => return None,
+        };
+        let gossip = gossip
+            .into_iter()
+            .map(|gossip| {
+                let Types::Array(fields) = gossip else {
+                    return None;
+                };
+                let [id, host, port, bus_port, failing] = <[Types; 5]>::try_from(fields).ok()?;
+                Some(Gossip {
+                    id: string(id)?,
+                    host: string(host)?,
+                    port: number(port)?,
+                    bus_port: number(bus_port)?,
+                    failing: number::<u8>(failing)? != 0,
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            kind,
+            sender: string(sender)?,
+            port: number(port)?,
+            bus_port: number(bus_port)?,
+            current_epoch: number(current_epoch)?,
+            config_epoch: number(config_epoch)?,
+            master: Some(string(master)?).filter(|master| !master.is_empty()),
+            slots: parse_slot_ranges(&string(slots)?)?,
+            gossip,
+            about: string(about)?,
+        })
+    }
+}
+
+fn string(types: Types) -> Option<String> {
+    match types {
+        Types::BulkString(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn number<T: TryFrom<i64>>(types: Types) -> Option<T> {
+    match types {
+        Types::Integer(value) => T::try_from(value).ok(),
+        _ => None,
+    }
+}
+
+/// Accepts connections from other nodes and answers their pings.
+pub(super) async fn listen(cluster: Arc<Cluster>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let cluster = cluster.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = answer(&cluster, stream, peer).await {
+                        debug!("Cluster bus connection from {peer} closed: {err}");
+                    }
+                });
+            }
+            Err(err) => warn!("Accepting a cluster bus connection failed: {err}"),
+        }
+    }
+}
+
+async fn answer(cluster: &Arc<Cluster>, stream: TcpStream, peer: SocketAddr) -> Result<()> {
+    let local = stream.local_addr()?.ip();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BusReader::new(reader);
+    loop {
+        let message = read(cluster, &mut reader).await?;
+        if matches!(message.kind, MessageKind::Pong | MessageKind::AuthAck) {
+            continue;
+        }
+        let pong = cluster.receive(message, peer.ip(), local).await;
+        write(cluster, &mut writer, &pong).await?;
+    }
+}
+
+/// Keeps the connection to node `id` and pings it, until the node is
+/// forgotten or turns out to be known under another id.
+pub(super) async fn link(cluster: Arc<Cluster>, mut id: String) {
+    let started = Instant::now();
+    while let Some((host, bus_port)) = cluster.link_target(&id) {
+        match ping(&cluster, &mut id, &host, bus_port).await {
+            Ok(()) => return,
+            Err(err) => {
+                debug!("Cluster bus link to {host}:{bus_port} failed: {err}");
+                cluster.disconnected(&id);
+                if started.elapsed() >= HANDSHAKE_TIMEOUT && cluster.forget_handshake(&id) {
+                    warn!("Node at {host}:{bus_port} did not answer the handshake");
+                    return;
+                }
+            }
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn ping(cluster: &Arc<Cluster>, id: &mut String, host: &str, bus_port: u16) -> Result<()> {
+    let (reader, mut writer) = TcpStream::connect((host, bus_port)).await?.into_split();
+    let mut reader = BusReader::new(reader);
+    loop {
+        let Some(ping) = cluster.ping(id) else {
+            return Ok(());
+        };
+        write(cluster, &mut writer, &ping).await?;
+        let pong = tokio::time::timeout(PONG_TIMEOUT, read(cluster, &mut reader))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "no pong"))??;
+        if pong.kind != MessageKind::Pong {
+            return Err(Error::new(ErrorKind::InvalidData, "expected a pong"));
+        }
+        if !cluster.pong(id, pong, host) {
+            return Ok(());
+        }
+        tokio::time::sleep(PING_INTERVAL).await;
+    }
+}
+
+/// Sends a single message to the node at `host:bus_port` on a connection of
+/// its own, and handles the reply.
+pub(super) async fn send(cluster: Arc<Cluster>, host: String, bus_port: u16, message: Message) {
+    let exchange = async {
+        let (reader, mut writer) = TcpStream::connect((host.as_str(), bus_port))
+            .await?
+            .into_split();
+        write(&cluster, &mut writer, &message).await?;
+        read(&cluster, &mut BusReader::new(reader)).await
+    };
+    match tokio::time::timeout(PONG_TIMEOUT, exchange).await {
+        Ok(Ok(reply)) => cluster.reply(reply, &host),
+        Ok(Err(err)) => debug!(
+            "Sending {} to {host}:{bus_port} failed: {err}",
+            message.kind.name()
+        ),
+        Err(_) => debug!(
+            "Sending {} to {host}:{bus_port} timed out",
+            message.kind.name()
+        ),
+    }
+}
+
+async fn read(cluster: &Cluster, reader: &mut BusReader) -> Result<Message> {
+    let types = Types::from_bytes(reader).await.map_err(|err| match err {
+        ParseError::ConnectionError(err) => err,
+        ParseError::ConnectionClosed => ErrorKind::UnexpectedEof.into(),
+        err => Error::new(ErrorKind::InvalidData, err),
+    })?;
+    cluster.messages_received.fetch_add(1, Ordering::Relaxed);
+    Message::from_types(types)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed cluster bus message"))
+}
+
+async fn write(cluster: &Cluster, writer: &mut OwnedWriteHalf, message: &Message) -> Result<()> {
+    writer.write_all(&message.to_types().to_bytes()).await?;
+    cluster.messages_sent.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+struct BusReader {
+    reader: BufReader<OwnedReadHalf>,
+}
+
+impl BusReader {
+    fn new(reader: OwnedReadHalf) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+        }
+    }
+}
+
+#[async_trait]
+impl BytesReader for BusReader {
+    async fn read_line(&mut self) -> std::result::Result<Vec<u8>, ParseError> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line).await? == 0 {
+            return Err(ParseError::ConnectionClosed);
+        }
+        match line.strip_suffix(b"\r\n") {
+            Some(line) => Ok(line.to_vec()),
+            None => Err(ParseError::MissingSeparator),
+        }
+    }
+
+    async fn read_bytes(&mut self, n: usize) -> std::result::Result<Vec<u8>, ParseError> {
+        let mut bytes = vec![0; n];
+        self.reader.read_exact(&mut bytes).await?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_message_round_trip() {
+        let message = Message {
+            kind: MessageKind::Ping,
+            sender: "a".repeat(40),
+            port: 7000,
+            bus_port: 17000,
+            current_epoch: 3,
+            config_epoch: 2,
+            master: None,
+            slots: vec![(0, 5460), (6000, 6000)],
+            gossip: vec![Gossip {
+                id: "b".repeat(40),
+                host: "10.0.0.2".to_string(),
+                port: 7001,
+                bus_port: 17001,
+                failing: true,
+            }],
+            about: String::new(),
+        };
+        let bytes = message.to_types().to_bytes();
+        let types = Types::from_slice(&bytes).await.unwrap();
+        assert_eq!(Message::from_types(types), Some(message));
+
+        let message = Message {
+            kind: MessageKind::AuthRequest,
+            sender: "c".repeat(40),
+            port: 7002,
+            bus_port: 17002,
+            current_epoch: 4,
+            config_epoch: 0,
+            master: Some("a".repeat(40)),
+            slots: vec![],
+            gossip: vec![],
+            about: "a".repeat(40),
+        };
+        let types = Types::from_slice(&message.to_types().to_bytes())
+            .await
+            .unwrap();
+        assert_eq!(Message::from_types(types), Some(message));
+
+        let types = Types::Array(vec![Types::BulkString("PING".to_string())]);
+        assert_eq!(Message::from_types(types), None);
+    }
+}
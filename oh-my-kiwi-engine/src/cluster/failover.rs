@@ -0,0 +1,386 @@
+//! Failure detection and failover. A node that leaves this node's pings
+//! unanswered for the node timeout is suspected to be down (PFAIL); nodes
+//! tell each other whom they suspect in their gossip, and once a majority of
+//! the masters serving slots do, the node is marked as failed (FAIL) and the
+//! news is broadcast. A replica of a failed master then starts an election
+//! in a new epoch: every master votes once per epoch, and the replica that
+//! gets a majority takes over its master's slots with that epoch as its
+//! config epoch, so its claim wins everywhere.
+
+use super::bus::{self, Message, MessageKind};
+use super::{Cluster, ClusterState};
+use crate::time::now_ms;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+/// An election starts at least this long after the master failed, leaving
+/// time for the FAIL to reach every master.
+const ELECTION_DELAY_MS: u64 = 500;
+/// Below this, a failed master only gets its FAIL cleared once it stopped
+/// serving slots.
+const FAIL_UNDO_TIME_MULT: u64 = 2;
+
+/// The election a replica runs to replace its failed master.
+pub(super) struct Election {
+    /// When to ask for votes.
+    start_ms: u64,
+    /// Epoch the votes were asked for in, 0 until then.
+    epoch: u64,
+    votes: HashSet<String>,
+}
+
+/// Checks for failures, runs elections and saves nodes.conf, ten times a second.
+pub(super) async fn run_cron(cluster: Arc<Cluster>) {
+    let mut interval = tokio::time::interval(CRON_INTERVAL);
+    loop {
+        interval.tick().await;
+        {
+            let mut state = cluster.state.lock().unwrap();
+            let now = now_ms();
+            cluster.detect_failures(&mut state, now);
+            cluster.run_election(&mut state, now);
+        }
+        if let Err(err) = cluster.save_config().await {
+            warn!(
+                "Saving the cluster config to {} failed: {err}",
+                cluster.config_path.display()
+            );
+        }
+    }
+}
+
+impl Cluster {
+    /// Suspects the nodes that stopped answering, and marks the suspects a
+    /// majority of the masters reported as failed.
+    fn detect_failures(self: &Arc<Self>, state: &mut ClusterState, now: u64) {
+        let voters = state.voters();
+        let quorum = voters.len() / 2 + 1;
+        let report_window = self.node_timeout_ms * 2;
+        let mut failed = Vec::new();
+        for (id, node) in state.nodes.iter_mut() {
+            if *id == self.myself || node.handshake {
+                continue;
+            }
+            if !node.pfail && now.saturating_sub(node.pong_received_ms) > self.node_timeout_ms {
+                info!("Node {id} is not answering, marking it as PFAIL");
+                node.pfail = true;
+            }
+            node.fail_reports.retain(|reporter, reported_ms| {
+                voters.contains(reporter) && now.saturating_sub(*reported_ms) <= report_window
+            });
+
+            let reports = node.fail_reports.len() + voters.contains(&self.myself) as usize;
+            if node.pfail && !node.fail && reports >= quorum {
+                info!("Marking node {id} as failing, {reports} of the masters agree");
+                node.fail = true;
+                node.fail_time_ms = now;
+                failed.push(id.clone());
+            }
+        }
+
+        for id in failed {
+            let recipients: Vec<String> = state
+                .nodes
+                .iter()
+                .filter(|(other, node)| **other != self.myself && **other != id && !node.handshake)
+                .map(|(other, _)| other.clone())
+                .collect();
+            for recipient in recipients {
+                let mut message = self.message(state, MessageKind::Fail, &recipient);
+                message.about = id.clone();
+                self.send(state, &recipient, message);
+            }
+        }
+    }
+
+    /// Marks node `id` as failed, as another node found a majority agreeing.
+    pub(super) fn mark_failed(&self, state: &mut ClusterState, id: &str) {
+        if id == self.myself {
+            return;
+        }
+        if let Some(node) = state.nodes.get_mut(id)
+            && !node.fail
+        {
+            info!("Node {id} failed, as reported by the cluster");
+            node.fail = true;
+            node.fail_time_ms = now_ms();
+        }
+    }
+
+    /// Clears the FAIL of node `id` now that it answers again. A master keeps
+    /// it for a while as long as it serves slots, giving a replica the time
+    /// to take over.
+    pub(super) fn clear_failure(&self, state: &mut ClusterState, id: &str) {
+        let serves_slots = !state.slot_ranges(id).is_empty();
+        let undo_ms = self.node_timeout_ms * FAIL_UNDO_TIME_MULT;
+        if let Some(node) = state.nodes.get_mut(id)
+            && node.fail
+            && (node.master.is_some()
+                || !serves_slots
+                || now_ms().saturating_sub(node.fail_time_ms) > undo_ms)
+        {
+            info!("Node {id} is reachable again, clearing its FAIL state");
+            node.fail = false;
+        }
+    }
+
+    /// Replaces this replica's master once it failed: waits a little, then
+    /// asks the masters for their votes in a new epoch, starting over when
+    /// they don't come in time.
+    fn run_election(self: &Arc<Self>, state: &mut ClusterState, now: u64) {
+        let master = state.nodes[&self.myself].master.clone().filter(|master| {
+            state.nodes.get(master).is_some_and(|node| node.fail)
+                && !state.slot_ranges(master).is_empty()
+        });
+        let Some(master) = master else {
+            state.election = None;
+            return;
+        };
+
+        let (start_ms, epoch) = match &state.election {
+            Some(election) => (election.start_ms, election.epoch),
+            None => {
+                let delay = ELECTION_DELAY_MS + jitter_ms(ELECTION_DELAY_MS);
+                info!("Master {master} failed, starting a failover election in {delay}ms");
+                state.election = Some(Election {
+                    start_ms: now + delay,
+                    epoch: 0,
+                    votes: HashSet::new(),
+                });
+                return;
+            }
+        };
+
+        if epoch == 0 && now >= start_ms {
+            state.current_epoch += 1;
+            let epoch = state.current_epoch;
+            if let Some(election) = &mut state.election {
+                election.epoch = epoch;
+            }
+            info!("Asking for votes to replace {master} in epoch {epoch}");
+            for voter in state.voters().iter().filter(|voter| **voter != master) {
+                let mut message = self.message(state, MessageKind::AuthRequest, voter);
+                message.about = master.clone();
+                self.send(state, voter, message);
+            }
+        } else if epoch > 0 && now > start_ms + (self.node_timeout_ms * 2).max(2000) {
+            warn!("The failover election of epoch {epoch} timed out");
+            state.election = None;
+        }
+    }
+
+    /// Whether to vote for the sender of an AUTH_REQUEST to replace its
+    /// master: only masters serving slots vote, once per epoch, and only for
+    /// a replica of a master that failed. The vote counts as cast as soon as
+    /// it is granted: should saving it fail, this node sits the epoch out
+    /// rather than risk voting twice in it.
+    pub(super) fn grant_vote(&self, state: &mut ClusterState, request: &Message) -> bool {
+        let epoch = request.current_epoch;
+        if !state.voters().contains(&self.myself)
+            || epoch < state.current_epoch
+            || state.last_vote_epoch >= epoch
+        {
+            return false;
+        }
+        let replica = state
+            .nodes
+            .get(&request.sender)
+            .is_some_and(|node| node.master.as_deref() == Some(request.about.as_str()));
+        let failed = state
+            .nodes
+            .get(&request.about)
+            .is_some_and(|node| node.fail);
+        if !replica || !failed {
+            return false;
+        }
+
+        info!(
+            "Voting for {} to replace {} in epoch {epoch}",
+            request.sender, request.about
+        );
+        state.last_vote_epoch = epoch;
+        true
+    }
+
+    /// Counts the vote an AUTH_ACK brings, taking over the master's slots
+    /// once a majority of the masters voted.
+    pub(super) fn count_vote(&self, state: &mut ClusterState, ack: &Message) {
+        let voters = state.voters();
+        let Some(election) = &mut state.election else {
+            return;
+        };
+        if election.epoch == 0 || ack.current_epoch != election.epoch {
+            return;
+        }
+        if voters.contains(&ack.sender) {
+            election.votes.insert(ack.sender.clone());
+        }
+        if election.votes.len() < voters.len() / 2 + 1 {
+            return;
+        }
+
+        let epoch = election.epoch;
+        state.election = None;
+        let Some(myself) = state.nodes.get_mut(&self.myself) else {
+            return;
+        };
+        let Some(master) = myself.master.take() else {
+            return;
+        };
+        myself.config_epoch = epoch;
+        for owner in state.slots.iter_mut() {
+            if owner.as_deref() == Some(master.as_str()) {
+                *owner = Some(self.myself.clone());
+            }
+        }
+        info!("Won the failover election of epoch {epoch}, serving the slots of {master}");
+        self.follow_leader(None);
+    }
+
+    /// Sends `message` to node `id` on a connection of its own.
+    fn send(self: &Arc<Self>, state: &ClusterState, id: &str, message: Message) {
+        if let Some(node) = state.nodes.get(id) {
+            let (host, bus_port) = (node.host.clone(), node.bus_port);
+            tokio::spawn(bus::send(self.clone(), host, bus_port, message));
+        }
+    }
+}
+
+/// Up to `max_ms` of delay, so replicas of the same master don't all ask for
+/// votes at once.
+fn jitter_ms(max_ms: u64) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    nanos as u64 % max_ms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::Node;
+    use crate::config::KiwiConfig;
+
+    fn node(port: u16) -> Arc<Cluster> {
+        let file = format!("kiwi-failover-{port}-{}.conf", std::process::id());
+        let config = KiwiConfig::new()
+            .with_dir(std::env::temp_dir())
+            .with_cluster_config_file(file);
+        Arc::new(Cluster::new(&config, "127.0.0.1", port).unwrap())
+    }
+
+    /// Adds master `id` serving `slots`, or a replica of `master`.
+    fn add(state: &mut ClusterState, id: &str, slots: &[(u16, u16)], master: Option<&str>) {
+        let mut node = Node::new("127.0.0.1".to_string(), 7100, 17100, false);
+        node.master = master.map(str::to_string);
+        state.nodes.insert(id.to_string(), node);
+        state.claim_slots(id, 0, slots);
+    }
+
+    fn request(kind: MessageKind, sender: &str, epoch: u64, about: &str) -> Message {
+        Message {
+            kind,
+            sender: sender.to_string(),
+            port: 7100,
+            bus_port: 17100,
+            current_epoch: epoch,
+            config_epoch: 0,
+            master: None,
+            slots: vec![],
+            gossip: vec![],
+            about: about.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detect_failures() {
+        let cluster = node(7000);
+        let mut state = cluster.state.lock().unwrap();
+        let myself = cluster.myself().to_string();
+        state.claim_slots(&myself, 0, &[(0, 10)]);
+        add(&mut state, "m", &[(11, 20)], None);
+        add(&mut state, "v", &[(21, 30)], None);
+
+        let now = now_ms() + cluster.node_timeout_ms + 1;
+        cluster.detect_failures(&mut state, now);
+        assert!(state.nodes["m"].pfail);
+        assert!(!state.nodes["m"].fail);
+
+        // Two of the three masters agree.
+        state
+            .nodes
+            .get_mut("m")
+            .unwrap()
+            .fail_reports
+            .insert("v".to_string(), now);
+        cluster.detect_failures(&mut state, now);
+        assert!(state.nodes["m"].fail);
+    }
+
+    #[tokio::test]
+    async fn test_grant_vote() {
+        let cluster = node(7000);
+        {
+            let mut state = cluster.state.lock().unwrap();
+            let myself = cluster.myself().to_string();
+            state.claim_slots(&myself, 0, &[(0, 10)]);
+            add(&mut state, "m", &[(11, 20)], None);
+            add(&mut state, "r", &[], Some("m"));
+            add(&mut state, "o", &[], None);
+
+            let vote = request(MessageKind::AuthRequest, "r", 1, "m");
+            assert!(!cluster.grant_vote(&mut state, &vote));
+            state.nodes.get_mut("m").unwrap().fail = true;
+            assert!(
+                !cluster.grant_vote(&mut state, &request(MessageKind::AuthRequest, "o", 1, "m"))
+            );
+            assert!(cluster.grant_vote(&mut state, &vote));
+            // One vote per epoch.
+            assert!(!cluster.grant_vote(&mut state, &vote));
+            state.nodes.get_mut("r").unwrap().master = Some("m".to_string());
+        }
+
+        // Saved before the vote goes out.
+        let local = "127.0.0.1".parse().unwrap();
+        let mut vote = request(MessageKind::AuthRequest, "r", 2, "m");
+        vote.master = Some("m".to_string());
+        let ack = cluster.receive(vote, local, local).await;
+        assert_eq!(ack.kind, MessageKind::AuthAck);
+        let saved = std::fs::read_to_string(&cluster.config_path).unwrap();
+        assert!(saved.ends_with("lastVoteEpoch 2\n"));
+
+        std::fs::remove_file(&cluster.config_path).unwrap();
+    }
+
+    #[test]
+    fn test_count_vote() {
+        let cluster = node(7001);
+        let mut state = cluster.state.lock().unwrap();
+        let myself = cluster.myself().to_string();
+        add(&mut state, "m", &[(0, 10)], None);
+        add(&mut state, "a", &[(11, 20)], None);
+        add(&mut state, "b", &[(21, 30)], None);
+        state.nodes.get_mut("m").unwrap().fail = true;
+        state.nodes.get_mut(&myself).unwrap().master = Some("m".to_string());
+        state.election = Some(Election {
+            start_ms: 0,
+            epoch: 3,
+            votes: HashSet::new(),
+        });
+
+        cluster.count_vote(&mut state, &request(MessageKind::AuthAck, "a", 2, ""));
+        cluster.count_vote(&mut state, &request(MessageKind::AuthAck, "a", 3, ""));
+        assert!(state.election.is_some());
+        assert_eq!(state.slot_ranges("m"), vec![(0, 10)]);
+
+        cluster.count_vote(&mut state, &request(MessageKind::AuthAck, "b", 3, ""));
+        assert!(state.election.is_none());
+        assert_eq!(state.slot_ranges(&myself), vec![(0, 10)]);
+        assert_eq!(state.nodes[&myself].master, None);
+        assert_eq!(state.nodes[&myself].config_epoch, 3);
+    }
+}
@@ -0,0 +1,119 @@
+//! The sending side of MIGRATE: keys are restored on the target from their
+//! DUMP payloads, all in one pipeline.
+
+use oh_my_kiwi_domain::error::CommandError;
+use oh_my_kiwi_domain::types::Types;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedReadHalf;
+
+/// Used when MIGRATE is given no timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A key to restore on the target.
+pub(crate) struct MigratedKey {
+    pub(crate) key: Types,
+    /// Remaining time to live in milliseconds, 0 for none.
+    pub(crate) ttl_ms: u64,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// Where and how MIGRATE connects.
+pub(crate) struct Target<'a> {
+    pub(crate) host: &'a str,
+    pub(crate) port: u16,
+    pub(crate) db: usize,
+    pub(crate) timeout_ms: u64,
+    pub(crate) auth: Option<&'a (Option<String>, String)>,
+    /// Send ASKING before each key, so a cluster node importing the slot accepts it.
+    pub(crate) asking: bool,
+}
+
+/// Restores `keys` on the target, returning the error the target answered
+/// each key with, if any. Every step, from connecting to each reply, has to
+/// finish within the timeout.
+pub(crate) async fn send(
+    target: &Target<'_>,
+    keys: &[MigratedKey],
+    replace: bool,
+) -> Result<Vec<Option<String>>, CommandError> {
+    let timeout = match target.timeout_ms {
+        0 => DEFAULT_TIMEOUT,
+        timeout_ms => Duration::from_millis(timeout_ms),
+    };
+    let stream = tokio::time::timeout(timeout, TcpStream::connect((target.host, target.port)))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(CommandError::MigrateIo("connecting to"))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let bulk = |value: &str| Types::BulkString(value.to_string());
+    let mut setup = Vec::new();
+    match target.auth {
+        Some((Some(username), password)) => {
+            setup.push(vec![bulk("AUTH"), bulk(username), bulk(password)])
+        }
+        Some((None, password)) => setup.push(vec![bulk("AUTH"), bulk(password)]),
+        None => {}
+    }
+    setup.push(vec![bulk("SELECT"), bulk(&target.db.to_string())]);
+
+    let mut bytes = Vec::new();
+    for command in &setup {
+        bytes.extend(Types::Array(command.clone()).to_bytes());
+    }
+    for key in keys {
+        if target.asking {
+            bytes.extend(Types::Array(vec![bulk("ASKING")]).to_bytes());
+        }
+        let mut restore = vec![
+            bulk("RESTORE"),
+            key.key.clone(),
+            bulk(&key.ttl_ms.to_string()),
+            Types::BulkBytes(key.payload.clone()),
+        ];
+        if replace {
+            restore.push(bulk("REPLACE"));
+        }
+        bytes.extend(Types::Array(restore).to_bytes());
+    }
+    tokio::time::timeout(timeout, writer.write_all(&bytes))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(CommandError::MigrateIo("writing to"))?;
+
+    for _ in &setup {
+        if let Some(err) = reply(&mut reader, timeout).await? {
+            return Err(CommandError::MigrateTarget(err));
+        }
+    }
+    let mut results = Vec::with_capacity(keys.len());
+    for _ in keys {
+        if target.asking
+            && let Some(err) = reply(&mut reader, timeout).await?
+        {
+            return Err(CommandError::MigrateTarget(err));
+        }
+        results.push(reply(&mut reader, timeout).await?);
+    }
+    Ok(results)
+}
+
+/// Reads a single line reply, returning the error it carries, if any.
+async fn reply(
+    reader: &mut BufReader<OwnedReadHalf>,
+    timeout: Duration,
+) -> Result<Option<String>, CommandError> {
+    let mut line = Vec::new();
+    match tokio::time::timeout(timeout, reader.read_until(b'\n', &mut line)).await {
+        Ok(Ok(read)) if read > 0 => {
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            Ok(line.strip_prefix('-').map(str::to_string))
+        }
+        _ => Err(CommandError::MigrateIo("reading from")),
+    }
+}
@@ -0,0 +1,191 @@
+//! nodes.conf: the cluster configuration in the format of CLUSTER NODES,
+//! followed by a `vars` line with the epochs. It is rewritten whenever the
+//! configuration changes, and read on startup so a restarted node comes back
+//! with its id, its peers and the slots they serve.
+
+use super::slot::parse_slot_ranges;
+use super::{Cluster, ClusterState, Node};
+use oh_my_kiwi_domain::command::cluster::CLUSTER_SLOTS;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A configuration read back from nodes.conf.
+pub(super) struct SavedConfig {
+    pub(super) myself: String,
+    pub(super) state: ClusterState,
+}
+
+/// Reads the configuration at `path`, `None` when there is none yet.
+pub(super) fn load(path: &Path) -> Result<Option<SavedConfig>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    parse(&content).map(Some).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid cluster config file {}: {err}", path.display()),
+        )
+    })
+}
+
+fn parse(content: &str) -> std::result::Result<SavedConfig, String> {
+    let mut myself = None;
+    let mut state = ClusterState {
+        current_epoch: 0,
+        last_vote_epoch: 0,
+        nodes: BTreeMap::new(),
+        slots: vec![None; CLUSTER_SLOTS as usize],
+        migrating: BTreeMap::new(),
+        importing: BTreeMap::new(),
+        election: None,
+        saved_config: content.to_string(),
+    };
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let ["vars", vars @ ..] = fields.as_slice() {
+            for pair in vars.chunks(2) {
+                let number = |value: Option<&&str>| {
+                    value
+                        .and_then(|value| value.parse().ok())
+                        .ok_or_else(|| format!("invalid vars line '{line}'"))
+                };
+                match pair[0] {
+                    "currentEpoch" => state.current_epoch = number(pair.get(1))?,
+                    "lastVoteEpoch" => state.last_vote_epoch = number(pair.get(1))?,
+                    _ => {}
+                }
+            }
+            continue;
+        }
+
+        let invalid = || format!("invalid node line '{line}'");
+        let [
+            id,
+            address,
+            flags,
+            master,
+            _,
+            _,
+            config_epoch,
+            _,
+            slots @ ..,
+        ] = fields.as_slice()
+        else {
+            return Err(invalid());
+        };
+        let (address, bus_port) = address.split_once('@').ok_or_else(invalid)?;
+        let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+        let mut node = Node::new(
+            host.to_string(),
+            port.parse().map_err(|_| invalid())?,
+            bus_port.parse().map_err(|_| invalid())?,
+            false,
+        );
+        node.config_epoch = config_epoch.parse().map_err(|_| invalid())?;
+        node.master = (*master != "-").then(|| master.to_string());
+        let flags: Vec<&str> = flags.split(',').collect();
+        node.fail = flags.contains(&"fail");
+
+        let (migrations, slots): (Vec<&str>, Vec<&str>) =
+            slots.iter().partition(|slot| slot.starts_with('['));
+        for (start, end) in parse_slot_ranges(&slots.join(" ")).ok_or_else(invalid)? {
+            state.slots[start as usize..=end as usize].fill(Some(id.to_string()));
+        }
+        if flags.contains(&"myself") {
+            myself = Some(id.to_string());
+            for migration in migrations {
+                let migration = migration.trim_start_matches('[').trim_end_matches(']');
+                let (slot, migrations, other) = match migration.split_once("->-") {
+                    Some((slot, target)) => (slot, &mut state.migrating, target),
+                    None => {
+                        let (slot, source) = migration.split_once("-<-").ok_or_else(invalid)?;
+                        (slot, &mut state.importing, source)
+                    }
+                };
+                migrations.insert(slot.parse().map_err(|_| invalid())?, other.to_string());
+            }
+        }
+        state.nodes.insert(id.to_string(), node);
+    }
+
+    let myself = myself.ok_or("no node is flagged as myself")?;
+    Ok(SavedConfig { myself, state })
+}
+
+impl Cluster {
+    /// Writes and fsyncs nodes.conf when the configuration changed since the
+    /// last time. The configuration is rendered under the state lock, but
+    /// written without it on a blocking thread, so routing never waits for
+    /// the disk. Saves run one at a time, so an older configuration never
+    /// lands over a newer one.
+    pub(super) async fn save_config(self: &Arc<Self>) -> Result<()> {
+        let _saving = self.saving.lock().await;
+        let config = {
+            let state = self.state.lock().unwrap();
+            let config = format!(
+                "{}vars currentEpoch {} lastVoteEpoch {}\n",
+                self.node_lines(&state, true),
+                state.current_epoch,
+                state.last_vote_epoch
+            );
+            if config == state.saved_config {
+                return Ok(());
+            }
+            config
+        };
+
+        let path = self.config_path.clone();
+        let config = tokio::task::spawn_blocking(move || write(&path, config))
+            .await
+            .map_err(Error::other)??;
+        self.state.lock().unwrap().saved_config = config;
+        Ok(())
+    }
+}
+
+/// Writes `config` to `path` next to the file and renames it over it, so a
+/// crash never leaves a partial configuration behind.
+fn write(path: &Path, config: String) -> Result<String> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(config.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = "\
+aaaa 127.0.0.1:7000@17000 myself,master - 0 0 3 connected 0-100 200 [300->-bbbb]
+bbbb 127.0.0.1:7001@17001 master,fail - 0 0 2 disconnected 101-199
+cccc 127.0.0.1:7002@17002 slave bbbb 0 0 2 disconnected
+vars currentEpoch 5 lastVoteEpoch 4
+";
+        let saved = parse(content).unwrap();
+        assert_eq!(saved.myself, "aaaa");
+        let state = saved.state;
+        assert_eq!(state.current_epoch, 5);
+        assert_eq!(state.last_vote_epoch, 4);
+        assert_eq!(state.slot_ranges("aaaa"), vec![(0, 100), (200, 200)]);
+        assert_eq!(state.slot_ranges("bbbb"), vec![(101, 199)]);
+        assert_eq!(state.migrating.get(&300).map(String::as_str), Some("bbbb"));
+        assert!(state.nodes["bbbb"].fail);
+        assert_eq!(state.nodes["aaaa"].config_epoch, 3);
+        assert_eq!(state.nodes["cccc"].master.as_deref(), Some("bbbb"));
+        assert_eq!(state.nodes["cccc"].port, 7002);
+
+        assert!(parse("aaaa 127.0.0.1:7000@17000 master - 0 0 0 connected\n").is_err());
+        assert!(parse("aaaa 127.0.0.1:7000 myself,master - 0 0 0 connected\n").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use crc::{CRC_16_XMODEM, Crc};
+use oh_my_kiwi_domain::command::cluster::CLUSTER_SLOTS;
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+
+/// Hash slot of `key`. When the key contains a non-empty `{hashtag}`, only
+/// the tag is hashed, so related keys can be kept in the same slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|byte| *byte == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|byte| *byte == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    CRC16.checksum(hashed) % CLUSTER_SLOTS
+}
+
+/// Collapses sorted slots into inclusive `(start, end)` ranges.
+pub(crate) fn slot_ranges(slots: impl IntoIterator<Item = u16>) -> Vec<(u16, u16)> {
+    let mut ranges: Vec<(u16, u16)> = Vec::new();
+    for slot in slots {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == slot => *end = slot,
+            _ => ranges.push((slot, slot)),
+        }
+    }
+    ranges
+}
+
+/// Formats ranges the way CLUSTER NODES lists them, e.g. `0-5460 5462`.
+pub(crate) fn format_slot_ranges(ranges: &[(u16, u16)]) -> String {
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{start}-{end}"),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn parse_slot_ranges(value: &str) -> Option<Vec<(u16, u16)>> {
+    value
+        .split_whitespace()
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end && end < CLUSTER_SLOTS).then_some((start, end))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(key_hash_slot(b"123456789"), 0x31C3 % CLUSTER_SLOTS);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"bar"), 5061);
+        assert_eq!(key_hash_slot(b""), 0);
+    }
+
+    #[test]
+    fn test_hashtags() {
+        assert_eq!(
+            key_hash_slot(b"{user1000}.following"),
+            key_hash_slot(b"user1000")
+        );
+        assert_eq!(
+            key_hash_slot(b"{user1000}.following"),
+            key_hash_slot(b"{user1000}.followers")
+        );
+        // Only the first tag counts, and an empty one hashes the whole key.
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+        assert_eq!(
+            key_hash_slot(b"foo{}{bar}"),
+            CRC16.checksum(b"foo{}{bar}") % CLUSTER_SLOTS
+        );
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), key_hash_slot(b"{bar"));
+        assert_eq!(
+            key_hash_slot(b"foo{bar"),
+            CRC16.checksum(b"foo{bar") % CLUSTER_SLOTS
+        );
+    }
+
+    #[test]
+    fn test_slot_ranges() {
+        let ranges = slot_ranges([0, 1, 2, 5, 7, 8]);
+        assert_eq!(ranges, vec![(0, 2), (5, 5), (7, 8)]);
+        assert_eq!(format_slot_ranges(&ranges), "0-2 5 7-8");
+        assert_eq!(parse_slot_ranges("0-2 5 7-8"), Some(ranges));
+        assert_eq!(parse_slot_ranges(""), Some(vec![]));
+        assert_eq!(parse_slot_ranges("5-2"), None);
+        assert_eq!(parse_slot_ranges("16384"), None);
+    }
+}
@@ -1,50 +1,863 @@
+use crate::acl::categories::{is_deny_oom_command, is_write_command};
+use crate::acl::{Acl, DEFAULT_USER};
+use crate::aof::AppendOnlyFile;
+use crate::cluster::migrate::{self, MigratedKey};
+use crate::cluster::{Cluster, Routing};
+use crate::cluster::slot::key_hash_slot;
+use crate::lazy_free;
+use crate::memory::MemoryReport;
+use crate::replication::{FollowerStream, ReplicaTarget, Replication, WaitFor};
+use crate::snapshot::Snapshots;
+use crate::snapshot::rdb::{RdbValue, dump_payload, read_dump_payload, verify_dump_payload};
+use crate::time::now_ms;
+use crate::tracking::{self, ClientId, PushReceiver, PushSender, TrackingTable, push_channel};
 use async_trait::async_trait;
-use std::sync::Arc;
-use oh_my_kiwi_domain::{CommandProcessor, Engine};
-use oh_my_kiwi_domain::command::KiwiCommand;
-use oh_my_kiwi_domain::error::KiwiError;
+use oh_my_kiwi_domain::command::acl::AclCommand;
+use oh_my_kiwi_domain::command::cluster::ClusterCommand;
+use oh_my_kiwi_domain::command::{
+    ClientCommand, DebugCommand, HelloOptions, KiwiCommand, MemoryCommand, MigrateOptions,
+    ObjectCommand, RestoreOptions, TrackingOptions,
+};
+use oh_my_kiwi_domain::error::{CommandError, KiwiError};
 use oh_my_kiwi_domain::response::Response;
 use oh_my_kiwi_domain::types::Types;
+use oh_my_kiwi_domain::{AccessHistory, CommandProcessor, Engine, Entry, MemoryStats};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
 pub struct KiwiCommandProcessor<E> {
     engine: Arc<E>,
+    tracking: Arc<TrackingTable>,
+    acl: Arc<Acl>,
+    snapshots: Arc<Snapshots>,
+    aof: Option<Arc<AppendOnlyFile>>,
+    replication: Arc<Replication>,
+    cluster: Option<Arc<Cluster>>,
+    client_id: ClientId,
+    client_addr: SocketAddr,
+    client_name: Option<String>,
+    user: String,
+    authenticated: bool,
+    /// Whether HELLO 3 switched the connection to RESP3, which push
+    /// messages need.
+    resp3: bool,
+    db: usize,
+    tracking_options: Option<TrackingOptions>,
+    caching: Option<bool>,
+    /// Set by ASKING for the next command.
+    asking: bool,
+    push_sender: PushSender,
+    push_receiver: PushReceiver,
+    /// Replication offset of the last write made on this connection.
+    write_offset: u64,
+    /// Port a follower connection announced with REPLCONF listening-port.
+    follower_port: Option<u16>,
+    /// Replication stream sent to this connection once it synced as a follower.
+    follower_stream: Option<FollowerStream>,
 }
 
 #[async_trait]
 impl<E> CommandProcessor for KiwiCommandProcessor<E>
 where
-    E: Engine + Send + Sync,
+    E: Engine + Send + Sync + 'static,
 {
     async fn process(&mut self, command: KiwiCommand) -> Result<Response, KiwiError> {
         self.process(command).await
     }
+
+    async fn next_push(&mut self) -> Option<Response> {
+        match &mut self.follower_stream {
+            Some(stream) => Some(stream.recv().await.map_or(Response::Close, Response::Raw)),
+            None => self.push_receiver.recv().await.map(Response::Value),
+        }
+    }
 }
 
 impl<E> KiwiCommandProcessor<E>
 where
-    E: Engine + Send + Sync,
+    E: Engine + Send + Sync + 'static,
 {
-    pub fn new(engine: Arc<E>) -> Self {
-        Self { engine }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        engine: Arc<E>,
+        tracking: Arc<TrackingTable>,
+        acl: Arc<Acl>,
+        snapshots: Arc<Snapshots>,
+        aof: Option<Arc<AppendOnlyFile>>,
+        replication: Arc<Replication>,
+        cluster: Option<Arc<Cluster>>,
+        client_addr: SocketAddr,
+    ) -> Self {
+        let (push_sender, push_receiver) = push_channel();
+        let authenticated = !acl.requires_auth();
+        Self {
+            engine,
+            tracking,
+            acl,
+            snapshots,
+            aof,
+            replication,
+            cluster,
+            client_id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            client_addr,
+            client_name: None,
+            user: DEFAULT_USER.to_string(),
+            authenticated,
+            resp3: false,
+            db: 0,
+            tracking_options: None,
+            caching: None,
+            asking: false,
+            push_sender,
+            push_receiver,
+            write_offset: 0,
+            follower_port: None,
+            follower_stream: None,
+        }
     }
 
     pub(crate) async fn process(&mut self, command: KiwiCommand) -> Result<Response, KiwiError> {
+        // CLIENT CACHING only applies to the command that immediately follows it.
+        let caching = self.caching.take();
+        let asking = std::mem::take(&mut self.asking);
+
+        let allowed_before_auth = matches!(
+            command,
+            KiwiCommand::Auth { .. } | KiwiCommand::Hello(_) | KiwiCommand::Quit
+        );
+        if !allowed_before_auth {
+            if !self.authenticated {
+                return Err(CommandError::NoAuth.into());
+            }
+            self.acl.check(&self.user, &command, &self.client_info())?;
+        }
+        if let Some(cluster) = &self.cluster {
+            let routing = cluster.route(&command, asking)?;
+            self.check_migration(routing, &command).await?;
+        }
+
+        let write = is_write_command(command.name());
+        if write && self.replication.rejects_writes() {
+            return Err(CommandError::ReadOnlyReplica.into());
+        }
+        // MIGRATE holds the barrier exclusively instead, see `migrate`.
+        let _barrier = match write && !matches!(command, KiwiCommand::Migrate { .. }) {
+            true => Some(self.replication.barrier().enter().await),
+            false => None,
+        };
+        if write && !self.replication.is_follower() {
+            self.evict(command.name()).await?;
+        }
+
         match command {
             KiwiCommand::None => Ok(Response::Ok),
             KiwiCommand::Ping => Ok(Response::Pong),
             KiwiCommand::Command(_) => Ok(Response::Ok),
             KiwiCommand::Set { key, value } => Ok(self.set(key, value).await),
-            KiwiCommand::Get { key } => Ok(self.get(key).await?),
+            KiwiCommand::Get { key } => Ok(self.get(key, caching).await?),
+            KiwiCommand::MGet { keys } => Ok(self.mget(keys, caching).await?),
+            KiwiCommand::MSet { pairs } => {
+                for (key, value) in pairs {
+                    self.set(key, value).await;
+                }
+                Ok(Response::Ok)
+            }
+            KiwiCommand::Del { keys } => Ok(self.del(keys, false).await),
+            KiwiCommand::Unlink { keys } => Ok(self.del(keys, true).await),
+            KiwiCommand::Client(command) => Ok(self.client(command)?),
+            KiwiCommand::Select { index } => Ok(self.select(index)?),
+            KiwiCommand::SwapDb { first, second } => Ok(self.swap_db(first, second).await?),
+            KiwiCommand::Move { key, db } => Ok(self.move_key(key, db).await?),
+            KiwiCommand::DbSize => Ok(self.db_size().await),
+            KiwiCommand::FlushDb { lazy } => Ok(self.flush_db(lazy).await),
+            KiwiCommand::FlushAll { lazy } => Ok(self.flush_all(lazy).await),
+            KiwiCommand::Auth { username, password } => Ok(self.auth(username, password)?),
+            KiwiCommand::Hello(options) => Ok(self.hello(options)?),
+            KiwiCommand::Quit => Ok(Response::Quit),
+            KiwiCommand::Acl(command) => Ok(self.acl(command)?),
+            KiwiCommand::Expire { key, seconds } => {
+                let timestamp_ms = seconds
+                    .checked_mul(1000)
+                    .and_then(|milliseconds| milliseconds.checked_add(now_ms() as i64))
+                    .ok_or(CommandError::InvalidExpireTime("expire"))?;
+                Ok(self.expire_at(key, timestamp_ms).await)
+            }
+            KiwiCommand::PExpire { key, milliseconds } => {
+                let timestamp_ms = milliseconds
+                    .checked_add(now_ms() as i64)
+                    .ok_or(CommandError::InvalidExpireTime("pexpire"))?;
+                Ok(self.expire_at(key, timestamp_ms).await)
+            }
+            KiwiCommand::ExpireAt { key, timestamp } => {
+                let timestamp_ms = timestamp
+                    .checked_mul(1000)
+                    .ok_or(CommandError::InvalidExpireTime("expireat"))?;
+                Ok(self.expire_at(key, timestamp_ms).await)
+            }
+            KiwiCommand::PExpireAt { key, timestamp_ms } => {
+                Ok(self.expire_at(key, timestamp_ms).await)
+            }
+            KiwiCommand::Ttl { key } => Ok(self.ttl(key, false).await),
+            KiwiCommand::PTtl { key } => Ok(self.ttl(key, true).await),
+            KiwiCommand::Persist { key } => Ok(self.persist(key).await),
+            KiwiCommand::Save => {
+                self.snapshots.save(self.engine.as_ref()).await?;
+                Ok(Response::Ok)
+            }
+            KiwiCommand::BgSave => {
+                self.snapshots.background_save(self.engine.clone())?;
+                Ok(Response::Value(Types::SimpleString(
+                    "Background saving started".to_string(),
+                )))
+            }
+            KiwiCommand::LastSave => Ok(Response::Value(Types::Integer(
+                self.snapshots.last_save() as i64,
+            ))),
+            KiwiCommand::BgRewriteAof => match &self.aof {
+                Some(aof) => {
+                    aof.background_rewrite(self.engine.clone()).await?;
+                    Ok(Response::Value(Types::SimpleString(
+                        "Background append only file rewriting started".to_string(),
+                    )))
+                }
+                None => Err(CommandError::AofDisabled.into()),
+            },
+            KiwiCommand::Debug(DebugCommand::Reload) => Ok(self.debug_reload().await?),
+            KiwiCommand::Object(ObjectCommand::Encoding { key }) => {
+                let encoding = self.engine.encoding(self.db, &key_bytes(&key)).await;
+                Ok(Response::Value(encoding.map_or(Types::Null, |encoding| {
+                    Types::BulkString(encoding.to_string())
+                })))
+            }
+            KiwiCommand::Memory(command) => Ok(self.memory(command).await),
+            KiwiCommand::Dump { key } => self.dump(key).await,
+            KiwiCommand::Restore {
+                key,
+                ttl,
+                payload,
+                options,
+            } => Ok(self.restore(key, ttl, payload, options).await?),
+            KiwiCommand::ReplicaOf(_) if self.cluster.is_some() => {
+                Err(CommandError::ReplicaOfInClusterMode.into())
+            }
+            KiwiCommand::ReplicaOf(leader) => Ok(self.replicaof(leader)),
+            KiwiCommand::ReplConf(options) => Ok(self.replconf(options)?),
+            KiwiCommand::Psync { replid, offset } => Ok(self.psync(&replid, offset).await?),
+            KiwiCommand::Sync => Ok(self.sync(false).await?),
+            KiwiCommand::Info(sections) => Ok(self.info(sections)),
+            KiwiCommand::Wait {
+                replicas,
+                timeout_ms,
+            } => {
+                if self.replication.is_follower() {
+                    return Err(CommandError::WaitOnReplica("WAIT").into());
+                }
+                let (_, acknowledged) = self.wait(false, replicas, timeout_ms, false).await?;
+                Ok(Response::Value(Types::Integer(acknowledged as i64)))
+            }
+            KiwiCommand::WaitAof {
+                local,
+                replicas,
+                timeout_ms,
+            } => {
+                if self.replication.is_follower() {
+                    return Err(CommandError::WaitOnReplica("WAITAOF").into());
+                }
+                if local > 0 && self.aof.is_none() {
+                    return Err(CommandError::WaitAofWithoutAof.into());
+                }
+                let (local, replicas) = self.wait(local > 0, replicas, timeout_ms, true).await?;
+                Ok(Response::Value(Types::Array(vec![
+                    Types::Integer(local as i64),
+                    Types::Integer(replicas as i64),
+                ])))
+            }
+            KiwiCommand::Cluster(command) => self.cluster(command).await,
+            KiwiCommand::Asking => match self.cluster {
+                Some(_) => {
+                    self.asking = true;
+                    Ok(Response::Ok)
+                }
+                None => Err(CommandError::ClusterDisabled.into()),
+            },
+            KiwiCommand::Migrate {
+                host,
+                port,
+                keys,
+                db,
+                timeout_ms,
+                options,
+            } => {
+                self.migrate(&host, port, keys, db, timeout_ms, options)
+                    .await
+            }
+        }
+    }
+
+    /// Serves the keys of a slot in migration only where they are: a source
+    /// sends clients asking for keys it no longer holds to the target with
+    /// ASK, and neither side serves a multi-key command with keys on both.
+    async fn check_migration(
+        &self,
+        routing: Routing,
+        command: &KiwiCommand,
+    ) -> Result<(), CommandError> {
+        let keys = command.keys();
+        let mut missing = 0;
+        if routing != Routing::Serve {
+            for key in &keys {
+                if self.engine.expiry(self.db, &key_bytes(key)).await.is_none() {
+                    missing += 1;
+                }
+            }
+        }
+        match routing {
+            Routing::Migrating { slot, address } if missing == keys.len() => {
+                Err(CommandError::Ask { slot, address })
+            }
+            Routing::Migrating { .. } if missing > 0 => Err(CommandError::TryAgain),
+            Routing::Importing if keys.len() > 1 && missing > 0 => Err(CommandError::TryAgain),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves keys to another instance with RESTORE. Keys the target refused
+    /// stay here, and the first refusal is returned.
+    async fn migrate(
+        &mut self,
+        host: &str,
+        port: u16,
+        keys: Vec<Types>,
+        db: usize,
+        timeout_ms: u64,
+        options: MigrateOptions,
+    ) -> Result<Response, KiwiError> {
+        // A write between reading the keys and deleting them would be lost
+        // with the local copy, so writers wait until the keys are gone.
+        let replication = self.replication.clone();
+        let _barrier = replication.barrier().exclusive().await;
+
+        let mut migrated = Vec::new();
+        for key in keys {
+            let raw_key = key_bytes(&key);
+            let (Some(value), Some(expires_at)) = (
+                self.engine.get(self.db, &raw_key).await,
+                self.engine.expiry(self.db, &raw_key).await,
+            ) else {
+                continue;
+            };
+            let value = RdbValue::from_types(&Types::from_slice(&value).await?);
+            migrated.push(MigratedKey {
+                key,
+                // A key about to expire still needs a TTL on the target.
+                ttl_ms: expires_at
+                    .map_or(0, |expires_at| expires_at.saturating_sub(now_ms()).max(1)),
+                payload: dump_payload(&value),
+            });
+        }
+        if migrated.is_empty() {
+            return Ok(Response::Value(Types::SimpleString("NOKEY".to_string())));
+        }
+
+        let target = migrate::Target {
+            host,
+            port,
+            db,
+            timeout_ms,
+            auth: options.auth.as_ref(),
+            asking: self.cluster.is_some(),
+        };
+        let results = migrate::send(&target, &migrated, options.replace).await?;
+
+        let mut deleted = Vec::new();
+        let mut error = None;
+        for (key, result) in migrated.into_iter().zip(results) {
+            match result {
+                None if !options.copy => deleted.push(key.key),
+                None => {}
+                Some(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        if !deleted.is_empty() {
+            self.del(deleted, false).await;
+        }
+        match error {
+            Some(err) => Err(CommandError::MigrateTarget(err).into()),
+            None => Ok(Response::Ok),
+        }
+    }
+
+    async fn cluster(&self, command: ClusterCommand) -> Result<Response, KiwiError> {
+        let Some(cluster) = &self.cluster else {
+            return Err(CommandError::ClusterDisabled.into());
+        };
+        let response = match command {
+            ClusterCommand::Info => Response::Value(Types::BulkString(cluster.info())),
+            ClusterCommand::MyId => {
+                Response::Value(Types::BulkString(cluster.myself().to_string()))
+            }
+            ClusterCommand::Nodes => Response::Value(Types::BulkString(cluster.nodes())),
+            ClusterCommand::Slots => Response::Value(cluster.slots()),
+            ClusterCommand::Shards => Response::Value(cluster.shards()),
+            ClusterCommand::KeySlot(key) => {
+                Response::Value(Types::Integer(key_hash_slot(&key_bytes(&key)) as i64))
+            }
+            ClusterCommand::CountKeysInSlot(slot) => {
+                let count = self.keys_in_slot(slot, usize::MAX).await?.len();
+                Response::Value(Types::Integer(count as i64))
+            }
+            ClusterCommand::GetKeysInSlot { slot, count } => Response::Value(Types::Array(
+                self.keys_in_slot(slot, count)
+                    .await?
+                    .into_iter()
+                    .map(Types::BulkBytes)
+                    .collect(),
+            )),
+            ClusterCommand::Meet {
+                host,
+                port,
+                bus_port,
+            } => {
+                cluster.meet(&host, port, bus_port)?;
+                Response::Ok
+            }
+            ClusterCommand::AddSlots(slots) => {
+                cluster.add_slots(&slots)?;
+                Response::Ok
+            }
+            ClusterCommand::AddSlotsRange(ranges) => {
+                cluster.add_slots(&expand_slot_ranges(&ranges))?;
+                Response::Ok
+            }
+            ClusterCommand::DelSlots(slots) => {
+                cluster.del_slots(&slots)?;
+                Response::Ok
+            }
+            ClusterCommand::DelSlotsRange(ranges) => {
+                cluster.del_slots(&expand_slot_ranges(&ranges))?;
+                Response::Ok
+            }
+            ClusterCommand::SetSlot { slot, state } => {
+                let has_keys = !self.keys_in_slot(slot, 1).await?.is_empty();
+                cluster.set_slot(slot, state, has_keys)?;
+                Response::Ok
+            }
+            ClusterCommand::Replicate(id) => {
+                let empty = self.engine.size(self.db).await == 0;
+                cluster.replicate(&id, empty)?;
+                Response::Ok
+            }
+        };
+        Ok(response)
+    }
+
+    /// Up to `count` keys of the selected database that hash to `slot`.
+    async fn keys_in_slot(&self, slot: u16, count: usize) -> Result<Vec<Vec<u8>>, CommandError> {
+        let snapshot = self.engine.snapshot().await;
+        let db = self.db;
+        // Disk-backed engines read the entries from their files.
+        let keys = tokio::task::spawn_blocking(move || {
+            let mut keys = Vec::new();
+            for entry in snapshot.entries(db) {
+                let entry = entry?;
+                if key_hash_slot(&entry.key) == slot {
+                    keys.push(entry.key);
+                }
+            }
+            std::io::Result::Ok(keys)
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|keys| keys);
+        let mut keys = keys.map_err(|err| CommandError::Storage(err.to_string()))?;
+        keys.sort();
+        keys.truncate(count);
+        Ok(keys)
+    }
+
+    fn replicaof(&self, leader: Option<(String, u16)>) -> Response {
+        match leader {
+            Some((host, port)) => {
+                let target = ReplicaTarget {
+                    engine: self.engine.clone(),
+                    tracking: self.tracking.clone(),
+                    snapshots: self.snapshots.clone(),
+                };
+                self.replication.follow(host, port, target);
+            }
+            None => self.replication.promote(),
+        }
+        Response::Ok
+    }
+
+    fn replconf(&mut self, options: Vec<(String, String)>) -> Result<Response, CommandError> {
+        fn number<T: std::str::FromStr>(value: &str) -> Result<T, CommandError> {
+            value.parse().map_err(|_| CommandError::NotAnInteger)
+        }
+
+        let mut ack = None;
+        let mut fsynced = None;
+        for (option, value) in options {
+            match option.as_str() {
+                "listening-port" => self.follower_port = Some(number(&value)?),
+                "ack" => ack = Some(number(&value)?),
+                "fack" => fsynced = Some(number(&value)?),
+                "ip-address" | "capa" => {}
+                _ => return Err(CommandError::UnknownReplConfOption(option)),
+            }
+        }
+
+        match ack {
+            Some(offset) => {
+                self.replication.ack(self.client_id, offset, fsynced);
+                // Acknowledgements get no reply.
+                Ok(Response::Raw(Vec::new()))
+            }
+            None => Ok(Response::Ok),
+        }
+    }
+
+    async fn wait(
+        &self,
+        local: bool,
+        replicas: i64,
+        timeout_ms: u64,
+        fsynced: bool,
+    ) -> Result<(bool, usize), CommandError> {
+        let wait = WaitFor {
+            offset: self.write_offset,
+            local,
+            replicas: usize::try_from(replicas).unwrap_or(0),
+            fsynced,
+        };
+        let timeout = (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms));
+        Ok(self.replication.wait(wait, timeout).await)
+    }
+
+    /// Turns the connection into a follower, resuming its stream from the
+    /// backlog when possible.
+    async fn psync(&mut self, replid: &str, offset: i64) -> Result<Response, CommandError> {
+        let Some(sync) = self.replication.partial_sync(
+            self.client_id,
+            self.client_addr,
+            self.follower_port,
+            replid,
+            offset,
+        ) else {
+            return self.sync(true).await;
+        };
+        self.follower_stream = Some(sync.stream);
+
+        let mut bytes = format!("+CONTINUE {}\r\n", sync.replid).into_bytes();
+        bytes.extend(sync.backlog);
+        Ok(Response::Raw(bytes))
+    }
+
+    /// Turns the connection into a follower: replies with a snapshot of the
+    /// data set, then streams every write after it. Both come from the
+    /// follower stream, after the reply.
+    async fn sync(&mut self, psync: bool) -> Result<Response, CommandError> {
+        let sync = self
+            .replication
+            .full_sync(
+                self.engine.as_ref(),
+                self.client_id,
+                self.client_addr,
+                self.follower_port,
+            )
+            .await;
+        self.follower_stream = Some(sync.stream);
+
+        let mut bytes = Vec::new();
+        if psync {
+            bytes.extend(format!("+FULLRESYNC {} {}\r\n", sync.replid, sync.offset).bytes());
+        }
+        Ok(Response::Raw(bytes))
+    }
+
+    /// INFO with the server, memory, stats, tiers, cluster and replication
+    /// sections; tiers only for engines that keep cold keys on disk.
+    fn info(&self, sections: Vec<String>) -> Response {
+        let all = sections.is_empty()
+            || sections
+                .iter()
+                .any(|section| matches!(section.as_str(), "all" | "default" | "everything"));
+        let wanted = |name: &str| all || sections.iter().any(|section| section == name);
+
+        let mut info = String::new();
+        if wanted("server") {
+            info.push_str("# Server\r\n");
+            for line in [
+                format!("redis_version:{}", env!("CARGO_PKG_VERSION")),
+                format!("redis_mode:{}", self.mode()),
+                format!("process_id:{}", std::process::id()),
+                format!("tcp_port:{}", self.replication.listening_port()),
+            ] {
+                info.push_str(&line);
+                info.push_str("\r\n");
+            }
+        }
+        if wanted("memory") {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            let memory = self.engine.memory();
+            info.push_str("# Memory\r\n");
+            for line in [
+                format!("used_memory:{}", memory.used),
+                format!("used_memory_human:{}", human_bytes(memory.used)),
+                format!("maxmemory:{}", memory.max),
+                format!("maxmemory_human:{}", human_bytes(memory.max)),
+                format!("maxmemory_policy:{}", memory.policy),
+                format!("compressed_values:{}", memory.compression.values),
+                format!(
+                    "compressed_values_original_bytes:{}",
+                    memory.compression.original_bytes
+                ),
+                format!(
+                    "compressed_values_bytes:{}",
+                    memory.compression.compressed_bytes
+                ),
+                format!("compression_ratio:{:.2}", compression_ratio(&memory)),
+                format!("lazyfree_pending_objects:{}", lazy_free::pending_objects()),
+            ] {
+                info.push_str(&line);
+                info.push_str("\r\n");
+            }
+        }
+        if wanted("stats") {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str("# Stats\r\n");
+            info.push_str(&format!(
+                "evicted_keys:{}\r\n",
+                self.engine.memory().evicted_keys
+            ));
+            info.push_str(&format!(
+                "lazyfreed_objects:{}\r\n",
+                lazy_free::freed_objects()
+            ));
+        }
+        if wanted("tiers")
+            && let Some(tiers) = self.engine.tiers()
+        {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            let reads = (tiers.memory_hits + tiers.disk_hits + tiers.misses).max(1) as f64;
+            info.push_str("# Tiers\r\n");
+            for line in [
+                format!("tier_memory_hits:{}", tiers.memory_hits),
+                format!("tier_disk_hits:{}", tiers.disk_hits),
+                format!("tier_misses:{}", tiers.misses),
+                format!(
+                    "tier_memory_hit_ratio:{:.4}",
+                    tiers.memory_hits as f64 / reads
+                ),
+                format!("tier_disk_hit_ratio:{:.4}", tiers.disk_hits as f64 / reads),
+                format!("tier_memory_keys:{}", tiers.memory_keys),
+                format!("tier_disk_keys:{}", tiers.disk_keys),
+                format!("tier_disk_bytes:{}", tiers.disk_bytes),
+            ] {
+                info.push_str(&line);
+                info.push_str("\r\n");
+            }
+        }
+        if wanted("cluster") {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str("# Cluster\r\n");
+            info.push_str(&format!(
+                "cluster_enabled:{}\r\n",
+                self.cluster.is_some() as u8
+            ));
+        }
+        if wanted("replication") {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str("# Replication\r\n");
+            for line in self.replication.info() {
+                info.push_str(&line);
+                info.push_str("\r\n");
+            }
+        }
+        Response::Value(Types::BulkString(info))
+    }
+
+    async fn memory(&self, command: MemoryCommand) -> Response {
+        let value = match command {
+            MemoryCommand::Usage { key, .. } => {
+                let usage = self.engine.memory_usage(self.db, &key_bytes(&key)).await;
+                usage.map_or(Types::Null, |bytes| Types::Integer(bytes as i64))
+            }
+            MemoryCommand::Stats => self.memory_report().await.stats(),
+            MemoryCommand::Doctor => Types::BulkString(self.memory_report().await.diagnose()),
+            MemoryCommand::MallocStats => {
+                Types::BulkString("Stats not supported for the current allocator".to_string())
+            }
+        };
+        Response::Value(value)
+    }
+
+    /// The engine's memory with the output waiting for the connected clients
+    /// and followers, and the replication backlog.
+    async fn memory_report(&self) -> MemoryReport {
+        let mut keys = 0;
+        for db in 0..self.engine.databases() {
+            keys += self.engine.size(db).await;
+        }
+        MemoryReport {
+            memory: self.engine.memory(),
+            keys,
+            clients: tracking::queued_pushes(),
+            replicas: self.replication.output_buffers(),
+            backlog: self.replication.backlog_len(),
+            lazyfree_pending: lazy_free::pending_objects(),
+        }
+    }
+
+    async fn dump(&self, key: Types) -> Result<Response, KiwiError> {
+        let Some(value) = self.engine.get(self.db, &key_bytes(&key)).await else {
+            return Ok(Response::Value(Types::Null));
+        };
+        let value = RdbValue::from_types(&Types::from_slice(&value).await?);
+        Ok(Response::Value(Types::BulkBytes(dump_payload(&value))))
+    }
+
+    /// Creates `key` from a DUMP payload, its access statistics seeded from
+    /// IDLETIME or FREQ. The key is checked for and created with its expiry in
+    /// one engine step, and propagated as a single RESTORE with an absolute
+    /// expiry.
+    async fn restore(
+        &mut self,
+        key: Types,
+        ttl: i64,
+        payload: Vec<u8>,
+        options: RestoreOptions,
+    ) -> Result<Response, CommandError> {
+        let (entry, history) = restored_entry(&key, ttl, &payload, &options)?;
+        let raw_key = entry.key.clone();
+        let expires_at = entry.expires_at;
+        if !self
+            .engine
+            .restore(self.db, entry, history, options.replace)
+            .await
+        {
+            return Err(CommandError::BusyKey);
         }
+
+        self.tracking.invalidate(&raw_key, Some(self.client_id));
+        self.propagate(KiwiCommand::Restore {
+            key,
+            ttl: expires_at.map_or(0, |expires_at| expires_at as i64),
+            payload,
+            options: RestoreOptions {
+                replace: true,
+                absttl: true,
+                ..RestoreOptions::default()
+            },
+        });
+        Ok(Response::Ok)
+    }
+
+    /// Saves the snapshot and loads it back in place of the data set. Writers
+    /// wait meanwhile, so none lands between the save and the load, and the
+    /// file is read in full before anything is flushed, so one that fails to
+    /// load leaves the data set as it was.
+    async fn debug_reload(&self) -> Result<Response, CommandError> {
+        let _barrier = self.replication.barrier().exclusive().await;
+        self.snapshots.save(self.engine.as_ref()).await?;
+        let databases = self
+            .snapshots
+            .read(self.engine.databases())
+            .await
+            .map_err(|err| CommandError::Persistence(err.to_string()))?;
+
+        self.engine.flush_all(false).await;
+        self.tracking.invalidate_all(None);
+        for (index, entries) in databases {
+            self.engine.insert_entries(index, entries).await;
+        }
+        Ok(Response::Ok)
     }
 
     async fn set(&mut self, key: Types, value: Types) -> Response {
-        self.engine.set(key.to_bytes(), value.to_bytes()).await;
+        let raw_key = key_bytes(&key);
+        self.engine
+            .set(self.db, raw_key.clone(), value.to_bytes())
+            .await;
+        self.tracking.invalidate(&raw_key, Some(self.client_id));
+        self.propagate(KiwiCommand::Set { key, value });
         Response::Ok
     }
 
-    async fn get(&self, key: Types) -> Result<Response, KiwiError> {
-        match self.engine.get(&key.to_bytes()).await {
+    /// Deletes `keys`; with `lazy`, as UNLINK, freeing large values in the
+    /// background.
+    async fn del(&mut self, keys: Vec<Types>, lazy: bool) -> Response {
+        let mut deleted = Vec::new();
+        for key in keys {
+            let raw_key = key_bytes(&key);
+            if self.engine.delete(self.db, &raw_key, lazy).await {
+                self.tracking.invalidate(&raw_key, Some(self.client_id));
+                deleted.push(key);
+            }
+        }
+        let count = deleted.len();
+        if count > 0 {
+            self.propagate(match lazy {
+                false => KiwiCommand::Del { keys: deleted },
+                true => KiwiCommand::Unlink { keys: deleted },
+            });
+        }
+        Response::Value(Types::Integer(count as i64))
+    }
+
+    /// Makes room under maxmemory before a write, refusing writes that may
+    /// add data when not enough could be evicted. Evicted keys are deleted on
+    /// followers and in the AOF as well.
+    async fn evict(&mut self, name: &str) -> Result<(), CommandError> {
+        let evicted = self.engine.evict().await;
+        self.snapshots.mark_dirty(evicted.len() as u64);
+        for (db, key) in evicted {
+            self.tracking.invalidate(&key, None);
+            let del = KiwiCommand::Del {
+                keys: vec![Types::BulkBytes(key)],
+            };
+            self.write_offset = self.replication.feed(db, &del);
+        }
+
+        let memory = self.engine.memory();
+        if memory.max > 0 && memory.used > memory.max && is_deny_oom_command(name) {
+            return Err(CommandError::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    /// Records a write that changed the data set so that it gets persisted
+    /// and replicated.
+    fn propagate(&mut self, command: KiwiCommand) {
+        self.snapshots.mark_dirty(1);
+        self.write_offset = self.replication.feed(self.db, &command);
+    }
+
+    async fn get(&self, key: Types, caching: Option<bool>) -> Result<Response, KiwiError> {
+        let key = key_bytes(&key);
+        let value = self.engine.get(self.db, &key).await;
+        if self.tracks_reads(caching) {
+            self.tracking.remember(self.client_id, &key);
+        }
+
+        match value {
             Some(value) => {
                 let types = Types::from_slice(value.as_slice()).await?;
                 Ok(Response::Value(types))
@@ -52,4 +865,382 @@ where
             None => Ok(Response::Null),
         }
     }
+
+    async fn mget(&self, keys: Vec<Types>, caching: Option<bool>) -> Result<Response, KiwiError> {
+        let tracks_reads = self.tracks_reads(caching);
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let key = key_bytes(&key);
+            let value = self.engine.get(self.db, &key).await;
+            if tracks_reads {
+                self.tracking.remember(self.client_id, &key);
+            }
+            values.push(match value {
+                Some(value) => Types::from_slice(value.as_slice()).await?,
+                None => Types::Null,
+            });
+        }
+        Ok(Response::Value(Types::Array(values)))
+    }
+
+    fn auth(
+        &mut self,
+        username: Option<String>,
+        password: String,
+    ) -> Result<Response, CommandError> {
+        self.authenticate(username.as_deref(), &password)?;
+        Ok(Response::Ok)
+    }
+
+    fn hello(&mut self, options: HelloOptions) -> Result<Response, CommandError> {
+        if options.protocol.is_some_and(|protocol| protocol != 3) {
+            return Err(CommandError::NoProto);
+        }
+        if let Some((username, password)) = &options.auth {
+            self.authenticate(Some(username), password)?;
+        }
+        if !self.authenticated {
+            return Err(CommandError::NoAuth);
+        }
+        if options.client_name.is_some() {
+            self.client_name = options.client_name;
+        }
+        if options.protocol.is_some() {
+            self.resp3 = true;
+        }
+
+        let mut info = BTreeMap::new();
+        let mut field = |name: &str, value: Types| {
+            info.insert(Types::BulkString(name.to_string()), value);
+        };
+        field("server", Types::BulkString("oh-my-kiwi".to_string()));
+        field(
+            "version",
+            Types::BulkString(env!("CARGO_PKG_VERSION").to_string()),
+        );
+        field("proto", Types::Integer(3));
+        field("id", Types::Integer(self.client_id as i64));
+        field("mode", Types::BulkString(self.mode().to_string()));
+        let role = match self.replication.is_follower() {
+            true => "replica",
+            false => "master",
+        };
+        field("role", Types::BulkString(role.to_string()));
+        field("modules", Types::Array(vec![]));
+        Ok(Response::Value(Types::Map(info)))
+    }
+
+    fn mode(&self) -> &'static str {
+        match self.cluster {
+            Some(_) => "cluster",
+            None => "standalone",
+        }
+    }
+
+    /// Authenticates the connection as `username`, or as the default user when
+    /// only a password is given.
+    fn authenticate(&mut self, username: Option<&str>, password: &str) -> Result<(), CommandError> {
+        if username.is_none() && !self.acl.requires_auth() {
+            return Err(CommandError::AuthNotConfigured);
+        }
+
+        let username = username.unwrap_or(DEFAULT_USER);
+        if !self
+            .acl
+            .authenticate(username, password, &self.client_info())
+        {
+            return Err(CommandError::WrongPass);
+        }
+        self.user = username.to_string();
+        self.authenticated = true;
+        Ok(())
+    }
+
+    fn acl(&self, command: AclCommand) -> Result<Response, CommandError> {
+        let response = match command {
+            AclCommand::SetUser { username, rules } => {
+                self.acl.set_user(&username, &rules)?;
+                Response::Ok
+            }
+            AclCommand::GetUser(username) => match self.acl.get_user(&username) {
+                Some(user) => Response::Value(user),
+                None => Response::Null,
+            },
+            AclCommand::DelUser(usernames) => {
+                let deleted = self.acl.delete_users(&usernames)?;
+                Response::Value(Types::Integer(deleted as i64))
+            }
+            AclCommand::List => bulk_strings(self.acl.list()),
+            AclCommand::Users => bulk_strings(self.acl.usernames()),
+            AclCommand::WhoAmI => Response::Value(Types::BulkString(self.user.clone())),
+            AclCommand::Cat(category) => bulk_strings(Acl::categories(category.as_deref())?),
+            AclCommand::Load => {
+                self.acl.load()?;
+                Response::Ok
+            }
+            AclCommand::Save => {
+                self.acl.save()?;
+                Response::Ok
+            }
+            AclCommand::Log(count) => Response::Value(Types::Array(self.acl.log().entries(count))),
+            AclCommand::LogReset => {
+                self.acl.log().reset();
+                Response::Ok
+            }
+        };
+        Ok(response)
+    }
+
+    /// Describes the connection for the ACL log.
+    fn client_info(&self) -> String {
+        format!(
+            "id={} addr={} name={} user={} db={}",
+            self.client_id,
+            self.client_addr,
+            self.client_name.as_deref().unwrap_or_default(),
+            self.user,
+            self.db
+        )
+    }
+
+    fn select(&mut self, index: usize) -> Result<Response, CommandError> {
+        self.check_db_index(index)?;
+        if self.cluster.is_some() && index != 0 {
+            return Err(CommandError::SelectInClusterMode);
+        }
+        self.db = index;
+        Ok(Response::Ok)
+    }
+
+    async fn swap_db(&mut self, first: usize, second: usize) -> Result<Response, CommandError> {
+        self.check_db_index(first)?;
+        self.check_db_index(second)?;
+
+        self.engine.swap(first, second).await;
+        self.tracking.invalidate_all(None);
+        self.propagate(KiwiCommand::SwapDb { first, second });
+        Ok(Response::Ok)
+    }
+
+    async fn move_key(&mut self, key: Types, db: usize) -> Result<Response, CommandError> {
+        self.check_db_index(db)?;
+        if db == self.db {
+            return Err(CommandError::SameObject);
+        }
+
+        let raw_key = key_bytes(&key);
+        let moved = self.engine.move_key(self.db, db, &raw_key).await;
+        if moved {
+            self.tracking.invalidate(&raw_key, Some(self.client_id));
+            self.propagate(KiwiCommand::Move { key, db });
+        }
+        Ok(Response::Value(Types::Integer(moved as i64)))
+    }
+
+    async fn db_size(&self) -> Response {
+        let size = self.engine.size(self.db).await;
+        Response::Value(Types::Integer(size as i64))
+    }
+
+    async fn flush_db(&mut self, lazy: bool) -> Response {
+        self.engine.flush(self.db, lazy).await;
+        self.tracking.invalidate_all(None);
+        self.propagate(KiwiCommand::FlushDb { lazy });
+        Response::Ok
+    }
+
+    async fn flush_all(&mut self, lazy: bool) -> Response {
+        self.engine.flush_all(lazy).await;
+        self.tracking.invalidate_all(None);
+        self.propagate(KiwiCommand::FlushAll { lazy });
+        Response::Ok
+    }
+
+    /// Expires `key` at `timestamp_ms`; a time in the past deletes it right away.
+    /// Relative expiries are converted first so they replay the same from the AOF.
+    async fn expire_at(&mut self, key: Types, timestamp_ms: i64) -> Response {
+        let raw_key = key_bytes(&key);
+        let updated = self
+            .engine
+            .set_expiry(self.db, &raw_key, Some(timestamp_ms.max(0) as u64))
+            .await;
+        if updated {
+            self.tracking.invalidate(&raw_key, Some(self.client_id));
+            self.propagate(KiwiCommand::PExpireAt { key, timestamp_ms });
+        }
+        Response::Value(Types::Integer(updated as i64))
+    }
+
+    async fn ttl(&self, key: Types, milliseconds: bool) -> Response {
+        let ttl = match self.engine.expiry(self.db, &key_bytes(&key)).await {
+            None => -2,
+            Some(None) => -1,
+            Some(Some(expires_at)) => {
+                let remaining = expires_at.saturating_sub(now_ms());
+                match milliseconds {
+                    true => remaining as i64,
+                    false => ((remaining + 500) / 1000) as i64,
+                }
+            }
+        };
+        Response::Value(Types::Integer(ttl))
+    }
+
+    async fn persist(&mut self, key: Types) -> Response {
+        let raw_key = key_bytes(&key);
+        let persisted = match self.engine.expiry(self.db, &raw_key).await {
+            Some(Some(_)) => self.engine.set_expiry(self.db, &raw_key, None).await,
+            _ => false,
+        };
+        if persisted {
+            self.tracking.invalidate(&raw_key, Some(self.client_id));
+            self.propagate(KiwiCommand::Persist { key });
+        }
+        Response::Value(Types::Integer(persisted as i64))
+    }
+
+    fn check_db_index(&self, index: usize) -> Result<(), CommandError> {
+        if index < self.engine.databases() {
+            Ok(())
+        } else {
+            Err(CommandError::DbIndexOutOfRange)
+        }
+    }
+
+    fn client(&mut self, command: ClientCommand) -> Result<Response, CommandError> {
+        match command {
+            ClientCommand::Id => Ok(Response::Value(Types::Integer(self.client_id as i64))),
+            ClientCommand::Tracking(options) if options.enabled => {
+                if !self.resp3 {
+                    return Err(CommandError::TrackingRequiresResp3);
+                }
+                if let Some(current) = &self.tracking_options {
+                    let same_mode = current.bcast == options.bcast
+                        && current.optin == options.optin
+                        && current.optout == options.optout;
+                    if !same_mode {
+                        return Err(CommandError::TrackingModeSwitch);
+                    }
+                }
+
+                self.tracking
+                    .enable(self.client_id, self.push_sender.clone(), &options);
+                self.tracking_options = Some(options);
+                Ok(Response::Ok)
+            }
+            ClientCommand::Tracking(_) => {
+                self.tracking.disable(self.client_id);
+                self.tracking_options = None;
+                Ok(Response::Ok)
+            }
+            ClientCommand::Caching(yes) => match &self.tracking_options {
+                Some(options) if (yes && options.optin) || (!yes && options.optout) => {
+                    self.caching = Some(yes);
+                    Ok(Response::Ok)
+                }
+                _ => Err(CommandError::TrackingCachingWithoutOptInOut),
+            },
+        }
+    }
+
+    fn tracks_reads(&self, caching: Option<bool>) -> bool {
+        match &self.tracking_options {
+            Some(options) if options.bcast => false,
+            Some(options) if options.optin => caching == Some(true),
+            Some(options) if options.optout => caching != Some(false),
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+impl<E> Drop for KiwiCommandProcessor<E> {
+    fn drop(&mut self) {
+        if self.tracking_options.is_some() {
+            self.tracking.disable(self.client_id);
+        }
+        if self.follower_stream.is_some() {
+            self.replication.remove_follower(self.client_id);
+        }
+    }
+}
+
+fn expand_slot_ranges(ranges: &[(u16, u16)]) -> Vec<u16> {
+    ranges
+        .iter()
+        .flat_map(|(start, end)| *start..=*end)
+        .collect()
+}
+
+fn bulk_strings(values: Vec<impl Into<String>>) -> Response {
+    Response::Value(Types::Array(
+        values
+            .into_iter()
+            .map(|value| Types::BulkString(value.into()))
+            .collect(),
+    ))
+}
+
+/// `bytes` the way INFO shows sizes to humans, like `1.50M`.
+pub(crate) fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2}{}", UNITS[unit])
+}
+
+/// How many times smaller the compressed values got, 1 when none are.
+fn compression_ratio(memory: &MemoryStats) -> f64 {
+    match memory.compression.compressed_bytes {
+        0 => 1.0,
+        compressed => memory.compression.original_bytes as f64 / compressed as f64,
+    }
+}
+
+/// The entry RESTORE creates from a DUMP `payload`, expiring `ttl`
+/// milliseconds from now, or at `ttl` with ABSTTL, and the access history it
+/// starts with.
+pub(crate) fn restored_entry(
+    key: &Types,
+    ttl: i64,
+    payload: &[u8],
+    options: &RestoreOptions,
+) -> Result<(Entry, AccessHistory), CommandError> {
+    if !verify_dump_payload(payload) {
+        return Err(CommandError::BadDumpPayload);
+    }
+    let value = read_dump_payload(payload).map_err(|_| CommandError::BadDataFormat)?;
+    let expires_at = match ttl {
+        0 => None,
+        ttl if options.absttl => Some(ttl as u64),
+        ttl => Some(now_ms().saturating_add(ttl as u64)),
+    };
+    let entry = Entry {
+        key: key_bytes(key),
+        value_type: value.value_type(),
+        value: value.into_types().to_bytes(),
+        expires_at,
+    };
+    let history = AccessHistory {
+        idle_ms: options
+            .idle_time
+            .map(|seconds| seconds.saturating_mul(1000)),
+        frequency: options.freq,
+    };
+    Ok((entry, history))
+}
+
+pub(crate) fn key_bytes(key: &Types) -> Vec<u8> {
+    match key {
+        Types::BulkString(key) | Types::SimpleString(key) => key.as_bytes().to_vec(),
+        Types::BulkBytes(key) => key.clone(),
+        other => other.to_bytes(),
+    }
 }
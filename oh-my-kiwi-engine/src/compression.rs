@@ -0,0 +1,57 @@
+//! Optional LZ4 compression of large values. Engines compress a value on
+//! its way in, before taking their lock, and decompress it on its way out,
+//! so commands only ever see the original bytes while memory accounting,
+//! and eviction with it, counts the compressed ones.
+
+use crate::config::KiwiConfig;
+
+/// Compresses values of at least `threshold` bytes; 0 disables compression.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Compression {
+    threshold: usize,
+}
+
+impl Compression {
+    pub(crate) fn from_config(config: &KiwiConfig) -> Self {
+        Self {
+            threshold: config.compression_threshold(),
+        }
+    }
+
+    /// `bytes` compressed, when they're large enough and compression
+    /// actually makes them smaller.
+    pub(crate) fn compress(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        if self.threshold == 0 || bytes.len() < self.threshold {
+            return None;
+        }
+        let compressed = lz4_flex::block::compress(bytes);
+        (compressed.len() < bytes.len()).then_some(compressed)
+    }
+
+    /// The `len` original bytes of `compressed`.
+    pub(crate) fn decompress(compressed: &[u8], len: usize) -> Vec<u8> {
+        lz4_flex::block::decompress(compressed, len)
+            .expect("values are compressed by Compression::compress")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let compression = Compression { threshold: 64 };
+        let json = br#"{"name":"kiwi","tags":["a","b"]}"#.repeat(10);
+        let compressed = compression.compress(&json).unwrap();
+        assert!(compressed.len() < json.len() / 2);
+        assert_eq!(Compression::decompress(&compressed, json.len()), json);
+
+        // Small values and ones that don't shrink are kept as they are.
+        assert_eq!(compression.compress(b"short"), None);
+        // Every byte differs, so no run of bytes repeats.
+        let distinct: Vec<u8> = (0..250u32).map(|i| (i * 138 % 251) as u8).collect();
+        assert_eq!(compression.compress(&distinct), None);
+        assert_eq!(Compression::default().compress(&json), None);
+    }
+}
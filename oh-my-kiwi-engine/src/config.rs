@@ -0,0 +1,875 @@
+use crate::acl::log::DEFAULT_ACLLOG_MAX_LEN;
+use crate::aof::{AppendFsync, DEFAULT_APPENDFILENAME};
+use crate::cluster::{DEFAULT_CLUSTER_CONFIG_FILE, DEFAULT_CLUSTER_NODE_TIMEOUT};
+use crate::encoding::{
+    DEFAULT_HASH_MAX_LISTPACK_ENTRIES, DEFAULT_HASH_MAX_LISTPACK_VALUE,
+    DEFAULT_LIST_MAX_LISTPACK_SIZE, DEFAULT_SET_MAX_INTSET_ENTRIES,
+    DEFAULT_SET_MAX_LISTPACK_ENTRIES, DEFAULT_SET_MAX_LISTPACK_VALUE,
+    DEFAULT_ZSET_MAX_LISTPACK_ENTRIES, DEFAULT_ZSET_MAX_LISTPACK_VALUE,
+};
+use crate::eviction::{DEFAULT_MAXMEMORY_SAMPLES, MaxmemoryPolicy};
+use crate::in_memory::DEFAULT_DATABASES;
+use crate::lsm::{DEFAULT_LSM_DIR, DEFAULT_LSM_MEMTABLE_SIZE};
+use crate::replication::{DEFAULT_REPL_BACKLOG_SIZE, DEFAULT_REPLICA_OUTPUT_BUFFER_LIMIT};
+use crate::snapshot::{DEFAULT_DBFILENAME, SaveRule, SnapshotFormat};
+use crate::tiered::DEFAULT_TIERED_FILE;
+use crate::tracking::DEFAULT_TRACKING_TABLE_MAX_KEYS;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Which engine stores the data set, chosen at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    /// Every database behind a single lock.
+    InMemory,
+    /// Keys spread by hash over shards, each behind its own lock.
+    Sharded,
+    /// Keys partitioned over core threads, each owning its partition and
+    /// the connections it serves.
+    SharedNothing,
+    /// A log-structured merge tree on disk, for data sets larger than memory.
+    Lsm,
+    /// Hot keys in memory, the values of cold ones spilled to a file.
+    Tiered,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "in-memory" => Ok(EngineKind::InMemory),
+            "sharded" => Ok(EngineKind::Sharded),
+            "shared-nothing" => Ok(EngineKind::SharedNothing),
+            "lsm" => Ok(EngineKind::Lsm),
+            "tiered" => Ok(EngineKind::Tiered),
+            _ => Err(format!("invalid engine '{value}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KiwiConfig {
+    databases: usize,
+    requirepass: Option<String>,
+    aclfile: Option<PathBuf>,
+    acllog_max_len: usize,
+    dir: PathBuf,
+    dbfilename: String,
+    /// The layout snapshots are written in; picked by `dbfilename` when unset.
+    snapshot_format: Option<SnapshotFormat>,
+    save_rules: Vec<SaveRule>,
+    appendonly: bool,
+    appendfilename: String,
+    appendfsync: AppendFsync,
+    replicaof: Option<(String, u16)>,
+    masteruser: Option<String>,
+    masterauth: Option<String>,
+    replica_read_only: bool,
+    repl_backlog_size: usize,
+    replica_output_buffer_limit: usize,
+    cluster: bool,
+    cluster_port: u16,
+    cluster_config_file: String,
+    cluster_node_timeout: u64,
+    maxmemory: usize,
+    maxmemory_policy: MaxmemoryPolicy,
+    maxmemory_samples: usize,
+    engine: EngineKind,
+    engine_shards: usize,
+    engine_threads: usize,
+    lsm_dir: String,
+    lsm_memtable_size: usize,
+    lsm_wal_fsync: AppendFsync,
+    tiered_file: String,
+    compression_threshold: usize,
+    list_max_listpack_size: usize,
+    hash_max_listpack_entries: usize,
+    hash_max_listpack_value: usize,
+    set_max_intset_entries: usize,
+    set_max_listpack_entries: usize,
+    set_max_listpack_value: usize,
+    zset_max_listpack_entries: usize,
+    zset_max_listpack_value: usize,
+    tracking_table_max_keys: usize,
+}
+
+impl Default for KiwiConfig {
+    fn default() -> Self {
+        Self {
+            databases: DEFAULT_DATABASES,
+            requirepass: None,
+            aclfile: None,
+            acllog_max_len: DEFAULT_ACLLOG_MAX_LEN,
+            dir: PathBuf::from("."),
+            dbfilename: DEFAULT_DBFILENAME.to_string(),
+            snapshot_format: None,
+            save_rules: SaveRule::defaults(),
+            appendonly: false,
+            appendfilename: DEFAULT_APPENDFILENAME.to_string(),
+            appendfsync: AppendFsync::EverySec,
+            replicaof: None,
+            masteruser: None,
+            masterauth: None,
+            replica_read_only: true,
+            repl_backlog_size: DEFAULT_REPL_BACKLOG_SIZE,
+            replica_output_buffer_limit: DEFAULT_REPLICA_OUTPUT_BUFFER_LIMIT,
+            cluster: false,
+            cluster_port: 0,
+            cluster_config_file: DEFAULT_CLUSTER_CONFIG_FILE.to_string(),
+            cluster_node_timeout: DEFAULT_CLUSTER_NODE_TIMEOUT,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_samples: DEFAULT_MAXMEMORY_SAMPLES,
+            engine: EngineKind::InMemory,
+            engine_shards: 0,
+            engine_threads: 0,
+            lsm_dir: DEFAULT_LSM_DIR.to_string(),
+            lsm_memtable_size: DEFAULT_LSM_MEMTABLE_SIZE,
+            lsm_wal_fsync: AppendFsync::EverySec,
+            tiered_file: DEFAULT_TIERED_FILE.to_string(),
+            compression_threshold: 0,
+            list_max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+            hash_max_listpack_entries: DEFAULT_HASH_MAX_LISTPACK_ENTRIES,
+            hash_max_listpack_value: DEFAULT_HASH_MAX_LISTPACK_VALUE,
+            set_max_intset_entries: DEFAULT_SET_MAX_INTSET_ENTRIES,
+            set_max_listpack_entries: DEFAULT_SET_MAX_LISTPACK_ENTRIES,
+            set_max_listpack_value: DEFAULT_SET_MAX_LISTPACK_VALUE,
+            zset_max_listpack_entries: DEFAULT_ZSET_MAX_LISTPACK_ENTRIES,
+            zset_max_listpack_value: DEFAULT_ZSET_MAX_LISTPACK_VALUE,
+            tracking_table_max_keys: DEFAULT_TRACKING_TABLE_MAX_KEYS,
+        }
+    }
+}
+
+impl KiwiConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_databases(mut self, databases: usize) -> Self {
+        self.databases = databases;
+        self
+    }
+
+    pub fn with_requirepass(mut self, password: impl Into<String>) -> Self {
+        self.requirepass = Some(password.into());
+        self
+    }
+
+    pub fn with_aclfile(mut self, path: impl Into<PathBuf>) -> Self {
+        self.aclfile = Some(path.into());
+        self
+    }
+
+    pub fn with_acllog_max_len(mut self, max_len: usize) -> Self {
+        self.acllog_max_len = max_len;
+        self
+    }
+
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    pub fn with_dbfilename(mut self, filename: impl Into<String>) -> Self {
+        self.dbfilename = filename.into();
+        self
+    }
+
+    pub fn with_snapshot_format(mut self, format: SnapshotFormat) -> Self {
+        self.snapshot_format = Some(format);
+        self
+    }
+
+    pub fn with_save_rules(mut self, rules: Vec<SaveRule>) -> Self {
+        self.save_rules = rules;
+        self
+    }
+
+    pub fn with_appendonly(mut self, enabled: bool) -> Self {
+        self.appendonly = enabled;
+        self
+    }
+
+    pub fn with_appendfilename(mut self, filename: impl Into<String>) -> Self {
+        self.appendfilename = filename.into();
+        self
+    }
+
+    pub fn with_appendfsync(mut self, policy: AppendFsync) -> Self {
+        self.appendfsync = policy;
+        self
+    }
+
+    pub fn with_replicaof(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.replicaof = Some((host.into(), port));
+        self
+    }
+
+    pub fn with_masteruser(mut self, user: impl Into<String>) -> Self {
+        self.masteruser = Some(user.into());
+        self
+    }
+
+    pub fn with_masterauth(mut self, password: impl Into<String>) -> Self {
+        self.masterauth = Some(password.into());
+        self
+    }
+
+    pub fn with_replica_read_only(mut self, read_only: bool) -> Self {
+        self.replica_read_only = read_only;
+        self
+    }
+
+    pub fn with_repl_backlog_size(mut self, size: usize) -> Self {
+        self.repl_backlog_size = size;
+        self
+    }
+
+    pub fn with_replica_output_buffer_limit(mut self, limit: usize) -> Self {
+        self.replica_output_buffer_limit = limit;
+        self
+    }
+
+    pub fn with_cluster(mut self, enabled: bool) -> Self {
+        self.cluster = enabled;
+        self
+    }
+
+    pub fn with_cluster_port(mut self, port: u16) -> Self {
+        self.cluster_port = port;
+        self
+    }
+
+    pub fn with_cluster_config_file(mut self, filename: impl Into<String>) -> Self {
+        self.cluster_config_file = filename.into();
+        self
+    }
+
+    pub fn with_cluster_node_timeout(mut self, milliseconds: u64) -> Self {
+        self.cluster_node_timeout = milliseconds;
+        self
+    }
+
+    pub fn with_maxmemory(mut self, bytes: usize) -> Self {
+        self.maxmemory = bytes;
+        self
+    }
+
+    pub fn with_maxmemory_policy(mut self, policy: MaxmemoryPolicy) -> Self {
+        self.maxmemory_policy = policy;
+        self
+    }
+
+    pub fn with_maxmemory_samples(mut self, samples: usize) -> Self {
+        self.maxmemory_samples = samples;
+        self
+    }
+
+    pub fn with_engine(mut self, engine: EngineKind) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    pub fn with_engine_shards(mut self, shards: usize) -> Self {
+        self.engine_shards = shards;
+        self
+    }
+
+    pub fn with_engine_threads(mut self, threads: usize) -> Self {
+        self.engine_threads = threads;
+        self
+    }
+
+    pub fn with_lsm_dir(mut self, dir: impl Into<String>) -> Self {
+        self.lsm_dir = dir.into();
+        self
+    }
+
+    pub fn with_lsm_memtable_size(mut self, bytes: usize) -> Self {
+        self.lsm_memtable_size = bytes;
+        self
+    }
+
+    pub fn with_lsm_wal_fsync(mut self, policy: AppendFsync) -> Self {
+        self.lsm_wal_fsync = policy;
+        self
+    }
+
+    pub fn with_tiered_file(mut self, filename: impl Into<String>) -> Self {
+        self.tiered_file = filename.into();
+        self
+    }
+
+    pub fn with_compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    pub fn with_list_max_listpack_size(mut self, entries: usize) -> Self {
+        self.list_max_listpack_size = entries;
+        self
+    }
+
+    pub fn with_hash_max_listpack_entries(mut self, entries: usize) -> Self {
+        self.hash_max_listpack_entries = entries;
+        self
+    }
+
+    pub fn with_hash_max_listpack_value(mut self, bytes: usize) -> Self {
+        self.hash_max_listpack_value = bytes;
+        self
+    }
+
+    pub fn with_set_max_intset_entries(mut self, entries: usize) -> Self {
+        self.set_max_intset_entries = entries;
+        self
+    }
+
+    pub fn with_set_max_listpack_entries(mut self, entries: usize) -> Self {
+        self.set_max_listpack_entries = entries;
+        self
+    }
+
+    pub fn with_set_max_listpack_value(mut self, bytes: usize) -> Self {
+        self.set_max_listpack_value = bytes;
+        self
+    }
+
+    pub fn with_zset_max_listpack_entries(mut self, entries: usize) -> Self {
+        self.zset_max_listpack_entries = entries;
+        self
+    }
+
+    pub fn with_zset_max_listpack_value(mut self, bytes: usize) -> Self {
+        self.zset_max_listpack_value = bytes;
+        self
+    }
+
+    pub fn with_tracking_table_max_keys(mut self, keys: usize) -> Self {
+        self.tracking_table_max_keys = keys;
+        self
+    }
+
+    pub fn set_databases(&mut self, databases: usize) {
+        self.databases = databases;
+    }
+
+    pub fn set_requirepass(&mut self, password: Option<String>) {
+        self.requirepass = password;
+    }
+
+    pub fn set_aclfile(&mut self, path: Option<PathBuf>) {
+        self.aclfile = path;
+    }
+
+    pub fn set_acllog_max_len(&mut self, max_len: usize) {
+        self.acllog_max_len = max_len;
+    }
+
+    pub fn set_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.dir = dir.into();
+    }
+
+    pub fn set_dbfilename(&mut self, filename: impl Into<String>) {
+        self.dbfilename = filename.into();
+    }
+
+    pub fn set_snapshot_format(&mut self, format: SnapshotFormat) {
+        self.snapshot_format = Some(format);
+    }
+
+    pub fn set_save_rules(&mut self, rules: Vec<SaveRule>) {
+        self.save_rules = rules;
+    }
+
+    pub fn set_appendonly(&mut self, enabled: bool) {
+        self.appendonly = enabled;
+    }
+
+    pub fn set_appendfilename(&mut self, filename: impl Into<String>) {
+        self.appendfilename = filename.into();
+    }
+
+    pub fn set_appendfsync(&mut self, policy: AppendFsync) {
+        self.appendfsync = policy;
+    }
+
+    pub fn set_replicaof(&mut self, leader: Option<(String, u16)>) {
+        self.replicaof = leader;
+    }
+
+    pub fn set_masteruser(&mut self, user: Option<String>) {
+        self.masteruser = user;
+    }
+
+    pub fn set_masterauth(&mut self, password: Option<String>) {
+        self.masterauth = password;
+    }
+
+    pub fn set_replica_read_only(&mut self, read_only: bool) {
+        self.replica_read_only = read_only;
+    }
+
+    pub fn set_repl_backlog_size(&mut self, size: usize) {
+        self.repl_backlog_size = size;
+    }
+
+    pub fn set_replica_output_buffer_limit(&mut self, limit: usize) {
+        self.replica_output_buffer_limit = limit;
+    }
+
+    pub fn set_cluster(&mut self, enabled: bool) {
+        self.cluster = enabled;
+    }
+
+    pub fn set_cluster_port(&mut self, port: u16) {
+        self.cluster_port = port;
+    }
+
+    pub fn set_cluster_config_file(&mut self, filename: impl Into<String>) {
+        self.cluster_config_file = filename.into();
+    }
+
+    pub fn set_cluster_node_timeout(&mut self, milliseconds: u64) {
+        self.cluster_node_timeout = milliseconds;
+    }
+
+    pub fn set_maxmemory(&mut self, bytes: usize) {
+        self.maxmemory = bytes;
+    }
+
+    pub fn set_maxmemory_policy(&mut self, policy: MaxmemoryPolicy) {
+        self.maxmemory_policy = policy;
+    }
+
+    pub fn set_maxmemory_samples(&mut self, samples: usize) {
+        self.maxmemory_samples = samples;
+    }
+
+    pub fn set_engine(&mut self, engine: EngineKind) {
+        self.engine = engine;
+    }
+
+    pub fn set_engine_shards(&mut self, shards: usize) {
+        self.engine_shards = shards;
+    }
+
+    pub fn set_engine_threads(&mut self, threads: usize) {
+        self.engine_threads = threads;
+    }
+
+    pub fn set_lsm_dir(&mut self, dir: impl Into<String>) {
+        self.lsm_dir = dir.into();
+    }
+
+    pub fn set_lsm_memtable_size(&mut self, bytes: usize) {
+        self.lsm_memtable_size = bytes;
+    }
+
+    pub fn set_lsm_wal_fsync(&mut self, policy: AppendFsync) {
+        self.lsm_wal_fsync = policy;
+    }
+
+    pub fn set_tiered_file(&mut self, filename: impl Into<String>) {
+        self.tiered_file = filename.into();
+    }
+
+    pub fn set_compression_threshold(&mut self, bytes: usize) {
+        self.compression_threshold = bytes;
+    }
+
+    pub fn set_list_max_listpack_size(&mut self, entries: usize) {
+        self.list_max_listpack_size = entries;
+    }
+
+    pub fn set_hash_max_listpack_entries(&mut self, entries: usize) {
+        self.hash_max_listpack_entries = entries;
+    }
+
+    pub fn set_hash_max_listpack_value(&mut self, bytes: usize) {
+        self.hash_max_listpack_value = bytes;
+    }
+
+    pub fn set_set_max_intset_entries(&mut self, entries: usize) {
+        self.set_max_intset_entries = entries;
+    }
+
+    pub fn set_set_max_listpack_entries(&mut self, entries: usize) {
+        self.set_max_listpack_entries = entries;
+    }
+
+    pub fn set_set_max_listpack_value(&mut self, bytes: usize) {
+        self.set_max_listpack_value = bytes;
+    }
+
+    pub fn set_zset_max_listpack_entries(&mut self, entries: usize) {
+        self.zset_max_listpack_entries = entries;
+    }
+
+    pub fn set_zset_max_listpack_value(&mut self, bytes: usize) {
+        self.zset_max_listpack_value = bytes;
+    }
+
+    pub fn set_tracking_table_max_keys(&mut self, keys: usize) {
+        self.tracking_table_max_keys = keys;
+    }
+
+    pub fn databases(&self) -> usize {
+        self.databases
+    }
+
+    pub fn requirepass(&self) -> Option<&str> {
+        self.requirepass.as_deref()
+    }
+
+    pub fn aclfile(&self) -> Option<&Path> {
+        self.aclfile.as_deref()
+    }
+
+    pub fn acllog_max_len(&self) -> usize {
+        self.acllog_max_len
+    }
+
+    /// Where snapshots are saved: `dbfilename` inside `dir`.
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    /// The layout snapshots are written in: the configured one, or by the
+    /// extension of `dbfilename`.
+    pub fn snapshot_format(&self) -> SnapshotFormat {
+        self.snapshot_format
+            .unwrap_or_else(|| SnapshotFormat::for_path(Path::new(&self.dbfilename)))
+    }
+
+    pub fn save_rules(&self) -> &[SaveRule] {
+        &self.save_rules
+    }
+
+    pub fn appendonly(&self) -> bool {
+        self.appendonly
+    }
+
+    /// Where the append-only file is kept: `appendfilename` inside `dir`.
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+
+    pub fn appendfsync(&self) -> AppendFsync {
+        self.appendfsync
+    }
+
+    /// The leader to follow on startup, as `(host, port)`.
+    pub fn replicaof(&self) -> Option<(&str, u16)> {
+        self.replicaof
+            .as_ref()
+            .map(|(host, port)| (host.as_str(), *port))
+    }
+
+    /// The user a follower authenticates as with its leader; the default
+    /// user when unset.
+    pub fn masteruser(&self) -> Option<&str> {
+        self.masteruser.as_deref()
+    }
+
+    /// The password a follower authenticates with its leader; no `AUTH` is
+    /// sent when unset.
+    pub fn masterauth(&self) -> Option<&str> {
+        self.masterauth.as_deref()
+    }
+
+    pub fn replica_read_only(&self) -> bool {
+        self.replica_read_only
+    }
+
+    pub fn repl_backlog_size(&self) -> usize {
+        self.repl_backlog_size
+    }
+
+    /// Bytes of the replication stream a follower may have waiting to be
+    /// sent before it is dropped, 0 for no limit.
+    pub fn replica_output_buffer_limit(&self) -> usize {
+        self.replica_output_buffer_limit
+    }
+
+    pub fn cluster(&self) -> bool {
+        self.cluster
+    }
+
+    /// Port of the cluster bus; 0 means the client port plus 10000.
+    pub fn cluster_port(&self) -> u16 {
+        self.cluster_port
+    }
+
+    /// Where the cluster configuration is kept: `cluster_config_file` inside `dir`.
+    pub fn cluster_config_path(&self) -> PathBuf {
+        self.dir.join(&self.cluster_config_file)
+    }
+
+    /// Milliseconds a node may go unanswered before it is suspected to be down.
+    pub fn cluster_node_timeout(&self) -> u64 {
+        self.cluster_node_timeout
+    }
+
+    /// Bytes the data set may take before keys get evicted; 0 means no limit.
+    pub fn maxmemory(&self) -> usize {
+        self.maxmemory
+    }
+
+    pub fn maxmemory_policy(&self) -> MaxmemoryPolicy {
+        self.maxmemory_policy
+    }
+
+    /// Keys sampled per database to pick each key to evict.
+    pub fn maxmemory_samples(&self) -> usize {
+        self.maxmemory_samples
+    }
+
+    pub fn engine(&self) -> EngineKind {
+        self.engine
+    }
+
+    /// Shards of the sharded engine; 0 picks a number from the cores.
+    pub fn engine_shards(&self) -> usize {
+        self.engine_shards
+    }
+
+    /// Core threads of the shared-nothing engine; 0 uses every core.
+    pub fn engine_threads(&self) -> usize {
+        self.engine_threads
+    }
+
+    /// Directory of the LSM engine's segment files, under `dir`.
+    pub fn lsm_path(&self) -> PathBuf {
+        self.dir.join(&self.lsm_dir)
+    }
+
+    /// Bytes of writes the LSM engine buffers in memory before writing them
+    /// out to a segment file.
+    pub fn lsm_memtable_size(&self) -> usize {
+        self.lsm_memtable_size
+    }
+
+    /// When the LSM engine syncs its write-ahead log to disk.
+    pub fn lsm_wal_fsync(&self) -> AppendFsync {
+        self.lsm_wal_fsync
+    }
+
+    /// File the tiered engine spills cold values to, under `dir`.
+    pub fn tiered_path(&self) -> PathBuf {
+        self.dir.join(&self.tiered_file)
+    }
+
+    /// Values of at least this many bytes are kept compressed; 0 disables
+    /// compression.
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /// Lists of at most this many entries are packed into a listpack.
+    pub fn list_max_listpack_size(&self) -> usize {
+        self.list_max_listpack_size
+    }
+
+    /// Hashes of at most this many fields are packed into a listpack, when
+    /// none of their fields and values is longer than `hash-max-listpack-value`.
+    pub fn hash_max_listpack_entries(&self) -> usize {
+        self.hash_max_listpack_entries
+    }
+
+    pub fn hash_max_listpack_value(&self) -> usize {
+        self.hash_max_listpack_value
+    }
+
+    /// Sets of at most this many integers are packed into an intset.
+    pub fn set_max_intset_entries(&self) -> usize {
+        self.set_max_intset_entries
+    }
+
+    /// Other sets of at most this many members are packed into a listpack,
+    /// when none of their members is longer than `set-max-listpack-value`.
+    pub fn set_max_listpack_entries(&self) -> usize {
+        self.set_max_listpack_entries
+    }
+
+    pub fn set_max_listpack_value(&self) -> usize {
+        self.set_max_listpack_value
+    }
+
+    /// Sorted sets of at most this many members are packed into a listpack,
+    /// when none of their members is longer than `zset-max-listpack-value`.
+    pub fn zset_max_listpack_entries(&self) -> usize {
+        self.zset_max_listpack_entries
+    }
+
+    pub fn zset_max_listpack_value(&self) -> usize {
+        self.zset_max_listpack_value
+    }
+
+    /// Keys read by tracking clients the server remembers at most, 0 for no
+    /// limit.
+    pub fn tracking_table_max_keys(&self) -> usize {
+        self.tracking_table_max_keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let config = KiwiConfig::default();
+        assert_eq!(config.databases, 16);
+        assert_eq!(config.requirepass, None);
+        assert_eq!(config.aclfile, None);
+        assert_eq!(config.acllog_max_len, 128);
+        assert_eq!(config.snapshot_path(), Path::new("./dump.kiwi"));
+        assert_eq!(config.snapshot_format(), SnapshotFormat::Kiwi);
+        assert_eq!(config.save_rules.len(), 3);
+        assert!(!config.appendonly);
+        assert_eq!(config.aof_path(), Path::new("./appendonly.aof"));
+        assert_eq!(config.appendfsync, AppendFsync::EverySec);
+        assert_eq!(config.replicaof, None);
+        assert_eq!(config.masteruser, None);
+        assert_eq!(config.masterauth, None);
+        assert!(config.replica_read_only);
+        assert_eq!(config.repl_backlog_size, 1024 * 1024);
+        assert_eq!(config.replica_output_buffer_limit, 256 * 1024 * 1024);
+        assert!(!config.cluster);
+        assert_eq!(config.cluster_port, 0);
+        assert_eq!(config.cluster_config_path(), Path::new("./nodes.conf"));
+        assert_eq!(config.cluster_node_timeout, 15000);
+        assert_eq!(config.maxmemory, 0);
+        assert_eq!(config.maxmemory_policy, MaxmemoryPolicy::NoEviction);
+        assert_eq!(config.maxmemory_samples, 5);
+        assert_eq!(config.engine, EngineKind::InMemory);
+        assert_eq!(config.engine_shards, 0);
+        assert_eq!(config.engine_threads, 0);
+        assert_eq!(config.lsm_path(), Path::new("./lsm"));
+        assert_eq!(config.lsm_memtable_size, 4 * 1024 * 1024);
+        assert_eq!(config.lsm_wal_fsync, AppendFsync::EverySec);
+        assert_eq!(config.tiered_path(), Path::new("./cold.kiwi"));
+        assert_eq!(config.compression_threshold, 0);
+        assert_eq!(config.list_max_listpack_size, 128);
+        assert_eq!(config.hash_max_listpack_entries, 128);
+        assert_eq!(config.hash_max_listpack_value, 64);
+        assert_eq!(config.set_max_intset_entries, 512);
+        assert_eq!(config.set_max_listpack_entries, 128);
+        assert_eq!(config.set_max_listpack_value, 64);
+        assert_eq!(config.zset_max_listpack_entries, 128);
+        assert_eq!(config.zset_max_listpack_value, 64);
+        assert_eq!(config.tracking_table_max_keys, 1_000_000);
+    }
+
+    #[test]
+    fn test_chained_builders() {
+        let config = KiwiConfig::new()
+            .with_databases(4)
+            .with_requirepass("secret")
+            .with_aclfile("users.acl")
+            .with_acllog_max_len(16)
+            .with_dir("/var/lib/kiwi")
+            .with_dbfilename("kiwi.snap")
+            .with_snapshot_format(SnapshotFormat::Rdb)
+            .with_save_rules(vec![])
+            .with_appendonly(true)
+            .with_appendfsync(AppendFsync::Always)
+            .with_replicaof("10.0.0.1", 6380)
+            .with_masteruser("replica")
+            .with_masterauth("secret")
+            .with_replica_read_only(false)
+            .with_repl_backlog_size(4096)
+            .with_replica_output_buffer_limit(8192)
+            .with_cluster(true)
+            .with_cluster_port(17000)
+            .with_cluster_config_file("nodes-7000.conf")
+            .with_cluster_node_timeout(5000)
+            .with_maxmemory(1 << 30)
+            .with_maxmemory_policy(MaxmemoryPolicy::AllKeysLfu)
+            .with_maxmemory_samples(10)
+            .with_engine(EngineKind::Sharded)
+            .with_engine_shards(64)
+            .with_engine_threads(8)
+            .with_lsm_dir("segments")
+            .with_lsm_memtable_size(1024)
+            .with_lsm_wal_fsync(AppendFsync::Always)
+            .with_tiered_file("spill.kiwi")
+            .with_compression_threshold(512)
+            .with_list_max_listpack_size(1)
+            .with_hash_max_listpack_entries(2)
+            .with_hash_max_listpack_value(3)
+            .with_set_max_intset_entries(4)
+            .with_set_max_listpack_entries(5)
+            .with_set_max_listpack_value(6)
+            .with_zset_max_listpack_entries(7)
+            .with_zset_max_listpack_value(8)
+            .with_tracking_table_max_keys(100);
+        assert_eq!(config.databases(), 4);
+        assert_eq!(config.requirepass(), Some("secret"));
+        assert_eq!(config.aclfile(), Some(Path::new("users.acl")));
+        assert_eq!(config.acllog_max_len(), 16);
+        assert_eq!(config.snapshot_path(), Path::new("/var/lib/kiwi/kiwi.snap"));
+        assert_eq!(config.snapshot_format(), SnapshotFormat::Rdb);
+        assert!(config.save_rules().is_empty());
+        assert!(config.appendonly());
+        assert_eq!(config.appendfsync(), AppendFsync::Always);
+        assert_eq!(config.replicaof(), Some(("10.0.0.1", 6380)));
+        assert_eq!(config.masteruser(), Some("replica"));
+        assert_eq!(config.masterauth(), Some("secret"));
+        assert!(!config.replica_read_only());
+        assert_eq!(config.repl_backlog_size(), 4096);
+        assert_eq!(config.replica_output_buffer_limit(), 8192);
+        assert!(config.cluster());
+        assert_eq!(config.cluster_port(), 17000);
+        assert_eq!(
+            config.cluster_config_path(),
+            Path::new("/var/lib/kiwi/nodes-7000.conf")
+        );
+        assert_eq!(config.cluster_node_timeout(), 5000);
+        assert_eq!(config.maxmemory(), 1 << 30);
+        assert_eq!(config.maxmemory_policy(), MaxmemoryPolicy::AllKeysLfu);
+        assert_eq!(config.maxmemory_samples(), 10);
+        assert_eq!(config.engine(), EngineKind::Sharded);
+        assert_eq!(config.engine_shards(), 64);
+        assert_eq!(config.engine_threads(), 8);
+        assert_eq!(config.lsm_path(), Path::new("/var/lib/kiwi/segments"));
+        assert_eq!(config.lsm_memtable_size(), 1024);
+        assert_eq!(config.lsm_wal_fsync(), AppendFsync::Always);
+        assert_eq!(config.tiered_path(), Path::new("/var/lib/kiwi/spill.kiwi"));
+        assert_eq!(config.compression_threshold(), 512);
+        assert_eq!(config.list_max_listpack_size(), 1);
+        assert_eq!(config.hash_max_listpack_entries(), 2);
+        assert_eq!(config.hash_max_listpack_value(), 3);
+        assert_eq!(config.set_max_intset_entries(), 4);
+        assert_eq!(config.set_max_listpack_entries(), 5);
+        assert_eq!(config.set_max_listpack_value(), 6);
+        assert_eq!(config.zset_max_listpack_entries(), 7);
+        assert_eq!(config.zset_max_listpack_value(), 8);
+        assert_eq!(config.tracking_table_max_keys(), 100);
+    }
+
+    #[test]
+    fn test_setters() {
+        let mut config = KiwiConfig::new().with_requirepass("secret");
+        config.set_databases(2);
+        config.set_requirepass(None);
+        assert_eq!(config.databases(), 2);
+        assert_eq!(config.requirepass(), None);
+    }
+
+    #[test]
+    fn test_parse_engine() {
+        assert_eq!("sharded".parse::<EngineKind>(), Ok(EngineKind::Sharded));
+        assert_eq!("In-Memory".parse::<EngineKind>(), Ok(EngineKind::InMemory));
+        assert_eq!(
+            "shared-nothing".parse::<EngineKind>(),
+            Ok(EngineKind::SharedNothing)
+        );
+        assert_eq!("lsm".parse::<EngineKind>(), Ok(EngineKind::Lsm));
+        assert_eq!("tiered".parse::<EngineKind>(), Ok(EngineKind::Tiered));
+        assert!("btree".parse::<EngineKind>().is_err());
+    }
+}
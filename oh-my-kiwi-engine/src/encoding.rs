@@ -0,0 +1,515 @@
+//! How values are kept in memory. Values arrive as RESP; small flat lists,
+//! sets, hashes and sorted sets are packed listpack-style, their elements
+//! one after the other with a one byte tag and a varint length, and small
+//! sets of integers as fixed-width numbers. Larger values stay as they came,
+//! compressed when they're big enough. Each value unpacks to the exact RESP
+//! it was stored from.
+
+use crate::compression::Compression;
+use crate::config::KiwiConfig;
+use oh_my_kiwi_domain::{Entry, ValueType};
+
+pub const DEFAULT_LIST_MAX_LISTPACK_SIZE: usize = 128;
+pub const DEFAULT_HASH_MAX_LISTPACK_ENTRIES: usize = 128;
+pub const DEFAULT_HASH_MAX_LISTPACK_VALUE: usize = 64;
+pub const DEFAULT_SET_MAX_INTSET_ENTRIES: usize = 512;
+pub const DEFAULT_SET_MAX_LISTPACK_ENTRIES: usize = 128;
+pub const DEFAULT_SET_MAX_LISTPACK_VALUE: usize = 64;
+pub const DEFAULT_ZSET_MAX_LISTPACK_ENTRIES: usize = 128;
+pub const DEFAULT_ZSET_MAX_LISTPACK_VALUE: usize = 64;
+
+/// Longest string Redis embeds in its object header, reported as `embstr`.
+const EMBSTR_MAX_LEN: usize = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    /// The RESP bytes as they came.
+    Plain,
+    /// The RESP bytes compressed, with their length.
+    Compressed(usize),
+    Listpack,
+    Intset,
+}
+
+/// A value as stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Value {
+    bytes: Vec<u8>,
+    encoding: Encoding,
+    value_type: ValueType,
+}
+
+impl Value {
+    /// Puts back a value taken apart with `stored`, `encoding` and
+    /// `value_type`.
+    pub(crate) fn from_stored(bytes: Vec<u8>, encoding: Encoding, value_type: ValueType) -> Self {
+        Self {
+            bytes,
+            encoding,
+            value_type,
+        }
+    }
+
+    /// The bytes kept in memory.
+    pub(crate) fn stored(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub(crate) fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// The Redis type the value was stored as.
+    pub(crate) fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    /// The RESP bytes the value was stored from.
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        match self.encoding {
+            Encoding::Plain => self.bytes,
+            Encoding::Compressed(len) => Compression::decompress(&self.bytes, len),
+            Encoding::Listpack => unpack_listpack(&self.bytes),
+            Encoding::Intset => unpack_intset(&self.bytes),
+        }
+    }
+
+    /// The encoding OBJECT ENCODING reports, named like Redis's.
+    pub(crate) fn name(&self) -> &'static str {
+        match self.encoding {
+            Encoding::Listpack => "listpack",
+            Encoding::Intset => "intset",
+            Encoding::Plain => plain_name(&self.bytes),
+            Encoding::Compressed(_) => plain_name(&self.clone().into_bytes()),
+        }
+    }
+}
+
+/// The encoding OBJECT ENCODING reports for a value kept as plain RESP.
+/// Collections too large to pack stay one buffer rather than becoming the
+/// structure Redis would convert them to, so they are reported as `raw`.
+pub(crate) fn plain_name(resp: &[u8]) -> &'static str {
+    match resp.first() {
+        Some(b':') => "int",
+        Some(b'$' | b'+') => match parse_flat_element(resp, 0) {
+            Some((element, _)) if is_integer(element.payload) => "int",
+            Some((element, _)) if element.payload.len() <= EMBSTR_MAX_LEN => "embstr",
+            _ => "raw",
+        },
+        _ => "raw",
+    }
+}
+
+/// Sizes up to which collections are packed.
+#[derive(Debug, Clone, Copy)]
+struct Packing {
+    list_entries: usize,
+    hash_entries: usize,
+    hash_value: usize,
+    intset_entries: usize,
+    set_entries: usize,
+    set_value: usize,
+    zset_entries: usize,
+    zset_value: usize,
+}
+
+impl Packing {
+    fn from_config(config: &KiwiConfig) -> Self {
+        Self {
+            list_entries: config.list_max_listpack_size(),
+            hash_entries: config.hash_max_listpack_entries(),
+            hash_value: config.hash_max_listpack_value(),
+            intset_entries: config.set_max_intset_entries(),
+            set_entries: config.set_max_listpack_entries(),
+            set_value: config.set_max_listpack_value(),
+            zset_entries: config.zset_max_listpack_entries(),
+            zset_value: config.zset_max_listpack_value(),
+        }
+    }
+
+    /// Packs `resp` by the limits of `value_type`, if it's a small enough
+    /// collection of that type.
+    fn pack(&self, resp: &[u8], value_type: ValueType) -> Option<Value> {
+        let flat = parse_flat(resp)?;
+        let fits = |entries: usize, value: usize| {
+            flat.len <= entries
+                && flat
+                    .elements
+                    .iter()
+                    .all(|element| element.payload.len() <= value)
+        };
+        let (bytes, encoding) = match (flat.prefix, value_type) {
+            (b'*', ValueType::List) if flat.len <= self.list_entries => {
+                (pack_listpack(&flat), Encoding::Listpack)
+            }
+            (b'~', ValueType::Set) if flat.len <= self.intset_entries && flat.is_intset() => {
+                (pack_intset(&flat), Encoding::Intset)
+            }
+            (b'~', ValueType::Set) if fits(self.set_entries, self.set_value) => {
+                (pack_listpack(&flat), Encoding::Listpack)
+            }
+            (b'%', ValueType::SortedSet) if fits(self.zset_entries, self.zset_value) => {
+                (pack_listpack(&flat), Encoding::Listpack)
+            }
+            (b'%', ValueType::Hash) if fits(self.hash_entries, self.hash_value) => {
+                (pack_listpack(&flat), Encoding::Listpack)
+            }
+            _ => return None,
+        };
+        Some(Value::from_stored(bytes, encoding, value_type))
+    }
+}
+
+/// Turns values into what's stored for them: small collections packed, large
+/// values compressed, everything else as it came.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Codec {
+    packing: Packing,
+    compression: Compression,
+}
+
+impl Codec {
+    pub(crate) fn from_config(config: &KiwiConfig) -> Self {
+        Self {
+            packing: Packing::from_config(config),
+            compression: Compression::from_config(config),
+        }
+    }
+
+    /// Encodes a value written as `bytes`, a map being a hash.
+    pub(crate) fn encode(&self, bytes: Vec<u8>) -> Value {
+        let value_type = ValueType::of(&bytes);
+        self.encode_as(bytes, value_type)
+    }
+
+    fn encode_as(&self, bytes: Vec<u8>, value_type: ValueType) -> Value {
+        if let Some(packed) = self.packing.pack(&bytes, value_type) {
+            return packed;
+        }
+        let (bytes, encoding) = match self.compression.compress(&bytes) {
+            Some(compressed) => (compressed, Encoding::Compressed(bytes.len())),
+            None => (bytes, Encoding::Plain),
+        };
+        Value::from_stored(bytes, encoding, value_type)
+    }
+
+    /// The key, stored value and expiry of `entry`.
+    pub(crate) fn encode_entry(&self, entry: Entry) -> (Vec<u8>, Value, Option<u64>) {
+        let value = self.encode_as(entry.value, entry.value_type);
+        (entry.key, value, entry.expires_at)
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::from_config(&KiwiConfig::default())
+    }
+}
+
+/// A scalar element of a collection: its RESP type prefix and payload.
+struct Element<'a> {
+    prefix: u8,
+    payload: &'a [u8],
+}
+
+/// An array, set or map holding only scalars.
+struct Flat<'a> {
+    prefix: u8,
+    /// Entries, counting a map's pairs once.
+    len: usize,
+    elements: Vec<Element<'a>>,
+}
+
+impl Flat<'_> {
+    /// Whether every element is an integer of the same RESP type.
+    fn is_intset(&self) -> bool {
+        let prefix = self.elements.first().map_or(b':', |element| element.prefix);
+        matches!(prefix, b':' | b'$')
+            && self
+                .elements
+                .iter()
+                .all(|element| element.prefix == prefix && is_integer(element.payload))
+    }
+}
+
+fn parse_flat(resp: &[u8]) -> Option<Flat<'_>> {
+    let prefix = *resp.first()?;
+    if !matches!(prefix, b'*' | b'~' | b'%') {
+        return None;
+    }
+    let (line, mut pos) = line(resp, 1)?;
+    let len: usize = std::str::from_utf8(line).ok()?.parse().ok()?;
+    let count = if prefix == b'%' { len * 2 } else { len };
+    let mut elements = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (element, next) = parse_flat_element(resp, pos)?;
+        elements.push(element);
+        pos = next;
+    }
+    (pos == resp.len()).then_some(Flat {
+        prefix,
+        len,
+        elements,
+    })
+}
+
+/// The scalar at `pos`, with the position after it.
+fn parse_flat_element(resp: &[u8], pos: usize) -> Option<(Element<'_>, usize)> {
+    let prefix = *resp.get(pos)?;
+    match prefix {
+        b'$' => {
+            let (line, start) = line(resp, pos + 1)?;
+            let len: usize = std::str::from_utf8(line).ok()?.parse().ok()?;
+            let payload = resp.get(start..start + len)?;
+            (resp.get(start + len..start + len + 2)? == b"\r\n")
+                .then_some((Element { prefix, payload }, start + len + 2))
+        }
+        b'+' | b':' | b',' => {
+            let (payload, next) = line(resp, pos + 1)?;
+            Some((Element { prefix, payload }, next))
+        }
+        _ => None,
+    }
+}
+
+/// The bytes from `pos` up to the next CRLF, with the position after it.
+fn line(resp: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let end = pos + resp.get(pos..)?.windows(2).position(|w| w == b"\r\n")?;
+    Some((&resp[pos..end], end + 2))
+}
+
+/// Whether `payload` is an integer written the way Redis would, so it can be
+/// packed as a number and written back the same.
+fn is_integer(payload: &[u8]) -> bool {
+    std::str::from_utf8(payload)
+        .ok()
+        .and_then(|text| text.parse::<i64>().ok())
+        .is_some_and(|number| number.to_string().as_bytes() == payload)
+}
+
+fn pack_listpack(flat: &Flat) -> Vec<u8> {
+    let payloads: usize = flat.elements.iter().map(|e| e.payload.len() + 2).sum();
+    let mut packed = Vec::with_capacity(payloads + 5);
+    packed.push(flat.prefix);
+    write_varint(&mut packed, flat.len as u64);
+    for element in &flat.elements {
+        packed.push(element.prefix);
+        write_varint(&mut packed, element.payload.len() as u64);
+        packed.extend_from_slice(element.payload);
+    }
+    packed
+}
+
+fn unpack_listpack(packed: &[u8]) -> Vec<u8> {
+    let mut resp = Vec::with_capacity(packed.len() * 2);
+    let prefix = packed[0];
+    let (len, mut pos) = read_varint(packed, 1);
+    resp.push(prefix);
+    resp.extend_from_slice(format!("{len}\r\n").as_bytes());
+    let count = if prefix == b'%' { len * 2 } else { len };
+    for _ in 0..count {
+        let element = packed[pos];
+        let (len, start) = read_varint(packed, pos + 1);
+        let payload = &packed[start..start + len as usize];
+        resp.push(element);
+        if element == b'$' {
+            resp.extend_from_slice(format!("{len}\r\n").as_bytes());
+        }
+        resp.extend_from_slice(payload);
+        resp.extend_from_slice(b"\r\n");
+        pos = start + len as usize;
+    }
+    resp
+}
+
+/// Packs the integers of `flat` with the smallest width that holds them all.
+fn pack_intset(flat: &Flat) -> Vec<u8> {
+    let numbers: Vec<i64> = flat
+        .elements
+        .iter()
+        .map(|element| {
+            std::str::from_utf8(element.payload)
+                .unwrap()
+                .parse()
+                .unwrap()
+        })
+        .collect();
+    let width = match numbers.iter().map(|number| number.unsigned_abs()).max() {
+        Some(max) if max > i32::MAX as u64 => 8,
+        Some(max) if max > i16::MAX as u64 => 4,
+        _ => 2,
+    };
+    let prefix = flat.elements.first().map_or(b':', |element| element.prefix);
+    let mut packed = vec![prefix, width as u8];
+    write_varint(&mut packed, numbers.len() as u64);
+    for number in numbers {
+        packed.extend_from_slice(&number.to_le_bytes()[..width]);
+    }
+    packed
+}
+
+fn unpack_intset(packed: &[u8]) -> Vec<u8> {
+    let (prefix, width) = (packed[0], packed[1] as usize);
+    let (len, start) = read_varint(packed, 2);
+    let mut resp = format!("~{len}\r\n").into_bytes();
+    for chunk in packed[start..].chunks(width) {
+        // Sign-extend the narrow number back to 64 bits.
+        let fill = if chunk[width - 1] & 0x80 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        let mut bytes = [fill; 8];
+        bytes[..width].copy_from_slice(chunk);
+        let number = i64::from_le_bytes(bytes).to_string();
+        resp.push(prefix);
+        if prefix == b'$' {
+            resp.extend_from_slice(format!("{}\r\n", number.len()).as_bytes());
+        }
+        resp.extend_from_slice(number.as_bytes());
+        resp.extend_from_slice(b"\r\n");
+    }
+    resp
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &[u8], mut pos: usize) -> (u64, usize) {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf[pos];
+        pos += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return (value, pos);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oh_my_kiwi_domain::types::Types;
+    use ordered_float::OrderedFloat;
+    use std::collections::BTreeMap;
+
+    fn bulk(value: &str) -> Types {
+        Types::BulkString(value.to_string())
+    }
+
+    fn stored(codec: &Codec, types: &Types) -> Value {
+        let resp = types.to_bytes();
+        let value = codec.encode(resp.clone());
+        assert_eq!(value.clone().into_bytes(), resp);
+        value
+    }
+
+    /// Stores `types` as a value created as `value_type`, like a restored one.
+    fn stored_as(codec: &Codec, types: &Types, value_type: ValueType) -> Value {
+        let entry = Entry {
+            key: b"key".to_vec(),
+            value: types.to_bytes(),
+            expires_at: None,
+            value_type,
+        };
+        let (_, value, _) = codec.encode_entry(entry);
+        assert_eq!(value.clone().into_bytes(), types.to_bytes());
+        value
+    }
+
+    #[test]
+    fn test_packed_collections() {
+        let codec = Codec::default();
+        let list = Types::Array(vec![bulk("a"), Types::Integer(-3), bulk("")]);
+        let value = stored(&codec, &list);
+        assert_eq!(value.name(), "listpack");
+        assert!(value.stored().len() < list.to_bytes().len());
+
+        let ints = Types::Set(vec![bulk("3"), bulk("-70000"), bulk("12")]);
+        let value = stored(&codec, &ints);
+        assert_eq!(value.name(), "intset");
+        assert_eq!(value.stored().len(), 3 + 3 * 4);
+        let numbers = Types::Set(vec![Types::Integer(i64::MIN), Types::Integer(1)]);
+        assert_eq!(stored(&codec, &numbers).name(), "intset");
+        let mixed = Types::Set(vec![bulk("1"), bulk("one"), bulk("007")]);
+        assert_eq!(stored(&codec, &mixed).name(), "listpack");
+
+        let hash = Types::Map(BTreeMap::from([(bulk("name"), bulk("kiwi"))]));
+        assert_eq!(stored(&codec, &hash).name(), "listpack");
+        let scores = Types::Map(BTreeMap::from([(
+            bulk("a"),
+            Types::Double(OrderedFloat(1.5)),
+        )]));
+        let value = stored_as(&codec, &scores, ValueType::SortedSet);
+        assert_eq!(value.name(), "listpack");
+        assert_eq!(value.value_type(), ValueType::SortedSet);
+    }
+
+    #[test]
+    fn test_large_collections_stay_plain() {
+        let config = KiwiConfig::new()
+            .with_list_max_listpack_size(2)
+            .with_set_max_intset_entries(2)
+            .with_set_max_listpack_value(3)
+            .with_hash_max_listpack_entries(1)
+            .with_zset_max_listpack_value(1);
+        let codec = Codec::from_config(&config);
+        let list = Types::Array(vec![bulk("a"), bulk("b"), bulk("c")]);
+        assert_eq!(stored(&codec, &list).name(), "raw");
+        let ints = Types::Set(vec![bulk("1"), bulk("2"), bulk("3")]);
+        assert_eq!(stored(&codec, &ints).name(), "listpack");
+        let long = Types::Set(vec![bulk("long")]);
+        assert_eq!(stored(&codec, &long).name(), "raw");
+        let hash = BTreeMap::from([(bulk("a"), bulk("1")), (bulk("b"), bulk("2"))]);
+        assert_eq!(stored(&codec, &Types::Map(hash)).name(), "raw");
+        let scores = BTreeMap::from([(bulk("ab"), Types::Double(OrderedFloat(1.0)))]);
+        let value = stored_as(&codec, &Types::Map(scores), ValueType::SortedSet);
+        assert_eq!(value.name(), "raw");
+        let nested = Types::Array(vec![Types::Array(vec![])]);
+        assert_eq!(stored(&codec, &nested).name(), "raw");
+    }
+
+    #[test]
+    fn test_maps_of_doubles_are_hashes() {
+        let config = KiwiConfig::new()
+            .with_zset_max_listpack_entries(1)
+            .with_hash_max_listpack_entries(2);
+        let codec = Codec::from_config(&config);
+        let doubles = Types::Map(BTreeMap::from([
+            (bulk("a"), Types::Double(OrderedFloat(1.0))),
+            (bulk("b"), Types::Double(OrderedFloat(2.5))),
+        ]));
+        let value = stored(&codec, &doubles);
+        assert_eq!(value.value_type(), ValueType::Hash);
+        assert_eq!(value.name(), "listpack");
+        let value = stored_as(&codec, &doubles, ValueType::SortedSet);
+        assert_eq!(value.value_type(), ValueType::SortedSet);
+        assert_eq!(value.name(), "raw");
+
+        let empty = stored(&codec, &Types::Map(BTreeMap::new()));
+        assert_eq!(empty.value_type(), ValueType::Hash);
+        assert_eq!(empty.name(), "listpack");
+    }
+
+    #[test]
+    fn test_string_encodings() {
+        let codec = Codec::default();
+        assert_eq!(stored(&codec, &bulk("12345")).name(), "int");
+        assert_eq!(stored(&codec, &bulk("0012")).name(), "embstr");
+        assert_eq!(stored(&codec, &bulk(&"x".repeat(44))).name(), "embstr");
+        assert_eq!(stored(&codec, &bulk(&"x".repeat(45))).name(), "raw");
+
+        let codec = Codec::from_config(&KiwiConfig::new().with_compression_threshold(16));
+        let value = stored(&codec, &bulk(&"x".repeat(100)));
+        assert_eq!(value.encoding(), Encoding::Compressed(108));
+        assert_eq!(value.name(), "raw");
+    }
+}
@@ -0,0 +1,260 @@
+//! Eviction under `maxmemory`. Like Redis, eviction is approximate: instead
+//! of keeping every key ordered by last access, each round samples a few
+//! random keys and evicts the best candidate among them. LFU counters grow
+//! logarithmically with accesses and decay while a key goes unused.
+
+use crate::config::KiwiConfig;
+use oh_my_kiwi_domain::AccessHistory;
+use std::cell::Cell;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_MAXMEMORY_SAMPLES: usize = 5;
+
+/// LFU counter of a new key, so it isn't evicted before it had a chance to
+/// be used.
+const LFU_INIT_VAL: u8 = 5;
+/// The higher, the more accesses it takes to grow the counter.
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// An unused key's counter drops by one every minute.
+const LFU_DECAY_MS: u64 = 60_000;
+
+/// Which keys to evict once the used memory goes over `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    /// Evict nothing; writes that may add data are refused instead.
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    /// Like `AllKeysLru`, among keys with an expiry.
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    /// Keys with the nearest expiry first.
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxmemoryPolicy::AllKeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::AllKeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    /// Whether only keys with an expiry may be evicted.
+    pub(crate) fn volatile(&self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+
+    /// How good a candidate for eviction a key is, the higher the better.
+    pub(crate) fn score(&self, access: &Access, expires_at: Option<u64>, now: u64) -> u64 {
+        match self {
+            MaxmemoryPolicy::NoEviction => 0,
+            MaxmemoryPolicy::AllKeysLru | MaxmemoryPolicy::VolatileLru => access.idle_ms(now),
+            MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                (u8::MAX - access.frequency(now)) as u64
+            }
+            MaxmemoryPolicy::AllKeysRandom | MaxmemoryPolicy::VolatileRandom => random(),
+            MaxmemoryPolicy::VolatileTtl => u64::MAX - expires_at.unwrap_or(u64::MAX),
+        }
+    }
+}
+
+impl FromStr for MaxmemoryPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "noeviction" => Ok(MaxmemoryPolicy::NoEviction),
+            "allkeys-lru" => Ok(MaxmemoryPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(MaxmemoryPolicy::AllKeysLfu),
+            "allkeys-random" => Ok(MaxmemoryPolicy::AllKeysRandom),
+            "volatile-lru" => Ok(MaxmemoryPolicy::VolatileLru),
+            "volatile-lfu" => Ok(MaxmemoryPolicy::VolatileLfu),
+            "volatile-random" => Ok(MaxmemoryPolicy::VolatileRandom),
+            "volatile-ttl" => Ok(MaxmemoryPolicy::VolatileTtl),
+            _ => Err(format!("invalid maxmemory policy '{value}'")),
+        }
+    }
+}
+
+/// The memory limit, and which keys to evict to stay under it.
+#[derive(Clone, Copy)]
+pub(crate) struct Limit {
+    pub(crate) maxmemory: usize,
+    pub(crate) policy: MaxmemoryPolicy,
+    pub(crate) samples: usize,
+}
+
+impl Limit {
+    pub(crate) fn from_config(config: &KiwiConfig) -> Self {
+        Self {
+            maxmemory: config.maxmemory(),
+            policy: config.maxmemory_policy(),
+            samples: config.maxmemory_samples().max(1),
+        }
+    }
+
+    pub(crate) fn exceeded(&self, used: usize) -> bool {
+        self.maxmemory > 0 && used > self.maxmemory
+    }
+
+    /// Whether eviction could bring `used` back under the limit.
+    pub(crate) fn evicts(&self, used: usize) -> bool {
+        self.policy != MaxmemoryPolicy::NoEviction && self.exceeded(used)
+    }
+}
+
+/// When a key was last used and how often, updated on reads that only hold
+/// the storage's read lock.
+pub(crate) struct Access {
+    accessed_ms: AtomicU64,
+    frequency: AtomicU8,
+}
+
+impl Access {
+    pub(crate) fn new(now: u64) -> Self {
+        Self {
+            accessed_ms: AtomicU64::new(now),
+            frequency: AtomicU8::new(LFU_INIT_VAL),
+        }
+    }
+
+    /// The history of a restored key, used `history.idle_ms` ago and as often
+    /// as its LFU counter says.
+    pub(crate) fn restored(history: AccessHistory, now: u64) -> Self {
+        let idle_ms = history.idle_ms.unwrap_or(0);
+        Self {
+            accessed_ms: AtomicU64::new(now.saturating_sub(idle_ms)),
+            frequency: AtomicU8::new(history.frequency.unwrap_or(LFU_INIT_VAL)),
+        }
+    }
+
+    pub(crate) fn touch(&self, now: u64) {
+        let frequency = self.frequency(now);
+        self.frequency
+            .store(increment_frequency(frequency), Ordering::Relaxed);
+        self.accessed_ms.store(now, Ordering::Relaxed);
+    }
+
+    pub(crate) fn idle_ms(&self, now: u64) -> u64 {
+        now.saturating_sub(self.accessed_ms.load(Ordering::Relaxed))
+    }
+
+    /// The LFU counter, decayed for the time the key went unused.
+    pub(crate) fn frequency(&self, now: u64) -> u8 {
+        let decay = self.idle_ms(now) / LFU_DECAY_MS;
+        let frequency = self.frequency.load(Ordering::Relaxed);
+        frequency.saturating_sub(decay.min(u8::MAX as u64) as u8)
+    }
+}
+
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        Self {
+            accessed_ms: AtomicU64::new(self.accessed_ms.load(Ordering::Relaxed)),
+            frequency: AtomicU8::new(self.frequency.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Grows an LFU counter with a probability that shrinks as it gets higher.
+fn increment_frequency(frequency: u8) -> u8 {
+    if frequency == u8::MAX {
+        return frequency;
+    }
+    let base = frequency.saturating_sub(LFU_INIT_VAL) as f64;
+    let probability = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+    let draw = (random() >> 11) as f64 / (1u64 << 53) as f64;
+    match draw < probability {
+        true => frequency + 1,
+        false => frequency,
+    }
+}
+
+/// A fast, non-cryptographic random number (xorshift64*), seeded per thread
+/// from the clock.
+pub(crate) fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+                | 1,
+        );
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(
+            "allkeys-lru".parse::<MaxmemoryPolicy>(),
+            Ok(MaxmemoryPolicy::AllKeysLru)
+        );
+        assert_eq!(
+            "VOLATILE-TTL".parse::<MaxmemoryPolicy>(),
+            Ok(MaxmemoryPolicy::VolatileTtl)
+        );
+        assert!("lru".parse::<MaxmemoryPolicy>().is_err());
+        assert_eq!(MaxmemoryPolicy::VolatileLfu.name(), "volatile-lfu");
+        assert!(MaxmemoryPolicy::VolatileRandom.volatile());
+        assert!(!MaxmemoryPolicy::AllKeysRandom.volatile());
+    }
+
+    #[test]
+    fn test_access() {
+        let access = Access::new(1_000);
+        assert_eq!(access.idle_ms(4_000), 3_000);
+        assert_eq!(access.frequency(1_000), LFU_INIT_VAL);
+
+        // Low counters grow on almost every access, then ever more slowly.
+        for _ in 0..1_000 {
+            access.touch(2_000);
+        }
+        let frequency = access.frequency(2_000);
+        assert!(frequency > LFU_INIT_VAL + 5 && frequency < u8::MAX);
+        assert_eq!(access.idle_ms(2_000), 0);
+
+        // Two unused minutes take two off the counter.
+        assert_eq!(access.frequency(2_000 + 2 * LFU_DECAY_MS), frequency - 2);
+    }
+
+    #[test]
+    fn test_scores() {
+        let now = 10_000;
+        let old = Access::new(1_000);
+        let recent = Access::new(9_000);
+        let policy = MaxmemoryPolicy::AllKeysLru;
+        assert!(policy.score(&old, None, now) > policy.score(&recent, None, now));
+
+        let policy = MaxmemoryPolicy::VolatileTtl;
+        assert!(policy.score(&old, Some(20_000), now) > policy.score(&old, Some(30_000), now));
+    }
+}
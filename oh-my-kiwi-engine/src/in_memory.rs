@@ -1,29 +1,669 @@
+mod database;
+mod dict;
+
+pub(crate) use database::{
+    Candidate, Database, DatabaseSnapshot, Lookup, Usage, active_expire, eviction_candidate,
+    total_compression,
+};
+
+use crate::config::KiwiConfig;
+use crate::encoding::Codec;
+use crate::eviction::Limit;
+use crate::lazy_free::{free, release};
+use crate::time::now_ms;
 use async_trait::async_trait;
-use std::collections::HashMap;
+use oh_my_kiwi_domain::{AccessHistory, Engine, Entry, MemoryStats, Snapshot};
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::RwLock;
-use oh_my_kiwi_domain::Engine;
+
+pub const DEFAULT_DATABASES: usize = 16;
 
 pub struct InMemoryEngine {
-    storage: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
+    databases: usize,
+    storage: RwLock<Vec<Database>>,
+    limit: Limit,
+    codec: Codec,
+    usage: Usage,
+    evicted_keys: AtomicU64,
 }
 
 impl InMemoryEngine {
     pub fn new() -> Self {
+        Self::from_config(&KiwiConfig::default())
+    }
+
+    pub fn with_databases(databases: usize) -> Self {
+        Self::from_config(&KiwiConfig::new().with_databases(databases))
+    }
+
+    /// An engine with the databases, memory limit and compression of `config`.
+    pub fn from_config(config: &KiwiConfig) -> Self {
+        let databases = config.databases().max(1);
         Self {
-            storage: RwLock::new(HashMap::new()),
+            databases,
+            storage: RwLock::new(vec![Database::default(); databases]),
+            limit: Limit::from_config(config),
+            codec: Codec::from_config(config),
+            usage: Usage::default(),
+            evicted_keys: AtomicU64::new(0),
         }
     }
+
+    /// Publishes the usage of the databases after a write.
+    fn update_used_memory(&self, storage: &[Database]) {
+        self.usage.update(storage);
+    }
+}
+
+impl Default for InMemoryEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[async_trait]
 impl Engine for InMemoryEngine {
-    async fn get(&self, key: &Vec<u8>) -> Option<Vec<u8>> {
+    fn databases(&self) -> usize {
+        self.databases
+    }
+
+    async fn get(&self, db: usize, key: &[u8]) -> Option<Vec<u8>> {
+        let now = now_ms();
         let storage = self.storage.read().await;
-        storage.get(key).cloned()
+        let lookup = storage[db].get(key, now);
+        let rehashing = storage[db].is_rehashing();
+        drop(storage);
+        if rehashing {
+            rehash_after_lookup(&self.storage, db);
+        }
+        match lookup {
+            Lookup::Found(value) => Some(value.into_bytes()),
+            Lookup::Expired => {
+                let mut storage = self.storage.write().await;
+                storage[db].remove_if_expired(key, now);
+                self.update_used_memory(&storage);
+                None
+            }
+            Lookup::Missing => None,
+        }
     }
 
-    async fn set(&self, key: Vec<u8>, value: Vec<u8>) {
+    async fn set(&self, db: usize, key: Vec<u8>, value: Vec<u8>) {
+        let value = self.codec.encode(value);
         let mut storage = self.storage.write().await;
-        storage.insert(key, value);
+        storage[db].set(key, value, now_ms());
+        self.update_used_memory(&storage);
+    }
+
+    async fn delete(&self, db: usize, key: &[u8], lazy: bool) -> bool {
+        let mut storage = self.storage.write().await;
+        let deleted = storage[db].delete(key, now_ms());
+        self.update_used_memory(&storage);
+        drop(storage);
+
+        deleted.map(|value| release(value, lazy)).is_some()
+    }
+
+    async fn move_key(&self, db: usize, target: usize, key: &[u8]) -> bool {
+        let now = now_ms();
+        let mut storage = self.storage.write().await;
+        storage[db].remove_if_expired(key, now);
+        let moved = match storage[target].contains(key, now) {
+            true => false,
+            false => move_entry(&mut storage, db, target, key),
+        };
+        self.update_used_memory(&storage);
+        moved
+    }
+
+    async fn swap(&self, first: usize, second: usize) {
+        let mut storage = self.storage.write().await;
+        storage.swap(first, second);
+    }
+
+    async fn size(&self, db: usize) -> usize {
+        let storage = self.storage.read().await;
+        storage[db].len()
+    }
+
+    async fn flush(&self, db: usize, lazy: bool) {
+        let mut storage = self.storage.write().await;
+        let database = std::mem::take(&mut storage[db]);
+        self.update_used_memory(&storage);
+        drop(storage);
+
+        free(database, lazy);
+    }
+
+    async fn flush_all(&self, lazy: bool) {
+        let mut storage = self.storage.write().await;
+        let databases: Vec<Database> = storage.iter_mut().map(std::mem::take).collect();
+        self.update_used_memory(&storage);
+        drop(storage);
+
+        free(databases, lazy);
+    }
+
+    async fn set_expiry(&self, db: usize, key: &[u8], expires_at: Option<u64>) -> bool {
+        let mut storage = self.storage.write().await;
+        let found = storage[db].expire(key, expires_at, now_ms());
+        self.update_used_memory(&storage);
+        found
+    }
+
+    async fn expiry(&self, db: usize, key: &[u8]) -> Option<Option<u64>> {
+        let storage = self.storage.read().await;
+        storage[db].expiry(key, now_ms())
+    }
+
+    async fn encoding(&self, db: usize, key: &[u8]) -> Option<&'static str> {
+        let storage = self.storage.read().await;
+        storage[db].encoding(key, now_ms())
+    }
+
+    async fn memory_usage(&self, db: usize, key: &[u8]) -> Option<usize> {
+        let storage = self.storage.read().await;
+        storage[db].memory_usage(key, now_ms())
+    }
+
+    async fn snapshot(&self) -> Box<dyn Snapshot> {
+        let storage = self.storage.read().await;
+        Box::new(DatabaseSnapshot::new(vec![storage.clone()], now_ms()))
+    }
+
+    async fn restore(
+        &self,
+        db: usize,
+        entry: Entry,
+        history: AccessHistory,
+        replace: bool,
+    ) -> bool {
+        let (key, value, expires_at) = self.codec.encode_entry(entry);
+        let mut storage = self.storage.write().await;
+        let restored = storage[db].restore(key, value, expires_at, history, replace, now_ms());
+        self.update_used_memory(&storage);
+        restored
+    }
+
+    async fn insert_entries(&self, db: usize, entries: Vec<Entry>) {
+        let now = now_ms();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|entry| self.codec.encode_entry(entry))
+            .collect();
+        let mut storage = self.storage.write().await;
+        for (key, value, expires_at) in entries {
+            storage[db].insert_value(key, value, expires_at, now);
+        }
+        self.update_used_memory(&storage);
+    }
+
+    fn memory(&self) -> MemoryStats {
+        MemoryStats {
+            used: self.usage.memory(),
+            overhead: self.usage.overhead(),
+            max: self.limit.maxmemory,
+            policy: self.limit.policy.name(),
+            evicted_keys: self.evicted_keys.load(Ordering::Relaxed),
+            compression: self.usage.compression(),
+        }
+    }
+
+    async fn evict(&self) -> Vec<(usize, Vec<u8>)> {
+        if !self.limit.evicts(self.usage.memory()) {
+            return Vec::new();
+        }
+
+        let now = now_ms();
+        let mut storage = self.storage.write().await;
+        let mut evicted = Vec::new();
+        let mut freed = Vec::new();
+        while self
+            .limit
+            .exceeded(storage.iter().map(Database::memory).sum())
+        {
+            let Some(candidate) = eviction_candidate(&storage, &self.limit, now) else {
+                break;
+            };
+            if let Some((key, stored)) = storage[candidate.db].remove(&candidate.key) {
+                freed.push(stored);
+                evicted.push((candidate.db, key));
+            }
+        }
+        self.update_used_memory(&storage);
+        drop(storage);
+
+        // The evicted values are only dropped once the lock is released.
+        drop(freed);
+        self.evicted_keys
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        evicted
+    }
+
+    async fn expire_cycle(&self) -> Vec<(usize, Vec<u8>)> {
+        let mut storage = self.storage.write().await;
+        let expired = active_expire(&mut storage, now_ms());
+        self.update_used_memory(&storage);
+        expired
+    }
+}
+
+/// Moves `key` from database `db` to `target`, returning `false` when it's missing.
+/// Moves a resize of `db` along after a lookup, as Redis's lookups do.
+/// Lookups only hold the read lock, so the step is taken if the write lock
+/// is free right away, and left to the next operation otherwise.
+pub(crate) fn rehash_after_lookup(storage: &RwLock<Vec<Database>>, db: usize) {
+    if let Ok(mut storage) = storage.try_write() {
+        storage[db].rehash_step();
+    }
+}
+
+pub(crate) fn move_entry(databases: &mut [Database], db: usize, target: usize, key: &[u8]) -> bool {
+    match databases[db].remove(key) {
+        Some((key, value)) => {
+            databases[target].insert(key, value);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::MaxmemoryPolicy;
+    use crate::lazy_free::{self, LAZYFREE_THRESHOLD};
+    use database::ENTRY_OVERHEAD;
+    use oh_my_kiwi_domain::ValueType;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_databases_are_isolated() {
+        let engine = InMemoryEngine::with_databases(2);
+        engine.set(0, b"key".to_vec(), b"zero".to_vec()).await;
+
+        assert_eq!(engine.get(0, b"key").await, Some(b"zero".to_vec()));
+        assert_eq!(engine.get(1, b"key").await, None);
+        assert_eq!(engine.size(0).await, 1);
+        assert_eq!(engine.size(1).await, 0);
+    }
+
+    /// Reads move a resize along too, so one finishes under a read-mostly
+    /// load instead of leaving every lookup checking two tables.
+    #[tokio::test]
+    async fn test_lookups_finish_rehashing() {
+        let engine = InMemoryEngine::new();
+        let rehashing = async || engine.storage.read().await[0].is_rehashing();
+        let mut longest = 0;
+        let mut rehashing_for = 0;
+        for i in 0..1 << 14 {
+            engine.set(0, key(i), b"value".to_vec()).await;
+            for j in 0..20 {
+                engine.get(0, &key(i * j % (i + 1))).await;
+            }
+            rehashing_for = match rehashing().await {
+                true => rehashing_for + 1,
+                false => 0,
+            };
+            longest = longest.max(rehashing_for);
+        }
+        // Writes alone take up to a write per bucket of the old table.
+        assert!(longest < 1 << 12, "rehashed for {longest} writes");
+
+        // Once writes stop, reads alone finish a resize.
+        let mut keys = 1 << 14;
+        while !rehashing().await {
+            engine.set(0, key(keys), b"value".to_vec()).await;
+            keys += 1;
+        }
+        for i in 0..keys {
+            assert!(engine.get(0, &key(i)).await.is_some());
+        }
+        assert!(!rehashing().await);
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key:{i}").into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_move_key() {
+        let engine = InMemoryEngine::with_databases(2);
+        engine.set(0, b"key".to_vec(), b"value".to_vec()).await;
+
+        assert!(engine.move_key(0, 1, b"key").await);
+        assert!(!engine.move_key(0, 1, b"key").await);
+        assert_eq!(engine.get(1, b"key").await, Some(b"value".to_vec()));
+
+        engine.set(0, b"key".to_vec(), b"other".to_vec()).await;
+        assert!(!engine.move_key(0, 1, b"key").await);
+        assert_eq!(engine.get(0, b"key").await, Some(b"other".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let engine = InMemoryEngine::new();
+        engine.set(0, b"key".to_vec(), b"value".to_vec()).await;
+
+        assert!(engine.delete(0, b"key", false).await);
+        assert!(!engine.delete(0, b"key", false).await);
+        assert_eq!(engine.get(0, b"key").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lazy_free() {
+        let engine = InMemoryEngine::new();
+        let large = vec![b'x'; LAZYFREE_THRESHOLD];
+        let freed = lazy_free::freed_objects();
+        engine.set(0, b"unlinked".to_vec(), large.clone()).await;
+        assert!(engine.delete(0, b"unlinked", true).await);
+        assert!(!engine.delete(0, b"unlinked", true).await);
+
+        engine.set(0, b"overwritten".to_vec(), large.clone()).await;
+        engine.set(0, b"overwritten".to_vec(), b"v".to_vec()).await;
+        engine.set(0, b"expired".to_vec(), large).await;
+        engine.set_expiry(0, b"expired", Some(1)).await;
+        assert_eq!(engine.size(0).await, 1);
+        assert_eq!(engine.memory().used, ENTRY_OVERHEAD + 12);
+
+        // Other tests free values too, so only wait for at least ours.
+        while lazy_free::freed_objects() < freed + 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Expired keys nobody reads again are swept too, their large values
+    /// freed in the background.
+    #[tokio::test]
+    async fn test_expire_cycle() {
+        let engine = InMemoryEngine::with_databases(2);
+        let freed = lazy_free::freed_objects();
+        let entry = |key, value: &[u8], expires_at| Entry {
+            key,
+            value: value.to_vec(),
+            expires_at,
+            value_type: ValueType::String,
+        };
+        let mut entries = vec![entry(
+            b"large".to_vec(),
+            &[b'x'; LAZYFREE_THRESHOLD],
+            Some(1),
+        )];
+        entries.extend((0..100).map(|i| entry(key(i), b"v", Some(1))));
+        entries.extend((100..110).map(|i| entry(key(i), b"v", Some(u64::MAX))));
+        entries.extend((110..120).map(|i| entry(key(i), b"v", None)));
+        engine.insert_entries(1, entries).await;
+
+        let mut expired = Vec::new();
+        while expired.len() < 101 {
+            expired.extend(engine.expire_cycle().await);
+        }
+        assert_eq!(expired.len(), 101);
+        assert!(expired.iter().all(|(db, _)| *db == 1));
+        assert!(expired.iter().any(|(_, key)| key == b"large"));
+        assert!(engine.expire_cycle().await.is_empty());
+        assert_eq!(engine.size(1).await, 20);
+        assert_eq!(engine.memory().used, 20 * (ENTRY_OVERHEAD + 7 + 1));
+
+        while lazy_free::freed_objects() <= freed {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swap_and_flush() {
+        let engine = InMemoryEngine::with_databases(3);
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+        engine.set(2, b"b".to_vec(), b"2".to_vec()).await;
+
+        engine.swap(0, 2).await;
+        assert_eq!(engine.get(0, b"b").await, Some(b"2".to_vec()));
+        assert_eq!(engine.get(2, b"a").await, Some(b"1".to_vec()));
+
+        engine.flush(0, false).await;
+        assert_eq!(engine.size(0).await, 0);
+        assert_eq!(engine.size(2).await, 1);
+
+        engine.flush_all(true).await;
+        assert_eq!(engine.size(2).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let engine = InMemoryEngine::new();
+        engine.set(0, b"key".to_vec(), b"value".to_vec()).await;
+        assert_eq!(engine.expiry(0, b"key").await, Some(None));
+        assert_eq!(engine.expiry(0, b"missing").await, None);
+
+        let later = now_ms() + 60_000;
+        assert!(engine.set_expiry(0, b"key", Some(later)).await);
+        assert_eq!(engine.expiry(0, b"key").await, Some(Some(later)));
+        assert!(!engine.set_expiry(0, b"missing", Some(later)).await);
+
+        engine.set(0, b"key".to_vec(), b"other".to_vec()).await;
+        assert_eq!(engine.expiry(0, b"key").await, Some(None));
+
+        assert!(engine.set_expiry(0, b"key", Some(now_ms() - 1)).await);
+        assert_eq!(engine.get(0, b"key").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_expired_keys_are_hidden() {
+        let engine = InMemoryEngine::new();
+        let expired = Entry {
+            key: b"old".to_vec(),
+            value: b"value".to_vec(),
+            expires_at: Some(1),
+            value_type: ValueType::String,
+        };
+        let live = Entry {
+            key: b"new".to_vec(),
+            value: b"value".to_vec(),
+            expires_at: None,
+            value_type: ValueType::String,
+        };
+        engine.insert_entries(0, vec![expired, live.clone()]).await;
+
+        assert_eq!(engine.get(0, b"old").await, None);
+        assert_eq!(
+            engine
+                .snapshot()
+                .await
+                .entries(0)
+                .collect::<std::io::Result<Vec<_>>>()
+                .unwrap(),
+            vec![live]
+        );
+        assert!(engine.move_key(0, 1, b"new").await);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_is_point_in_time() {
+        let engine = InMemoryEngine::with_databases(2);
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+        engine.set(1, b"b".to_vec(), b"2".to_vec()).await;
+        engine.set_expiry(1, b"b", Some(now_ms() + 60_000)).await;
+
+        let snapshot = engine.snapshot().await;
+        engine.set(0, b"a".to_vec(), b"changed".to_vec()).await;
+        engine.set(0, b"c".to_vec(), b"3".to_vec()).await;
+        engine.flush(1, false).await;
+
+        let keys = |db| {
+            snapshot
+                .entries(db)
+                .map(|entry| entry.unwrap().key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(0), vec![b"a".to_vec()]);
+        assert_eq!(
+            snapshot.entries(0).next().unwrap().unwrap().value,
+            b"1".to_vec()
+        );
+        assert_eq!(keys(1), vec![b"b".to_vec()]);
+        assert_eq!(snapshot.size_hint(1), (1, 1));
+        assert_eq!(engine.get(0, b"a").await, Some(b"changed".to_vec()));
+        assert_eq!(engine.size(1).await, 0);
+    }
+
+    /// Size of an entry with a two byte key and a one byte value.
+    const SMALL_ENTRY: usize = ENTRY_OVERHEAD + 3;
+
+    fn limited(entries: usize, policy: MaxmemoryPolicy) -> InMemoryEngine {
+        let config = KiwiConfig::new()
+            .with_maxmemory(entries * SMALL_ENTRY)
+            .with_maxmemory_policy(policy)
+            .with_maxmemory_samples(1000);
+        InMemoryEngine::from_config(&config)
+    }
+
+    #[tokio::test]
+    async fn test_memory_accounting() {
+        let engine = InMemoryEngine::with_databases(2);
+        engine.set(0, b"key".to_vec(), b"value".to_vec()).await;
+        assert_eq!(engine.memory().used, ENTRY_OVERHEAD + 8);
+        engine
+            .set(0, b"key".to_vec(), b"other value".to_vec())
+            .await;
+        assert_eq!(engine.memory().used, ENTRY_OVERHEAD + 14);
+        assert_eq!(engine.memory().overhead, ENTRY_OVERHEAD);
+        assert_eq!(
+            engine.memory_usage(0, b"key").await,
+            Some(ENTRY_OVERHEAD + 14)
+        );
+        assert_eq!(engine.memory_usage(0, b"missing").await, None);
+
+        assert!(engine.move_key(0, 1, b"key").await);
+        assert_eq!(engine.memory().used, ENTRY_OVERHEAD + 14);
+        assert!(engine.delete(1, b"key", false).await);
+        assert_eq!(engine.memory().used, 0);
+
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+        engine.flush_all(false).await;
+        assert_eq!(engine.memory().used, 0);
+        assert_eq!(engine.memory().policy, "noeviction");
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let engine = InMemoryEngine::from_config(&KiwiConfig::new().with_compression_threshold(64));
+        let json = br#"{"id":1,"status":"active"}"#.repeat(40);
+        engine.set(0, b"json".to_vec(), json.clone()).await;
+        engine.set(0, b"small".to_vec(), b"value".to_vec()).await;
+
+        assert_eq!(engine.get(0, b"json").await, Some(json.clone()));
+        let memory = engine.memory();
+        assert!(memory.used < json.len());
+        assert_eq!(memory.compression.values, 1);
+        assert_eq!(memory.compression.original_bytes, json.len());
+        assert!(memory.compression.compressed_bytes < json.len() / 4);
+        let entries = engine
+            .snapshot()
+            .await
+            .entries(0)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert!(entries.iter().any(|entry| entry.value == json));
+
+        engine.set(0, b"json".to_vec(), b"{}".to_vec()).await;
+        assert_eq!(engine.memory().compression, Default::default());
+        assert_eq!(engine.memory().used, 2 * ENTRY_OVERHEAD + 16);
+    }
+
+    #[tokio::test]
+    async fn test_packed_collections() {
+        let engine = InMemoryEngine::from_config(&KiwiConfig::new().with_set_max_intset_entries(3));
+        let small = b"~3\r\n$1\r\n1\r\n$1\r\n2\r\n$1\r\n3\r\n".to_vec();
+        let large = b"~4\r\n$1\r\n1\r\n$1\r\n2\r\n$1\r\n3\r\n$1\r\n4\r\n".to_vec();
+        engine.set(0, b"small".to_vec(), small.clone()).await;
+        assert_eq!(engine.encoding(0, b"small").await, Some("intset"));
+        assert_eq!(engine.get(0, b"small").await, Some(small.clone()));
+        assert!(engine.memory().used < ENTRY_OVERHEAD + 5 + small.len());
+
+        engine.set(0, b"large".to_vec(), large.clone()).await;
+        assert_eq!(engine.encoding(0, b"large").await, Some("listpack"));
+        assert_eq!(engine.get(0, b"large").await, Some(large));
+        engine
+            .set(0, b"text".to_vec(), b"$3\r\n100\r\n".to_vec())
+            .await;
+        assert_eq!(engine.encoding(0, b"text").await, Some("int"));
+        assert_eq!(engine.encoding(0, b"missing").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_evict_lru() {
+        let engine = limited(3, MaxmemoryPolicy::AllKeysLru);
+        for key in [b"k1", b"k2", b"k3"] {
+            engine.set(0, key.to_vec(), b"v".to_vec()).await;
+        }
+        assert!(engine.evict().await.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        engine.get(0, b"k1").await;
+        engine.get(0, b"k3").await;
+        engine.set(1, b"k4".to_vec(), b"v".to_vec()).await;
+        assert_eq!(engine.evict().await, vec![(0, b"k2".to_vec())]);
+        assert_eq!(engine.memory().used, 3 * SMALL_ENTRY);
+        assert_eq!(engine.memory().evicted_keys, 1);
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let engine = limited(2, MaxmemoryPolicy::AllKeysLru);
+        let entry = |key: &[u8], expires_at| Entry {
+            key: key.to_vec(),
+            value: b"v".to_vec(),
+            expires_at,
+            value_type: ValueType::String,
+        };
+        let idle = AccessHistory {
+            idle_ms: Some(60_000),
+            frequency: None,
+        };
+        engine.set(0, b"k1".to_vec(), b"v".to_vec()).await;
+        assert!(!engine.restore(0, entry(b"k1", None), idle, false).await);
+        assert!(engine.restore(0, entry(b"k2", None), idle, false).await);
+        engine.set(0, b"k3".to_vec(), b"v".to_vec()).await;
+        // k2 went unused for a minute before it was restored, so it goes first.
+        assert_eq!(engine.evict().await, vec![(0, b"k2".to_vec())]);
+
+        let fresh = AccessHistory::default();
+        let later = now_ms() + 60_000;
+        assert!(
+            engine
+                .restore(0, entry(b"k1", Some(later)), fresh, true)
+                .await
+        );
+        assert_eq!(engine.expiry(0, b"k1").await, Some(Some(later)));
+        assert!(engine.restore(0, entry(b"k1", Some(1)), fresh, true).await);
+        assert_eq!(engine.get(0, b"k1").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_evict_volatile() {
+        let engine = limited(2, MaxmemoryPolicy::VolatileTtl);
+        let later = now_ms() + 60_000;
+        engine.set(0, b"k1".to_vec(), b"v".to_vec()).await;
+        engine.set(0, b"k2".to_vec(), b"v".to_vec()).await;
+        engine.set(0, b"k3".to_vec(), b"v".to_vec()).await;
+        engine.set_expiry(0, b"k2", Some(later + 1000)).await;
+        engine.set_expiry(0, b"k3", Some(later)).await;
+        assert_eq!(engine.evict().await, vec![(0, b"k3".to_vec())]);
+
+        // Keys without an expiry are never evicted.
+        engine.set(0, b"k4".to_vec(), b"v".to_vec()).await;
+        engine.set(0, b"k5".to_vec(), b"v".to_vec()).await;
+        assert_eq!(engine.evict().await, vec![(0, b"k2".to_vec())]);
+        assert_eq!(engine.size(0).await, 3);
+        assert!(engine.memory().used > engine.memory().max);
+    }
+
+    #[tokio::test]
+    async fn test_noeviction() {
+        let engine = limited(1, MaxmemoryPolicy::NoEviction);
+        engine.set(0, b"k1".to_vec(), b"v".to_vec()).await;
+        engine.set(0, b"k2".to_vec(), b"v".to_vec()).await;
+        assert!(engine.evict().await.is_empty());
+        assert_eq!(engine.size(0).await, 2);
     }
 }
@@ -0,0 +1,490 @@
+//! One logical database: its entries and the bookkeeping eviction needs.
+//! Locking is left to the engines holding the databases.
+
+use super::dict::{self, Dict};
+use crate::encoding::{Encoding, Value};
+use crate::eviction::{Access, Limit, random};
+use crate::lazy_free::release;
+use oh_my_kiwi_domain::{AccessHistory, CompressionStats, Entry, Snapshot};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bookkeeping counted for every entry on top of its key and value: the
+/// dictionary node with its hash and bucket, and the stored value itself.
+pub(crate) const ENTRY_OVERHEAD: usize = dict::entry_size::<StoredValue>();
+
+/// Keys with an expiry the active expire cycle checks at a time.
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+/// Samples the cycle takes of one database at most, as long as more than a
+/// quarter of the keys in each turned out expired.
+const ACTIVE_EXPIRE_ROUNDS: usize = 16;
+
+#[derive(Clone)]
+pub(crate) struct StoredValue {
+    value: Value,
+    expires_at: Option<u64>,
+    access: Access,
+}
+
+impl StoredValue {
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub(crate) fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub(crate) fn into_parts(self) -> (Value, Option<u64>) {
+        (self.value, self.expires_at)
+    }
+}
+
+/// The result of looking a key up without the right to remove it.
+pub(crate) enum Lookup {
+    Found(Value),
+    Expired,
+    Missing,
+}
+
+/// The keys of one database, in a dictionary that rehashes incrementally
+/// and lets eviction sample random keys.
+#[derive(Clone, Default)]
+pub(crate) struct Database {
+    entries: Dict<StoredValue>,
+    /// Approximate bytes taken by the entries.
+    memory: usize,
+    /// Number of entries with an expiry.
+    volatile: usize,
+    compression: CompressionStats,
+}
+
+impl Database {
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// The value of `key`, counting as an access to it.
+    pub(crate) fn get(&self, key: &[u8], now: u64) -> Lookup {
+        match self.entries.get(key) {
+            Some(stored) if !stored.is_expired(now) => {
+                stored.access.touch(now);
+                Lookup::Found(stored.value.clone())
+            }
+            Some(_) => Lookup::Expired,
+            None => Lookup::Missing,
+        }
+    }
+
+    /// Whether the dictionary is still moving keys to a resized table, which
+    /// lookups help along with `rehash_step`.
+    pub(crate) fn is_rehashing(&self) -> bool {
+        self.entries.is_rehashing()
+    }
+
+    pub(crate) fn rehash_step(&mut self) {
+        self.entries.rehash_step();
+    }
+
+    /// The encoding of `key`'s value, without counting as an access.
+    pub(crate) fn encoding(&self, key: &[u8], now: u64) -> Option<&'static str> {
+        self.entries
+            .get(key)
+            .filter(|stored| !stored.is_expired(now))
+            .map(|stored| stored.value.name())
+    }
+
+    /// The bytes `key` takes, counted like the database's memory.
+    pub(crate) fn memory_usage(&self, key: &[u8], now: u64) -> Option<usize> {
+        self.entries
+            .get(key)
+            .filter(|stored| !stored.is_expired(now))
+            .map(|stored| ENTRY_OVERHEAD + key.len() + stored.value.stored().len())
+    }
+
+    /// Whether `key` holds `value`, without counting as an access.
+    pub(crate) fn holds(&self, key: &[u8], value: &Value) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|stored| stored.value == *value)
+    }
+
+    /// Whether `key` exists and hasn't expired.
+    pub(crate) fn contains(&self, key: &[u8], now: u64) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|stored| !stored.is_expired(now))
+    }
+
+    /// Sets `key` to `value` without an expiry. An overwritten key keeps its
+    /// access history.
+    pub(crate) fn set(&mut self, key: Vec<u8>, value: Value, now: u64) {
+        let access = match self.entries.get(&key) {
+            Some(stored) if !stored.is_expired(now) => stored.access.clone(),
+            _ => Access::new(now),
+        };
+        access.touch(now);
+        self.insert(
+            key,
+            StoredValue {
+                value,
+                expires_at: None,
+                access,
+            },
+        );
+    }
+
+    /// Removes `key`, returning its value for the caller to free.
+    pub(crate) fn delete(&mut self, key: &[u8], now: u64) -> Option<Value> {
+        self.remove_if_expired(key, now);
+        self.remove(key).map(|(_, stored)| stored.value)
+    }
+
+    /// Sets or clears the expiry of `key`; one in the past deletes the key.
+    pub(crate) fn expire(&mut self, key: &[u8], expires_at: Option<u64>, now: u64) -> bool {
+        self.remove_if_expired(key, now);
+        let Some(stored) = self.entries.get_mut(key) else {
+            return false;
+        };
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            self.expire_now(key);
+            return true;
+        }
+        self.volatile -= stored.expires_at.is_some() as usize;
+        self.volatile += expires_at.is_some() as usize;
+        stored.expires_at = expires_at;
+        true
+    }
+
+    pub(crate) fn expiry(&self, key: &[u8], now: u64) -> Option<Option<u64>> {
+        self.entries
+            .get(key)
+            .filter(|stored| !stored.is_expired(now))
+            .map(|stored| stored.expires_at)
+    }
+
+    pub(crate) fn live_entries(&self, now: u64) -> Vec<Entry> {
+        self.live(now).collect()
+    }
+
+    fn live(&self, now: u64) -> impl Iterator<Item = Entry> + '_ {
+        self.entries
+            .iter()
+            .filter(move |(_, stored)| !stored.is_expired(now))
+            .map(|(key, stored)| Entry {
+                key: key.clone(),
+                value: stored.value.clone().into_bytes(),
+                expires_at: stored.expires_at,
+                value_type: stored.value.value_type(),
+            })
+    }
+
+    /// Creates `key` as RESTORE does. Without `replace` an existing key is left
+    /// alone and `false` returned; an expiry in the past only removes the key
+    /// being replaced.
+    pub(crate) fn restore(
+        &mut self,
+        key: Vec<u8>,
+        value: Value,
+        expires_at: Option<u64>,
+        history: AccessHistory,
+        replace: bool,
+        now: u64,
+    ) -> bool {
+        self.remove_if_expired(&key, now);
+        if !replace && self.entries.get(&key).is_some() {
+            return false;
+        }
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            self.expire_now(&key);
+            return true;
+        }
+        let access = Access::restored(history, now);
+        self.insert(
+            key,
+            StoredValue {
+                value,
+                expires_at,
+                access,
+            },
+        );
+        true
+    }
+
+    /// Inserts a key with no access history, like one loaded from a snapshot.
+    pub(crate) fn insert_value(
+        &mut self,
+        key: Vec<u8>,
+        value: Value,
+        expires_at: Option<u64>,
+        now: u64,
+    ) {
+        self.insert(
+            key,
+            StoredValue {
+                value,
+                expires_at,
+                access: Access::new(now),
+            },
+        );
+    }
+
+    /// Inserts `key`, handing a large overwritten value to the lazy free worker.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, stored: StoredValue) {
+        let key_len = key.len();
+        self.count(key_len, &stored, true);
+        if let Some(old) = self.entries.insert(key, stored) {
+            self.count(key_len, &old, false);
+            release(old.value, true);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<(Vec<u8>, StoredValue)> {
+        let (key, stored) = self.entries.remove(key)?;
+        self.count(key.len(), &stored, false);
+        Some((key, stored))
+    }
+
+    pub(crate) fn remove_if_expired(&mut self, key: &[u8], now: u64) {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|stored| stored.is_expired(now))
+        {
+            self.expire_now(key);
+        }
+    }
+
+    /// Removes the expired keys among random ones with an expiry, sampling
+    /// again while many of them expired, as Redis's active expire cycle does.
+    /// Large values go to the lazy free worker. Returns the removed keys.
+    pub(crate) fn active_expire(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let mut removed = Vec::new();
+        for _ in 0..ACTIVE_EXPIRE_ROUNDS {
+            let mut expired: Vec<Vec<u8>> = self
+                .sample(true, ACTIVE_EXPIRE_SAMPLE)
+                .into_iter()
+                .filter(|(_, stored)| stored.is_expired(now))
+                .map(|(key, _)| key.clone())
+                .collect();
+            // Random picks may repeat a key.
+            expired.sort_unstable();
+            expired.dedup();
+            for key in &expired {
+                self.expire_now(key);
+            }
+            let enough = expired.len() * 4 <= ACTIVE_EXPIRE_SAMPLE;
+            removed.extend(expired);
+            if enough {
+                break;
+            }
+        }
+        removed
+    }
+
+    /// Removes an expired `key`, handing a large value to the lazy free worker.
+    fn expire_now(&mut self, key: &[u8]) {
+        if let Some((_, stored)) = self.remove(key) {
+            release(stored.value, true);
+        }
+    }
+
+    fn count(&mut self, key_len: usize, stored: &StoredValue, added: bool) {
+        let stored_len = stored.value.stored().len();
+        let size = ENTRY_OVERHEAD + key_len + stored_len;
+        let volatile = stored.expires_at.is_some() as usize;
+        let (compressed, original_len, compressed_len) = match stored.value.encoding() {
+            Encoding::Compressed(original_len) => (1, original_len, stored_len),
+            _ => (0, 0, 0),
+        };
+        let stats = &mut self.compression;
+        if added {
+            self.memory += size;
+            self.volatile += volatile;
+            stats.values += compressed;
+            stats.original_bytes += original_len;
+            stats.compressed_bytes += compressed_len;
+        } else {
+            self.memory -= size;
+            self.volatile -= volatile;
+            stats.values -= compressed;
+            stats.original_bytes -= original_len;
+            stats.compressed_bytes -= compressed_len;
+        }
+    }
+
+    /// Up to `count` random entries, only ones with an expiry when `volatile`.
+    fn sample(&self, volatile: bool, count: usize) -> Vec<(&Vec<u8>, &StoredValue)> {
+        if self.entries.len() == 0 || (volatile && self.volatile == 0) {
+            return Vec::new();
+        }
+        let mut sample = Vec::with_capacity(count);
+        if !volatile {
+            for _ in 0..count {
+                sample.extend(self.entries.random_entry());
+            }
+            return sample;
+        }
+
+        // Keys with an expiry may be rare: probe a few random ones, then walk
+        // on from a random bucket until enough turned up.
+        for _ in 0..count * 4 {
+            let entry = self.entries.random_entry();
+            sample.extend(entry.filter(|(_, stored)| stored.expires_at.is_some()));
+            if sample.len() == count {
+                return sample;
+            }
+        }
+        sample.extend(
+            self.entries
+                .iter_from(random() as usize)
+                .filter(|(_, stored)| stored.expires_at.is_some())
+                .take(count - sample.len()),
+        );
+        sample
+    }
+}
+
+/// Clones of the databases, taken at once: one set per lock for engines that
+/// split their keys. The clones share their entries with the engine, which
+/// copies what it writes to afterwards.
+pub(crate) struct DatabaseSnapshot {
+    parts: Vec<Vec<Database>>,
+    now: u64,
+}
+
+impl DatabaseSnapshot {
+    pub(crate) fn new(parts: Vec<Vec<Database>>, now: u64) -> Self {
+        Self { parts, now }
+    }
+}
+
+impl Snapshot for DatabaseSnapshot {
+    fn databases(&self) -> usize {
+        self.parts.first().map_or(0, Vec::len)
+    }
+
+    fn size_hint(&self, db: usize) -> (usize, usize) {
+        self.parts.iter().fold((0, 0), |(keys, volatile), part| {
+            (keys + part[db].len(), volatile + part[db].volatile)
+        })
+    }
+
+    fn entries(&self, db: usize) -> Box<dyn Iterator<Item = std::io::Result<Entry>> + '_> {
+        Box::new(
+            self.parts
+                .iter()
+                .flat_map(move |part| part[db].live(self.now))
+                .map(Ok),
+        )
+    }
+}
+
+/// What the databases behind one lock take, readable without the lock.
+#[derive(Default)]
+pub(crate) struct Usage {
+    memory: AtomicUsize,
+    keys: AtomicUsize,
+    compressed_values: AtomicUsize,
+    original_bytes: AtomicUsize,
+    compressed_bytes: AtomicUsize,
+}
+
+impl Usage {
+    /// Publishes the usage of `databases` after a write.
+    pub(crate) fn update(&self, databases: &[Database]) {
+        let mut memory = 0;
+        let mut keys = 0;
+        let mut compression = CompressionStats::default();
+        for database in databases {
+            memory += database.memory;
+            keys += database.len();
+            compression.values += database.compression.values;
+            compression.original_bytes += database.compression.original_bytes;
+            compression.compressed_bytes += database.compression.compressed_bytes;
+        }
+        self.memory.store(memory, Ordering::Relaxed);
+        self.keys.store(keys, Ordering::Relaxed);
+        self.compressed_values
+            .store(compression.values, Ordering::Relaxed);
+        self.original_bytes
+            .store(compression.original_bytes, Ordering::Relaxed);
+        self.compressed_bytes
+            .store(compression.compressed_bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn memory(&self) -> usize {
+        self.memory.load(Ordering::Relaxed)
+    }
+
+    /// The part of the memory taken by the entries' bookkeeping.
+    pub(crate) fn overhead(&self) -> usize {
+        self.keys.load(Ordering::Relaxed) * ENTRY_OVERHEAD
+    }
+
+    pub(crate) fn compression(&self) -> CompressionStats {
+        CompressionStats {
+            values: self.compressed_values.load(Ordering::Relaxed),
+            original_bytes: self.original_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sums the compression statistics of several locks' databases.
+pub(crate) fn total_compression<'a>(usages: impl Iterator<Item = &'a Usage>) -> CompressionStats {
+    usages.fold(CompressionStats::default(), |mut total, usage| {
+        let compression = usage.compression();
+        total.values += compression.values;
+        total.original_bytes += compression.original_bytes;
+        total.compressed_bytes += compression.compressed_bytes;
+        total
+    })
+}
+
+/// A key chosen for eviction, with its database and how good a choice it is.
+pub(crate) struct Candidate {
+    pub(crate) db: usize,
+    pub(crate) key: Vec<u8>,
+    pub(crate) score: u64,
+}
+
+/// Runs the active expire cycle on every database, returning the removed
+/// keys with their database.
+pub(crate) fn active_expire(databases: &mut [Database], now: u64) -> Vec<(usize, Vec<u8>)> {
+    databases
+        .iter_mut()
+        .enumerate()
+        .flat_map(|(db, database)| {
+            database
+                .active_expire(now)
+                .into_iter()
+                .map(move |key| (db, key))
+        })
+        .collect()
+}
+
+/// The best candidate for eviction among a sample of every database.
+pub(crate) fn eviction_candidate(
+    databases: &[Database],
+    limit: &Limit,
+    now: u64,
+) -> Option<Candidate> {
+    databases
+        .iter()
+        .enumerate()
+        .flat_map(|(db, database)| {
+            database
+                .sample(limit.policy.volatile(), limit.samples)
+                .into_iter()
+                .map(move |(key, stored)| Candidate {
+                    db,
+                    key: key.clone(),
+                    score: limit.policy.score(&stored.access, stored.expires_at, now),
+                })
+        })
+        .max_by_key(|candidate| candidate.score)
+}
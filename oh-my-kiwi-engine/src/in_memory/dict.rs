@@ -0,0 +1,515 @@
+//! The dictionary holding a database's keys: a chained hash table that,
+//! like Redis's, grows and shrinks incrementally. A resize only allocates
+//! the new table's index of chunks; later operations each move a bucket or
+//! so over, and lookups check both tables meanwhile. A write landing on a
+//! resize of millions of keys thus costs about what any other write does,
+//! instead of stalling every connection waiting on the lock while all keys
+//! are rehashed.
+//!
+//! Cloning a dictionary is how snapshots are taken, so it shares the buckets
+//! instead of copying them: they are split into chunks behind an `Arc`, and
+//! a write copies the chunk it lands in if a snapshot still holds it, much
+//! like a forked process copies the pages it writes to. Chunks are allocated
+//! on the first write to one of their buckets, and freed as soon as a
+//! rehash has emptied them, so neither cost lands on a single write.
+
+use crate::eviction::random;
+use std::hash::{BuildHasher, RandomState};
+use std::iter;
+use std::sync::Arc;
+
+const INITIAL_SIZE: usize = 4;
+/// Buckets a write moves to the new table while rehashing.
+const REHASH_STEP: usize = 1;
+/// Empty buckets a write may skip per bucket it should move, so a step
+/// stays short even across a sparse stretch of the table.
+const EMPTY_VISITS: usize = 10;
+/// Tables are shrunk once less than one bucket in this many is used.
+const MIN_FILL: usize = 8;
+/// Buckets per chunk, the unit copied when a write lands on a shared table.
+const CHUNK_SIZE: usize = 64;
+
+#[derive(Clone)]
+struct Node<V> {
+    hash: u64,
+    key: Vec<u8>,
+    value: V,
+    next: Option<Box<Node<V>>>,
+}
+
+/// Bytes the dictionary takes per entry holding a `V`: its node, and the
+/// bucket pointing to it at full load.
+pub(super) const fn entry_size<V>() -> usize {
+    size_of::<Node<V>>() + size_of::<Option<Box<Node<V>>>>()
+}
+
+type Bucket<V> = Option<Box<Node<V>>>;
+
+#[derive(Clone)]
+struct Table<V> {
+    /// `None` for a chunk whose buckets are all empty.
+    chunks: Vec<Option<Arc<Vec<Bucket<V>>>>>,
+    size: usize,
+    used: usize,
+}
+
+impl<V: Clone> Table<V> {
+    /// A table of `size` buckets, a power of two. Only the index of chunks is
+    /// allocated, a pointer per `CHUNK_SIZE` buckets; the chunks come with
+    /// the writes to them.
+    fn with_size(size: usize) -> Self {
+        Self {
+            chunks: iter::repeat_with(|| None)
+                .take(size.div_ceil(CHUNK_SIZE))
+                .collect(),
+            size,
+            used: 0,
+        }
+    }
+
+    /// Bucket `index`, for writing: its chunk is allocated first if it has
+    /// none yet, or copied if a snapshot shares it.
+    fn bucket_mut(&mut self, index: usize) -> &mut Bucket<V> {
+        let len = self.size.min(CHUNK_SIZE);
+        let chunk =
+            self.chunks[index / CHUNK_SIZE].get_or_insert_with(|| Arc::new(vec![None; len]));
+        &mut Arc::make_mut(chunk)[index % CHUNK_SIZE]
+    }
+
+    fn push(&mut self, mut node: Box<Node<V>>) {
+        let bucket = self.bucket_mut(self.bucket(node.hash));
+        node.next = bucket.take();
+        *bucket = Some(node);
+        self.used += 1;
+    }
+}
+
+impl<V> Table<V> {
+    fn bucket(&self, hash: u64) -> usize {
+        hash as usize & (self.size - 1)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn get(&self, index: usize) -> &Bucket<V> {
+        match &self.chunks[index / CHUNK_SIZE] {
+            Some(chunk) => &chunk[index % CHUNK_SIZE],
+            None => &None,
+        }
+    }
+
+    fn buckets(&self) -> impl Iterator<Item = &Bucket<V>> {
+        (0..self.size).map(|index| self.get(index))
+    }
+}
+
+impl<V> Default for Table<V> {
+    fn default() -> Self {
+        Self {
+            chunks: Vec::new(),
+            size: 0,
+            used: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Dict<V> {
+    /// The table in use and, while rehashing, the one it's moved into; the
+    /// second is empty otherwise.
+    tables: [Table<V>; 2],
+    /// Next bucket of the first table to move, while rehashing.
+    rehash_index: Option<usize>,
+    hasher: RandomState,
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Self {
+            tables: Default::default(),
+            rehash_index: None,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V: Clone> Dict<V> {
+    pub(crate) fn len(&self) -> usize {
+        self.tables[0].used + self.tables[1].used
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&V> {
+        let hash = self.hasher.hash_one(key);
+        self.live_tables()
+            .flat_map(|table| chain(table.get(table.bucket(hash))))
+            .find(|node| node.hash == hash && node.key == key)
+            .map(|node| &node.value)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        let hash = self.hasher.hash_one(key);
+        for table in self.tables.iter_mut().filter(|t| !t.is_empty()) {
+            let bucket = table.bucket(hash);
+            // Looked up first, so a miss doesn't copy a shared chunk.
+            if !chain(table.get(bucket)).any(|node| node.hash == hash && node.key == key) {
+                continue;
+            }
+            let mut node = table.bucket_mut(bucket).as_deref_mut();
+            while let Some(current) = node {
+                if current.hash == hash && current.key == key {
+                    return Some(&mut current.value);
+                }
+                node = current.next.as_deref_mut();
+            }
+        }
+        None
+    }
+
+    /// Inserts `key`, returning the value it replaced.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: V) -> Option<V> {
+        self.rehash_step();
+        if let Some(current) = self.get_mut(&key) {
+            return Some(std::mem::replace(current, value));
+        }
+        self.expand_if_needed();
+        let node = Box::new(Node {
+            hash: self.hasher.hash_one(&key),
+            key,
+            value,
+            next: None,
+        });
+        // New keys go straight to the new table while rehashing.
+        let target = if self.rehash_index.is_some() { 1 } else { 0 };
+        self.tables[target].push(node);
+        None
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<(Vec<u8>, V)> {
+        self.rehash_step();
+        let hash = self.hasher.hash_one(key);
+        let removed = self.tables.iter_mut().find_map(|table| {
+            let bucket = (!table.is_empty()).then(|| table.bucket(hash))?;
+            if !chain(table.get(bucket)).any(|node| node.hash == hash && node.key == key) {
+                return None;
+            }
+            let mut link = table.bucket_mut(bucket);
+            while link
+                .as_ref()
+                .is_some_and(|node| node.hash != hash || node.key != key)
+            {
+                link = &mut link.as_mut().unwrap().next;
+            }
+            let mut node = link.take()?;
+            *link = node.next.take();
+            table.used -= 1;
+            Some((node.key, node.value))
+        });
+        self.shrink_if_needed();
+        removed
+    }
+
+    /// A random entry, picked like Redis does: a random non-empty bucket,
+    /// then a random entry of its chain.
+    pub(crate) fn random_entry(&self) -> Option<(&Vec<u8>, &V)> {
+        if self.len() == 0 {
+            return None;
+        }
+        // Buckets of the first table before the rehash index are empty.
+        let skipped = self.rehash_index.unwrap_or(0);
+        let buckets = self.tables[0].size + self.tables[1].size - skipped;
+        let head = loop {
+            let position = skipped + random() as usize % buckets;
+            let bucket = match position.checked_sub(self.tables[0].size) {
+                None => self.tables[0].get(position),
+                Some(position) => self.tables[1].get(position),
+            };
+            if let Some(head) = bucket {
+                break head;
+            }
+        };
+        let len = chain_from(head).count();
+        chain_from(head)
+            .nth(random() as usize % len)
+            .map(|node| (&node.key, &node.value))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &V)> {
+        self.iter_from(0)
+    }
+
+    /// Every entry, starting at bucket `start` of both tables together and
+    /// wrapping around.
+    pub(crate) fn iter_from(&self, start: usize) -> impl Iterator<Item = (&Vec<u8>, &V)> {
+        let [first, second] = &self.tables;
+        let buckets = || first.buckets().chain(second.buckets());
+        let start = start % (first.size + second.size).max(1);
+        buckets()
+            .skip(start)
+            .chain(buckets().take(start))
+            .flat_map(|bucket| chain(bucket))
+            .map(|node| (&node.key, &node.value))
+    }
+
+    /// Whether a resize is still moving buckets over. Lookups should then
+    /// call `rehash_step` as writes do, whenever they can get the dictionary
+    /// mutably without waiting.
+    pub(crate) fn is_rehashing(&self) -> bool {
+        self.rehash_index.is_some()
+    }
+
+    fn live_tables(&self) -> impl Iterator<Item = &Table<V>> {
+        self.tables.iter().filter(|table| !table.is_empty())
+    }
+
+    /// Grows the table once there are as many keys as buckets. A shrink
+    /// outgrown by the keys added since it started is turned around: the
+    /// larger table takes the new keys again, and the entries already moved
+    /// are moved back to it.
+    fn expand_if_needed(&mut self) {
+        let [table, target] = &self.tables;
+        if table.is_empty() {
+            self.tables[0] = Table::with_size(INITIAL_SIZE);
+        } else if self.rehash_index.is_none() {
+            if table.used >= table.size {
+                self.resize(table.size * 2);
+            }
+        } else if target.used >= target.size && target.size < table.size {
+            self.tables.swap(0, 1);
+            self.rehash_index = Some(0);
+        }
+    }
+
+    fn shrink_if_needed(&mut self) {
+        let table = &self.tables[0];
+        if self.rehash_index.is_none()
+            && table.size > INITIAL_SIZE
+            && table.used * MIN_FILL < table.size
+        {
+            self.resize(table.used.next_power_of_two().max(INITIAL_SIZE));
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.tables[1] = Table::with_size(size);
+        self.rehash_index = Some(0);
+    }
+
+    /// Moves up to `REHASH_STEP` buckets to the new table, freeing the chunks
+    /// of the old one it empties, and makes the new table the one in use once
+    /// the old one is empty.
+    pub(crate) fn rehash_step(&mut self) {
+        let Some(mut index) = self.rehash_index else {
+            return;
+        };
+        let [old, new] = &mut self.tables;
+        let mut moved = 0;
+        let mut visits = REHASH_STEP * EMPTY_VISITS;
+        while moved < REHASH_STEP && index < old.size {
+            let taken = match old.get(index) {
+                Some(_) => old.bucket_mut(index).take(),
+                None => None,
+            };
+            index += 1;
+            if index % CHUNK_SIZE == 0 {
+                old.chunks[index / CHUNK_SIZE - 1] = None;
+            }
+            let Some(mut node) = taken else {
+                visits -= 1;
+                if visits == 0 {
+                    break;
+                }
+                continue;
+            };
+            loop {
+                let next = node.next.take();
+                old.used -= 1;
+                new.push(node);
+                match next {
+                    Some(next) => node = next,
+                    None => break,
+                }
+            }
+            moved += 1;
+        }
+
+        if index < old.size {
+            self.rehash_index = Some(index);
+        } else {
+            self.tables[0] = std::mem::take(&mut self.tables[1]);
+            self.rehash_index = None;
+        }
+    }
+}
+
+fn chain<V>(bucket: &Bucket<V>) -> impl Iterator<Item = &Node<V>> {
+    iter::successors(bucket.as_deref(), |node| node.next.as_deref())
+}
+
+fn chain_from<V>(head: &Node<V>) -> impl Iterator<Item = &Node<V>> {
+    iter::successors(Some(head), |node| node.next.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key:{i}").into_bytes()
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut dict = Dict::default();
+        for i in 0..10_000 {
+            assert_eq!(dict.insert(key(i), i), None);
+        }
+        assert_eq!(dict.insert(key(7), 70), Some(7));
+        assert_eq!(dict.len(), 10_000);
+        assert!((0..10_000).all(|i| dict.get(&key(i)).is_some()));
+        *dict.get_mut(&key(8)).unwrap() = 80;
+        assert_eq!(dict.get(&key(8)), Some(&80));
+
+        assert_eq!(dict.remove(&key(7)), Some((key(7), 70)));
+        for i in (0..9_990).filter(|&i| i != 7) {
+            assert_eq!(dict.remove(&key(i)).map(|(key, _)| key), Some(key(i)));
+        }
+        assert_eq!(dict.remove(&key(0)), None);
+        assert_eq!(dict.len(), 10);
+        let keys: HashSet<_> = dict.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, (9_990..10_000).map(key).collect());
+        assert_eq!(dict.iter_from(3).count(), 10);
+        // Shrinking is incremental too; later writes finish it.
+        for _ in 0..10_000 {
+            dict.remove(b"missing");
+        }
+        assert!(!dict.is_rehashing());
+        assert_eq!(dict.tables[0].size, 16);
+    }
+
+    #[test]
+    fn test_clone_is_copy_on_write() {
+        let mut dict = Dict::default();
+        for i in 0..10_000 {
+            dict.insert(key(i), i);
+        }
+        let snapshot = dict.clone();
+        let shared = |dict: &Dict<usize>| {
+            let chunks = || {
+                dict.tables
+                    .iter()
+                    .flat_map(|table| table.chunks.iter().flatten())
+            };
+            chunks()
+                .filter(|chunk| Arc::strong_count(chunk) > 1)
+                .count()
+        };
+        let chunks = shared(&dict);
+        assert!(chunks > 0);
+
+        *dict.get_mut(&key(1)).unwrap() = 10;
+        dict.remove(&key(2));
+        dict.insert(key(10_000), 10_000);
+        assert!(dict.get_mut(b"missing").is_none());
+        // Only the chunks written to were copied.
+        assert!(shared(&dict) >= chunks - 6);
+
+        assert_eq!(snapshot.get(&key(1)), Some(&1));
+        assert_eq!(snapshot.get(&key(2)), Some(&2));
+        assert_eq!(snapshot.get(&key(10_000)), None);
+        assert_eq!(snapshot.len(), 10_000);
+        assert_eq!(dict.get(&key(1)), Some(&10));
+        assert_eq!(dict.len(), 10_000);
+    }
+
+    #[test]
+    fn test_outgrown_shrink_turns_around() {
+        let mut dict = Dict::default();
+        for i in 0..1 << 16 {
+            dict.insert(key(i), ());
+        }
+        while dict.is_rehashing() {
+            dict.rehash_step();
+        }
+        let mut removed = 0;
+        while !dict.is_rehashing() {
+            dict.remove(&key(removed));
+            removed += 1;
+        }
+        assert!(dict.tables[1].size < dict.tables[0].size);
+
+        // Keys come back faster than the sparse old table drains.
+        for i in 0..removed {
+            dict.insert(key(i), ());
+            let target = &dict.tables[1];
+            assert!(!dict.is_rehashing() || target.used <= target.size);
+        }
+        assert_eq!(dict.len(), 1 << 16);
+        assert!((0..1 << 16).all(|i| dict.get(&key(i)).is_some()));
+    }
+
+    #[test]
+    fn test_random_entry() {
+        let mut dict = Dict::default();
+        assert!(dict.random_entry().is_none());
+        for i in 0..100 {
+            dict.insert(key(i), i);
+        }
+        let seen: HashSet<_> = (0..10_000)
+            .map(|_| *dict.random_entry().unwrap().1)
+            .collect();
+        assert!(seen.len() > 90);
+    }
+
+    fn allocated_chunks(dict: &Dict<()>) -> usize {
+        let chunks = dict.tables.iter().flat_map(|table| &table.chunks);
+        chunks.filter(|chunk| chunk.is_some()).count()
+    }
+
+    /// The latency of a write is bounded by the entries it moves to the new
+    /// table and the chunks it allocates, which stay a handful however large
+    /// the table grows, where a one-shot rehash moves every key on the write
+    /// that triggers it.
+    #[test]
+    fn test_insert_latency_stays_flat() {
+        let mut dict = Dict::default();
+        let mut worst = 0;
+        let mut most_allocated = 0;
+        let mut longest_rehash = 0;
+        let mut rehashing_for = 0;
+        for i in 0..1 << 17 {
+            let old = dict.tables[0].used;
+            let rehashing = dict.is_rehashing();
+            // Counting the chunks takes a walk over them, so only early on.
+            let chunks = (i < 1 << 13).then(|| allocated_chunks(&dict));
+            dict.insert(key(i), ());
+            if let Some(chunks) = chunks {
+                let allocated = allocated_chunks(&dict).saturating_sub(chunks);
+                most_allocated = most_allocated.max(allocated);
+            }
+            if rehashing {
+                let moved = old - dict.tables[0].used.min(old);
+                worst = worst.max(moved);
+            }
+            rehashing_for = if dict.is_rehashing() {
+                rehashing_for + 1
+            } else {
+                0
+            };
+            longest_rehash = longest_rehash.max(rehashing_for);
+        }
+        assert!(worst <= 16, "a write moved {worst} entries");
+        // The chunk the key lands in, and those a moved bucket splits into.
+        assert!(
+            most_allocated <= 3,
+            "a write allocated {most_allocated} chunks"
+        );
+        // Rehashing freed the old table's chunks as it went.
+        assert_eq!(allocated_chunks(&dict), dict.tables[0].chunks.len());
+        // Growing to 2^17 keys spread the last resize over thousands of writes.
+        assert!(longest_rehash >= 1 << 15);
+        assert_eq!(dict.len(), 1 << 17);
+    }
+}
@@ -0,0 +1,100 @@
+//! A background worker freeing memory away from the engine locks. UNLINK,
+//! FLUSHDB/FLUSHALL ASYNC, overwrites and expirations hand what they
+//! removed to it instead of dropping it on the spot, so a huge value or a
+//! whole database doesn't stall every connection waiting on the lock.
+
+use crate::encoding::Value;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+
+/// Values of at least this many stored bytes are freed in the background;
+/// smaller ones cost less to drop in place than to hand off.
+pub(crate) const LAZYFREE_THRESHOLD: usize = 64 * 1024;
+
+type Garbage = Box<dyn Send>;
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicU64 = AtomicU64::new(0);
+
+/// The worker is started the first time anything is handed to it.
+static WORKER: LazyLock<Sender<Garbage>> = LazyLock::new(|| {
+    let (sender, receiver) = channel::<Garbage>();
+    thread::Builder::new()
+        .name("lazy-free".to_string())
+        .spawn(move || {
+            for garbage in receiver {
+                drop(garbage);
+                PENDING.fetch_sub(1, Ordering::Relaxed);
+                FREED.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .expect("failed to spawn the lazy free thread");
+    sender
+});
+
+/// Drops `value` on the background worker.
+fn free_later<T: Send + 'static>(value: T) {
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    if let Err(unsent) = WORKER.send(Box::new(value)) {
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+        drop(unsent);
+    }
+}
+
+/// Drops `value` outside of the storage lock, on the background worker when `lazy`.
+pub(crate) fn free<T: Send + 'static>(value: T, lazy: bool) {
+    if lazy {
+        free_later(value);
+    } else {
+        drop(value);
+    }
+}
+
+/// Drops a removed value, on the background worker when `lazy` and the
+/// value is large.
+pub(crate) fn release(value: Value, lazy: bool) {
+    if lazy && value.stored().len() >= LAZYFREE_THRESHOLD {
+        free_later(value);
+    }
+}
+
+/// Values and databases handed to the worker and not freed yet.
+pub fn pending_objects() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// Values and databases the worker has freed since the start.
+pub fn freed_objects() -> u64 {
+    FREED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, SyncSender};
+    use std::time::Duration;
+
+    /// Reports the thread it's dropped on.
+    struct Tracked(SyncSender<Option<String>>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            let _ = self.0.send(thread::current().name().map(str::to_string));
+        }
+    }
+
+    #[test]
+    fn test_free() {
+        let (sender, dropped) = mpsc::sync_channel(2);
+        free(Tracked(sender.clone()), true);
+        let thread = dropped.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(thread.as_deref(), Some("lazy-free"));
+
+        free(Tracked(sender), false);
+        let thread = dropped.try_recv().unwrap();
+        assert_ne!(thread.as_deref(), Some("lazy-free"));
+        assert!(freed_objects() >= 1);
+    }
+}
@@ -1,3 +1,23 @@
+pub mod acl;
+pub mod aof;
+pub mod auth;
+pub mod cluster;
 pub mod command_processor;
+pub mod compression;
+pub mod config;
+pub mod encoding;
+pub mod eviction;
+pub mod glob;
 pub mod in_memory;
+pub mod lazy_free;
+pub mod lsm;
+pub mod memory;
+pub mod propagation;
+pub mod replication;
 pub mod response_writer;
+pub mod sharded;
+pub mod shared_nothing;
+pub mod snapshot;
+pub mod tiered;
+pub mod time;
+pub mod tracking;
@@ -0,0 +1,1353 @@
+//! A disk-backed engine for data sets larger than memory, built as a
+//! log-structured merge tree. Writes go to a sorted memtable. Once it grows
+//! past `lsm-memtable-size` it is frozen, and written out as a segment file
+//! on a blocking thread while a fresh memtable takes the writes. Lookups
+//! check the memtables, then the segments from newest to oldest, reading
+//! segments without holding the lock. In the background, every four
+//! segments of a level are merged into one of the next level, dropping
+//! overwritten records, and deleted ones once nothing older is left.
+//!
+//! Each database maps to a namespace of keys, so SWAPDB swaps two numbers
+//! and FLUSHDB moves the database to a fresh namespace, whose old records
+//! compaction drops.
+//!
+//! Writes are also appended to the write-ahead log of their memtable, which
+//! goes once the memtable is on disk, and synced as `lsm-wal-fsync` says. On
+//! start, the segments of the earlier run are reopened from their footers and
+//! the logs replayed, the key counts coming from the logs as well, so the
+//! store keeps the data set across restarts without reading it through;
+//! snapshots and the AOF are only loaded into an empty one.
+
+mod segment;
+mod wal;
+
+use crate::aof::AppendFsync;
+use crate::config::KiwiConfig;
+use crate::encoding;
+use crate::eviction::Limit;
+use crate::lazy_free::free;
+use crate::time::now_ms;
+use async_trait::async_trait;
+use oh_my_kiwi_domain::{
+    AccessHistory, CompressionStats, Engine, Entry, MemoryStats, Snapshot, ValueType,
+};
+use segment::{Key, Record, Segment, SegmentWriter, TEMP_EXTENSION};
+use wal::{Logged, Wal};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Result;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock, RwLockWriteGuard};
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+pub const DEFAULT_LSM_DIR: &str = "lsm";
+pub const DEFAULT_LSM_MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Segments of a level merged at once.
+const COMPACTION_FANIN: usize = 4;
+const SEGMENT_EXTENSION: &str = "seg";
+const WAL_EXTENSION: &str = "wal";
+
+pub struct LsmEngine {
+    databases: usize,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    path: PathBuf,
+    memtable_size: usize,
+    wal_fsync: AppendFsync,
+    limit: Limit,
+    state: RwLock<State>,
+    /// Memory of the memtables and of the segment indexes.
+    used_memory: AtomicUsize,
+    /// The part of `used_memory` that isn't keys and values.
+    overhead: AtomicUsize,
+    /// Held while frozen memtables are written out, one at a time.
+    flushing: Mutex<()>,
+    compacting: AtomicBool,
+}
+
+struct State {
+    /// The namespace of each database.
+    namespaces: Vec<u32>,
+    next_namespace: u32,
+    /// Keys of each database whose newest record is a value, expired or not.
+    counts: Vec<usize>,
+    memtable: BTreeMap<Key, Record>,
+    memtable_bytes: usize,
+    /// The memtable's id, which its log and its segment are named after.
+    memtable_id: u64,
+    wal: Wal,
+    /// Full memtables waiting to be written out, newest first.
+    frozen: Vec<Arc<Frozen>>,
+    /// Newest first.
+    segments: Vec<Arc<Segment>>,
+    /// The id of the next memtable.
+    next_memtable: u64,
+    /// Bumped whenever a frozen memtable becomes a segment, so that a lookup
+    /// made on disk without the lock can tell whether it still holds.
+    flushes: u64,
+}
+
+/// A full memtable, read-only while it is written out.
+struct Frozen {
+    id: u64,
+    records: BTreeMap<Key, Record>,
+    bytes: usize,
+}
+
+impl State {
+    /// The newest record of `key` held in memory.
+    fn find_in_memory(&self, key: &Key) -> Option<&Record> {
+        self.memtable.get(key).or_else(|| {
+            self.frozen
+                .iter()
+                .find_map(|frozen| frozen.records.get(key))
+        })
+    }
+
+    /// The segments that may hold `key`, newest first.
+    fn segments_for(&self, key: &Key) -> Vec<Arc<Segment>> {
+        self.segments
+            .iter()
+            .filter(|segment| segment.may_contain(key))
+            .cloned()
+            .collect()
+    }
+
+    fn write(&mut self, key: Key, record: Record) {
+        if let Err(err) = self.wal.append(&key, &record) {
+            error!("Writing to {} failed: {err}", self.wal.path().display());
+        }
+        let size = size_of::<Key>() + key.1.len();
+        self.memtable_bytes += size + record.size();
+        if let Some(old) = self.memtable.insert(key, record) {
+            self.memtable_bytes -= size + old.size();
+        }
+    }
+
+    /// Writes `record` over the newest record of `key`, keeping the count of
+    /// keys of `db` right.
+    fn replace(&mut self, db: usize, key: Key, old: Option<&Record>, record: Record) {
+        let was_put = matches!(old, Some(Record::Put { .. }));
+        let is_put = matches!(record, Record::Put { .. });
+        self.counts[db] = self.counts[db] + is_put as usize - was_put as usize;
+        self.write(key, record);
+    }
+
+    /// Logs the namespaces after they changed.
+    fn log_namespaces(&mut self) {
+        if let Err(err) =
+            self.wal
+                .append_namespaces(&self.namespaces, &self.counts, self.next_namespace)
+        {
+            error!("Writing to {} failed: {err}", self.wal.path().display());
+        }
+    }
+
+    /// Moves the memtable to the frozen ones, the next one logging to `wal`.
+    fn freeze(&mut self, wal: Wal) {
+        let frozen = Frozen {
+            id: std::mem::replace(&mut self.memtable_id, self.next_memtable),
+            records: std::mem::take(&mut self.memtable),
+            bytes: std::mem::take(&mut self.memtable_bytes),
+        };
+        self.next_memtable += 1;
+        self.wal = wal;
+        self.frozen.insert(0, Arc::new(frozen));
+    }
+
+    fn key(&self, db: usize, key: &[u8]) -> Key {
+        (self.namespaces[db], key.to_vec())
+    }
+
+    fn memory(&self) -> usize {
+        let frozen: usize = self.frozen.iter().map(|frozen| frozen.bytes).sum();
+        self.memtable_bytes + frozen + self.segment_memory()
+    }
+
+    /// The memtables' bookkeeping for their records, and the segment indexes.
+    fn overhead(&self) -> usize {
+        let records = self.memtable.len()
+            + self
+                .frozen
+                .iter()
+                .map(|frozen| frozen.records.len())
+                .sum::<usize>();
+        records * (size_of::<Key>() + size_of::<Record>()) + self.segment_memory()
+    }
+
+    fn segment_memory(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.memory())
+            .sum::<usize>()
+    }
+
+    /// Reopens the segments an earlier run left in `dir`, and replays the
+    /// logs of the memtables it didn't write out, which stay frozen until the
+    /// next flush. The records then start a new log.
+    ///
+    /// The key counts are the ones last logged with the namespaces, adjusted
+    /// for each record replayed after them by looking up the record it
+    /// replaced. Only without any log are the records counted one by one.
+    fn recover(dir: &Path, databases: usize, fsync: AppendFsync) -> Result<Self> {
+        let mut found = Vec::new();
+        let mut logs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some(SEGMENT_EXTENSION) => {
+                    if let Some((ids, level)) = parse_segment_name(stem) {
+                        found.push((ids, level, path));
+                    }
+                }
+                Some(WAL_EXTENSION) => {
+                    if let Ok(id) = stem.parse::<u64>() {
+                        logs.push((id, path));
+                    }
+                }
+                Some(TEMP_EXTENSION) => std::fs::remove_file(&path)?,
+                _ => {}
+            }
+        }
+        let mut next_memtable = found
+            .iter()
+            .map(|((_, last), _, _)| last + 1)
+            .chain(logs.iter().map(|(id, _)| id + 1))
+            .max()
+            .unwrap_or(0);
+
+        // A compaction cut short leaves its inputs next to its output.
+        let (stale, mut kept): (Vec<_>, Vec<_>) =
+            found.iter().partition(|((first, last), level, _)| {
+                found
+                    .iter()
+                    .any(|((outer_first, outer_last), outer_level, _)| {
+                        outer_level > level && outer_first <= first && last <= outer_last
+                    })
+            });
+        for (_, _, path) in stale {
+            std::fs::remove_file(path)?;
+        }
+        kept.sort_by_key(|((_, last), _, _)| Reverse(*last));
+        let segments = kept
+            .into_iter()
+            .map(|(ids, level, path)| Segment::open(path.clone(), *level, *ids).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+
+        let flushed = segments.iter().map(|segment| segment.ids.1).max();
+        let mut namespaces: Vec<u32> = (0..databases as u32).collect();
+        let mut next_namespace = databases as u32;
+        let mut counts: Option<Vec<usize>> = None;
+        let mut frozen: Vec<Arc<Frozen>> = Vec::new();
+        let mut empty = Vec::new();
+        logs.sort();
+        for (id, path) in logs {
+            if flushed.is_some_and(|flushed| id <= flushed) {
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            let mut records = BTreeMap::new();
+            for logged in wal::read(&path)? {
+                match logged {
+                    Logged::Record(key, record) => {
+                        let db = namespaces.iter().position(|&namespace| namespace == key.0);
+                        if let (Some(counts), Some(db)) = (&mut counts, db) {
+                            let old = match records.get(&key).or_else(|| {
+                                frozen.iter().find_map(|frozen| frozen.records.get(&key))
+                            }) {
+                                Some(old) => Some(old.clone()),
+                                None => find_in_segments(&segments, &key)?,
+                            };
+                            let was_put = matches!(old, Some(Record::Put { .. }));
+                            let is_put = matches!(record, Record::Put { .. });
+                            counts[db] =
+                                (counts[db] + is_put as usize).saturating_sub(was_put as usize);
+                        }
+                        records.insert(key, record);
+                    }
+                    Logged::Namespaces {
+                        namespaces: logged,
+                        counts: logged_counts,
+                        next_namespace: next,
+                    } => {
+                        (namespaces, next_namespace) = (logged, next);
+                        counts = Some(logged_counts);
+                    }
+                }
+            }
+            if records.is_empty() {
+                empty.push(path);
+                continue;
+            }
+            let bytes = memtable_bytes(&records);
+            frozen.insert(0, Arc::new(Frozen { id, records, bytes }));
+        }
+        let mut counts = match counts {
+            Some(counts) => counts,
+            None => namespaces
+                .iter()
+                .map(|&namespace| {
+                    let mut count = 0;
+                    for item in records(&frozen, &segments, namespace) {
+                        count += matches!(item?.1, Record::Put { .. }) as usize;
+                    }
+                    Ok(count)
+                })
+                .collect::<Result<Vec<_>>>()?,
+        };
+        namespaces.truncate(databases);
+        counts.truncate(databases);
+        while namespaces.len() < databases {
+            namespaces.push(next_namespace);
+            counts.push(0);
+            next_namespace += 1;
+        }
+
+        let memtable_id = next_memtable;
+        next_memtable += 1;
+        let wal = Wal::create(
+            wal_path(dir, memtable_id),
+            fsync,
+            &namespaces,
+            &counts,
+            next_namespace,
+        )?;
+        // The new log holds the namespaces now, so the empty ones can go.
+        for path in empty {
+            std::fs::remove_file(path)?;
+        }
+        Ok(Self {
+            namespaces,
+            next_namespace,
+            counts,
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            memtable_id,
+            wal,
+            frozen,
+            segments,
+            next_memtable,
+            flushes: 0,
+        })
+    }
+}
+
+/// The newest record of `key` among `segments`, newest first.
+fn find_in_segments(segments: &[Arc<Segment>], key: &Key) -> Result<Option<Record>> {
+    for segment in segments {
+        if let Some(record) = segment.get(key)? {
+            return Ok(Some(record));
+        }
+    }
+    Ok(None)
+}
+
+/// Syncs the log `wal` on a blocking thread, if it has appends not synced yet.
+fn sync_wal(wal: &mut Wal) -> Option<JoinHandle<()>> {
+    let file = wal.take_unsynced()?;
+    let path = wal.path().to_path_buf();
+    Some(tokio::task::spawn_blocking(move || {
+        if let Err(err) = file.and_then(|file| file.sync_data()) {
+            error!("Syncing {} failed: {err}", path.display());
+        }
+    }))
+}
+
+/// Syncs the log of the memtable being written to once a second, for as
+/// long as the engine lives.
+async fn run_wal_fsync(shared: Weak<Shared>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let sync = sync_wal(&mut shared.state.write().await.wal);
+        if let Some(sync) = sync {
+            let _ = sync.await;
+        }
+    }
+}
+
+/// The bytes the records of a memtable take.
+fn memtable_bytes(records: &BTreeMap<Key, Record>) -> usize {
+    records
+        .iter()
+        .map(|(key, record)| size_of::<Key>() + key.1.len() + record.size())
+        .sum()
+}
+
+/// Segments are named after the memtables they hold and their level.
+fn segment_path(dir: &Path, (first, last): (u64, u64), level: u32) -> PathBuf {
+    dir.join(format!(
+        "{first:010}-{last:010}-{level}.{SEGMENT_EXTENSION}"
+    ))
+}
+
+fn parse_segment_name(stem: &str) -> Option<((u64, u64), u32)> {
+    let mut parts = stem.split('-');
+    let first = parts.next()?.parse().ok()?;
+    let last = parts.next()?.parse().ok()?;
+    let level = parts.next()?.parse().ok()?;
+    parts.next().is_none().then_some(((first, last), level))
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:010}.{WAL_EXTENSION}"))
+}
+
+impl LsmEngine {
+    /// An engine storing its segments in the configured directory, holding
+    /// the keys an earlier run left there. Under `everysec`, it syncs its log
+    /// from a task of the current runtime.
+    pub fn from_config(config: &KiwiConfig) -> std::io::Result<Self> {
+        let path = config.lsm_path();
+        std::fs::create_dir_all(&path)?;
+        let databases = config.databases().max(1);
+        let wal_fsync = config.lsm_wal_fsync();
+        let state = State::recover(&path, databases, wal_fsync)?;
+        let shared = Arc::new(Shared {
+            path,
+            memtable_size: config.lsm_memtable_size().max(1),
+            wal_fsync,
+            limit: Limit::from_config(config),
+            used_memory: AtomicUsize::new(state.memory()),
+            overhead: AtomicUsize::new(state.overhead()),
+            state: RwLock::new(state),
+            flushing: Mutex::new(()),
+            compacting: AtomicBool::new(false),
+        });
+        if wal_fsync == AppendFsync::EverySec {
+            tokio::spawn(run_wal_fsync(Arc::downgrade(&shared)));
+        }
+        Ok(Self { databases, shared })
+    }
+
+    /// Segment files on disk, and the bytes they take.
+    pub async fn segments(&self) -> (usize, u64) {
+        let state = self.shared.state.read().await;
+        let bytes = state
+            .segments
+            .iter()
+            .map(|segment| segment.disk_size())
+            .sum();
+        (state.segments.len(), bytes)
+    }
+
+    /// The newest record of `key` in `db`.
+    async fn find(&self, db: usize, key: &[u8]) -> Option<Record> {
+        let (key, segments) = {
+            let state = self.shared.state.read().await;
+            let key = state.key(db, key);
+            if let Some(record) = state.find_in_memory(&key) {
+                return Some(record.clone());
+            }
+            let segments = state.segments_for(&key);
+            (key, segments)
+        };
+        read_segments(segments, key).await
+    }
+
+    /// Write-locks the store along with the newest record of `key` in `db`.
+    async fn lock_record(
+        &self,
+        db: usize,
+        key: &[u8],
+    ) -> (RwLockWriteGuard<'_, State>, Key, Option<Record>) {
+        let (state, [(key, record)]) = self.lock_records([(db, key)]).await;
+        (state, key, record)
+    }
+
+    /// Write-locks the store along with the newest record of each key. Keys
+    /// only found on disk are read without the lock, then read again if a
+    /// flush moved records to a new segment meanwhile.
+    async fn lock_records<const N: usize>(
+        &self,
+        keys: [(usize, &[u8]); N],
+    ) -> (RwLockWriteGuard<'_, State>, [(Key, Option<Record>); N]) {
+        let mut read: [Option<(u64, Key, Option<Record>)>; N] = std::array::from_fn(|_| None);
+        loop {
+            let state = self.shared.state.write().await;
+            let mut unread = Vec::new();
+            let records = std::array::from_fn(|index| {
+                let (db, key) = keys[index];
+                let key = state.key(db, key);
+                if let Some(record) = state.find_in_memory(&key) {
+                    return (key, Some(record.clone()));
+                }
+                match &read[index] {
+                    Some((flushes, read_key, record))
+                        if *flushes == state.flushes && *read_key == key =>
+                    {
+                        (key, record.clone())
+                    }
+                    _ => {
+                        let segments = state.segments_for(&key);
+                        if !segments.is_empty() {
+                            unread.push((index, key.clone(), segments));
+                        }
+                        (key, None)
+                    }
+                }
+            });
+            if unread.is_empty() {
+                return (state, records);
+            }
+
+            let flushes = state.flushes;
+            drop(state);
+            for (index, key, segments) in unread {
+                let record = read_segments(segments, key.clone()).await;
+                read[index] = Some((flushes, key, record));
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// Freezes the memtable once it is full.
+    fn after_write(self: &Arc<Self>, state: &mut State) {
+        if state.memtable_bytes >= self.memtable_size {
+            self.freeze(state);
+        }
+        self.publish_memory(state);
+    }
+
+    /// Freezes the memtable and writes it out in the background, syncing
+    /// what its log holds meanwhile. Without a log for the next memtable, the
+    /// current one keeps the writes.
+    fn freeze(self: &Arc<Self>, state: &mut State) {
+        if state.memtable.is_empty() {
+            return;
+        }
+        let path = wal_path(&self.path, state.next_memtable);
+        let wal = Wal::create(
+            path.clone(),
+            self.wal_fsync,
+            &state.namespaces,
+            &state.counts,
+            state.next_namespace,
+        );
+        match wal {
+            Ok(wal) => {
+                sync_wal(&mut state.wal);
+                state.freeze(wal);
+                tokio::spawn(self.clone().flush_frozen());
+            }
+            Err(err) => error!("Creating {} failed: {err}", path.display()),
+        }
+    }
+
+    fn publish_memory(&self, state: &State) {
+        self.used_memory.store(state.memory(), Ordering::Relaxed);
+        self.overhead.store(state.overhead(), Ordering::Relaxed);
+    }
+
+    /// Writes the frozen memtables out as segments, oldest first, on a
+    /// blocking thread, then drops their logs. A memtable that fails to be
+    /// written stays in memory, to be written out with the next one.
+    async fn flush_frozen(self: Arc<Self>) {
+        let _flushing = self.flushing.lock().await;
+        loop {
+            let Some(frozen) = self.state.read().await.frozen.last().cloned() else {
+                return;
+            };
+            let ids = (frozen.id, frozen.id);
+            let path = segment_path(&self.path, ids, 0);
+            let written = {
+                let (path, frozen) = (path.clone(), frozen.clone());
+                tokio::task::spawn_blocking(move || {
+                    let mut writer = SegmentWriter::create(path)?;
+                    for (key, record) in &frozen.records {
+                        writer.add(key, record)?;
+                    }
+                    writer.finish(0, ids)
+                })
+                .await
+                .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+            };
+            let segment = match written {
+                Ok(segment) => segment,
+                Err(err) => {
+                    error!("Writing the memtable to {} failed: {err}", path.display());
+                    return;
+                }
+            };
+
+            let mut state = self.state.write().await;
+            // FLUSHALL drops the frozen memtables, and what they were written to.
+            if !state
+                .frozen
+                .last()
+                .is_some_and(|last| Arc::ptr_eq(last, &frozen))
+            {
+                drop(state);
+                if segment.is_some() {
+                    remove_files(vec![path]);
+                }
+                continue;
+            }
+            state.frozen.pop();
+            if let Some(segment) = segment {
+                debug!(
+                    "Flushed {} records to {}",
+                    frozen.records.len(),
+                    path.display()
+                );
+                state.segments.insert(0, Arc::new(segment));
+            }
+            state.flushes += 1;
+            self.publish_memory(&state);
+            self.compact(&mut state);
+            drop(state);
+            remove_files(vec![wal_path(&self.path, frozen.id)]);
+        }
+    }
+
+    /// Merges the oldest segments of the first level holding enough of them,
+    /// on a blocking thread, unless a compaction is already running.
+    fn compact(self: &Arc<Self>, state: &mut State) {
+        let mut start = 0;
+        let group = loop {
+            if start >= state.segments.len() {
+                return;
+            }
+            let level = state.segments[start].level;
+            let end = start
+                + state.segments[start..]
+                    .iter()
+                    .take_while(|segment| segment.level == level)
+                    .count();
+            if end - start >= COMPACTION_FANIN {
+                break state.segments[end - COMPACTION_FANIN..end].to_vec();
+            }
+            start = end;
+        };
+        if self.compacting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let ids = (group[COMPACTION_FANIN - 1].ids.0, group[0].ids.1);
+        // Deletes only shadow older records, so they go once none are left.
+        let drop_deletes = Arc::ptr_eq(
+            &group[COMPACTION_FANIN - 1],
+            &state.segments[state.segments.len() - 1],
+        );
+        let namespaces: HashSet<u32> = state.namespaces.iter().copied().collect();
+        let shared = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let level = group[0].level + 1;
+            let path = segment_path(&shared.path, ids, level);
+            let sources = group
+                .iter()
+                .map(|segment| Box::new(segment.iter(&(0, Vec::new()))) as Source)
+                .collect();
+            let merged = SegmentWriter::create(path.clone()).and_then(|mut writer| {
+                for item in Merge::new(sources) {
+                    let (key, record) = item?;
+                    if namespaces.contains(&key.0) && !(drop_deletes && record == Record::Delete) {
+                        writer.add(&key, &record)?;
+                    }
+                }
+                writer.finish(level, ids)
+            });
+
+            let mut state = shared.state.blocking_write();
+            shared.compacting.store(false, Ordering::Release);
+            let merged = match merged {
+                Ok(merged) => merged,
+                Err(err) => {
+                    drop(state);
+                    error!("Compacting into {} failed: {err}", path.display());
+                    let _ = std::fs::remove_file(&path);
+                    return;
+                }
+            };
+            // Newer segments may have been flushed meanwhile, never older ones.
+            let Some(position) = state
+                .segments
+                .iter()
+                .position(|segment| Arc::ptr_eq(segment, &group[0]))
+            else {
+                drop(state);
+                let _ = std::fs::remove_file(&path);
+                return;
+            };
+            let records = merged.as_ref().map_or(0, |merged| merged.records());
+            state
+                .segments
+                .splice(position..position + group.len(), merged.map(Arc::new));
+            debug!(
+                "Compacted {} segments into level {level}, keeping {records} records",
+                group.len()
+            );
+            shared.publish_memory(&state);
+            shared.compact(&mut state);
+            drop(state);
+
+            for segment in &group {
+                let _ = std::fs::remove_file(segment.path());
+            }
+        });
+    }
+}
+
+/// The newest record of `key` among `segments`, newest first, read on a
+/// blocking thread. A failed read is logged, and answered as a missing key.
+async fn read_segments(segments: Vec<Arc<Segment>>, key: Key) -> Option<Record> {
+    if segments.is_empty() {
+        return None;
+    }
+    let read = tokio::task::spawn_blocking(move || {
+        for segment in &segments {
+            if let Some(record) = segment.get(&key)? {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    })
+    .await
+    .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+    read.unwrap_or_else(|err| {
+        error!("Reading the LSM store failed: {err}");
+        None
+    })
+}
+
+/// Removes files no longer used, on a blocking thread.
+fn remove_files(paths: Vec<PathBuf>) {
+    tokio::task::spawn_blocking(move || {
+        for path in paths {
+            let _ = std::fs::remove_file(path);
+        }
+    });
+}
+
+type Source<'a> = Box<dyn Iterator<Item = Result<(Key, Record)>> + Send + 'a>;
+
+/// Merges sorted sources, newest first, into the newest record of each key.
+/// A failed read is passed on as it comes.
+struct Merge<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Self {
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<(Key, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for source in &mut self.sources {
+            if matches!(source.peek(), Some(Err(_))) {
+                return source.next();
+            }
+        }
+        let min = self
+            .sources
+            .iter_mut()
+            .filter_map(|source| match source.peek() {
+                Some(Ok((key, _))) => Some(key),
+                _ => None,
+            })
+            .min()?
+            .clone();
+        let mut newest = None;
+        for source in &mut self.sources {
+            if matches!(source.peek(), Some(Ok((key, _))) if *key == min) {
+                let item = source.next();
+                newest = newest.or(item);
+            }
+        }
+        newest
+    }
+}
+
+/// The newest record of each key of `namespace` among the frozen memtables
+/// and the segments, both newest first, in key order.
+fn records<'a>(
+    memtables: &'a [Arc<Frozen>],
+    segments: &'a [Arc<Segment>],
+    namespace: u32,
+) -> impl Iterator<Item = Result<(Key, Record)>> + 'a {
+    let from: Key = (namespace, Vec::new());
+    let mut sources: Vec<Source> = Vec::new();
+    for memtable in memtables {
+        let records = memtable.records.range(from.clone()..);
+        sources.push(Box::new(
+            records.map(|(key, record)| Ok((key.clone(), record.clone()))),
+        ));
+    }
+    for segment in segments {
+        sources.push(Box::new(segment.iter(&from)));
+    }
+    Merge::new(sources).take_while(move |item| {
+        item.as_ref().map_or(true, |((record_namespace, _), _)| {
+            *record_namespace == namespace
+        })
+    })
+}
+
+/// The memtables and segments as they were when the snapshot was taken,
+/// merged as the entries are read.
+struct LsmSnapshot {
+    namespaces: Vec<u32>,
+    counts: Vec<usize>,
+    /// Newest first.
+    memtables: Vec<Arc<Frozen>>,
+    segments: Vec<Arc<Segment>>,
+    now: u64,
+}
+
+impl Snapshot for LsmSnapshot {
+    fn databases(&self) -> usize {
+        self.namespaces.len()
+    }
+
+    fn size_hint(&self, db: usize) -> (usize, usize) {
+        (self.counts[db], 0)
+    }
+
+    fn entries(&self, db: usize) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+        let now = self.now;
+        Box::new(
+            records(&self.memtables, &self.segments, self.namespaces[db]).filter_map(move |item| {
+                match item {
+                    Ok(((_, key), Record::Put { value, expires_at }))
+                        if expires_at.is_none_or(|at| at > now) =>
+                    {
+                        Some(Ok(Entry {
+                            key,
+                            value_type: ValueType::of(&value),
+                            value,
+                            expires_at,
+                        }))
+                    }
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err)),
+                }
+            }),
+        )
+    }
+}
+
+#[async_trait]
+impl Engine for LsmEngine {
+    fn databases(&self) -> usize {
+        self.databases
+    }
+
+    async fn get(&self, db: usize, key: &[u8]) -> Option<Vec<u8>> {
+        let now = now_ms();
+        match self.find(db, key).await {
+            Some(Record::Put { value, expires_at }) if expires_at.is_none_or(|at| at > now) => {
+                Some(value)
+            }
+            Some(Record::Put { .. }) => {
+                let (mut state, key, old) = self.lock_record(db, key).await;
+                if let Some(old @ Record::Put { .. }) = old
+                    && !old.is_live(now)
+                {
+                    state.replace(db, key, Some(&old), Record::Delete);
+                    self.shared.after_write(&mut state);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    async fn set(&self, db: usize, key: Vec<u8>, value: Vec<u8>) {
+        let (mut state, key, old) = self.lock_record(db, &key).await;
+        let record = Record::Put {
+            value,
+            expires_at: None,
+        };
+        state.replace(db, key, old.as_ref(), record);
+        self.shared.after_write(&mut state);
+    }
+
+    /// Deleting only writes a tombstone, so there's nothing to free lazily.
+    async fn delete(&self, db: usize, key: &[u8], _lazy: bool) -> bool {
+        let now = now_ms();
+        let (mut state, key, old) = self.lock_record(db, key).await;
+        let Some(old @ Record::Put { .. }) = old else {
+            return false;
+        };
+        let live = old.is_live(now);
+        state.replace(db, key, Some(&old), Record::Delete);
+        self.shared.after_write(&mut state);
+        live
+    }
+
+    async fn move_key(&self, db: usize, target: usize, key: &[u8]) -> bool {
+        let now = now_ms();
+        let (mut state, [(source, old), (destination, existing)]) =
+            self.lock_records([(db, key), (target, key)]).await;
+        let Some(old @ Record::Put { .. }) = old else {
+            return false;
+        };
+        if !old.is_live(now) {
+            state.replace(db, source, Some(&old), Record::Delete);
+            self.shared.after_write(&mut state);
+            return false;
+        }
+        if existing.as_ref().is_some_and(|record| record.is_live(now)) {
+            return false;
+        }
+        state.replace(target, destination, existing.as_ref(), old.clone());
+        state.replace(db, source, Some(&old), Record::Delete);
+        self.shared.after_write(&mut state);
+        true
+    }
+
+    async fn swap(&self, first: usize, second: usize) {
+        let mut state = self.shared.state.write().await;
+        state.namespaces.swap(first, second);
+        state.counts.swap(first, second);
+        state.log_namespaces();
+    }
+
+    async fn size(&self, db: usize) -> usize {
+        self.shared.state.read().await.counts[db]
+    }
+
+    async fn flush(&self, db: usize, lazy: bool) {
+        let mut state = self.shared.state.write().await;
+        let namespace = state.namespaces[db];
+        state.namespaces[db] = state.next_namespace;
+        state.next_namespace += 1;
+        state.counts[db] = 0;
+        state.log_namespaces();
+
+        // The records on disk and in frozen memtables go with the next
+        // compaction of their segments.
+        let memtable = std::mem::take(&mut state.memtable);
+        let (flushed, kept): (BTreeMap<_, _>, BTreeMap<_, _>) = memtable
+            .into_iter()
+            .partition(|((record_namespace, _), _)| *record_namespace == namespace);
+        state.memtable_bytes = memtable_bytes(&kept);
+        state.memtable = kept;
+        self.shared.after_write(&mut state);
+        drop(state);
+
+        free(flushed, lazy);
+    }
+
+    async fn flush_all(&self, lazy: bool) {
+        let mut state = self.shared.state.write().await;
+        let databases = state.namespaces.len() as u32;
+        let next_namespace = state.next_namespace;
+        state.namespaces = (next_namespace..next_namespace + databases).collect();
+        state.next_namespace += databases;
+        state.counts.fill(0);
+        state.log_namespaces();
+        let memtable = std::mem::take(&mut state.memtable);
+        state.memtable_bytes = 0;
+        let frozen = std::mem::take(&mut state.frozen);
+        // Nothing on disk is reachable anymore; a running flush or
+        // compaction's output is thrown away as its inputs are gone.
+        let segments = std::mem::take(&mut state.segments);
+        self.shared.after_write(&mut state);
+        drop(state);
+
+        let logs = frozen
+            .iter()
+            .map(|frozen| wal_path(&self.shared.path, frozen.id));
+        remove_files(
+            segments
+                .iter()
+                .map(|segment| segment.path().to_path_buf())
+                .chain(logs)
+                .collect(),
+        );
+        free((memtable, frozen), lazy);
+    }
+
+    async fn set_expiry(&self, db: usize, key: &[u8], expires_at: Option<u64>) -> bool {
+        let now = now_ms();
+        let (mut state, key, old) = self.lock_record(db, key).await;
+        let Some(old @ Record::Put { .. }) = old else {
+            return false;
+        };
+        let live = old.is_live(now);
+        let record = match old.clone() {
+            Record::Put { value, .. }
+                if live && expires_at.is_none_or(|expires_at| expires_at > now) =>
+            {
+                Record::Put { value, expires_at }
+            }
+            _ => Record::Delete,
+        };
+        state.replace(db, key, Some(&old), record);
+        self.shared.after_write(&mut state);
+        live
+    }
+
+    async fn expiry(&self, db: usize, key: &[u8]) -> Option<Option<u64>> {
+        let now = now_ms();
+        match self.find(db, key).await {
+            Some(Record::Put { expires_at, .. }) if expires_at.is_none_or(|at| at > now) => {
+                Some(expires_at)
+            }
+            _ => None,
+        }
+    }
+
+    /// Values are kept as they came, so collections are never packed.
+    async fn encoding(&self, db: usize, key: &[u8]) -> Option<&'static str> {
+        let now = now_ms();
+        match self.find(db, key).await {
+            Some(Record::Put { value, expires_at }) if expires_at.is_none_or(|at| at > now) => {
+                Some(encoding::plain_name(&value))
+            }
+            _ => None,
+        }
+    }
+
+    /// What the key's record takes in the memtable, wherever it is now.
+    async fn memory_usage(&self, db: usize, key: &[u8]) -> Option<usize> {
+        let now = now_ms();
+        let record = self.find(db, key).await?;
+        record
+            .is_live(now)
+            .then(|| size_of::<Key>() + key.len() + record.size())
+    }
+
+    /// Freezes the memtable, so that the snapshot shares every record with
+    /// the store instead of copying them.
+    async fn snapshot(&self) -> Box<dyn Snapshot> {
+        let now = now_ms();
+        let mut state = self.shared.state.write().await;
+        self.shared.freeze(&mut state);
+        Box::new(LsmSnapshot {
+            namespaces: state.namespaces[..self.databases].to_vec(),
+            counts: state.counts.clone(),
+            memtables: state.frozen.clone(),
+            segments: state.segments.clone(),
+            now,
+        })
+    }
+
+    /// Keys carry no access history here, so `history` is ignored.
+    async fn restore(
+        &self,
+        db: usize,
+        entry: Entry,
+        _history: AccessHistory,
+        replace: bool,
+    ) -> bool {
+        let now = now_ms();
+        let (mut state, key, old) = self.lock_record(db, &entry.key).await;
+        let exists = old.as_ref().is_some_and(|old| old.is_live(now));
+        if !replace && exists {
+            return false;
+        }
+        let record = match entry.expires_at {
+            Some(expires_at) if expires_at <= now => match exists {
+                true => Record::Delete,
+                false => return true,
+            },
+            expires_at => Record::Put {
+                value: entry.value,
+                expires_at,
+            },
+        };
+        state.replace(db, key, old.as_ref(), record);
+        self.shared.after_write(&mut state);
+        true
+    }
+
+    async fn insert_entries(&self, db: usize, entries: Vec<Entry>) {
+        for entry in entries {
+            let (mut state, key, old) = self.lock_record(db, &entry.key).await;
+            let record = Record::Put {
+                value: entry.value,
+                expires_at: entry.expires_at,
+            };
+            state.replace(db, key, old.as_ref(), record);
+            self.shared.after_write(&mut state);
+        }
+    }
+
+    fn memory(&self) -> MemoryStats {
+        MemoryStats {
+            used: self.shared.used_memory.load(Ordering::Relaxed),
+            overhead: self.shared.overhead.load(Ordering::Relaxed),
+            max: self.shared.limit.maxmemory,
+            policy: self.shared.limit.policy.name(),
+            evicted_keys: 0,
+            compression: CompressionStats::default(),
+        }
+    }
+
+    /// Nothing is evicted: over the limit, the memtable is written out to
+    /// disk instead, whatever the policy.
+    async fn evict(&self) -> Vec<(usize, Vec<u8>)> {
+        if self
+            .shared
+            .limit
+            .exceeded(self.shared.used_memory.load(Ordering::Relaxed))
+        {
+            let mut state = self.shared.state.write().await;
+            self.shared.freeze(&mut state);
+            self.shared.publish_memory(&state);
+            drop(state);
+            self.shared.clone().flush_frozen().await;
+        }
+        Vec::new()
+    }
+
+    /// Expired records are dropped by compaction rather than swept.
+    async fn expire_cycle(&self) -> Vec<(usize, Vec<u8>)> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(name: &str, memtable_size: usize) -> KiwiConfig {
+        KiwiConfig::new()
+            .with_dir(std::env::temp_dir())
+            .with_lsm_dir(format!("kiwi-lsm-{name}-{}", std::process::id()))
+            .with_lsm_memtable_size(memtable_size)
+    }
+
+    fn engine(name: &str, memtable_size: usize) -> LsmEngine {
+        LsmEngine::from_config(&config(name, memtable_size)).unwrap()
+    }
+
+    async fn compacted(engine: &LsmEngine) {
+        engine.shared.clone().flush_frozen().await;
+        while engine.shared.compacting.load(Ordering::Acquire) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn remove(engine: LsmEngine) {
+        compacted(&engine).await;
+        std::fs::remove_dir_all(&engine.shared.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keys_on_disk() {
+        let engine = engine("keys", 4096);
+        for i in 0..2_000 {
+            let key = format!("key{i:04}").into_bytes();
+            engine.set(0, key, i.to_string().into_bytes()).await;
+        }
+        engine.set(0, b"key0001".to_vec(), b"new".to_vec()).await;
+        assert!(engine.delete(0, b"key0002", false).await);
+        assert!(!engine.delete(0, b"key0002", false).await);
+        compacted(&engine).await;
+
+        let (segments, bytes) = engine.segments().await;
+        // Some forty memtables were flushed, and merged level by level.
+        assert!(segments > 1 && segments < 10 && bytes > 0);
+        assert_eq!(engine.size(0).await, 1_999);
+        assert_eq!(engine.get(0, b"key1500").await, Some(b"1500".to_vec()));
+        assert_eq!(engine.get(0, b"key0001").await, Some(b"new".to_vec()));
+        assert_eq!(engine.get(0, b"key0002").await, None);
+        let usage = engine.memory_usage(0, b"key1500").await.unwrap();
+        assert!(usage > b"key15001500".len());
+        assert_eq!(engine.memory_usage(0, b"key0002").await, None);
+        assert!(engine.memory().overhead > 0);
+
+        let entries = engine
+            .snapshot()
+            .await
+            .entries(0)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 1_999);
+        assert!(entries.windows(2).all(|pair| pair[0].key < pair[1].key));
+        assert_eq!(entries[1].value, b"new");
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_is_point_in_time() {
+        let engine = engine("snapshot", 1024);
+        for i in 0..100 {
+            let key = format!("key{i:03}").into_bytes();
+            engine.set(0, key, b"old".to_vec()).await;
+        }
+        let snapshot = engine.snapshot().await;
+        for i in 0..100 {
+            let key = format!("key{i:03}").into_bytes();
+            engine.set(0, key, b"new".to_vec()).await;
+        }
+        assert!(engine.delete(0, b"key000", false).await);
+        compacted(&engine).await;
+
+        let entries = snapshot.entries(0).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), 100);
+        assert!(entries.iter().all(|entry| entry.value == b"old"));
+        assert_eq!(engine.get(0, b"key001").await, Some(b"new".to_vec()));
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_flush_keeps_memtable() {
+        let engine = engine("failed-flush", 1024);
+        std::fs::remove_dir_all(&engine.shared.path).unwrap();
+        for i in 0..100 {
+            let key = format!("key{i:03}").into_bytes();
+            engine.set(0, key, b"value".to_vec()).await;
+        }
+        compacted(&engine).await;
+        assert_eq!(engine.segments().await.0, 0);
+        assert_eq!(engine.get(0, b"key042").await, Some(b"value".to_vec()));
+
+        // The next flush writes out what the failed one couldn't.
+        std::fs::create_dir_all(&engine.shared.path).unwrap();
+        engine.set(0, b"key100".to_vec(), b"value".to_vec()).await;
+        compacted(&engine).await;
+        assert!(engine.segments().await.0 > 0);
+        assert!(engine.shared.state.read().await.frozen.is_empty());
+        assert_eq!(engine.get(0, b"key042").await, Some(b"value".to_vec()));
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let config = config("reopen", 1024);
+        let engine = LsmEngine::from_config(&config).unwrap();
+        for i in 0..100 {
+            let key = format!("key{i:03}").into_bytes();
+            engine.set(0, key, b"value".to_vec()).await;
+        }
+        engine.set(1, b"other".to_vec(), b"value".to_vec()).await;
+        assert!(engine.delete(0, b"key000", false).await);
+        engine.swap(0, 1).await;
+        compacted(&engine).await;
+        engine.set(1, b"key001".to_vec(), b"new".to_vec()).await;
+
+        // A compaction cut short leaves its inputs next to its output.
+        let path = engine.shared.path.clone();
+        let merged = engine
+            .shared
+            .state
+            .read()
+            .await
+            .segments
+            .iter()
+            .find(|segment| segment.level > 0)
+            .map(|segment| (segment.path().to_path_buf(), segment.ids))
+            .unwrap();
+        let input = segment_path(&path, (merged.1.0, merged.1.0), 0);
+        std::fs::copy(&merged.0, &input).unwrap();
+        drop(engine);
+
+        let engine = LsmEngine::from_config(&config).unwrap();
+        assert!(!input.exists());
+        assert!(engine.segments().await.0 > 0);
+        assert_eq!(engine.size(0).await, 1);
+        assert_eq!(engine.size(1).await, 99);
+        assert_eq!(engine.get(0, b"other").await, Some(b"value".to_vec()));
+        assert_eq!(engine.get(1, b"key000").await, None);
+        assert_eq!(engine.get(1, b"key001").await, Some(b"new".to_vec()));
+        assert_eq!(engine.get(1, b"key099").await, Some(b"value".to_vec()));
+
+        // The keys written after the restart survive the next one too.
+        engine.set(0, b"later".to_vec(), b"value".to_vec()).await;
+        drop(engine);
+        let engine = LsmEngine::from_config(&config).unwrap();
+        assert_eq!(engine.size(0).await, 2);
+        assert_eq!(engine.get(1, b"key001").await, Some(b"new".to_vec()));
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_databases() {
+        let engine = engine("databases", 256);
+        for i in 0..100 {
+            engine
+                .set(0, format!("key{i}").into_bytes(), b"value".to_vec())
+                .await;
+        }
+        assert!(engine.move_key(0, 1, b"key7").await);
+        assert!(!engine.move_key(0, 1, b"key7").await);
+        assert_eq!(engine.get(1, b"key7").await, Some(b"value".to_vec()));
+
+        engine.swap(0, 1).await;
+        assert_eq!(engine.size(0).await, 1);
+        assert_eq!(engine.size(1).await, 99);
+
+        engine.flush(1, false).await;
+        assert_eq!(engine.size(1).await, 0);
+        assert_eq!(engine.get(1, b"key8").await, None);
+        assert!(engine.snapshot().await.entries(1).next().is_none());
+        assert_eq!(engine.get(0, b"key7").await, Some(b"value".to_vec()));
+
+        engine.flush_all(false).await;
+        assert_eq!(engine.size(0).await, 0);
+        assert_eq!(engine.segments().await.0, 0);
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let engine = engine("expiry", 1024);
+        engine.set(0, b"key".to_vec(), b"value".to_vec()).await;
+        let later = now_ms() + 60_000;
+        assert!(engine.set_expiry(0, b"key", Some(later)).await);
+        assert_eq!(engine.expiry(0, b"key").await, Some(Some(later)));
+
+        engine
+            .insert_entries(
+                0,
+                vec![Entry {
+                    key: b"old".to_vec(),
+                    value: b"value".to_vec(),
+                    expires_at: Some(1),
+                    value_type: ValueType::String,
+                }],
+            )
+            .await;
+        assert_eq!(engine.size(0).await, 2);
+        assert_eq!(engine.get(0, b"old").await, None);
+        assert_eq!(engine.size(0).await, 1);
+        assert!(!engine.set_expiry(0, b"old", None).await);
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_evict_spills_memtable() {
+        let config = KiwiConfig::new()
+            .with_dir(std::env::temp_dir())
+            .with_lsm_dir(format!("kiwi-lsm-evict-{}", std::process::id()))
+            .with_maxmemory(100);
+        let engine = LsmEngine::from_config(&config).unwrap();
+        for i in 0..10 {
+            engine
+                .set(0, format!("key{i}").into_bytes(), vec![b'x'; 100])
+                .await;
+        }
+        assert!(engine.memory().used > 1_000);
+        assert!(engine.evict().await.is_empty());
+        assert!(engine.memory().used < 1_000);
+        assert_eq!(engine.segments().await.0, 1);
+        assert_eq!(engine.get(0, b"key3").await, Some(vec![b'x'; 100]));
+        remove(engine).await;
+    }
+}
@@ -0,0 +1,524 @@
+//! Sorted segment files: the records of a flushed memtable or of a
+//! compaction, ordered by namespace and key and cut into blocks. The first
+//! key of every block and a bloom filter of all keys stay in memory, so a
+//! lookup reads at most one block from disk.
+//!
+//! The blocks are followed by a footer holding the record count, the bloom
+//! filter and the block index, then by a fixed-size trailer giving where the
+//! footer starts. Opening a segment reads only those, not its records.
+
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A key within its database's namespace.
+pub(super) type Key = (u32, Vec<u8>);
+
+/// Blocks are cut once they grow past this many bytes.
+const BLOCK_SIZE: usize = 4096;
+/// The trailer: the offset of the footer, then the magic number.
+const TRAILER_SIZE: u64 = 16;
+const MAGIC: u64 = u64::from_le_bytes(*b"KIWISEG1");
+/// The extension of a segment until it is complete.
+pub(super) const TEMP_EXTENSION: &str = "tmp";
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+const DELETE: u8 = 0;
+const PUT: u8 = 1;
+const PUT_EXPIRING: u8 = 2;
+
+/// The newest write of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Record {
+    Put {
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Delete,
+}
+
+impl Record {
+    /// Approximate bytes the record takes in memory.
+    pub(super) fn size(&self) -> usize {
+        match self {
+            Record::Put { value, .. } => size_of::<Record>() + value.len(),
+            Record::Delete => size_of::<Record>(),
+        }
+    }
+
+    pub(super) fn is_live(&self, now: u64) -> bool {
+        match self {
+            Record::Put { expires_at, .. } => expires_at.is_none_or(|expires_at| expires_at > now),
+            Record::Delete => false,
+        }
+    }
+}
+
+pub(super) fn encode(buf: &mut Vec<u8>, (namespace, key): &Key, record: &Record) {
+    buf.extend_from_slice(&namespace.to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    match record {
+        Record::Put { value, expires_at } => {
+            match expires_at {
+                Some(expires_at) => {
+                    buf.push(PUT_EXPIRING);
+                    buf.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                None => buf.push(PUT),
+            }
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value);
+        }
+        Record::Delete => buf.push(DELETE),
+    }
+}
+
+/// Decodes the record at the start of `buf`, with the bytes it took.
+pub(super) fn decode(buf: &[u8]) -> Option<(Key, Record, usize)> {
+    let mut pos = 0;
+    let mut take = |len: usize| {
+        let bytes = buf.get(pos..pos + len)?;
+        pos += len;
+        Some(bytes)
+    };
+    let namespace = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let key_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+    let key = take(key_len)?.to_vec();
+    let record = match take(1)?[0] {
+        DELETE => Record::Delete,
+        tag => {
+            let expires_at = match tag {
+                PUT_EXPIRING => Some(u64::from_le_bytes(take(8)?.try_into().ok()?)),
+                _ => None,
+            };
+            let value_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+            Record::Put {
+                value: take(value_len)?.to_vec(),
+                expires_at,
+            }
+        }
+    };
+    Some(((namespace, key), record, pos))
+}
+
+/// Writes records, given in key order, into a new segment file. The file
+/// only gets its name once complete, so a crash never leaves a partial
+/// segment behind.
+pub(super) struct SegmentWriter {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: BufWriter<File>,
+    block: Vec<u8>,
+    block_key: Option<Key>,
+    offset: u64,
+    index: Vec<(Key, u64)>,
+    hashes: Vec<u64>,
+}
+
+impl SegmentWriter {
+    pub(super) fn create(path: PathBuf) -> Result<Self> {
+        let temp_path = path.with_extension(TEMP_EXTENSION);
+        Ok(Self {
+            file: BufWriter::new(File::create(&temp_path)?),
+            path,
+            temp_path,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            block_key: None,
+            offset: 0,
+            index: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    pub(super) fn add(&mut self, key: &Key, record: &Record) -> Result<()> {
+        if self.block_key.is_none() {
+            self.block_key = Some(key.clone());
+        }
+        encode(&mut self.block, key, record);
+        self.hashes.push(hash(key));
+        if self.block.len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        if let Some(key) = self.block_key.take() {
+            self.file.write_all(&self.block)?;
+            self.index.push((key, self.offset));
+            self.offset += self.block.len() as u64;
+            self.block.clear();
+        }
+        Ok(())
+    }
+
+    /// The finished segment holding the records of memtables `ids`, `None`
+    /// when no record was added.
+    pub(super) fn finish(mut self, level: u32, ids: (u64, u64)) -> Result<Option<Segment>> {
+        self.write_block()?;
+        if self.index.is_empty() {
+            drop(self.file);
+            std::fs::remove_file(&self.temp_path)?;
+            return Ok(None);
+        }
+        let bloom = Bloom::new(&self.hashes);
+        let records = self.hashes.len() as u64;
+        let mut footer = Vec::new();
+        encode_footer(&mut footer, records, &bloom, &self.index);
+        footer.extend_from_slice(&self.offset.to_le_bytes());
+        footer.extend_from_slice(&MAGIC.to_le_bytes());
+        self.file.write_all(&footer)?;
+        let file = self.file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&self.temp_path, &self.path)?;
+        Ok(Some(Segment {
+            level,
+            ids,
+            file: File::open(&self.path)?,
+            path: self.path,
+            index: self.index,
+            end: self.offset,
+            records,
+            bloom,
+        }))
+    }
+}
+
+fn encode_footer(buf: &mut Vec<u8>, records: u64, bloom: &Bloom, index: &[(Key, u64)]) {
+    buf.extend_from_slice(&records.to_le_bytes());
+    buf.extend_from_slice(&(bloom.bits.len() as u32).to_le_bytes());
+    for word in &bloom.bits {
+        buf.extend_from_slice(&word.to_le_bytes());
+    }
+    buf.extend_from_slice(&(index.len() as u32).to_le_bytes());
+    for ((namespace, key), offset) in index {
+        buf.extend_from_slice(&namespace.to_le_bytes());
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&offset.to_le_bytes());
+    }
+}
+
+/// What a segment keeps in memory, as read back from its footer.
+struct Footer {
+    records: u64,
+    bloom: Bloom,
+    index: Vec<(Key, u64)>,
+}
+
+/// Decodes a footer, `None` unless it takes all of `buf`.
+fn decode_footer(buf: &[u8]) -> Option<Footer> {
+    let mut pos = 0;
+    let mut take = |len: usize| {
+        let bytes = buf.get(pos..pos + len)?;
+        pos += len;
+        Some(bytes)
+    };
+    let records = u64::from_le_bytes(take(8)?.try_into().ok()?);
+    let words = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let bits = (0..words)
+        .map(|_| Some(u64::from_le_bytes(take(8)?.try_into().ok()?)))
+        .collect::<Option<Vec<_>>>()?;
+    let blocks = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let index = (0..blocks)
+        .map(|_| {
+            let namespace = u32::from_le_bytes(take(4)?.try_into().ok()?);
+            let key_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+            let key = take(key_len)?.to_vec();
+            let offset = u64::from_le_bytes(take(8)?.try_into().ok()?);
+            Some(((namespace, key), offset))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (pos == buf.len() && !bits.is_empty()).then_some(Footer {
+        records,
+        bloom: Bloom { bits },
+        index,
+    })
+}
+
+pub(super) struct Segment {
+    /// How many compactions the segment's records went through.
+    pub(super) level: u32,
+    /// The first and last memtable whose records it holds, by id.
+    pub(super) ids: (u64, u64),
+    path: PathBuf,
+    file: File,
+    /// The first key of every block, with its offset.
+    index: Vec<(Key, u64)>,
+    /// Where the blocks end and the footer starts.
+    end: u64,
+    records: u64,
+    bloom: Bloom,
+}
+
+impl Segment {
+    /// Opens a segment written by an earlier run, reading its block index
+    /// and bloom filter from the footer.
+    pub(super) fn open(path: PathBuf, level: u32, ids: (u64, u64)) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid segment {}", path.display()),
+            )
+        };
+        let file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        let mut trailer = [0; TRAILER_SIZE as usize];
+        if len < TRAILER_SIZE {
+            return Err(invalid());
+        }
+        file.read_exact_at(&mut trailer, len - TRAILER_SIZE)?;
+        let end = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        let magic = u64::from_le_bytes(trailer[8..].try_into().unwrap());
+        if magic != MAGIC || end > len - TRAILER_SIZE {
+            return Err(invalid());
+        }
+        let mut footer = vec![0; (len - TRAILER_SIZE - end) as usize];
+        file.read_exact_at(&mut footer, end)?;
+        let Footer {
+            records,
+            bloom,
+            index,
+        } = decode_footer(&footer).ok_or_else(invalid)?;
+        if index.is_empty() || index.iter().any(|(_, offset)| *offset >= end) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            level,
+            ids,
+            path,
+            file,
+            index,
+            end,
+            records,
+            bloom,
+        })
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the segment may hold `key`, answered from memory.
+    pub(super) fn may_contain(&self, key: &Key) -> bool {
+        self.bloom.contains(hash(key))
+    }
+
+    pub(super) fn get(&self, key: &Key) -> Result<Option<Record>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self.index.partition_point(|(first, _)| first <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+        let buf = self.read_block(block - 1)?;
+        let mut pos = 0;
+        while let Some((found, record, len)) = decode(&buf[pos..]) {
+            if found == *key {
+                return Ok(Some(record));
+            }
+            if found > *key {
+                break;
+            }
+            pos += len;
+        }
+        Ok(None)
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<u8>> {
+        let start = self.index[block].1;
+        let end = self
+            .index
+            .get(block + 1)
+            .map_or(self.end, |(_, offset)| *offset);
+        let mut buf = vec![0; (end - start) as usize];
+        self.file.read_exact_at(&mut buf, start)?;
+        Ok(buf)
+    }
+
+    /// Records from `from` on, in key order, ending after a failed read.
+    pub(super) fn iter(self: &Arc<Self>, from: &Key) -> SegmentIter {
+        let block = self
+            .index
+            .partition_point(|(first, _)| first <= from)
+            .saturating_sub(1);
+        SegmentIter {
+            segment: self.clone(),
+            from: from.clone(),
+            block,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Bytes of the block index and bloom filter.
+    pub(super) fn memory(&self) -> usize {
+        let index: usize = self
+            .index
+            .iter()
+            .map(|((_, key), _)| size_of::<(Key, u64)>() + key.len())
+            .sum();
+        index + self.bloom.bits.len() * size_of::<u64>()
+    }
+
+    /// Records held, deletes included.
+    pub(super) fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub(super) fn disk_size(&self) -> u64 {
+        self.end
+    }
+}
+
+pub(super) struct SegmentIter {
+    segment: Arc<Segment>,
+    from: Key,
+    block: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Iterator for SegmentIter {
+    type Item = Result<(Key, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, record, len)) = decode(&self.buf[self.pos..]) {
+                self.pos += len;
+                if key >= self.from {
+                    return Some(Ok((key, record)));
+                }
+                continue;
+            }
+            if self.block >= self.segment.index.len() {
+                return None;
+            }
+            let block = self.block;
+            self.block += 1;
+            self.pos = 0;
+            match self.segment.read_block(block) {
+                Ok(buf) => self.buf = buf,
+                Err(err) => {
+                    self.block = self.segment.index.len();
+                    self.buf.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+fn hash(key: &Key) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Answers whether a segment may hold a key, with about 1% false positives.
+struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    fn new(hashes: &[u64]) -> Self {
+        let words = (hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(64).max(1);
+        let mut bits = vec![0u64; words];
+        for &hash in hashes {
+            for bit in probes(hash, words * 64) {
+                bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        Self { bits }
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        probes(hash, self.bits.len() * 64).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// The bits of `hash` among `bits`, by double hashing.
+fn probes(hash: u64, bits: usize) -> impl Iterator<Item = usize> {
+    let step = hash.rotate_left(32) | 1;
+    (0..BLOOM_HASHES).map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % bits as u64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(value: &[u8]) -> Record {
+        Record::Put {
+            value: value.to_vec(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let key = (3, b"key".to_vec());
+        let expiring = Record::Put {
+            value: b"value".to_vec(),
+            expires_at: Some(1_000),
+        };
+        for record in [put(b""), expiring, Record::Delete] {
+            let mut buf = Vec::new();
+            encode(&mut buf, &key, &record);
+            assert_eq!(decode(&buf), Some((key.clone(), record, buf.len())));
+            assert_eq!(decode(&buf[..buf.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn test_segment() {
+        let path = std::env::temp_dir().join(format!("kiwi-segment-{}.seg", std::process::id()));
+        let mut writer = SegmentWriter::create(path.clone()).unwrap();
+        let keys: Vec<Key> = (0..2_000)
+            .map(|i| (i / 1_000, format!("key{i:05}").into_bytes()))
+            .collect();
+        for key in &keys {
+            writer.add(key, &put(&key.1)).unwrap();
+        }
+        let segment = Arc::new(writer.finish(0, (0, 0)).unwrap().unwrap());
+        assert!(segment.index.len() > 1);
+        let reopened = Segment::open(path.clone(), 0, (0, 0)).unwrap();
+        assert_eq!(reopened.index, segment.index);
+        assert_eq!(reopened.end, segment.end);
+        assert_eq!(reopened.records(), 2_000);
+        assert_eq!(reopened.bloom.bits, segment.bloom.bits);
+
+        // A segment cut short has no trailer to trust.
+        let truncated = path.with_extension("cut");
+        std::fs::copy(&path, &truncated).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&truncated)
+            .unwrap();
+        file.set_len(segment.end + 4).unwrap();
+        assert!(Segment::open(truncated.clone(), 0, (0, 0)).is_err());
+        std::fs::remove_file(&truncated).unwrap();
+
+        assert_eq!(
+            segment.get(&keys[1_500]).unwrap(),
+            Some(put(&keys[1_500].1))
+        );
+        assert_eq!(segment.get(&(0, b"key01500".to_vec())).unwrap(), None);
+        assert_eq!(segment.get(&(0, b"a".to_vec())).unwrap(), None);
+
+        let from = (1, Vec::new());
+        let rest: Vec<Key> = segment.iter(&from).map(|item| item.unwrap().0).collect();
+        assert_eq!(rest, keys[1_000..]);
+
+        std::fs::remove_file(&path).unwrap();
+        let empty = SegmentWriter::create(path.clone()).unwrap();
+        assert!(empty.finish(0, (0, 0)).unwrap().is_none());
+        assert!(!path.exists() && !path.with_extension(TEMP_EXTENSION).exists());
+    }
+}
@@ -0,0 +1,192 @@
+//! Write-ahead logs: every record written to a memtable is appended to the
+//! log of that memtable, and so are the database namespaces, with the key
+//! count of each database, whenever they change. A log is deleted once its
+//! memtable is on disk as a segment, so the logs found on start hold what
+//! the segments don't.
+//!
+//! How much of a log survives power loss follows `lsm-wal-fsync`, the way
+//! `appendfsync` does for the AOF: `always` syncs every append before the
+//! write is answered, `everysec` syncs once a second, losing up to a second
+//! of writes, and `no` leaves it to the OS. A crash of the process alone
+//! loses nothing either way.
+
+use super::segment::{Key, Record, decode, encode};
+use crate::aof::AppendFsync;
+use std::fs::File;
+use std::io::{Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+const RECORD: u8 = 0;
+const NAMESPACES: u8 = 1;
+
+/// The log of the memtable being written to.
+pub(super) struct Wal {
+    path: PathBuf,
+    file: File,
+    fsync: AppendFsync,
+    /// Whether appends were made since the last sync, under `everysec`.
+    unsynced: bool,
+}
+
+impl Wal {
+    /// A new log, starting with the current namespaces and counts.
+    pub(super) fn create(
+        path: PathBuf,
+        fsync: AppendFsync,
+        namespaces: &[u32],
+        counts: &[usize],
+        next_namespace: u32,
+    ) -> Result<Self> {
+        let mut wal = Self {
+            file: File::create(&path)?,
+            path,
+            fsync,
+            unsynced: false,
+        };
+        wal.append_namespaces(namespaces, counts, next_namespace)?;
+        Ok(wal)
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn append(&mut self, key: &Key, record: &Record) -> Result<()> {
+        let mut buf = vec![RECORD];
+        encode(&mut buf, key, record);
+        self.write(&buf)
+    }
+
+    /// Logs the namespaces of the databases, and how many keys each holds.
+    pub(super) fn append_namespaces(
+        &mut self,
+        namespaces: &[u32],
+        counts: &[usize],
+        next_namespace: u32,
+    ) -> Result<()> {
+        let mut buf = vec![NAMESPACES];
+        buf.extend_from_slice(&next_namespace.to_le_bytes());
+        buf.extend_from_slice(&(namespaces.len() as u32).to_le_bytes());
+        for (namespace, count) in namespaces.iter().zip(counts) {
+            buf.extend_from_slice(&namespace.to_le_bytes());
+            buf.extend_from_slice(&(*count as u64).to_le_bytes());
+        }
+        self.write(&buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.file.write_all(buf)?;
+        match self.fsync {
+            AppendFsync::Always => self.file.sync_data(),
+            AppendFsync::EverySec => {
+                self.unsynced = true;
+                Ok(())
+            }
+            AppendFsync::No => Ok(()),
+        }
+    }
+
+    /// A handle to sync the log through, if appends were made since the last
+    /// time one was taken.
+    pub(super) fn take_unsynced(&mut self) -> Option<Result<File>> {
+        std::mem::take(&mut self.unsynced).then(|| self.file.try_clone())
+    }
+}
+
+/// An entry of a log.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Logged {
+    Record(Key, Record),
+    Namespaces {
+        namespaces: Vec<u32>,
+        counts: Vec<usize>,
+        next_namespace: u32,
+    },
+}
+
+/// Reads the log at `path`, dropping an entry a crash cut short at its end.
+pub(super) fn read(path: &Path) -> Result<Vec<Logged>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    let mut logged = Vec::new();
+    let mut pos = 0;
+    while let Some((entry, len)) = decode_logged(&buf[pos..]) {
+        logged.push(entry);
+        pos += len;
+    }
+    Ok(logged)
+}
+
+/// Decodes the entry at the start of `buf`, with the bytes it took.
+fn decode_logged(buf: &[u8]) -> Option<(Logged, usize)> {
+    match *buf.first()? {
+        RECORD => {
+            let (key, record, len) = decode(&buf[1..])?;
+            Some((Logged::Record(key, record), 1 + len))
+        }
+        NAMESPACES => {
+            let mut pos = 1;
+            let mut take = |len: usize| {
+                let bytes = buf.get(pos..pos + len)?;
+                pos += len;
+                Some(bytes)
+            };
+            let next_namespace = u32::from_le_bytes(take(4)?.try_into().ok()?);
+            let databases = u32::from_le_bytes(take(4)?.try_into().ok()?);
+            let mut namespaces = Vec::new();
+            let mut counts = Vec::new();
+            for _ in 0..databases {
+                namespaces.push(u32::from_le_bytes(take(4)?.try_into().ok()?));
+                counts.push(u64::from_le_bytes(take(8)?.try_into().ok()?) as usize);
+            }
+            let logged = Logged::Namespaces {
+                namespaces,
+                counts,
+                next_namespace,
+            };
+            Some((logged, pos))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log() {
+        let path = std::env::temp_dir().join(format!("kiwi-wal-{}.wal", std::process::id()));
+        let key = (1, b"key".to_vec());
+        let record = Record::Put {
+            value: b"value".to_vec(),
+            expires_at: Some(1_000),
+        };
+        let mut wal =
+            Wal::create(path.clone(), AppendFsync::EverySec, &[0, 1], &[0, 5], 2).unwrap();
+        assert!(wal.take_unsynced().is_some_and(|file| file.is_ok()));
+        assert!(wal.take_unsynced().is_none());
+        wal.append(&key, &record).unwrap();
+        assert!(wal.take_unsynced().is_some());
+        wal.append_namespaces(&[2, 1], &[0, 5], 3).unwrap();
+        wal.append(&key, &Record::Delete).unwrap();
+
+        let namespaces = |namespaces: Vec<u32>, next_namespace| Logged::Namespaces {
+            namespaces,
+            counts: vec![0, 5],
+            next_namespace,
+        };
+        let logged = vec![
+            namespaces(vec![0, 1], 2),
+            Logged::Record(key.clone(), record),
+            namespaces(vec![2, 1], 3),
+            Logged::Record(key, Record::Delete),
+        ];
+        assert_eq!(read(&path).unwrap(), logged);
+
+        // A crash while appending leaves a partial entry behind.
+        wal.file.write_all(&[RECORD, 1, 0]).unwrap();
+        assert_eq!(read(&path).unwrap(), logged);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
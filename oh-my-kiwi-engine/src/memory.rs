@@ -0,0 +1,214 @@
+//! Where the memory goes, for MEMORY STATS and MEMORY DOCTOR: the engine's
+//! data set and bookkeeping, plus the output waiting for clients and followers
+//! and the replication backlog, kept outside of it.
+
+use crate::command_processor::human_bytes;
+use oh_my_kiwi_domain::MemoryStats;
+use oh_my_kiwi_domain::types::Types;
+use ordered_float::OrderedFloat;
+use std::collections::BTreeMap;
+
+/// Below this, there's too little data for the doctor to judge.
+const SMALL_DATASET: usize = 5 * 1024 * 1024;
+/// Maxmemory counts as nearly reached above this share of it.
+const NEAR_MAXMEMORY: f64 = 0.9;
+/// Compression that shrinks values less than this costs more than it saves.
+const POOR_COMPRESSION: f64 = 1.2;
+/// Objects queued for the lazy free worker worth worrying about.
+const LAZYFREE_BACKLOG: usize = 1000;
+
+pub struct MemoryReport {
+    pub memory: MemoryStats,
+    pub keys: usize,
+    /// Bytes of push messages waiting to be written to the connected clients.
+    pub clients: usize,
+    /// Bytes of the replication stream waiting to be sent to the followers.
+    pub replicas: usize,
+    /// Bytes of the replication stream held in the backlog.
+    pub backlog: usize,
+    pub lazyfree_pending: usize,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> usize {
+        self.memory.used + self.clients + self.replicas + self.backlog
+    }
+
+    /// Bytes of the keys and values themselves.
+    pub fn dataset(&self) -> usize {
+        self.memory.used.saturating_sub(self.memory.overhead)
+    }
+
+    /// Everything that isn't keys and values.
+    pub fn overhead(&self) -> usize {
+        self.memory.overhead + self.clients + self.replicas + self.backlog
+    }
+
+    /// The fields of MEMORY STATS, named like Redis's.
+    pub fn stats(&self) -> Types {
+        let total = self.total();
+        let mut stats = BTreeMap::new();
+        let mut field = |name: &str, value: Types| {
+            stats.insert(Types::BulkString(name.to_string()), value);
+        };
+        let integer = |value: usize| Types::Integer(value as i64);
+        field("total.allocated", integer(total));
+        field("replication.backlog", integer(self.backlog));
+        field("clients.slaves", integer(self.replicas));
+        field("clients.normal", integer(self.clients));
+        field("overhead.hashtable.main", integer(self.memory.overhead));
+        field("overhead.total", integer(self.overhead()));
+        field("keys.count", integer(self.keys));
+        field(
+            "keys.bytes-per-key",
+            integer(self.memory.used / self.keys.max(1)),
+        );
+        field("dataset.bytes", integer(self.dataset()));
+        field(
+            "dataset.percentage",
+            percentage(self.dataset(), total.max(1)),
+        );
+        field("lazyfree.pending_objects", integer(self.lazyfree_pending));
+        Types::Map(stats)
+    }
+
+    /// MEMORY DOCTOR's findings, in plain words.
+    pub fn diagnose(&self) -> String {
+        if self.memory.used < SMALL_DATASET {
+            return "This instance is empty or is using very little memory, so there is \
+                    nothing to diagnose yet. Run MEMORY DOCTOR again once it holds some data.\n"
+                .to_string();
+        }
+
+        let mut issues = Vec::new();
+        let memory = &self.memory;
+        if memory.overhead * 2 > memory.used {
+            issues.push(format!(
+                "High overhead: bookkeeping takes {:.0}% of the data set, as most keys are \
+                 small. Grouping small values into hashes or sets lets them be packed \
+                 together.",
+                memory.overhead as f64 * 100.0 / memory.used as f64
+            ));
+        }
+        if memory.max > 0 && memory.used as f64 >= memory.max as f64 * NEAR_MAXMEMORY {
+            let consequence = match memory.policy {
+                "noeviction" => "writes will soon fail with OOM errors".to_string(),
+                policy => format!("keys are evicted by the {policy} policy"),
+            };
+            issues.push(format!(
+                "Near maxmemory: the data set takes {} of the {} limit, so {consequence}.",
+                human_bytes(memory.used),
+                human_bytes(memory.max)
+            ));
+        }
+        if memory.evicted_keys > 0 {
+            issues.push(format!(
+                "Evictions: {} keys were evicted since startup. If they were still needed, \
+                 raise maxmemory.",
+                memory.evicted_keys
+            ));
+        }
+        if self.clients + self.replicas > memory.used {
+            issues.push(format!(
+                "Client output buffers: {} of pushes and {} of replication stream are waiting \
+                 to be sent, more than the data set. Some clients or followers read too \
+                 slowly; lower replica-output-buffer-limit to drop slow followers.",
+                human_bytes(self.clients),
+                human_bytes(self.replicas)
+            ));
+        }
+        let compression = &memory.compression;
+        if compression.compressed_bytes > 0
+            && (compression.original_bytes as f64)
+                < compression.compressed_bytes as f64 * POOR_COMPRESSION
+        {
+            issues.push(format!(
+                "Poor compression: the {} compressed values only shrank from {} to {}, while \
+                 every read pays for decompressing them. Raise --compression-threshold or turn \
+                 compression off.",
+                compression.values,
+                human_bytes(compression.original_bytes),
+                human_bytes(compression.compressed_bytes)
+            ));
+        }
+        if self.lazyfree_pending >= LAZYFREE_BACKLOG {
+            issues.push(format!(
+                "Lazy free backlog: {} objects are waiting to be freed in the background, so \
+                 the memory they take isn't counted above yet.",
+                self.lazyfree_pending
+            ));
+        }
+
+        if issues.is_empty() {
+            return "I can't find any memory issue in this instance.\n".to_string();
+        }
+        let issues: Vec<_> = issues.iter().map(|issue| format!(" * {issue}\n")).collect();
+        format!(
+            "I detected a few issues in this instance's memory:\n\n{}",
+            issues.join("\n")
+        )
+    }
+}
+
+fn percentage(part: usize, whole: usize) -> Types {
+    Types::Double(OrderedFloat(part as f64 * 100.0 / whole as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(used: usize, overhead: usize) -> MemoryReport {
+        MemoryReport {
+            memory: MemoryStats {
+                used,
+                overhead,
+                policy: "noeviction",
+                ..MemoryStats::default()
+            },
+            keys: 10,
+            clients: 1024,
+            replicas: 256,
+            backlog: 512,
+            lazyfree_pending: 0,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let Types::Map(stats) = report(2000, 500).stats() else {
+            panic!("expected a map");
+        };
+        let field = |name: &str| stats[&Types::BulkString(name.to_string())].clone();
+        assert_eq!(field("total.allocated"), Types::Integer(3792));
+        assert_eq!(field("overhead.total"), Types::Integer(2292));
+        assert_eq!(field("dataset.bytes"), Types::Integer(1500));
+        assert_eq!(field("keys.bytes-per-key"), Types::Integer(200));
+        assert_eq!(field("clients.normal"), Types::Integer(1024));
+        assert_eq!(field("clients.slaves"), Types::Integer(256));
+        assert_eq!(field("replication.backlog"), Types::Integer(512));
+    }
+
+    #[test]
+    fn test_diagnose() {
+        assert!(report(1000, 500).diagnose().contains("very little memory"));
+        let healthy = report(100 << 20, 10 << 20);
+        assert!(healthy.diagnose().contains("can't find any memory issue"));
+
+        let mut sick = report(100 << 20, 60 << 20);
+        sick.memory.max = 101 << 20;
+        sick.memory.evicted_keys = 3;
+        let diagnosis = sick.diagnose();
+        assert!(diagnosis.contains("High overhead: bookkeeping takes 60%"));
+        assert!(diagnosis.contains("writes will soon fail with OOM errors"));
+        assert!(diagnosis.contains("3 keys were evicted"));
+        assert!(!diagnosis.contains("Client output buffers"));
+
+        let mut slow = report(10 << 20, 1 << 20);
+        slow.replicas = 12 << 20;
+        assert!(
+            slow.diagnose()
+                .contains("of replication stream are waiting")
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Write commands as they are propagated to the append-only file and to
+//! followers, and applied back from there.
+
+use crate::command_processor::{key_bytes, restored_entry};
+use oh_my_kiwi_domain::Engine;
+use oh_my_kiwi_domain::command::KiwiCommand;
+use oh_my_kiwi_domain::types::Types;
+use std::sync::Arc;
+use tokio::sync::{OwnedRwLockReadGuard, RwLock, RwLockWriteGuard};
+
+/// Lets a copy of the data set be taken at an exact position of the stream of
+/// propagated writes.
+///
+/// Write commands hold [`WriteBarrier::enter`] while they change the engine and
+/// propagate, so whoever holds [`WriteBarrier::exclusive`] sees each write
+/// either in the engine or later in the stream, never in both.
+#[derive(Default)]
+pub struct WriteBarrier {
+    lock: Arc<RwLock<()>>,
+}
+
+impl WriteBarrier {
+    pub async fn enter(&self) -> OwnedRwLockReadGuard<()> {
+        self.lock.clone().read_owned().await
+    }
+
+    pub async fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.lock.write().await
+    }
+}
+
+/// RESP encoding of a write command, `None` for commands that are not
+/// propagated.
+pub(crate) fn command_bytes(command: &KiwiCommand) -> Option<Vec<u8>> {
+    command_args(command).map(|args| Types::Array(args).to_bytes())
+}
+
+/// Arguments propagated for a write command, `None` for commands that are not
+/// propagated.
+fn command_args(command: &KiwiCommand) -> Option<Vec<Types>> {
+    let bulk = |value: &str| Types::BulkString(value.to_string());
+    let args = match command {
+        KiwiCommand::Set { key, value } => vec![bulk("SET"), key.clone(), value.clone()],
+        KiwiCommand::Del { keys } => [vec![bulk("DEL")], keys.clone()].concat(),
+        KiwiCommand::Unlink { keys } => [vec![bulk("UNLINK")], keys.clone()].concat(),
+        KiwiCommand::Move { key, db } => vec![bulk("MOVE"), key.clone(), bulk(&db.to_string())],
+        KiwiCommand::SwapDb { first, second } => vec![
+            bulk("SWAPDB"),
+            bulk(&first.to_string()),
+            bulk(&second.to_string()),
+        ],
+        KiwiCommand::FlushDb { .. } => vec![bulk("FLUSHDB")],
+        KiwiCommand::FlushAll { .. } => vec![bulk("FLUSHALL")],
+        KiwiCommand::PExpireAt { key, timestamp_ms } => vec![
+            bulk("PEXPIREAT"),
+            key.clone(),
+            bulk(&timestamp_ms.to_string()),
+        ],
+        KiwiCommand::Persist { key } => vec![bulk("PERSIST"), key.clone()],
+        KiwiCommand::Restore {
+            key,
+            ttl,
+            payload,
+            options,
+        } => {
+            let mut args = vec![
+                bulk("RESTORE"),
+                key.clone(),
+                bulk(&ttl.to_string()),
+                Types::BulkBytes(payload.clone()),
+            ];
+            if options.replace {
+                args.push(bulk("REPLACE"));
+            }
+            if options.absttl {
+                args.push(bulk("ABSTTL"));
+            }
+            args
+        }
+        _ => return None,
+    };
+    Some(args)
+}
+
+pub(crate) fn parse_command(types: Types) -> Result<KiwiCommand, String> {
+    let Types::Array(mut args) = types else {
+        return Err("expected a command".to_string());
+    };
+    if args.is_empty() {
+        return Err("empty command".to_string());
+    }
+    match args.remove(0) {
+        Types::BulkString(name) => {
+            KiwiCommand::parse_command(&name, args).map_err(|err| err.to_string())
+        }
+        _ => Err("expected a command name".to_string()),
+    }
+}
+
+/// Applies propagated commands to the engine, tracking the selected database.
+#[derive(Default)]
+pub(crate) struct Replay {
+    pub(crate) db: usize,
+    pub(crate) commands: usize,
+}
+
+impl Replay {
+    pub(crate) async fn apply<E: Engine>(
+        &mut self,
+        engine: &E,
+        command: KiwiCommand,
+    ) -> Result<(), String> {
+        let check = |index: usize| match index < engine.databases() {
+            true => Ok(index),
+            false => Err(format!("database {index} is out of range")),
+        };
+
+        match command {
+            KiwiCommand::Select { index } => self.db = check(index)?,
+            KiwiCommand::Set { key, value } => {
+                engine.set(self.db, key_bytes(&key), value.to_bytes()).await
+            }
+            KiwiCommand::Del { keys } => {
+                for key in keys {
+                    engine.delete(self.db, &key_bytes(&key), false).await;
+                }
+            }
+            KiwiCommand::Unlink { keys } => {
+                for key in keys {
+                    engine.delete(self.db, &key_bytes(&key), true).await;
+                }
+            }
+            KiwiCommand::Move { key, db } => {
+                engine.move_key(self.db, check(db)?, &key_bytes(&key)).await;
+            }
+            KiwiCommand::SwapDb { first, second } => {
+                engine.swap(check(first)?, check(second)?).await
+            }
+            KiwiCommand::FlushDb { .. } => engine.flush(self.db, false).await,
+            KiwiCommand::FlushAll { .. } => engine.flush_all(false).await,
+            KiwiCommand::PExpireAt { key, timestamp_ms } => {
+                let expires_at = Some(timestamp_ms.max(0) as u64);
+                engine
+                    .set_expiry(self.db, &key_bytes(&key), expires_at)
+                    .await;
+            }
+            KiwiCommand::Persist { key } => {
+                engine.set_expiry(self.db, &key_bytes(&key), None).await;
+            }
+            KiwiCommand::Restore {
+                key,
+                ttl,
+                payload,
+                options,
+            } => {
+                let (entry, history) =
+                    restored_entry(&key, ttl, &payload, &options).map_err(|err| err.to_string())?;
+                engine
+                    .restore(self.db, entry, history, options.replace)
+                    .await;
+            }
+            other => return Err(format!("unexpected '{}' command", other.name())),
+        }
+        self.commands += 1;
+        Ok(())
+    }
+}
+
+pub(crate) fn select_bytes(db: usize) -> Vec<u8> {
+    let mut bytes = b"*2\r\n".to_vec();
+    bytes.extend(bulk_bytes(b"SELECT"));
+    bytes.extend(bulk_bytes(db.to_string().as_bytes()));
+    bytes
+}
+
+pub(crate) fn bulk_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut result = format!("${}\r\n", bytes.len()).into_bytes();
+    result.extend_from_slice(bytes);
+    result.extend_from_slice(b"\r\n");
+    result
+}
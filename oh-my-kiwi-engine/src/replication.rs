@@ -0,0 +1,947 @@
+//! Leader/follower replication.
+//!
+//! A follower connects to its leader and asks for a full sync: the leader
+//! sends an RDB snapshot of its data set taken at an offset of its replication
+//! stream, then every write past that offset. The follower applies the stream
+//! as it arrives and passes it on unchanged to its own followers.
+//!
+//! The snapshot is sent as it is encoded, so its length is not known up
+//! front: it is framed as `$EOF:<mark>\r\n`, the RDB, then the 40 byte mark
+//! again. Writes made meanwhile wait in the follower's output buffer, and a
+//! follower whose buffer outgrows `replica-output-buffer-limit` is dropped
+//! and has to sync again.
+//!
+//! The most recent part of the stream is kept in a backlog. A follower that
+//! reconnects asks to resume from its offset, and gets only the missing part
+//! of the stream when the backlog still holds it.
+//!
+//! Followers acknowledge the offset they have processed, and the offset their
+//! append-only file has on disk, with `REPLCONF ACK`. WAIT and WAITAOF block on
+//! these acknowledgements.
+//!
+//! Keys are removed on expiry by the leader alone, which streams their
+//! deletion: a follower only hides its expired keys until the DEL arrives.
+
+use crate::aof::AppendOnlyFile;
+use crate::config::KiwiConfig;
+use crate::propagation::{WriteBarrier, bulk_bytes, command_bytes, select_bytes};
+use crate::snapshot::rdb::write_rdb;
+use crate::time::now_ms;
+use crate::tracking::ClientId;
+use backlog::Backlog;
+use oh_my_kiwi_domain::Engine;
+use oh_my_kiwi_domain::command::KiwiCommand;
+use oh_my_kiwi_domain::types::Types;
+use sha2::{Digest, Sha256};
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+use tracing::warn;
+
+mod backlog;
+mod link;
+
+pub use link::ReplicaTarget;
+
+pub const DEFAULT_REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_REPLICA_OUTPUT_BUFFER_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Bytes of a snapshot sent to a follower at a time.
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks encoded ahead of what the follower's connection has written.
+const SNAPSHOT_CHUNKS_AHEAD: usize = 16;
+
+/// How often the leader sweeps expired keys, like Redis's default `hz`.
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// RDB auxiliary field holding the database the replication stream has
+/// selected at the snapshot's offset.
+const STREAM_DB_AUX: &str = "repl-stream-db";
+
+/// Propagates writes to the append-only file and to followers, so both see
+/// them in the same order.
+pub struct Replication {
+    barrier: Arc<WriteBarrier>,
+    aof: Option<Arc<AppendOnlyFile>>,
+    listening_port: u16,
+    /// Credentials sent to the leader before the handshake, from `masteruser`
+    /// and `masterauth`.
+    masteruser: Option<String>,
+    masterauth: Option<String>,
+    read_only: bool,
+    state: Mutex<ReplicationState>,
+    /// Bumped whenever a follower acknowledges an offset.
+    acks: watch::Sender<u64>,
+}
+
+struct ReplicationState {
+    replid: String,
+    /// History this one continues, with the first offset that is not part of
+    /// it, so that followers of a previous leader can resume here.
+    replid2: Option<(String, u64)>,
+    /// Bytes of the replication stream produced or received so far.
+    offset: u64,
+    /// Database the stream has selected, `None` until the next write selects one.
+    db: Option<usize>,
+    backlog: Backlog,
+    followers: Vec<Follower>,
+    /// Bytes a follower may have waiting to be sent, 0 for no limit.
+    output_buffer_limit: usize,
+    leader: Option<Leader>,
+}
+
+struct Follower {
+    id: ClientId,
+    addr: SocketAddr,
+    listening_port: Option<u16>,
+    stream: UnboundedSender<Vec<u8>>,
+    /// Bytes of the stream its connection has not written yet.
+    buffered: Arc<AtomicUsize>,
+    /// Offset the follower has processed.
+    ack_offset: u64,
+    /// Offset the follower's append-only file has on disk.
+    fsynced_offset: u64,
+    last_ack_ms: u64,
+}
+
+impl Follower {
+    /// A follower and the stream its connection sends, which starts with
+    /// what `snapshot` yields when given.
+    fn new(
+        id: ClientId,
+        addr: SocketAddr,
+        listening_port: Option<u16>,
+        output_buffer_limit: usize,
+        snapshot: Option<mpsc::Receiver<std::io::Result<Vec<u8>>>>,
+    ) -> (Self, FollowerStream) {
+        let (sender, receiver) = unbounded_channel();
+        let buffered = Arc::new(AtomicUsize::new(0));
+        let follower = Self {
+            id,
+            addr,
+            listening_port,
+            stream: sender,
+            buffered: buffered.clone(),
+            ack_offset: 0,
+            fsynced_offset: 0,
+            last_ack_ms: now_ms(),
+        };
+        let stream = FollowerStream {
+            snapshot,
+            stream: receiver,
+            buffered,
+            limit: output_buffer_limit,
+        };
+        (follower, stream)
+    }
+
+    /// Queues `bytes` for the follower. False once its connection is gone,
+    /// or once it fell further behind than `limit`.
+    fn send(&self, bytes: &[u8], limit: usize) -> bool {
+        let buffered = self.buffered.fetch_add(bytes.len(), Ordering::Relaxed) + bytes.len();
+        if limit > 0 && buffered > limit {
+            warn!(
+                "Dropping follower {}, {buffered} bytes are waiting to be sent to it",
+                self.addr
+            );
+            return false;
+        }
+        self.stream.send(bytes.to_vec()).is_ok()
+    }
+}
+
+/// What a follower's connection sends: the snapshot of a full sync as it is
+/// encoded, then the replication stream.
+pub struct FollowerStream {
+    snapshot: Option<mpsc::Receiver<std::io::Result<Vec<u8>>>>,
+    stream: UnboundedReceiver<Vec<u8>>,
+    buffered: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl FollowerStream {
+    /// Waits for the next bytes to send. `None` means the follower is done
+    /// for and its connection should be closed: encoding the snapshot
+    /// failed, or it fell further behind than the output buffer limit.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        if self.limit > 0 && self.buffered() > self.limit {
+            return None;
+        }
+        if let Some(snapshot) = &mut self.snapshot {
+            match snapshot.recv().await {
+                Some(Ok(chunk)) => return Some(chunk),
+                Some(Err(_)) => return None,
+                None => self.snapshot = None,
+            }
+        }
+        let bytes = self.stream.recv().await?;
+        self.buffered.fetch_sub(bytes.len(), Ordering::Relaxed);
+        Some(bytes)
+    }
+
+    /// Bytes of the stream waiting to be sent.
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+}
+
+/// What WAIT and WAITAOF wait for: the local append-only file and a number of
+/// followers reaching a stream offset.
+pub struct WaitFor {
+    pub offset: u64,
+    /// Wait for the local append-only file to fsync the offset.
+    pub local: bool,
+    pub replicas: usize,
+    /// Count followers that fsynced the offset rather than just received it.
+    pub fsynced: bool,
+}
+
+/// The instance this one follows.
+struct Leader {
+    host: String,
+    port: u16,
+    link: LinkState,
+    last_io_ms: u64,
+    task: AbortHandle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkState {
+    Connecting,
+    Syncing,
+    Connected,
+}
+
+/// What a resuming follower receives: the part of the stream it missed and
+/// the writes after it.
+pub struct PartialSync {
+    pub replid: String,
+    pub backlog: Vec<u8>,
+    pub stream: FollowerStream,
+}
+
+/// What a follower receives from a full sync: an RDB snapshot of the data set
+/// at `offset` of the stream identified by `replid`, and the writes after it.
+/// The stream starts with the snapshot.
+pub struct FullSync {
+    pub replid: String,
+    pub offset: u64,
+    pub stream: FollowerStream,
+}
+
+impl Replication {
+    pub fn new(
+        config: &KiwiConfig,
+        listening_port: u16,
+        barrier: Arc<WriteBarrier>,
+        aof: Option<Arc<AppendOnlyFile>>,
+    ) -> Self {
+        Self {
+            barrier,
+            aof,
+            listening_port,
+            masteruser: config.masteruser().map(str::to_string),
+            masterauth: config.masterauth().map(str::to_string),
+            read_only: config.replica_read_only(),
+            state: Mutex::new(ReplicationState {
+                replid: new_replid(),
+                replid2: None,
+                offset: 0,
+                db: None,
+                backlog: Backlog::new(config.repl_backlog_size()),
+                followers: Vec::new(),
+                output_buffer_limit: config.replica_output_buffer_limit(),
+                leader: None,
+            }),
+            acks: watch::Sender::new(0),
+        }
+    }
+
+    pub fn barrier(&self) -> &WriteBarrier {
+        &self.barrier
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.state.lock().unwrap().leader.is_some()
+    }
+
+    /// Whether client writes are rejected, as on a read-only follower.
+    pub fn rejects_writes(&self) -> bool {
+        self.read_only && self.is_follower()
+    }
+
+    /// Appends a write executed against `db` to the append-only file and
+    /// streams it to the followers, returning the stream offset once it is in.
+    /// Writes made directly on a follower are not passed on.
+    pub fn feed(&self, db: usize, command: &KiwiCommand) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let Some(command_bytes) = command_bytes(command) else {
+            return state.offset;
+        };
+        if state.leader.is_none() {
+            let mut bytes = Vec::new();
+            if state.db != Some(db) {
+                bytes.extend(select_bytes(db));
+                state.db = Some(db);
+            }
+            bytes.extend(command_bytes);
+            state.append(bytes);
+        }
+        if let Some(aof) = &self.aof {
+            aof.append(db, command, state.offset);
+        }
+        state.offset
+    }
+
+    /// Removes expired keys nobody reads in the background while leading, and
+    /// deletes them on the followers and in the append-only file.
+    pub async fn run_active_expire<E>(self: Arc<Self>, target: ReplicaTarget<E>)
+    where
+        E: Engine + Send + Sync + 'static,
+    {
+        let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.is_follower() {
+                continue;
+            }
+            let _barrier = self.barrier.enter().await;
+            let expired = target.engine.expire_cycle().await;
+            target.snapshots.mark_dirty(expired.len() as u64);
+            for (db, key) in expired {
+                target.tracking.invalidate(&key, None);
+                let del = KiwiCommand::Del {
+                    keys: vec![Types::BulkBytes(key)],
+                };
+                self.feed(db, &del);
+            }
+        }
+    }
+
+    /// Resumes a follower at `offset`, the next byte it needs of the history
+    /// `replid`. Returns `None` when it needs a full sync instead.
+    pub fn partial_sync(
+        &self,
+        id: ClientId,
+        addr: SocketAddr,
+        listening_port: Option<u16>,
+        replid: &str,
+        offset: i64,
+    ) -> Option<PartialSync> {
+        let mut state = self.state.lock().unwrap();
+        let known = replid == state.replid
+            || matches!(&state.replid2, Some((replid2, end)) if replid2 == replid && offset as u64 <= *end);
+        let received = u64::try_from(offset).ok()?.checked_sub(1)?;
+        if !known || received > state.offset {
+            return None;
+        }
+        let backlog = state.backlog.tail((state.offset - received) as usize)?;
+
+        let limit = state.output_buffer_limit;
+        let (follower, stream) = Follower::new(id, addr, listening_port, limit, None);
+        state.add_follower(follower);
+        Some(PartialSync {
+            replid: state.replid.clone(),
+            backlog,
+            stream,
+        })
+    }
+
+    /// Registers a follower and takes the snapshot it starts from, which is
+    /// encoded on a blocking thread as the follower's connection sends it.
+    pub async fn full_sync<E: Engine>(
+        &self,
+        engine: &E,
+        id: ClientId,
+        addr: SocketAddr,
+        listening_port: Option<u16>,
+    ) -> FullSync {
+        let (chunks, snapshot) = mpsc::channel(SNAPSHOT_CHUNKS_AHEAD);
+        let barrier = self.barrier.exclusive().await;
+        let (replid, offset, db, stream) = {
+            let mut state = self.state.lock().unwrap();
+            let limit = state.output_buffer_limit;
+            let (follower, stream) = Follower::new(id, addr, listening_port, limit, Some(snapshot));
+            state.add_follower(follower);
+            (state.replid.clone(), state.offset, state.db, stream)
+        };
+        // Writers only wait for the copy-on-write snapshot to be taken; the
+        // RDB is encoded from it afterwards.
+        let databases = engine.snapshot().await;
+        drop(barrier);
+
+        let stream_db = db.map(|db| db.to_string());
+        tokio::task::spawn_blocking(move || {
+            let aux: Vec<_> = stream_db
+                .iter()
+                .map(|db| (STREAM_DB_AUX, db.as_str()))
+                .collect();
+            let mark = new_replid();
+            let mut writer = ChunkWriter {
+                chunk: format!("$EOF:{mark}\r\n").into_bytes(),
+                chunks,
+            };
+            let written = write_rdb(&mut writer, &aux, &*databases)
+                .and_then(|_| writer.write_all(mark.as_bytes()))
+                .and_then(|_| writer.flush());
+            if let Err(err) = written {
+                if err.kind() != ErrorKind::BrokenPipe {
+                    warn!("Sending a snapshot to follower {addr} failed: {err}");
+                }
+                writer.chunks.blocking_send(Err(err)).ok();
+            }
+        });
+        FullSync {
+            replid,
+            offset,
+            stream,
+        }
+    }
+
+    /// Records a follower's `REPLCONF ACK`.
+    pub fn ack(&self, id: ClientId, offset: u64, fsynced_offset: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(follower) = state
+            .followers
+            .iter_mut()
+            .find(|follower| follower.id == id)
+        {
+            follower.ack_offset = follower.ack_offset.max(offset);
+            if let Some(fsynced_offset) = fsynced_offset {
+                follower.fsynced_offset = follower.fsynced_offset.max(fsynced_offset);
+            }
+            follower.last_ack_ms = now_ms();
+            self.acks.send_modify(|acks| *acks += 1);
+        }
+    }
+
+    /// Waits until `wait` is met or `timeout` passes, then returns whether the
+    /// local append-only file fsynced the offset and how many followers
+    /// acknowledged it.
+    pub async fn wait(&self, wait: WaitFor, timeout: Option<Duration>) -> (bool, usize) {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut acks = self.acks.subscribe();
+        let mut fsynced = self.aof.as_ref().map(|aof| aof.fsynced_offset());
+        let mut requested = false;
+        loop {
+            let local = fsynced
+                .as_ref()
+                .is_some_and(|fsynced| *fsynced.borrow() >= wait.offset);
+            let replicas = self.acknowledged(wait.offset, wait.fsynced);
+            if (local || !wait.local) && replicas >= wait.replicas {
+                return (local, replicas);
+            }
+            if !requested && replicas < wait.replicas {
+                self.request_acks();
+                requested = true;
+            }
+
+            let local_changed = async {
+                match &mut fsynced {
+                    Some(fsynced) if wait.local => fsynced.changed().await,
+                    _ => std::future::pending().await,
+                }
+            };
+            let expired = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = acks.changed() => {}
+                _ = local_changed => {}
+                _ = expired => return (local, replicas),
+            }
+        }
+    }
+
+    fn acknowledged(&self, offset: u64, fsynced: bool) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .followers
+            .iter()
+            .filter(|follower| match fsynced {
+                true => follower.fsynced_offset >= offset,
+                false => follower.ack_offset >= offset,
+            })
+            .count()
+    }
+
+    /// Asks the followers to acknowledge their offset right away.
+    fn request_acks(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.leader.is_none() && !state.followers.is_empty() {
+            state.append(command_line(&["REPLCONF", "GETACK", "*"]));
+        }
+    }
+
+    pub fn remove_follower(&self, id: ClientId) {
+        let mut state = self.state.lock().unwrap();
+        state.followers.retain(|follower| follower.id != id);
+    }
+
+    /// Starts following the leader at `host:port`, replacing the current one.
+    pub fn follow<E>(self: &Arc<Self>, host: String, port: u16, target: ReplicaTarget<E>)
+    where
+        E: Engine + Send + Sync + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        if let Some(leader) = &state.leader {
+            if leader.host == host && leader.port == port {
+                return;
+            }
+            leader.task.abort();
+        }
+        let task = tokio::spawn(link::run(self.clone(), host.clone(), port, target));
+        state.leader = Some(Leader {
+            host,
+            port,
+            link: LinkState::Connecting,
+            last_io_ms: 0,
+            task: task.abort_handle(),
+        });
+    }
+
+    /// Stops following and starts a new history, as REPLICAOF NO ONE does.
+    pub fn promote(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(leader) = state.leader.take() {
+            leader.task.abort();
+            state.switch_replid(new_replid());
+            state.db = None;
+        }
+    }
+
+    /// Lines of the replication section of INFO.
+    pub fn info(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut lines = Vec::new();
+        match &state.leader {
+            Some(leader) => {
+                let last_io = match leader.last_io_ms {
+                    0 => -1,
+                    last_io_ms => (now_ms().saturating_sub(last_io_ms) / 1000) as i64,
+                };
+                lines.extend([
+                    "role:slave".to_string(),
+                    format!("master_host:{}", leader.host),
+                    format!("master_port:{}", leader.port),
+                    format!(
+                        "master_link_status:{}",
+                        match leader.link {
+                            LinkState::Connected => "up",
+                            _ => "down",
+                        }
+                    ),
+                    format!("master_last_io_seconds_ago:{last_io}"),
+                    format!(
+                        "master_sync_in_progress:{}",
+                        (leader.link == LinkState::Syncing) as u8
+                    ),
+                    format!("slave_repl_offset:{}", state.offset),
+                    format!("slave_read_only:{}", self.read_only as u8),
+                ]);
+            }
+            None => lines.push("role:master".to_string()),
+        }
+
+        lines.push(format!("connected_slaves:{}", state.followers.len()));
+        for (index, follower) in state.followers.iter().enumerate() {
+            let port = follower.listening_port.unwrap_or(follower.addr.port());
+            lines.push(format!(
+                "slave{index}:ip={},port={port},state=online,offset={},lag={}",
+                follower.addr.ip(),
+                follower.ack_offset,
+                now_ms().saturating_sub(follower.last_ack_ms) / 1000
+            ));
+        }
+
+        let histlen = state.backlog.len() as u64;
+        let (replid2, second_offset) = match &state.replid2 {
+            Some((replid2, end)) => (replid2.clone(), *end as i64),
+            None => ("0".repeat(40), -1),
+        };
+        lines.extend([
+            format!("master_replid:{}", state.replid),
+            format!("master_replid2:{replid2}"),
+            format!("master_repl_offset:{}", state.offset),
+            format!("second_repl_offset:{second_offset}"),
+            format!("repl_backlog_active:{}", !state.followers.is_empty() as u8),
+            format!("repl_backlog_size:{}", state.backlog.capacity()),
+            format!(
+                "repl_backlog_first_byte_offset:{}",
+                state.offset - histlen + 1
+            ),
+            format!("repl_backlog_histlen:{histlen}"),
+        ]);
+        lines
+    }
+
+    /// Bytes of the stream the backlog holds.
+    pub fn backlog_len(&self) -> usize {
+        self.state.lock().unwrap().backlog.len()
+    }
+
+    /// Bytes of the stream waiting to be sent to the followers.
+    pub fn output_buffers(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .followers
+            .iter()
+            .map(|follower| follower.buffered.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn listening_port(&self) -> u16 {
+        self.listening_port
+    }
+
+    fn set_link_state(&self, link: LinkState) {
+        if let Some(leader) = &mut self.state.lock().unwrap().leader {
+            leader.link = link;
+            leader.last_io_ms = now_ms();
+        }
+    }
+
+    /// Adopts the leader's stream after a full sync. Followers of this instance
+    /// were synced from the previous data set, so they are dropped.
+    fn reset_stream(&self, replid: String, offset: u64, db: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.replid2 = None;
+        state.offset = offset;
+        state.db = Some(db);
+        state.backlog.clear();
+        state.followers.clear();
+        if let Some(leader) = &mut state.leader {
+            leader.link = LinkState::Connected;
+            leader.last_io_ms = now_ms();
+        }
+    }
+
+    /// Where a reconnecting follower asks to resume: its history and the next
+    /// byte it needs.
+    fn resume_position(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.replid.clone(), state.offset + 1)
+    }
+
+    /// Continues the stream after a partial sync, adopting the leader's
+    /// history when it has a new ID. Returns the database the stream has
+    /// selected.
+    fn continue_stream(&self, replid: Option<String>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replid) = replid.filter(|replid| *replid != state.replid) {
+            state.switch_replid(replid);
+        }
+        if let Some(leader) = &mut state.leader {
+            leader.link = LinkState::Connected;
+            leader.last_io_ms = now_ms();
+        }
+        state.db.unwrap_or(0)
+    }
+
+    /// Passes on the bytes of a command from the leader's stream, which runs
+    /// against `db`, and appends it to the append-only file.
+    fn forward(&self, bytes: Vec<u8>, db: usize, command: &KiwiCommand) {
+        let mut state = self.state.lock().unwrap();
+        state.db = Some(match command {
+            KiwiCommand::Select { index } => *index,
+            _ => db,
+        });
+        if let Some(leader) = &mut state.leader {
+            leader.last_io_ms = now_ms();
+        }
+        state.append(bytes);
+        if let Some(aof) = &self.aof {
+            aof.append(db, command, state.offset);
+        }
+    }
+
+    /// Offsets a follower acknowledges: the stream it processed and, with an
+    /// append-only file, what of it is on disk.
+    fn ack_offsets(&self) -> (u64, Option<u64>) {
+        let offset = self.state.lock().unwrap().offset;
+        let fsynced = self.aof.as_ref().map(|aof| *aof.fsynced_offset().borrow());
+        (offset, fsynced)
+    }
+}
+
+impl ReplicationState {
+    fn add_follower(&mut self, follower: Follower) {
+        self.followers.retain(|current| current.id != follower.id);
+        self.followers.push(follower);
+    }
+
+    /// Starts a new history that continues the current one.
+    fn switch_replid(&mut self, replid: String) {
+        let previous = std::mem::replace(&mut self.replid, replid);
+        self.replid2 = Some((previous, self.offset + 1));
+    }
+
+    fn append(&mut self, bytes: Vec<u8>) {
+        self.offset += bytes.len() as u64;
+        self.backlog.push(&bytes);
+        let limit = self.output_buffer_limit;
+        self.followers
+            .retain(|follower| follower.send(&bytes, limit));
+    }
+}
+
+/// Hands what is written to it to a follower's connection in chunks, waiting
+/// while the connection is behind.
+struct ChunkWriter {
+    chunk: Vec<u8>,
+    chunks: mpsc::Sender<std::io::Result<Vec<u8>>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= SNAPSHOT_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.chunk.is_empty() {
+            let chunk = std::mem::take(&mut self.chunk);
+            self.chunks
+                .blocking_send(Ok(chunk))
+                .map_err(|_| std::io::Error::from(ErrorKind::BrokenPipe))?;
+        }
+        Ok(())
+    }
+}
+
+/// RESP encoding of a command given as plain arguments.
+pub(crate) fn command_line(args: &[&str]) -> Vec<u8> {
+    let mut bytes = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        bytes.extend(bulk_bytes(arg.as_bytes()));
+    }
+    bytes
+}
+
+/// A random 40 character hex identifier, naming replication histories and
+/// cluster nodes.
+pub(crate) fn new_replid() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let seed = format!(
+        "{nanos}:{}:{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    Sha256::digest(seed.as_bytes())[..20]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_memory::InMemoryEngine;
+    use crate::snapshot::rdb::read_rdb_with_aux;
+    use oh_my_kiwi_domain::types::Types;
+
+    fn replication() -> Replication {
+        Replication::new(&KiwiConfig::default(), 6379, Arc::default(), None)
+    }
+
+    fn set(key: &str, value: &str) -> KiwiCommand {
+        KiwiCommand::parse_command(
+            "SET",
+            vec![
+                Types::BulkString(key.to_string()),
+                Types::BulkString(value.to_string()),
+            ],
+        )
+        .unwrap()
+    }
+
+    /// Reads the snapshot a full sync starts the stream with.
+    async fn snapshot(stream: &mut FollowerStream) -> Vec<u8> {
+        let mut chunks = stream.snapshot.take().unwrap();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = chunks.recv().await {
+            bytes.extend(chunk.unwrap());
+        }
+        let header = bytes.iter().position(|byte| *byte == b'\n').unwrap() + 1;
+        let mark = bytes[5..header - 2].to_vec();
+        assert!(bytes.starts_with(b"$EOF:"));
+        assert!(bytes.ends_with(&mark));
+        bytes[header..bytes.len() - mark.len()].to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_full_sync_then_stream() {
+        let replication = replication();
+        let engine = InMemoryEngine::with_databases(2);
+        engine.set(1, b"k".to_vec(), b"+v\r\n".to_vec()).await;
+        replication.feed(1, &set("k", "v"));
+        let offset = replication.state.lock().unwrap().offset;
+
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let mut sync = replication.full_sync(&engine, 7, addr, Some(6380)).await;
+        assert_eq!(sync.offset, offset);
+        let (aux, databases) = read_rdb_with_aux(&snapshot(&mut sync.stream).await).unwrap();
+        assert!(aux.contains(&(STREAM_DB_AUX.to_string(), "1".to_string())));
+        assert_eq!(databases.len(), 1);
+
+        // Still in database 1, so no SELECT is needed.
+        replication.feed(1, &set("a", "b"));
+        let bytes = sync.stream.recv().await.unwrap();
+        assert_eq!(bytes, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n");
+        replication.feed(0, &set("a", "b"));
+        let bytes = sync.stream.recv().await.unwrap();
+        assert!(bytes.starts_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n"));
+
+        let info = replication.info();
+        assert!(info.contains(&"role:master".to_string()));
+        assert!(
+            info.iter()
+                .any(|line| line.starts_with("slave0:ip=127.0.0.1,port=6380,state=online"))
+        );
+        replication.remove_follower(7);
+        assert!(
+            replication
+                .info()
+                .contains(&"connected_slaves:0".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_sync() {
+        let replication = replication();
+        replication.feed(0, &set("a", "1"));
+        let (replid, offset) = replication.resume_position();
+        replication.feed(0, &set("b", "2"));
+
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let mut sync = replication
+            .partial_sync(7, addr, None, &replid, offset as i64)
+            .unwrap();
+        assert_eq!(sync.backlog, b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n");
+        replication.feed(0, &set("c", "3"));
+        assert!(sync.stream.recv().await.is_some());
+
+        assert!(
+            replication
+                .partial_sync(8, addr, None, "unknown", offset as i64)
+                .is_none()
+        );
+        assert!(
+            replication
+                .partial_sync(8, addr, None, &replid, -1)
+                .is_none()
+        );
+        assert!(
+            replication
+                .partial_sync(8, addr, None, &replid, 10_000)
+                .is_none()
+        );
+
+        // Followers of the previous history can resume after a switch.
+        replication
+            .state
+            .lock()
+            .unwrap()
+            .switch_replid(new_replid());
+        let (_, next) = replication.resume_position();
+        let sync = replication
+            .partial_sync(8, addr, None, &replid, next as i64)
+            .unwrap();
+        assert!(sync.backlog.is_empty());
+        assert!(
+            replication
+                .partial_sync(9, addr, None, &replid, next as i64 + 1)
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_acks() {
+        let replication = Arc::new(replication());
+        let engine = InMemoryEngine::new();
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let mut sync = replication.full_sync(&engine, 7, addr, None).await;
+        snapshot(&mut sync.stream).await;
+        let offset = replication.feed(0, &set("a", "1"));
+        sync.stream.recv().await.unwrap();
+
+        let wait = move |replicas| WaitFor {
+            offset,
+            local: false,
+            replicas,
+            fsynced: false,
+        };
+        let timeout = Some(Duration::from_millis(10));
+        assert_eq!(replication.wait(wait(1), timeout).await, (false, 0));
+        // Waiting asked the follower for an acknowledgement.
+        let getack = sync.stream.recv().await.unwrap();
+        assert_eq!(getack, command_line(&["REPLCONF", "GETACK", "*"]));
+
+        let waiter = tokio::spawn({
+            let replication = replication.clone();
+            async move { replication.wait(wait(1), None).await }
+        });
+        tokio::task::yield_now().await;
+        replication.ack(7, offset, None);
+        assert_eq!(waiter.await.unwrap(), (false, 1));
+        assert_eq!(replication.wait(wait(0), None).await, (false, 1));
+        assert_eq!(replication.acknowledged(offset, true), 0);
+    }
+
+    #[tokio::test]
+    async fn test_output_buffer_limit() {
+        let config = KiwiConfig::default().with_replica_output_buffer_limit(64);
+        let replication = Replication::new(&config, 6379, Arc::default(), None);
+        let engine = InMemoryEngine::new();
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let mut sync = replication.full_sync(&engine, 7, addr, None).await;
+
+        // Writes wait while the snapshot is being sent, until there are too many.
+        let followers = || replication.state.lock().unwrap().followers.len();
+        replication.feed(0, &set("a", "1"));
+        assert_eq!(followers(), 1);
+        replication.feed(0, &set("b", "2"));
+        assert_eq!(followers(), 0);
+        assert!(sync.stream.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_tiny_backlog() {
+        let config = KiwiConfig::default().with_repl_backlog_size(4);
+        let replication = Replication::new(&config, 6379, Arc::default(), None);
+        let (replid, offset) = replication.resume_position();
+        replication.feed(0, &set("a", "1"));
+
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        assert!(
+            replication
+                .partial_sync(7, addr, None, &replid, offset as i64)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_new_replid() {
+        let replid = new_replid();
+        assert_eq!(replid.len(), 40);
+        assert!(replid.chars().all(|char| char.is_ascii_hexdigit()));
+        assert_ne!(replid, new_replid());
+    }
+}
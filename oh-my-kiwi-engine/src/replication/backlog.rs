@@ -0,0 +1,60 @@
+use std::collections::VecDeque;
+
+/// The most recent bytes of the replication stream, bounded to a fixed size.
+pub(crate) struct Backlog {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl Backlog {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            bytes: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+        let excess = self.bytes.len().saturating_sub(self.capacity);
+        self.bytes.drain(..excess);
+    }
+
+    /// The last `count` bytes, if the backlog still holds that many.
+    pub(crate) fn tail(&self, count: usize) -> Option<Vec<u8>> {
+        let start = self.bytes.len().checked_sub(count)?;
+        Some(self.bytes.range(start..).copied().collect())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_the_most_recent_bytes() {
+        let mut backlog = Backlog::new(4);
+        backlog.push(b"abc");
+        backlog.push(b"def");
+        assert_eq!(backlog.len(), 4);
+        assert_eq!(backlog.bytes, b"cdef");
+        assert_eq!(backlog.tail(2).unwrap(), b"ef");
+        assert_eq!(backlog.tail(0).unwrap(), b"");
+        assert_eq!(backlog.tail(5), None);
+
+        backlog.clear();
+        assert_eq!(backlog.len(), 0);
+    }
+}
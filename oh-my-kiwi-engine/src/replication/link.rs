@@ -0,0 +1,420 @@
+//! The follower side of replication: the connection to the leader.
+
+use super::{LinkState, Replication, STREAM_DB_AUX};
+use crate::command_processor::key_bytes;
+use crate::propagation::{Replay, parse_command};
+use crate::snapshot::Snapshots;
+use crate::snapshot::rdb::{RdbEntry, read_rdb_with_aux};
+use crate::tracking::TrackingTable;
+use async_trait::async_trait;
+use oh_my_kiwi_domain::command::KiwiCommand;
+use oh_my_kiwi_domain::error::ParseError;
+use oh_my_kiwi_domain::types::Types;
+use oh_my_kiwi_domain::{BytesReader, Engine};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+/// Length of the mark that ends a snapshot sent without its length.
+const EOF_MARK_LEN: usize = 40;
+
+/// Where a follower applies what it receives from its leader.
+pub struct ReplicaTarget<E> {
+    pub engine: Arc<E>,
+    pub tracking: Arc<TrackingTable>,
+    pub snapshots: Arc<Snapshots>,
+}
+
+impl<E> Clone for ReplicaTarget<E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            tracking: self.tracking.clone(),
+            snapshots: self.snapshots.clone(),
+        }
+    }
+}
+
+/// Keeps syncing from the leader at `host:port`, reconnecting whenever the
+/// link breaks, until the task is aborted.
+pub(super) async fn run<E>(
+    replication: Arc<Replication>,
+    host: String,
+    port: u16,
+    target: ReplicaTarget<E>,
+) where
+    E: Engine + Send + Sync + 'static,
+{
+    loop {
+        replication.set_link_state(LinkState::Connecting);
+        if let Err(err) = sync(replication.clone(), &host, port, &target).await {
+            warn!("Replication link with {host}:{port} failed: {err}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync<E>(
+    replication: Arc<Replication>,
+    host: &str,
+    port: u16,
+    target: &ReplicaTarget<E>,
+) -> Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+{
+    let (reader, mut writer) = TcpStream::connect((host, port)).await?.into_split();
+    let mut reader = LeaderReader::new(reader);
+
+    let listening_port = replication.listening_port().to_string();
+    let mut handshake: Vec<Vec<&str>> = Vec::new();
+    if let Some(password) = &replication.masterauth {
+        let mut auth = vec!["AUTH"];
+        auth.extend(replication.masteruser.as_deref());
+        auth.push(password);
+        handshake.push(auth);
+    }
+    handshake.extend([
+        vec!["PING"],
+        vec!["REPLCONF", "listening-port", &listening_port],
+        vec!["REPLCONF", "capa", "eof", "capa", "psync2"],
+    ]);
+    for command in &handshake {
+        send(&mut writer, command).await?;
+        let reply = reader.reply().await?;
+        if reply.starts_with('-') {
+            return Err(Error::other(format!("{} failed: {reply}", command[0])));
+        }
+    }
+
+    // Ask to resume where the stream left off. The leader falls back to a
+    // full sync for an unknown history or an offset its backlog no longer holds.
+    let (replid, offset) = replication.resume_position();
+    send(&mut writer, &["PSYNC", &replid, &offset.to_string()]).await?;
+    let reply = reader.reply().await?;
+    let db = match reply.split_whitespace().collect::<Vec<_>>()[..] {
+        ["+FULLRESYNC", replid, offset] => {
+            let offset = parse(offset)?;
+            replication.set_link_state(LinkState::Syncing);
+            let snapshot = reader.payload().await?;
+            let len = snapshot.len();
+            let db = load(&replication, target, snapshot).await?;
+            replication.reset_stream(replid.to_string(), offset, db);
+            info!("Full sync with {host}:{port} done, {len} bytes");
+            if let Some(aof) = &replication.aof {
+                // The log still describes the data set from before the sync.
+                if let Err(err) = aof.background_rewrite(target.engine.clone()).await {
+                    warn!("Rewriting the append only file after the sync failed: {err}");
+                }
+            }
+            db
+        }
+        ["+CONTINUE", ref replid @ ..] => {
+            info!("Resumed replication with {host}:{port} at offset {offset}");
+            replication.continue_stream(replid.first().map(|replid| replid.to_string()))
+        }
+        _ => return Err(Error::other(format!("unexpected PSYNC reply: {reply}"))),
+    };
+
+    let writer = Arc::new(Mutex::new(writer));
+    let acks = tokio::spawn(send_acks(replication.clone(), writer.clone()));
+    let _acks = AbortOnDrop(acks.abort_handle());
+
+    let mut replay = Replay {
+        db,
+        ..Replay::default()
+    };
+    loop {
+        reader.recorded.clear();
+        let types = Types::from_bytes(&mut reader).await.map_err(parse_error)?;
+        let bytes = std::mem::take(&mut reader.recorded);
+        let command = parse_command(types).map_err(Error::other)?;
+        let getack = matches!(
+            &command,
+            KiwiCommand::ReplConf(options) if options.iter().any(|(option, _)| option == "getack")
+        );
+        apply(&replication, target, &mut replay, command, bytes).await?;
+        if getack {
+            send_ack(&replication, &writer).await?;
+        }
+    }
+}
+
+/// Replaces the data set with the snapshot, returning the database the stream
+/// has selected at the snapshot's offset. The snapshot is parsed on a
+/// blocking thread.
+async fn load<E: Engine>(
+    replication: &Replication,
+    target: &ReplicaTarget<E>,
+    snapshot: Vec<u8>,
+) -> Result<usize> {
+    let (aux, databases) = tokio::task::spawn_blocking(move || read_rdb_with_aux(&snapshot))
+        .await
+        .map_err(Error::other)??;
+    let engine = target.engine.as_ref();
+    if let Some((index, _)) = databases
+        .iter()
+        .find(|(index, _)| *index >= engine.databases())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("snapshot contains database {index}, which is out of range"),
+        ));
+    }
+
+    let _barrier = replication.barrier.exclusive().await;
+    engine.flush_all(false).await;
+    for (index, entries) in databases {
+        let entries = entries.into_iter().map(RdbEntry::into_entry).collect();
+        engine.insert_entries(index, entries).await;
+    }
+    target.tracking.invalidate_all(None);
+    target.snapshots.mark_dirty(1);
+
+    let db = aux
+        .iter()
+        .find(|(key, _)| key == STREAM_DB_AUX)
+        .map_or(Ok(0), |(_, db)| parse(db))?;
+    Ok(db)
+}
+
+/// Applies a command from the leader and passes it on.
+async fn apply<E: Engine>(
+    replication: &Replication,
+    target: &ReplicaTarget<E>,
+    replay: &mut Replay,
+    command: KiwiCommand,
+    bytes: Vec<u8>,
+) -> Result<()> {
+    let _barrier = replication.barrier.enter().await;
+    replication.forward(bytes, replay.db, &command);
+    match &command {
+        // Leaders ping their followers to show the link is alive.
+        KiwiCommand::Ping | KiwiCommand::ReplConf(_) => return Ok(()),
+        KiwiCommand::Select { .. } => {}
+        KiwiCommand::SwapDb { .. } | KiwiCommand::FlushDb { .. } | KiwiCommand::FlushAll { .. } => {
+            target.tracking.invalidate_all(None);
+            target.snapshots.mark_dirty(1);
+        }
+        command => {
+            for key in command.keys() {
+                target.tracking.invalidate(&key_bytes(key), None);
+            }
+            target.snapshots.mark_dirty(1);
+        }
+    }
+    replay
+        .apply(target.engine.as_ref(), command)
+        .await
+        .map_err(Error::other)
+}
+
+/// Acknowledges the processed offset to the leader once a second.
+async fn send_acks(replication: Arc<Replication>, writer: Arc<Mutex<OwnedWriteHalf>>) {
+    let mut interval = tokio::time::interval(ACK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if send_ack(&replication, &writer).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn send_ack(replication: &Replication, writer: &Mutex<OwnedWriteHalf>) -> Result<()> {
+    let (offset, fsynced) = replication.ack_offsets();
+    let offset = offset.to_string();
+    let fsynced = fsynced.map(|fsynced| fsynced.to_string());
+    let mut args = vec!["REPLCONF", "ACK", &offset];
+    if let Some(fsynced) = &fsynced {
+        args.extend(["FACK", fsynced]);
+    }
+    send(&mut *writer.lock().await, &args).await
+}
+
+/// Aborts a task when dropped.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn send(writer: &mut (impl AsyncWriteExt + Unpin), args: &[&str]) -> Result<()> {
+    let args = args
+        .iter()
+        .map(|arg| Types::BulkString(arg.to_string()))
+        .collect();
+    writer.write_all(&Types::Array(args).to_bytes()).await
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("invalid number '{value}'")))
+}
+
+fn parse_error(err: ParseError) -> Error {
+    match err {
+        ParseError::ConnectionError(err) => err,
+        err => Error::new(ErrorKind::InvalidData, err),
+    }
+}
+
+/// Reads from the leader, recording the raw bytes of what it parses so they
+/// can be passed on to followers as they are.
+struct LeaderReader {
+    reader: BufReader<OwnedReadHalf>,
+    recorded: Vec<u8>,
+}
+
+impl LeaderReader {
+    fn new(reader: OwnedReadHalf) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            recorded: Vec::new(),
+        }
+    }
+
+    /// Reads a single line reply, skipping the bare newlines leaders send to
+    /// keep the link alive while they prepare a snapshot.
+    async fn reply(&mut self) -> Result<String> {
+        loop {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            if !line.is_empty() {
+                return Ok(line);
+            }
+        }
+    }
+
+    /// Reads the snapshot sent as `$<len>\r\n` followed by its bytes, or as
+    /// `$EOF:<mark>\r\n` followed by its bytes and the mark. The buffer
+    /// grows with the bytes that actually arrive rather than with the
+    /// announced length.
+    async fn payload(&mut self) -> Result<Vec<u8>> {
+        let reply = self.reply().await?;
+        if let Some(mark) = reply.strip_prefix("$EOF:") {
+            return self.payload_until(mark.as_bytes()).await;
+        }
+        let len: u64 = match reply.strip_prefix('$') {
+            Some(len) => parse(len)?,
+            None => return Err(Error::other(format!("unexpected sync reply: {reply}"))),
+        };
+        let mut payload = Vec::new();
+        (&mut self.reader)
+            .take(len)
+            .read_to_end(&mut payload)
+            .await?;
+        if (payload.len() as u64) < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(payload)
+    }
+
+    /// Reads up to `mark`, leaving what follows it, the start of the stream,
+    /// to be read.
+    async fn payload_until(&mut self, mark: &[u8]) -> Result<Vec<u8>> {
+        if mark.len() != EOF_MARK_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid EOF mark"));
+        }
+        let mut payload = Vec::new();
+        loop {
+            let buf = self.reader.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let read = buf.len();
+            // The mark may have started in the previous read.
+            let start = payload.len().saturating_sub(mark.len() - 1);
+            payload.extend_from_slice(buf);
+            let found = payload[start..]
+                .windows(mark.len())
+                .position(|window| window == mark);
+            if let Some(position) = found {
+                let end = start + position;
+                let after = payload.len() - end - mark.len();
+                self.reader.consume(read - after);
+                payload.truncate(end);
+                return Ok(payload);
+            }
+            self.reader.consume(read);
+        }
+    }
+}
+
+#[async_trait]
+impl BytesReader for LeaderReader {
+    async fn read_line(&mut self) -> std::result::Result<Vec<u8>, ParseError> {
+        let start = self.recorded.len();
+        if self.reader.read_until(b'\n', &mut self.recorded).await? == 0 {
+            return Err(ParseError::ConnectionClosed);
+        }
+        let line = &self.recorded[start..];
+        match line.strip_suffix(b"\r\n") {
+            Some(line) if !line.is_empty() => Ok(line.to_vec()),
+            _ => Err(ParseError::MissingSeparator),
+        }
+    }
+
+    async fn read_bytes(&mut self, n: usize) -> std::result::Result<Vec<u8>, ParseError> {
+        let mut bytes = vec![0; n];
+        self.reader.read_exact(&mut bytes).await?;
+        self.recorded.extend_from_slice(&bytes);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A reader for what the returned writer sends.
+    async fn connection() -> (LeaderReader, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let writer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (reader, _) = listener.accept().await.unwrap();
+        let (reader, _) = reader.into_split();
+        (LeaderReader::new(reader), writer)
+    }
+
+    #[tokio::test]
+    async fn test_payload() {
+        let (mut reader, mut writer) = connection().await;
+        writer.write_all(b"\n$3\r\nabc").await.unwrap();
+        assert_eq!(reader.payload().await.unwrap(), b"abc");
+
+        let mark = "m".repeat(EOF_MARK_LEN);
+        writer
+            .write_all(format!("$EOF:{mark}\r\nrdb{}", &mark[..10]).as_bytes())
+            .await
+            .unwrap();
+        writer
+            .write_all(format!("{}*1\r\n", &mark[10..]).as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(reader.payload().await.unwrap(), b"rdb");
+        // What follows the mark is left for the stream.
+        assert_eq!(reader.reply().await.unwrap(), "*1");
+
+        // A length the leader never sends the bytes for is not allocated.
+        writer.write_all(b"$1000000000000\r\nabc").await.unwrap();
+        drop(writer);
+        let err = reader.payload().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
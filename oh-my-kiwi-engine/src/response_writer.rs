@@ -20,8 +20,11 @@ impl<Writer: BytesWriter> KiwiResponseWriter<Writer> {
     }
 
     pub(crate) async fn write(&mut self, response: Response) -> Result<(), KiwiError> {
-        let types = response.to_types();
-        let bytes = types.to_bytes();
+        let bytes = match response {
+            Response::Raw(bytes) => bytes,
+            Response::Close => return Ok(()),
+            response => response.to_types().to_bytes(),
+        };
         self.writer.write_all(&bytes).await?;
 
         Ok(())
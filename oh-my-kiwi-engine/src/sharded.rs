@@ -0,0 +1,401 @@
+//! An in-memory engine split into shards by key hash, each with its own
+//! lock, so commands on different keys don't wait for each other. Commands
+//! on whole databases, like FLUSHDB or SWAPDB, lock every shard, always in
+//! the same order.
+
+use crate::config::KiwiConfig;
+use crate::encoding::Codec;
+use crate::eviction::Limit;
+use crate::in_memory::{
+    Candidate, Database, DatabaseSnapshot, Lookup, Usage, active_expire, eviction_candidate,
+    move_entry, rehash_after_lookup, total_compression,
+};
+use crate::lazy_free::{free, release};
+use crate::time::now_ms;
+use async_trait::async_trait;
+use oh_my_kiwi_domain::{AccessHistory, Engine, Entry, MemoryStats, Snapshot};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{RwLock, RwLockWriteGuard};
+
+/// Shards per core when the number of shards isn't configured, enough for
+/// two busy keys to rarely share a lock.
+const SHARDS_PER_CORE: usize = 4;
+
+struct Shard {
+    /// The shard's part of every database.
+    storage: RwLock<Vec<Database>>,
+    usage: Usage,
+}
+
+impl Shard {
+    fn update_memory(&self, storage: &[Database]) {
+        self.usage.update(storage);
+    }
+}
+
+pub struct ShardedEngine {
+    databases: usize,
+    shards: Vec<Shard>,
+    hasher: RandomState,
+    limit: Limit,
+    codec: Codec,
+    evicted_keys: AtomicU64,
+}
+
+impl ShardedEngine {
+    /// An engine with the databases, shards, memory limit and compression of
+    /// `config`.
+    pub fn from_config(config: &KiwiConfig) -> Self {
+        let databases = config.databases().max(1);
+        let shards = match config.engine_shards() {
+            0 => std::thread::available_parallelism().map_or(1, usize::from) * SHARDS_PER_CORE,
+            shards => shards,
+        };
+        Self {
+            databases,
+            shards: (0..shards)
+                .map(|_| Shard {
+                    storage: RwLock::new(vec![Database::default(); databases]),
+                    usage: Usage::default(),
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            limit: Limit::from_config(config),
+            codec: Codec::from_config(config),
+            evicted_keys: AtomicU64::new(0),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard(&self, key: &[u8]) -> &Shard {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    /// Write locks on every shard, taken in order.
+    async fn lock_all(&self) -> Vec<RwLockWriteGuard<'_, Vec<Database>>> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            guards.push(shard.storage.write().await);
+        }
+        guards
+    }
+
+    fn used_memory(&self) -> usize {
+        self.shards.iter().map(|shard| shard.usage.memory()).sum()
+    }
+}
+
+#[async_trait]
+impl Engine for ShardedEngine {
+    fn databases(&self) -> usize {
+        self.databases
+    }
+
+    async fn get(&self, db: usize, key: &[u8]) -> Option<Vec<u8>> {
+        let now = now_ms();
+        let shard = self.shard(key);
+        let storage = shard.storage.read().await;
+        let lookup = storage[db].get(key, now);
+        let rehashing = storage[db].is_rehashing();
+        drop(storage);
+        if rehashing {
+            rehash_after_lookup(&shard.storage, db);
+        }
+        match lookup {
+            Lookup::Found(value) => Some(value.into_bytes()),
+            Lookup::Expired => {
+                let mut storage = shard.storage.write().await;
+                storage[db].remove_if_expired(key, now);
+                shard.update_memory(&storage);
+                None
+            }
+            Lookup::Missing => None,
+        }
+    }
+
+    async fn set(&self, db: usize, key: Vec<u8>, value: Vec<u8>) {
+        let value = self.codec.encode(value);
+        let shard = self.shard(&key);
+        let mut storage = shard.storage.write().await;
+        storage[db].set(key, value, now_ms());
+        shard.update_memory(&storage);
+    }
+
+    async fn delete(&self, db: usize, key: &[u8], lazy: bool) -> bool {
+        let shard = self.shard(key);
+        let mut storage = shard.storage.write().await;
+        let deleted = storage[db].delete(key, now_ms());
+        shard.update_memory(&storage);
+        drop(storage);
+
+        deleted.map(|value| release(value, lazy)).is_some()
+    }
+
+    async fn move_key(&self, db: usize, target: usize, key: &[u8]) -> bool {
+        let now = now_ms();
+        let shard = self.shard(key);
+        let mut storage = shard.storage.write().await;
+        storage[db].remove_if_expired(key, now);
+        let moved = match storage[target].contains(key, now) {
+            true => false,
+            false => move_entry(&mut storage, db, target, key),
+        };
+        shard.update_memory(&storage);
+        moved
+    }
+
+    async fn swap(&self, first: usize, second: usize) {
+        for mut storage in self.lock_all().await {
+            storage.swap(first, second);
+        }
+    }
+
+    async fn size(&self, db: usize) -> usize {
+        let mut size = 0;
+        for shard in &self.shards {
+            size += shard.storage.read().await[db].len();
+        }
+        size
+    }
+
+    async fn flush(&self, db: usize, lazy: bool) {
+        let mut storage = self.lock_all().await;
+        let databases: Vec<Database> = storage
+            .iter_mut()
+            .map(|storage| std::mem::take(&mut storage[db]))
+            .collect();
+        for (shard, storage) in self.shards.iter().zip(&storage) {
+            shard.update_memory(storage);
+        }
+        drop(storage);
+
+        free(databases, lazy);
+    }
+
+    async fn flush_all(&self, lazy: bool) {
+        let mut storage = self.lock_all().await;
+        let databases: Vec<Vec<Database>> = storage
+            .iter_mut()
+            .map(|storage| storage.iter_mut().map(std::mem::take).collect())
+            .collect();
+        for (shard, storage) in self.shards.iter().zip(&storage) {
+            shard.update_memory(storage);
+        }
+        drop(storage);
+
+        free(databases, lazy);
+    }
+
+    async fn set_expiry(&self, db: usize, key: &[u8], expires_at: Option<u64>) -> bool {
+        let shard = self.shard(key);
+        let mut storage = shard.storage.write().await;
+        let found = storage[db].expire(key, expires_at, now_ms());
+        shard.update_memory(&storage);
+        found
+    }
+
+    async fn expiry(&self, db: usize, key: &[u8]) -> Option<Option<u64>> {
+        let storage = self.shard(key).storage.read().await;
+        storage[db].expiry(key, now_ms())
+    }
+
+    async fn encoding(&self, db: usize, key: &[u8]) -> Option<&'static str> {
+        let storage = self.shard(key).storage.read().await;
+        storage[db].encoding(key, now_ms())
+    }
+
+    async fn memory_usage(&self, db: usize, key: &[u8]) -> Option<usize> {
+        let storage = self.shard(key).storage.read().await;
+        storage[db].memory_usage(key, now_ms())
+    }
+
+    async fn snapshot(&self) -> Box<dyn Snapshot> {
+        let now = now_ms();
+        // Every shard stays locked until all are cloned, for a consistent view.
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            guards.push(shard.storage.read().await);
+        }
+        let parts = guards.iter().map(|storage| storage.to_vec()).collect();
+        Box::new(DatabaseSnapshot::new(parts, now))
+    }
+
+    async fn restore(
+        &self,
+        db: usize,
+        entry: Entry,
+        history: AccessHistory,
+        replace: bool,
+    ) -> bool {
+        let (key, value, expires_at) = self.codec.encode_entry(entry);
+        let shard = self.shard(&key);
+        let mut storage = shard.storage.write().await;
+        let restored = storage[db].restore(key, value, expires_at, history, replace, now_ms());
+        shard.update_memory(&storage);
+        restored
+    }
+
+    async fn insert_entries(&self, db: usize, entries: Vec<Entry>) {
+        let now = now_ms();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|entry| self.codec.encode_entry(entry))
+            .collect();
+        let mut storage = self.lock_all().await;
+        for (key, value, expires_at) in entries {
+            let index = self.hasher.hash_one(&key) as usize % self.shards.len();
+            storage[index][db].insert_value(key, value, expires_at, now);
+        }
+        for (shard, storage) in self.shards.iter().zip(&storage) {
+            shard.update_memory(storage);
+        }
+    }
+
+    fn memory(&self) -> MemoryStats {
+        MemoryStats {
+            used: self.used_memory(),
+            overhead: self.shards.iter().map(|shard| shard.usage.overhead()).sum(),
+            max: self.limit.maxmemory,
+            policy: self.limit.policy.name(),
+            evicted_keys: self.evicted_keys.load(Ordering::Relaxed),
+            compression: total_compression(self.shards.iter().map(|shard| &shard.usage)),
+        }
+    }
+
+    async fn evict(&self) -> Vec<(usize, Vec<u8>)> {
+        let now = now_ms();
+        let mut evicted = Vec::new();
+        while self.limit.evicts(self.used_memory()) {
+            // The best candidate of all shards, each sampled on its own.
+            let mut best: Option<(usize, Candidate)> = None;
+            for (index, shard) in self.shards.iter().enumerate() {
+                let storage = shard.storage.read().await;
+                if let Some(candidate) = eviction_candidate(&storage, &self.limit, now)
+                    && best
+                        .as_ref()
+                        .is_none_or(|(_, best)| candidate.score > best.score)
+                {
+                    best = Some((index, candidate));
+                }
+            }
+            let Some((index, candidate)) = best else {
+                break;
+            };
+
+            let shard = &self.shards[index];
+            let mut storage = shard.storage.write().await;
+            let removed = storage[candidate.db].remove(&candidate.key);
+            shard.update_memory(&storage);
+            drop(storage);
+            if let Some((key, _)) = removed {
+                evicted.push((candidate.db, key));
+            }
+        }
+        self.evicted_keys
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        evicted
+    }
+
+    async fn expire_cycle(&self) -> Vec<(usize, Vec<u8>)> {
+        let now = now_ms();
+        let mut expired = Vec::new();
+        for shard in &self.shards {
+            let mut storage = shard.storage.write().await;
+            expired.extend(active_expire(&mut storage, now));
+            shard.update_memory(&storage);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::MaxmemoryPolicy;
+    use std::sync::Arc;
+
+    fn engine(databases: usize, shards: usize) -> ShardedEngine {
+        ShardedEngine::from_config(
+            &KiwiConfig::new()
+                .with_databases(databases)
+                .with_engine_shards(shards),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_keys_across_shards() {
+        let engine = engine(2, 8);
+        assert_eq!(engine.shard_count(), 8);
+        for i in 0..100 {
+            engine
+                .set(0, format!("key{i}").into_bytes(), b"value".to_vec())
+                .await;
+        }
+        assert_eq!(engine.size(0).await, 100);
+        assert_eq!(engine.get(0, b"key42").await, Some(b"value".to_vec()));
+        assert_eq!(engine.snapshot().await.entries(0).count(), 100);
+
+        assert!(engine.move_key(0, 1, b"key42").await);
+        assert!(engine.delete(0, b"key7", false).await);
+        assert_eq!(engine.size(0).await, 98);
+
+        engine.swap(0, 1).await;
+        assert_eq!(engine.size(0).await, 1);
+        assert_eq!(engine.get(0, b"key42").await, Some(b"value".to_vec()));
+
+        engine.flush(1, false).await;
+        assert_eq!(engine.size(1).await, 0);
+        engine.flush_all(false).await;
+        assert_eq!(engine.size(0).await, 0);
+        assert_eq!(engine.memory().used, 0);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let engine = engine(1, 4);
+        engine.set(0, b"key".to_vec(), b"value".to_vec()).await;
+        assert!(engine.set_expiry(0, b"key", Some(now_ms() + 60_000)).await);
+        assert!(matches!(engine.expiry(0, b"key").await, Some(Some(_))));
+        assert!(engine.set_expiry(0, b"key", Some(1)).await);
+        assert_eq!(engine.get(0, b"key").await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_writes() {
+        let engine = Arc::new(engine(1, 16));
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    for i in 0..500 {
+                        let key = format!("{task}:{i}").into_bytes();
+                        engine.set(0, key, b"value".to_vec()).await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(engine.size(0).await, 4000);
+    }
+
+    #[tokio::test]
+    async fn test_evict() {
+        let config = KiwiConfig::new()
+            .with_engine_shards(4)
+            .with_maxmemory(1)
+            .with_maxmemory_policy(MaxmemoryPolicy::AllKeysRandom);
+        let engine = ShardedEngine::from_config(&config);
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+        engine.set(0, b"b".to_vec(), b"2".to_vec()).await;
+        assert_eq!(engine.evict().await.len(), 2);
+        assert_eq!(engine.memory().used, 0);
+        assert_eq!(engine.memory().evicted_keys, 2);
+    }
+}
@@ -0,0 +1,583 @@
+//! A thread-per-core engine. Each core thread runs its own single-threaded
+//! runtime and owns a partition of the keyspace, chosen by key hash, that no
+//! other thread touches. Connections served on a core work on its partition
+//! directly; commands on another core's keys are sent to that core as jobs
+//! and wait for its reply. SWAPDB, FLUSHDB and FLUSHALL park every core
+//! before any applies them and hold it until all have, so no command sees
+//! them half done. Other commands spanning the keyspace, like DBSIZE or a
+//! snapshot, visit the cores in turn.
+
+use crate::config::KiwiConfig;
+use crate::encoding::Codec;
+use crate::eviction::Limit;
+use crate::in_memory::{
+    Candidate, Database, DatabaseSnapshot, Lookup, Usage, active_expire, eviction_candidate,
+    move_entry, total_compression,
+};
+use crate::lazy_free::{free, release};
+use crate::time::now_ms;
+use async_trait::async_trait;
+use oh_my_kiwi_domain::{AccessHistory, Engine, Entry, MemoryStats, Snapshot};
+use std::cell::RefCell;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Barrier};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+use tokio::sync::{Mutex, oneshot};
+
+thread_local! {
+    /// The partition owned by this thread, when it is a core thread.
+    static PARTITION: RefCell<Option<Partition>> = const { RefCell::new(None) };
+}
+
+/// Work sent to the core owning the keys it touches.
+type Job = Box<dyn FnOnce(&mut Partition) + Send>;
+
+struct Partition {
+    core: usize,
+    databases: Vec<Database>,
+    /// Usage of every partition, readable from any thread.
+    usage: Arc<Vec<Usage>>,
+}
+
+impl Partition {
+    fn run<T>(&mut self, op: impl FnOnce(&mut Vec<Database>) -> T) -> T {
+        let result = op(&mut self.databases);
+        self.usage[self.core].update(&self.databases);
+        result
+    }
+}
+
+struct Core {
+    jobs: UnboundedSender<Job>,
+    runtime: Handle,
+}
+
+pub struct SharedNothingEngine {
+    databases: usize,
+    cores: Vec<Core>,
+    usage: Arc<Vec<Usage>>,
+    hasher: RandomState,
+    limit: Limit,
+    codec: Codec,
+    evicted_keys: AtomicU64,
+    forwarded: AtomicU64,
+    /// Held while every core is parked, so two callers never park the cores
+    /// in different orders.
+    parking: Mutex<()>,
+}
+
+impl SharedNothingEngine {
+    /// Starts a core thread per configured core, every available one by
+    /// default, each owning its partition of the databases of `config`.
+    pub fn from_config(config: &KiwiConfig) -> std::io::Result<Self> {
+        let databases = config.databases().max(1);
+        let cores = match config.engine_threads() {
+            0 => std::thread::available_parallelism().map_or(1, usize::from),
+            cores => cores,
+        };
+        let usage = Arc::new((0..cores).map(|_| Usage::default()).collect::<Vec<_>>());
+        Ok(Self {
+            databases,
+            cores: (0..cores)
+                .map(|core| start_core(core, databases, usage.clone()))
+                .collect::<std::io::Result<_>>()?,
+            usage,
+            hasher: RandomState::new(),
+            limit: Limit::from_config(config),
+            codec: Codec::from_config(config),
+            evicted_keys: AtomicU64::new(0),
+            forwarded: AtomicU64::new(0),
+            parking: Mutex::new(()),
+        })
+    }
+
+    /// The runtimes of the core threads, to serve connections on.
+    pub fn runtimes(&self) -> Vec<Handle> {
+        self.cores.iter().map(|core| core.runtime.clone()).collect()
+    }
+
+    /// Commands sent to another core than the one they were issued on.
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    fn owner(&self, key: &[u8]) -> usize {
+        self.hasher.hash_one(key) as usize % self.cores.len()
+    }
+
+    /// Runs `op` on the partition of `core`: right away on that core's own
+    /// thread, through a job from anywhere else.
+    async fn on_core<T, F>(&self, core: usize, op: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Vec<Database>) -> T + Send + 'static,
+    {
+        let local = PARTITION.with_borrow(|partition| {
+            partition
+                .as_ref()
+                .is_some_and(|partition| partition.core == core)
+        });
+        if local {
+            return PARTITION
+                .with_borrow_mut(|partition| partition.as_mut().expect("checked above").run(op));
+        }
+
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |partition| {
+            let _ = reply.send(partition.run(op));
+        });
+        self.cores[core]
+            .jobs
+            .send(job)
+            .expect("core threads run as long as the engine");
+        result.await.expect("core threads answer every job")
+    }
+
+    /// Runs `op` on every partition in turn, returning each result.
+    async fn on_every_core<T, F>(&self, op: F) -> Vec<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Vec<Database>) -> T + Clone + Send + 'static,
+    {
+        let mut results = Vec::with_capacity(self.cores.len());
+        for core in 0..self.cores.len() {
+            results.push(self.on_core(core, op.clone()).await);
+        }
+        results
+    }
+
+    /// Runs `op` on every partition at once, returning each result. Every
+    /// core is parked before any runs it, and resumes once all have, so no
+    /// command is served while only some partitions changed. Run from a core
+    /// thread, the caller's own core is parked in place.
+    async fn on_all_cores<T, F>(&self, op: F) -> Vec<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Vec<Database>) -> T + Clone + Send + 'static,
+    {
+        let _parking = self.parking.lock().await;
+        let barrier = Arc::new(Barrier::new(self.cores.len()));
+        let parked = |partition: &mut Partition, barrier: &Barrier, op: F| {
+            barrier.wait();
+            let result = partition.run(op);
+            barrier.wait();
+            result
+        };
+        let local =
+            PARTITION.with_borrow(|partition| partition.as_ref().map(|partition| partition.core));
+        let mut replies = Vec::with_capacity(self.cores.len());
+        for (core, target) in self.cores.iter().enumerate() {
+            if local == Some(core) {
+                continue;
+            }
+            self.forwarded.fetch_add(1, Ordering::Relaxed);
+            let (reply, result) = oneshot::channel();
+            let (op, barrier) = (op.clone(), barrier.clone());
+            let job: Job = Box::new(move |partition| {
+                let _ = reply.send(parked(partition, &barrier, op));
+            });
+            target
+                .jobs
+                .send(job)
+                .expect("core threads run as long as the engine");
+            replies.push((core, result));
+        }
+
+        let mut results: Vec<Option<T>> = (0..self.cores.len()).map(|_| None).collect();
+        if let Some(core) = local {
+            results[core] = Some(PARTITION.with_borrow_mut(|partition| {
+                parked(partition.as_mut().expect("checked above"), &barrier, op)
+            }));
+        }
+        for (core, result) in replies {
+            results[core] = Some(result.await.expect("core threads answer every job"));
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    fn used_memory(&self) -> usize {
+        self.usage.iter().map(Usage::memory).sum()
+    }
+}
+
+/// Spawns the thread of `core`, which runs the jobs sent to its partition
+/// alongside whatever else is spawned on its runtime.
+fn start_core(core: usize, databases: usize, usage: Arc<Vec<Usage>>) -> std::io::Result<Core> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let handle = runtime.handle().clone();
+    let (jobs, mut receiver) = unbounded_channel::<Job>();
+    std::thread::Builder::new()
+        .name(format!("kiwi-core-{core}"))
+        .spawn(move || {
+            PARTITION.set(Some(Partition {
+                core,
+                databases: vec![Database::default(); databases],
+                usage,
+            }));
+            runtime.block_on(async move {
+                while let Some(job) = receiver.recv().await {
+                    PARTITION.with_borrow_mut(|partition| {
+                        job(partition.as_mut().expect("set when the thread started"))
+                    });
+                }
+            });
+        })?;
+    Ok(Core {
+        jobs,
+        runtime: handle,
+    })
+}
+
+#[async_trait]
+impl Engine for SharedNothingEngine {
+    fn databases(&self) -> usize {
+        self.databases
+    }
+
+    async fn get(&self, db: usize, key: &[u8]) -> Option<Vec<u8>> {
+        let now = now_ms();
+        let key = key.to_vec();
+        let value = self
+            .on_core(self.owner(&key), move |databases| {
+                let lookup = databases[db].get(&key, now);
+                // The core owns its databases, so lookups help a resize along.
+                databases[db].rehash_step();
+                match lookup {
+                    Lookup::Found(value) => Some(value),
+                    Lookup::Expired => {
+                        databases[db].remove_if_expired(&key, now);
+                        None
+                    }
+                    Lookup::Missing => None,
+                }
+            })
+            .await;
+        value.map(|value| value.into_bytes())
+    }
+
+    async fn set(&self, db: usize, key: Vec<u8>, value: Vec<u8>) {
+        let now = now_ms();
+        let value = self.codec.encode(value);
+        self.on_core(self.owner(&key), move |databases| {
+            databases[db].set(key, value, now)
+        })
+        .await
+    }
+
+    async fn delete(&self, db: usize, key: &[u8], lazy: bool) -> bool {
+        let now = now_ms();
+        let key = key.to_vec();
+        let deleted = self
+            .on_core(self.owner(&key), move |databases| {
+                databases[db].delete(&key, now)
+            })
+            .await;
+        deleted.map(|value| release(value, lazy)).is_some()
+    }
+
+    async fn move_key(&self, db: usize, target: usize, key: &[u8]) -> bool {
+        let now = now_ms();
+        let key = key.to_vec();
+        self.on_core(self.owner(&key), move |databases| {
+            databases[db].remove_if_expired(&key, now);
+            match databases[target].contains(&key, now) {
+                true => false,
+                false => move_entry(databases, db, target, &key),
+            }
+        })
+        .await
+    }
+
+    async fn swap(&self, first: usize, second: usize) {
+        self.on_all_cores(move |databases| databases.swap(first, second))
+            .await;
+    }
+
+    async fn size(&self, db: usize) -> usize {
+        self.on_every_core(move |databases| databases[db].len())
+            .await
+            .into_iter()
+            .sum()
+    }
+
+    async fn flush(&self, db: usize, lazy: bool) {
+        let databases = self
+            .on_all_cores(move |databases| std::mem::take(&mut databases[db]))
+            .await;
+        free(databases, lazy);
+    }
+
+    async fn flush_all(&self, lazy: bool) {
+        let databases = self
+            .on_all_cores(|databases| databases.iter_mut().map(std::mem::take).collect::<Vec<_>>())
+            .await;
+        free(databases, lazy);
+    }
+
+    async fn set_expiry(&self, db: usize, key: &[u8], expires_at: Option<u64>) -> bool {
+        let now = now_ms();
+        let key = key.to_vec();
+        self.on_core(self.owner(&key), move |databases| {
+            databases[db].expire(&key, expires_at, now)
+        })
+        .await
+    }
+
+    async fn expiry(&self, db: usize, key: &[u8]) -> Option<Option<u64>> {
+        let now = now_ms();
+        let key = key.to_vec();
+        self.on_core(self.owner(&key), move |databases| {
+            databases[db].expiry(&key, now)
+        })
+        .await
+    }
+
+    async fn encoding(&self, db: usize, key: &[u8]) -> Option<&'static str> {
+        let now = now_ms();
+        let key = key.to_vec();
+        self.on_core(self.owner(&key), move |databases| {
+            databases[db].encoding(&key, now)
+        })
+        .await
+    }
+
+    async fn memory_usage(&self, db: usize, key: &[u8]) -> Option<usize> {
+        let now = now_ms();
+        let key = key.to_vec();
+        self.on_core(self.owner(&key), move |databases| {
+            databases[db].memory_usage(&key, now)
+        })
+        .await
+    }
+
+    /// Each partition is cloned in turn, like other commands spanning the
+    /// keyspace, so a snapshot is only consistent within each core.
+    async fn snapshot(&self) -> Box<dyn Snapshot> {
+        let now = now_ms();
+        let parts = self.on_every_core(|databases| databases.clone()).await;
+        Box::new(DatabaseSnapshot::new(parts, now))
+    }
+
+    async fn restore(
+        &self,
+        db: usize,
+        entry: Entry,
+        history: AccessHistory,
+        replace: bool,
+    ) -> bool {
+        let now = now_ms();
+        let (key, value, expires_at) = self.codec.encode_entry(entry);
+        self.on_core(self.owner(&key), move |databases| {
+            databases[db].restore(key, value, expires_at, history, replace, now)
+        })
+        .await
+    }
+
+    async fn insert_entries(&self, db: usize, entries: Vec<Entry>) {
+        let now = now_ms();
+        let mut partitions: Vec<Vec<_>> = (0..self.cores.len()).map(|_| Vec::new()).collect();
+        for entry in entries {
+            let owner = self.owner(&entry.key);
+            partitions[owner].push(self.codec.encode_entry(entry));
+        }
+        for (core, entries) in partitions.into_iter().enumerate() {
+            self.on_core(core, move |databases| {
+                for (key, value, expires_at) in entries {
+                    databases[db].insert_value(key, value, expires_at, now);
+                }
+            })
+            .await;
+        }
+    }
+
+    fn memory(&self) -> MemoryStats {
+        MemoryStats {
+            used: self.used_memory(),
+            overhead: self.usage.iter().map(Usage::overhead).sum(),
+            max: self.limit.maxmemory,
+            policy: self.limit.policy.name(),
+            evicted_keys: self.evicted_keys.load(Ordering::Relaxed),
+            compression: total_compression(self.usage.iter()),
+        }
+    }
+
+    async fn evict(&self) -> Vec<(usize, Vec<u8>)> {
+        let now = now_ms();
+        let limit = self.limit;
+        let mut evicted = Vec::new();
+        while self.limit.evicts(self.used_memory()) {
+            // The best candidate of all partitions, each sampled on its core.
+            let mut best: Option<(usize, Candidate)> = None;
+            for core in 0..self.cores.len() {
+                let candidate = self
+                    .on_core(core, move |databases| {
+                        eviction_candidate(databases, &limit, now)
+                    })
+                    .await;
+                if let Some(candidate) = candidate
+                    && best
+                        .as_ref()
+                        .is_none_or(|(_, best)| candidate.score > best.score)
+                {
+                    best = Some((core, candidate));
+                }
+            }
+            let Some((core, candidate)) = best else {
+                break;
+            };
+
+            let db = candidate.db;
+            let removed = self
+                .on_core(core, move |databases| {
+                    databases[db].remove(&candidate.key).map(|(key, _)| key)
+                })
+                .await;
+            if let Some(key) = removed {
+                evicted.push((db, key));
+            }
+        }
+        self.evicted_keys
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        evicted
+    }
+
+    async fn expire_cycle(&self) -> Vec<(usize, Vec<u8>)> {
+        let now = now_ms();
+        let mut expired = Vec::new();
+        for core in 0..self.cores.len() {
+            expired.extend(
+                self.on_core(core, move |databases| active_expire(databases, now))
+                    .await,
+            );
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::MaxmemoryPolicy;
+    use std::time::Duration;
+
+    fn engine(databases: usize, cores: usize) -> Arc<SharedNothingEngine> {
+        let config = KiwiConfig::new()
+            .with_databases(databases)
+            .with_engine_threads(cores);
+        Arc::new(SharedNothingEngine::from_config(&config).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_keys_across_cores() {
+        let engine = engine(2, 4);
+        assert_eq!(engine.runtimes().len(), 4);
+        for i in 0..100 {
+            let key = format!("key{i}").into_bytes();
+            engine.set(0, key, b"value".to_vec()).await;
+        }
+        assert_eq!(engine.size(0).await, 100);
+        assert_eq!(engine.get(0, b"key42").await, Some(b"value".to_vec()));
+        assert_eq!(engine.snapshot().await.entries(0).count(), 100);
+
+        assert!(engine.move_key(0, 1, b"key42").await);
+        assert!(engine.delete(0, b"key7", false).await);
+        assert_eq!(engine.size(0).await, 98);
+
+        engine.swap(0, 1).await;
+        assert_eq!(engine.size(0).await, 1);
+        assert!(engine.set_expiry(0, b"key42", Some(1)).await);
+        assert_eq!(engine.get(0, b"key42").await, None);
+
+        engine.flush_all(false).await;
+        assert_eq!(engine.size(1).await, 0);
+        assert_eq!(engine.memory().used, 0);
+    }
+
+    #[tokio::test]
+    async fn test_local_and_forwarded() {
+        let engine = engine(1, 2);
+        let key = (0..)
+            .map(|i| format!("key{i}").into_bytes())
+            .find(|key| engine.owner(key) == 0)
+            .unwrap();
+        let other = (0..)
+            .map(|i| format!("key{i}").into_bytes())
+            .find(|key| engine.owner(key) == 1)
+            .unwrap();
+
+        // On core 0, its own key is served in place and the other forwarded.
+        let runtime = engine.runtimes()[0].clone();
+        let on_core = engine.clone();
+        runtime
+            .spawn(async move {
+                on_core.set(0, key, b"1".to_vec()).await;
+                on_core.set(0, other, b"2".to_vec()).await;
+            })
+            .await
+            .unwrap();
+        assert_eq!(engine.forwarded(), 1);
+        assert_eq!(engine.size(0).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_swap_parks_every_core() {
+        let engine = engine(2, 2);
+        let key = (0..)
+            .map(|i| format!("key{i}").into_bytes())
+            .find(|key| engine.owner(key) == 0)
+            .unwrap();
+        engine.set(0, key.clone(), b"value".to_vec()).await;
+
+        // With core 1 busy, the swap can't start on core 0 either.
+        let (release, busy) = std::sync::mpsc::channel::<()>();
+        let job: Job = Box::new(move |_| {
+            let _ = busy.recv();
+        });
+        engine.cores[1].jobs.send(job).unwrap();
+        let swapping = engine.clone();
+        let swap = tokio::spawn(async move { swapping.swap(0, 1).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let reading = engine.clone();
+        let read = engine.runtimes()[0].spawn(async move { reading.get(1, &key).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!read.is_finished());
+
+        release.send(()).unwrap();
+        swap.await.unwrap();
+        assert_eq!(read.await.unwrap(), Some(b"value".to_vec()));
+
+        // Cores parked from each other's threads take turns.
+        let mut swaps = Vec::new();
+        for runtime in engine.runtimes() {
+            let engine = engine.clone();
+            swaps.push(runtime.spawn(async move {
+                for _ in 0..10 {
+                    engine.swap(0, 1).await;
+                    engine.flush(1, false).await;
+                }
+            }));
+        }
+        for swap in swaps {
+            swap.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evict() {
+        let config = KiwiConfig::new()
+            .with_engine_threads(2)
+            .with_maxmemory(1)
+            .with_maxmemory_policy(MaxmemoryPolicy::AllKeysRandom);
+        let engine = SharedNothingEngine::from_config(&config).unwrap();
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+        engine.set(0, b"b".to_vec(), b"2".to_vec()).await;
+        assert_eq!(engine.evict().await.len(), 2);
+        assert_eq!(engine.memory().used, 0);
+        assert_eq!(engine.memory().evicted_keys, 2);
+    }
+}
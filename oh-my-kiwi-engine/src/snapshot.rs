@@ -0,0 +1,405 @@
+use crate::config::KiwiConfig;
+use crate::snapshot::format::{read_snapshot, write_snapshot};
+use crate::snapshot::rdb::{RdbEntry, is_rdb, read_rdb, write_rdb};
+use crate::time::now_ms;
+use oh_my_kiwi_domain::error::CommandError;
+use oh_my_kiwi_domain::{Engine, Entry};
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub mod format;
+pub mod rdb;
+
+pub const DEFAULT_DBFILENAME: &str = "dump.kiwi";
+
+/// Seconds to wait before retrying an automatic save that failed.
+const RETRY_AFTER_FAILURE_SECS: u64 = 5;
+
+/// Layout of the snapshot file: kiwi's own format or the Redis RDB format.
+/// Unless configured, files named `*.rdb` are written as RDB and anything else
+/// in kiwi's format; loading recognizes both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Kiwi,
+    Rdb,
+}
+
+impl SnapshotFormat {
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("rdb") => SnapshotFormat::Rdb,
+            _ => SnapshotFormat::Kiwi,
+        }
+    }
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "kiwi" => Ok(SnapshotFormat::Kiwi),
+            "rdb" => Ok(SnapshotFormat::Rdb),
+            _ => Err(format!("invalid snapshot format '{value}'")),
+        }
+    }
+}
+
+/// Save automatically once at least `changes` writes happened in `seconds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    pub fn new(seconds: u64, changes: u64) -> Self {
+        Self { seconds, changes }
+    }
+
+    /// The rules Redis uses when none are configured.
+    pub fn defaults() -> Vec<SaveRule> {
+        vec![
+            SaveRule::new(3600, 1),
+            SaveRule::new(300, 100),
+            SaveRule::new(60, 10000),
+        ]
+    }
+}
+
+/// Point-in-time snapshots of all databases, written to a single file.
+///
+/// Writes since the last successful save are counted so the save rules can be
+/// checked; a save takes a copy-on-write snapshot of the databases and writes
+/// it on a blocking thread, so connections keep being served meanwhile.
+pub struct Snapshots {
+    path: PathBuf,
+    format: SnapshotFormat,
+    rules: Vec<SaveRule>,
+    dirty: AtomicU64,
+    last_save: AtomicU64,
+    last_failure: AtomicU64,
+    in_progress: AtomicBool,
+}
+
+impl Snapshots {
+    pub fn new(config: &KiwiConfig) -> Self {
+        Self {
+            path: config.snapshot_path(),
+            format: config.snapshot_format(),
+            rules: config.save_rules().to_vec(),
+            dirty: AtomicU64::new(0),
+            last_save: AtomicU64::new(now_ms() / 1000),
+            last_failure: AtomicU64::new(0),
+            in_progress: AtomicBool::new(false),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records `changes` writes made since the last save.
+    pub fn mark_dirty(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::Relaxed);
+    }
+
+    /// Unix time in seconds of the last successful save.
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    /// Loads the snapshot file into `engine`, if there is one. Returns the number
+    /// of keys loaded.
+    pub async fn load<E: Engine>(&self, engine: &E) -> std::io::Result<usize> {
+        let databases = self.read(engine.databases()).await?;
+        let keys = databases.iter().map(|(_, entries)| entries.len()).sum();
+        for (index, entries) in databases {
+            engine.insert_entries(index, entries).await;
+        }
+        Ok(keys)
+    }
+
+    /// Reads the whole snapshot file on a blocking thread, checking that it
+    /// fits `databases` databases, so nothing is applied from a file that
+    /// can't be loaded. Gives no databases when there is no file.
+    pub async fn read(&self, databases: usize) -> std::io::Result<Vec<(usize, Vec<Entry>)>> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || read_file(&path, databases))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// Saves a snapshot, returning once it has been written.
+    pub async fn save<E: Engine>(&self, engine: &E) -> Result<(), CommandError> {
+        if self.in_progress.swap(true, Ordering::AcqRel) {
+            return Err(CommandError::BackgroundSaveInProgress);
+        }
+        let result = self.write(engine).await;
+        self.in_progress.store(false, Ordering::Release);
+        result
+    }
+
+    /// Starts saving a snapshot in the background.
+    pub fn background_save<E>(self: &Arc<Self>, engine: Arc<E>) -> Result<(), CommandError>
+    where
+        E: Engine + Send + Sync + 'static,
+    {
+        if self.in_progress.swap(true, Ordering::AcqRel) {
+            return Err(CommandError::BackgroundSaveInProgress);
+        }
+
+        let snapshots = self.clone();
+        tokio::spawn(async move {
+            match snapshots.write(engine.as_ref()).await {
+                Ok(()) => info!("Background saving terminated with success"),
+                Err(err) => warn!("Background saving failed: {err}"),
+            }
+            snapshots.in_progress.store(false, Ordering::Release);
+        });
+        Ok(())
+    }
+
+    /// Checks the save rules every second and starts a background save when one
+    /// of them is met.
+    pub async fn run_save_rules<E>(self: Arc<Self>, engine: Arc<E>)
+    where
+        E: Engine + Send + Sync + 'static,
+    {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            if self.should_save(now_ms() / 1000) {
+                self.background_save(engine.clone()).ok();
+            }
+        }
+    }
+
+    fn should_save(&self, now: u64) -> bool {
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let last_failure = self.last_failure.load(Ordering::Relaxed);
+        if dirty == 0 || now < last_failure + RETRY_AFTER_FAILURE_SECS {
+            return false;
+        }
+
+        let elapsed = now.saturating_sub(self.last_save());
+        self.rules
+            .iter()
+            .any(|rule| dirty >= rule.changes && elapsed >= rule.seconds)
+    }
+
+    /// Takes a point-in-time snapshot of every database, which shares the data
+    /// with the engine, and writes it out on a blocking thread.
+    async fn write<E: Engine>(&self, engine: &E) -> Result<(), CommandError> {
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let snapshot = engine.snapshot().await;
+        let result = match self.format {
+            SnapshotFormat::Kiwi => {
+                spawn_write(&self.path, move |writer| write_snapshot(writer, &*snapshot)).await
+            }
+            SnapshotFormat::Rdb => {
+                spawn_write(&self.path, move |writer| write_rdb(writer, &[], &*snapshot)).await
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+                self.last_save.store(now_ms() / 1000, Ordering::Relaxed);
+                self.last_failure.store(0, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => {
+                self.last_failure.store(now_ms() / 1000, Ordering::Relaxed);
+                Err(CommandError::Persistence(format!(
+                    "{}: {err}",
+                    self.path.display()
+                )))
+            }
+        }
+    }
+}
+
+fn read_file(path: &Path, databases: usize) -> std::io::Result<Vec<(usize, Vec<Entry>)>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let contents: Vec<(usize, Vec<Entry>)> = if is_rdb(&data) {
+        read_rdb(&data)?
+            .into_iter()
+            .map(|(index, entries)| {
+                (
+                    index,
+                    entries.into_iter().map(RdbEntry::into_entry).collect(),
+                )
+            })
+            .collect()
+    } else {
+        read_snapshot(&mut data.as_slice())?
+    };
+
+    if let Some((index, _)) = contents.iter().find(|(index, _)| *index >= databases) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("snapshot contains database {index}, which is out of range"),
+        ));
+    }
+    Ok(contents)
+}
+
+/// Runs `write` against a temporary file on a blocking thread and renames the
+/// file over `path` once done, so a crash never leaves a partially written
+/// snapshot behind.
+async fn spawn_write<F>(path: &Path, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> std::io::Result<()> + Send + 'static,
+{
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        write(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&temp_path, &path)
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_memory::InMemoryEngine;
+    use oh_my_kiwi_domain::types::Types;
+
+    fn config(name: &str) -> KiwiConfig {
+        let file = format!("kiwi-snapshot-{name}-{}.kiwi", std::process::id());
+        KiwiConfig::new()
+            .with_dir(std::env::temp_dir())
+            .with_dbfilename(file)
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let config = config("save");
+        let engine = InMemoryEngine::new();
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+        engine.set(5, b"b".to_vec(), b"2".to_vec()).await;
+        let expires_at = now_ms() + 60_000;
+        engine.set_expiry(5, b"b", Some(expires_at)).await;
+
+        let snapshots = Snapshots::new(&config);
+        snapshots.mark_dirty(2);
+        snapshots.save(&engine).await.unwrap();
+        assert!(!snapshots.should_save(u64::MAX));
+
+        let restored = InMemoryEngine::new();
+        assert_eq!(snapshots.load(&restored).await.unwrap(), 2);
+        assert_eq!(restored.get(0, b"a").await, Some(b"1".to_vec()));
+        assert_eq!(restored.expiry(5, b"b").await, Some(Some(expires_at)));
+
+        std::fs::remove_file(snapshots.path()).unwrap();
+        assert_eq!(snapshots.load(&restored).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rdb_format() {
+        let file = format!("kiwi-snapshot-rdb-{}.rdb", std::process::id());
+        let config = KiwiConfig::new()
+            .with_dir(std::env::temp_dir())
+            .with_dbfilename(file);
+        let value = Types::Array(vec![Types::BulkString("a".to_string())]).to_bytes();
+        let engine = InMemoryEngine::new();
+        engine.set(2, b"list".to_vec(), value.clone()).await;
+
+        let snapshots = Snapshots::new(&config);
+        assert_eq!(snapshots.format, SnapshotFormat::Rdb);
+        snapshots.save(&engine).await.unwrap();
+        assert!(
+            std::fs::read(snapshots.path())
+                .unwrap()
+                .starts_with(b"REDIS")
+        );
+
+        let restored = InMemoryEngine::new();
+        assert_eq!(snapshots.load(&restored).await.unwrap(), 1);
+        assert_eq!(restored.get(2, b"list").await, Some(value));
+        std::fs::remove_file(snapshots.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_configured_format() {
+        let config = config("format").with_snapshot_format(SnapshotFormat::Rdb);
+        let engine = InMemoryEngine::new();
+        engine.set(0, b"a".to_vec(), b"$1\r\n1\r\n".to_vec()).await;
+
+        let snapshots = Snapshots::new(&config);
+        snapshots.save(&engine).await.unwrap();
+        assert!(
+            std::fs::read(snapshots.path())
+                .unwrap()
+                .starts_with(b"REDIS")
+        );
+        assert_eq!(snapshots.read(16).await.unwrap().len(), 1);
+        std::fs::remove_file(snapshots.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_database_loads_nothing() {
+        let config = config("range");
+        let engine = InMemoryEngine::new();
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+        engine.set(9, b"b".to_vec(), b"2".to_vec()).await;
+        let snapshots = Snapshots::new(&config);
+        snapshots.save(&engine).await.unwrap();
+
+        let restored = InMemoryEngine::with_databases(4);
+        assert!(snapshots.load(&restored).await.is_err());
+        assert_eq!(restored.size(0).await, 0);
+        std::fs::remove_file(snapshots.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_background_save() {
+        let config = config("background");
+        let engine = Arc::new(InMemoryEngine::new());
+        engine.set(0, b"a".to_vec(), b"1".to_vec()).await;
+
+        let snapshots = Arc::new(Snapshots::new(&config));
+        snapshots.background_save(engine.clone()).unwrap();
+        assert!(matches!(
+            snapshots.background_save(engine.clone()),
+            Err(CommandError::BackgroundSaveInProgress)
+        ));
+
+        while snapshots.in_progress.load(Ordering::Acquire) {
+            tokio::task::yield_now().await;
+        }
+        assert!(snapshots.path().exists());
+        std::fs::remove_file(snapshots.path()).unwrap();
+    }
+
+    #[test]
+    fn test_save_rules() {
+        let config = config("rules").with_save_rules(vec![SaveRule::new(60, 10)]);
+        let snapshots = Snapshots::new(&config);
+        let last_save = snapshots.last_save();
+
+        snapshots.mark_dirty(9);
+        assert!(!snapshots.should_save(last_save + 120));
+        snapshots.mark_dirty(1);
+        assert!(!snapshots.should_save(last_save + 30));
+        assert!(snapshots.should_save(last_save + 60));
+    }
+}
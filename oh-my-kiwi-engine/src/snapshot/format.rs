@@ -0,0 +1,213 @@
+//! Snapshot file layout:
+//!
+//! ```text
+//! "KIWISNAP" version:u8
+//! ( DB index:u32 ( ENTRY | ENTRY_EXPIRY expires_at:u64 ) type:u8 key value )*
+//! EOF
+//! ```
+//!
+//! Keys and values are written as a `u32` length followed by the bytes, all
+//! integers are little endian. Values are the RESP encoding the engine stores,
+//! and their type tells the maps that are sorted sets from the hashes.
+//! Version 1 files have no type, their maps are read as hashes.
+
+use oh_my_kiwi_domain::{Entry, Snapshot, ValueType};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+const MAGIC: &[u8; 8] = b"KIWISNAP";
+const VERSION: u8 = 2;
+const UNTYPED_VERSION: u8 = 1;
+
+const DB: u8 = 0xFE;
+const ENTRY: u8 = 0x01;
+const ENTRY_EXPIRY: u8 = 0x02;
+const EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 3;
+const TYPE_SORTED_SET: u8 = 4;
+
+/// Writes the non-empty databases of `snapshot`.
+pub fn write_snapshot(writer: &mut impl Write, snapshot: &dyn Snapshot) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+
+    for index in 0..snapshot.databases() {
+        let mut entries = snapshot.entries(index).peekable();
+        if entries.peek().is_none() {
+            continue;
+        }
+        writer.write_all(&[DB])?;
+        writer.write_all(&(index as u32).to_le_bytes())?;
+
+        for entry in entries {
+            let entry = entry?;
+            match entry.expires_at {
+                Some(expires_at) => {
+                    writer.write_all(&[ENTRY_EXPIRY])?;
+                    writer.write_all(&expires_at.to_le_bytes())?;
+                }
+                None => writer.write_all(&[ENTRY])?,
+            }
+            writer.write_all(&[type_code(entry.value_type)])?;
+            write_bytes(writer, &entry.key)?;
+            write_bytes(writer, &entry.value)?;
+        }
+    }
+
+    writer.write_all(&[EOF])?;
+    writer.flush()
+}
+
+/// Reads a snapshot written by [`write_snapshot`].
+pub fn read_snapshot(reader: &mut impl Read) -> Result<Vec<(usize, Vec<Entry>)>> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    let version = read_u8(reader)?;
+    if version != VERSION && version != UNTYPED_VERSION {
+        return Err(invalid(format!("unsupported snapshot version {version}")));
+    }
+
+    let mut databases: Vec<(usize, Vec<Entry>)> = Vec::new();
+    loop {
+        let opcode = read_u8(reader)?;
+        let expires_at = match opcode {
+            EOF => return Ok(databases),
+            DB => {
+                let index = read_u32(reader)? as usize;
+                databases.push((index, Vec::new()));
+                continue;
+            }
+            ENTRY => None,
+            ENTRY_EXPIRY => Some(read_u64(reader)?),
+            other => return Err(invalid(format!("unknown opcode {other:#04x}"))),
+        };
+
+        let Some((_, entries)) = databases.last_mut() else {
+            return Err(invalid("entry outside of a database"));
+        };
+        let value_type = match version {
+            UNTYPED_VERSION => None,
+            _ => Some(read_type(reader)?),
+        };
+        let key = read_bytes(reader)?;
+        let value = read_bytes(reader)?;
+        entries.push(Entry {
+            key,
+            value_type: value_type.unwrap_or_else(|| ValueType::of(&value)),
+            value,
+            expires_at,
+        });
+    }
+}
+
+fn type_code(value_type: ValueType) -> u8 {
+    match value_type {
+        ValueType::String => TYPE_STRING,
+        ValueType::List => TYPE_LIST,
+        ValueType::Set => TYPE_SET,
+        ValueType::Hash => TYPE_HASH,
+        ValueType::SortedSet => TYPE_SORTED_SET,
+    }
+}
+
+fn read_type(reader: &mut impl Read) -> Result<ValueType> {
+    match read_u8(reader)? {
+        TYPE_STRING => Ok(ValueType::String),
+        TYPE_LIST => Ok(ValueType::List),
+        TYPE_SET => Ok(ValueType::Set),
+        TYPE_HASH => Ok(ValueType::Hash),
+        TYPE_SORTED_SET => Ok(ValueType::SortedSet),
+        other => Err(invalid(format!("unknown value type {other:#04x}"))),
+    }
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| invalid("value is too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(bytes)
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oh_my_kiwi_domain::CollectedSnapshot;
+
+    fn entry(key: &str, expires_at: Option<u64>) -> Entry {
+        Entry {
+            key: key.as_bytes().to_vec(),
+            value: b"$5\r\nvalue\r\n".to_vec(),
+            expires_at,
+            value_type: ValueType::String,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let first = vec![entry("a", None), entry("b", Some(1_700_000_000_000))];
+        let scores = Entry {
+            key: b"z".to_vec(),
+            value: b"%1\r\n$1\r\nm\r\n,1.5\r\n".to_vec(),
+            expires_at: None,
+            value_type: ValueType::SortedSet,
+        };
+        let third = vec![entry("c", None), scores];
+        let snapshot = CollectedSnapshot::new(vec![first.clone(), vec![], vec![], third.clone()]);
+        let mut bytes = Vec::new();
+        write_snapshot(&mut bytes, &snapshot).unwrap();
+
+        let restored = read_snapshot(&mut bytes.as_slice()).unwrap();
+        assert_eq!(restored, vec![(0, first), (3, third)]);
+    }
+
+    #[test]
+    fn test_truncated_and_corrupt_files() {
+        let mut bytes = Vec::new();
+        let snapshot = CollectedSnapshot::new(vec![vec![entry("a", None)]]);
+        write_snapshot(&mut bytes, &snapshot).unwrap();
+
+        let truncated = &bytes[..bytes.len() - 3];
+        assert!(read_snapshot(&mut &truncated[..]).is_err());
+
+        let mut corrupt = bytes.clone();
+        corrupt[0] = b'X';
+        assert!(read_snapshot(&mut corrupt.as_slice()).is_err());
+    }
+}
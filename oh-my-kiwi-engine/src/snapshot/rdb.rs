@@ -0,0 +1,812 @@
+//! Reader and writer for the Redis RDB format, so datasets can be moved
+//! between Redis and kiwi.
+//!
+//! Files up to RDB version 12 are read, including the ziplist, listpack,
+//! intset and quicklist encodings and LZF compressed strings. Files are written
+//! as version 11 using the plain encodings of each type, which Redis 7.2 and
+//! later load as they are.
+
+use crc::{CRC_64_REDIS, Crc, Digest};
+use oh_my_kiwi_domain::{Entry, Snapshot, ValueType};
+use oh_my_kiwi_domain::error::ParseError;
+use oh_my_kiwi_domain::types::Types;
+use ordered_float::OrderedFloat;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result, Write};
+
+mod encoding;
+
+use encoding::{intset_entries, listpack_entries, lzf_decompress, ziplist_entries};
+
+pub const RDB_VERSION: u16 = 11;
+const MAX_READ_VERSION: u16 = 12;
+/// First version with a checksum after the EOF opcode.
+const CHECKSUM_VERSION: u16 = 5;
+const MAGIC: &[u8; 5] = b"REDIS";
+/// DUMP payloads end with the RDB version as `u16` and a CRC-64.
+const DUMP_FOOTER_LEN: usize = 10;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+
+static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+/// A value of one of the Redis data types.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    SortedSet(Vec<(Vec<u8>, f64)>),
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+impl RdbValue {
+    /// Converts a kiwi value. Arrays, sets and maps of plain values become
+    /// lists, sets and hashes, and maps whose values are all doubles sorted
+    /// sets. Anything else, like a nested array, is kept as a string holding
+    /// its RESP encoding.
+    pub fn from_types(types: &Types) -> Self {
+        let converted = match types {
+            Types::Array(items) => items
+                .iter()
+                .map(scalar)
+                .collect::<Option<_>>()
+                .map(RdbValue::List),
+            Types::Set(items) => items
+                .iter()
+                .map(scalar)
+                .collect::<Option<_>>()
+                .map(RdbValue::Set),
+            Types::Map(map)
+                if !map.is_empty()
+                    && map.values().all(|value| matches!(value, Types::Double(_))) =>
+            {
+                map.iter()
+                    .map(|(member, score)| match score {
+                        Types::Double(score) => Some((scalar(member)?, score.0)),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()
+                    .map(RdbValue::SortedSet)
+            }
+            Types::Map(map) => map
+                .iter()
+                .map(|(field, value)| Some((scalar(field)?, scalar(value)?)))
+                .collect::<Option<_>>()
+                .map(RdbValue::Hash),
+            other => scalar(other).map(RdbValue::String),
+        };
+        converted.unwrap_or_else(|| RdbValue::String(types.to_bytes()))
+    }
+
+    /// The Redis type of the value.
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            RdbValue::String(_) => ValueType::String,
+            RdbValue::List(_) => ValueType::List,
+            RdbValue::Set(_) => ValueType::Set,
+            RdbValue::SortedSet(_) => ValueType::SortedSet,
+            RdbValue::Hash(_) => ValueType::Hash,
+        }
+    }
+
+    pub fn into_types(self) -> Types {
+        match self {
+            RdbValue::String(value) => bulk_string(value),
+            RdbValue::List(items) => Types::Array(items.into_iter().map(bulk_string).collect()),
+            RdbValue::Set(items) => Types::Set(items.into_iter().map(bulk_string).collect()),
+            RdbValue::SortedSet(items) => Types::Map(
+                items
+                    .into_iter()
+                    .map(|(member, score)| {
+                        (bulk_string(member), Types::Double(OrderedFloat(score)))
+                    })
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+            RdbValue::Hash(items) => Types::Map(
+                items
+                    .into_iter()
+                    .map(|(field, value)| (bulk_string(field), bulk_string(value)))
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+        }
+    }
+}
+
+/// A key with its value and optional expiry time, in unix milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RdbEntry {
+    pub key: Vec<u8>,
+    pub value: RdbValue,
+    pub expires_at: Option<u64>,
+}
+
+impl RdbEntry {
+    /// Decodes the RESP value the engine stores for `entry`.
+    pub fn from_entry(entry: Entry) -> std::result::Result<Self, ParseError> {
+        let types = Types::parse_slice(&entry.value)?;
+        Ok(Self {
+            key: entry.key,
+            value: RdbValue::from_types(&types),
+            expires_at: entry.expires_at,
+        })
+    }
+
+    pub fn into_entry(self) -> Entry {
+        Entry {
+            key: self.key,
+            value_type: self.value.value_type(),
+            value: self.value.into_types().to_bytes(),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// Whether `data` starts like an RDB file.
+pub fn is_rdb(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Writes the non-empty databases of `snapshot` after the auxiliary fields in
+/// `aux`, converting the entries one at a time.
+pub fn write_rdb(
+    writer: &mut impl Write,
+    aux: &[(&str, &str)],
+    snapshot: &dyn Snapshot,
+) -> Result<()> {
+    let mut writer = ChecksumWriter {
+        inner: writer,
+        digest: CRC64.digest(),
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_all(format!("{RDB_VERSION:04}").as_bytes())?;
+    for (key, value) in [("redis-ver", "7.2.0"), ("redis-bits", "64")]
+        .iter()
+        .chain(aux)
+    {
+        writer.write_all(&[OPCODE_AUX])?;
+        write_string(&mut writer, key.as_bytes())?;
+        write_string(&mut writer, value.as_bytes())?;
+    }
+
+    for index in 0..snapshot.databases() {
+        let mut entries = snapshot.entries(index).peekable();
+        if entries.peek().is_none() {
+            continue;
+        }
+        writer.write_all(&[OPCODE_SELECTDB])?;
+        write_length(&mut writer, index as u64)?;
+        let (keys, expires) = snapshot.size_hint(index);
+        writer.write_all(&[OPCODE_RESIZEDB])?;
+        write_length(&mut writer, keys as u64)?;
+        write_length(&mut writer, expires as u64)?;
+
+        for entry in entries {
+            let entry = RdbEntry::from_entry(entry?).map_err(|err| invalid(err.to_string()))?;
+            if let Some(expires_at) = entry.expires_at {
+                writer.write_all(&[OPCODE_EXPIRETIME_MS])?;
+                writer.write_all(&expires_at.to_le_bytes())?;
+            }
+            writer.write_all(&[object_type(&entry.value)])?;
+            write_string(&mut writer, &entry.key)?;
+            write_object(&mut writer, &entry.value)?;
+        }
+    }
+
+    writer.write_all(&[OPCODE_EOF])?;
+    let checksum = writer.digest.finalize();
+    writer.inner.write_all(&checksum.to_le_bytes())?;
+    writer.inner.flush()
+}
+
+/// Reads an RDB file, returning the databases in it as `(index, entries)`
+/// pairs. Functions are skipped; modules and streams are not supported.
+pub fn read_rdb(data: &[u8]) -> Result<Vec<(usize, Vec<RdbEntry>)>> {
+    read_rdb_with_aux(data).map(|(_, databases)| databases)
+}
+
+/// Like [`read_rdb`], also returning the auxiliary fields of the file.
+#[allow(clippy::type_complexity)]
+pub fn read_rdb_with_aux(
+    data: &[u8],
+) -> Result<(Vec<(String, String)>, Vec<(usize, Vec<RdbEntry>)>)> {
+    let mut cursor = Cursor::new(data);
+    if cursor.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not an RDB file"));
+    }
+    let version = std::str::from_utf8(cursor.take(4)?)
+        .ok()
+        .and_then(|version| version.parse::<u16>().ok())
+        .ok_or_else(|| invalid("invalid RDB version"))?;
+    if version == 0 || version > MAX_READ_VERSION {
+        return Err(invalid(format!("unsupported RDB version {version}")));
+    }
+
+    let mut aux = Vec::new();
+    let mut databases: Vec<(usize, Vec<RdbEntry>)> = Vec::new();
+    let mut expires_at = None;
+    loop {
+        match cursor.u8()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => databases.push((cursor.plain_length()? as usize, Vec::new())),
+            OPCODE_RESIZEDB => {
+                cursor.plain_length()?;
+                cursor.plain_length()?;
+            }
+            OPCODE_AUX => {
+                let key = cursor.string()?;
+                let value = cursor.string()?;
+                aux.push((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                ));
+            }
+            OPCODE_EXPIRETIME_MS => expires_at = Some(u64::from_le_bytes(cursor.array()?)),
+            OPCODE_EXPIRETIME => {
+                expires_at = Some(u32::from_le_bytes(cursor.array()?) as u64 * 1000);
+            }
+            OPCODE_IDLE => {
+                cursor.plain_length()?;
+            }
+            OPCODE_FREQ => {
+                cursor.u8()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    cursor.plain_length()?;
+                }
+            }
+            OPCODE_FUNCTION => {
+                cursor.string()?;
+            }
+            OPCODE_MODULE_AUX => return Err(invalid("modules are not supported")),
+            object_type => {
+                let key = cursor.string()?;
+                let value = read_object(&mut cursor, object_type)?;
+                if databases.is_empty() {
+                    databases.push((0, Vec::new()));
+                }
+                if let Some((_, entries)) = databases.last_mut() {
+                    entries.push(RdbEntry {
+                        key,
+                        value,
+                        expires_at: expires_at.take(),
+                    });
+                }
+            }
+        }
+    }
+
+    if version >= CHECKSUM_VERSION {
+        let end = cursor.pos;
+        let expected = u64::from_le_bytes(cursor.array()?);
+        if expected != 0 && expected != crc64(&data[..end]) {
+            return Err(invalid("RDB checksum mismatch"));
+        }
+    }
+    Ok((aux, databases))
+}
+
+/// Serializes `value` the way DUMP does: the object, the RDB version it was
+/// written with and a CRC-64 of both.
+pub fn dump_payload(value: &RdbValue) -> Vec<u8> {
+    let mut payload = vec![object_type(value)];
+    write_object(&mut payload, value).expect("writing to a Vec cannot fail");
+    payload.extend(RDB_VERSION.to_le_bytes());
+    payload.extend(crc64(&payload).to_le_bytes());
+    payload
+}
+
+/// Whether `payload` was written by a supported RDB version and its checksum
+/// matches.
+pub fn verify_dump_payload(payload: &[u8]) -> bool {
+    let Some(body_len) = payload.len().checked_sub(DUMP_FOOTER_LEN) else {
+        return false;
+    };
+    let mut footer = Cursor::new(&payload[body_len..]);
+    match (footer.array(), footer.array()) {
+        (Ok(version), Ok(checksum)) => {
+            u16::from_le_bytes(version) <= MAX_READ_VERSION
+                && u64::from_le_bytes(checksum) == crc64(&payload[..body_len + 2])
+        }
+        _ => false,
+    }
+}
+
+/// Decodes the value of a payload accepted by [`verify_dump_payload`].
+pub fn read_dump_payload(payload: &[u8]) -> Result<RdbValue> {
+    let body = &payload[..payload.len().saturating_sub(DUMP_FOOTER_LEN)];
+    let mut cursor = Cursor::new(body);
+    let object_type = cursor.u8()?;
+    let value = read_object(&mut cursor, object_type)?;
+    if cursor.pos != body.len() {
+        return Err(invalid("unexpected bytes after the value"));
+    }
+    Ok(value)
+}
+
+/// CRC-64 as Redis computes it for RDB files and DUMP payloads.
+fn crc64(data: &[u8]) -> u64 {
+    CRC64.checksum(data)
+}
+
+/// Type byte written before the key of `value`.
+fn object_type(value: &RdbValue) -> u8 {
+    match value {
+        RdbValue::String(_) => TYPE_STRING,
+        RdbValue::List(_) => TYPE_LIST,
+        RdbValue::Set(_) => TYPE_SET,
+        RdbValue::SortedSet(_) => TYPE_ZSET_2,
+        RdbValue::Hash(_) => TYPE_HASH,
+    }
+}
+
+/// Writes `value` in the encoding [`object_type`] names.
+fn write_object(writer: &mut impl Write, value: &RdbValue) -> Result<()> {
+    match value {
+        RdbValue::String(value) => write_string(writer, value),
+        RdbValue::List(items) | RdbValue::Set(items) => {
+            write_length(writer, items.len() as u64)?;
+            items.iter().try_for_each(|item| write_string(writer, item))
+        }
+        RdbValue::SortedSet(items) => {
+            write_length(writer, items.len() as u64)?;
+            items.iter().try_for_each(|(member, score)| {
+                write_string(writer, member)?;
+                writer.write_all(&score.to_le_bytes())
+            })
+        }
+        RdbValue::Hash(items) => {
+            write_length(writer, items.len() as u64)?;
+            items.iter().try_for_each(|(field, value)| {
+                write_string(writer, field)?;
+                write_string(writer, value)
+            })
+        }
+    }
+}
+
+/// Reads a value of `object_type`, in any of the encodings Redis writes.
+fn read_object(cursor: &mut Cursor, object_type: u8) -> Result<RdbValue> {
+    let value = match object_type {
+        TYPE_STRING => RdbValue::String(cursor.string()?),
+        TYPE_LIST => RdbValue::List(cursor.strings()?),
+        TYPE_SET => RdbValue::Set(cursor.strings()?),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = cursor.plain_length()?;
+            let mut items = Vec::new();
+            for _ in 0..len {
+                let member = cursor.string()?;
+                let score = match object_type {
+                    TYPE_ZSET_2 => f64::from_le_bytes(cursor.array()?),
+                    _ => cursor.string_double()?,
+                };
+                items.push((member, score));
+            }
+            RdbValue::SortedSet(items)
+        }
+        TYPE_HASH => {
+            let len = cursor.plain_length()?;
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push((cursor.string()?, cursor.string()?));
+            }
+            RdbValue::Hash(items)
+        }
+        TYPE_LIST_ZIPLIST => RdbValue::List(ziplist_entries(&cursor.string()?)?),
+        TYPE_SET_INTSET => RdbValue::Set(intset_entries(&cursor.string()?)?),
+        TYPE_SET_LISTPACK => RdbValue::Set(listpack_entries(&cursor.string()?)?),
+        TYPE_ZSET_ZIPLIST => RdbValue::SortedSet(scored(ziplist_entries(&cursor.string()?)?)?),
+        TYPE_ZSET_LISTPACK => RdbValue::SortedSet(scored(listpack_entries(&cursor.string()?)?)?),
+        TYPE_HASH_ZIPLIST => RdbValue::Hash(pairs(ziplist_entries(&cursor.string()?)?)?),
+        TYPE_HASH_LISTPACK => RdbValue::Hash(pairs(listpack_entries(&cursor.string()?)?)?),
+        TYPE_LIST_QUICKLIST => {
+            let nodes = cursor.plain_length()?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                items.extend(ziplist_entries(&cursor.string()?)?);
+            }
+            RdbValue::List(items)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let nodes = cursor.plain_length()?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                let container = cursor.plain_length()?;
+                let node = cursor.string()?;
+                match container {
+                    QUICKLIST_NODE_PLAIN => items.push(node),
+                    _ => items.extend(listpack_entries(&node)?),
+                }
+            }
+            RdbValue::List(items)
+        }
+        other => return Err(invalid(format!("unsupported RDB object type {other}"))),
+    };
+    Ok(value)
+}
+
+/// Position in an RDB file or in one of the encoded blobs inside it.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length(&mut self) -> Result<Length> {
+        let first = self.u8()?;
+        let length = match first >> 6 {
+            0b00 => Length::Plain((first & 0x3F) as u64),
+            0b01 => Length::Plain(((first & 0x3F) as u64) << 8 | self.u8()? as u64),
+            0b10 if first == 0x80 => Length::Plain(u32::from_be_bytes(self.array()?) as u64),
+            0b10 if first == 0x81 => Length::Plain(u64::from_be_bytes(self.array()?)),
+            0b10 => return Err(invalid(format!("unknown length encoding {first:#04x}"))),
+            _ => Length::Encoded(first & 0x3F),
+        };
+        Ok(length)
+    }
+
+    fn plain_length(&mut self) -> Result<u64> {
+        match self.length()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => Err(invalid("expected a length")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let string = match self.length()? {
+            Length::Plain(len) => self.take(len as usize)?.to_vec(),
+            Length::Encoded(ENCODING_INT8) => (self.u8()? as i8).to_string().into_bytes(),
+            Length::Encoded(ENCODING_INT16) => {
+                i16::from_le_bytes(self.array()?).to_string().into_bytes()
+            }
+            Length::Encoded(ENCODING_INT32) => {
+                i32::from_le_bytes(self.array()?).to_string().into_bytes()
+            }
+            Length::Encoded(ENCODING_LZF) => {
+                let compressed_len = self.plain_length()? as usize;
+                let len = self.plain_length()? as usize;
+                lzf_decompress(self.take(compressed_len)?, len)?
+            }
+            Length::Encoded(other) => {
+                return Err(invalid(format!("unknown string encoding {other}")));
+            }
+        };
+        Ok(string)
+    }
+
+    fn strings(&mut self) -> Result<Vec<Vec<u8>>> {
+        let len = self.plain_length()?;
+        (0..len).map(|_| self.string()).collect()
+    }
+
+    /// A score of the old sorted set encoding, stored as text.
+    fn string_double(&mut self) -> Result<f64> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(self.take(len as usize)?),
+        }
+    }
+}
+
+struct ChecksumWriter<'a, W: Write> {
+    inner: &'a mut W,
+    digest: Digest<'static, u64>,
+}
+
+impl<W: Write> Write for ChecksumWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_length(writer: &mut impl Write, len: u64) -> Result<()> {
+    if len < 1 << 6 {
+        writer.write_all(&[len as u8])
+    } else if len < 1 << 14 {
+        writer.write_all(&[0x40 | (len >> 8) as u8, len as u8])
+    } else if len <= u32::MAX as u64 {
+        writer.write_all(&[0x80])?;
+        writer.write_all(&(len as u32).to_be_bytes())
+    } else {
+        writer.write_all(&[0x81])?;
+        writer.write_all(&len.to_be_bytes())
+    }
+}
+
+fn write_string(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    write_length(writer, bytes.len() as u64)?;
+    writer.write_all(bytes)
+}
+
+fn pairs(items: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if !items.len().is_multiple_of(2) {
+        return Err(invalid("odd number of hash elements"));
+    }
+    let mut items = items.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        pairs.push((field, value));
+    }
+    Ok(pairs)
+}
+
+fn scored(items: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, f64)>> {
+    pairs(items)?
+        .into_iter()
+        .map(|(member, score)| Ok((member, parse_score(&score)?)))
+        .collect()
+}
+
+fn parse_score(bytes: &[u8]) -> Result<f64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|score| score.parse().ok())
+        .ok_or_else(|| invalid("invalid sorted set score"))
+}
+
+fn scalar(types: &Types) -> Option<Vec<u8>> {
+    match types {
+        Types::BulkString(value) | Types::SimpleString(value) => Some(value.as_bytes().to_vec()),
+        Types::BulkBytes(value) => Some(value.clone()),
+        Types::Integer(value) => Some(value.to_string().into_bytes()),
+        Types::Double(value) => Some(value.to_string().into_bytes()),
+        Types::BigNumber(value) => Some(value.to_string().into_bytes()),
+        _ => None,
+    }
+}
+
+fn bulk_string(bytes: Vec<u8>) -> Types {
+    match String::from_utf8(bytes) {
+        Ok(value) => Types::BulkString(value),
+        Err(err) => Types::BulkBytes(err.into_bytes()),
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oh_my_kiwi_domain::CollectedSnapshot;
+
+    fn bytes(value: &str) -> Vec<u8> {
+        value.as_bytes().to_vec()
+    }
+
+    fn entry(key: &str, value: RdbValue, expires_at: Option<u64>) -> RdbEntry {
+        RdbEntry {
+            key: bytes(key),
+            value,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let databases = [
+            (
+                0,
+                vec![
+                    entry("string", RdbValue::String(vec![b'x'; 20_000]), None),
+                    entry(
+                        "list",
+                        RdbValue::List(vec![bytes("a"), bytes("b")]),
+                        Some(1_700_000_000_000),
+                    ),
+                    entry("set", RdbValue::Set(vec![bytes("m")]), None),
+                ],
+            ),
+            (1, vec![]),
+            (
+                2,
+                vec![
+                    entry(
+                        "zset",
+                        RdbValue::SortedSet(vec![(bytes("a"), 1.5), (bytes("b"), f64::INFINITY)]),
+                        None,
+                    ),
+                    entry("hash", RdbValue::Hash(vec![(bytes("f"), bytes("v"))]), None),
+                ],
+            ),
+        ];
+        let snapshot = CollectedSnapshot::new(
+            databases
+                .iter()
+                .map(|(_, entries)| entries.iter().cloned().map(RdbEntry::into_entry).collect())
+                .collect(),
+        );
+        let mut data = Vec::new();
+        write_rdb(&mut data, &[("repl-stream-db", "2")], &snapshot).unwrap();
+        assert!(data.starts_with(b"REDIS0011"));
+
+        let (aux, restored) = read_rdb_with_aux(&data).unwrap();
+        assert_eq!(restored, vec![databases[0].clone(), databases[2].clone()]);
+        assert!(aux.contains(&("repl-stream-db".to_string(), "2".to_string())));
+
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        assert!(read_rdb(&data).is_err());
+        assert!(read_rdb(&data[..data.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn test_dump_payload() {
+        let value = RdbValue::Hash(vec![(bytes("f"), bytes("v"))]);
+        let mut payload = dump_payload(&value);
+        assert_eq!(payload[0], TYPE_HASH);
+        assert_eq!(payload[payload.len() - 10..payload.len() - 8], [11, 0]);
+        assert!(verify_dump_payload(&payload));
+        assert_eq!(read_dump_payload(&payload).unwrap(), value);
+
+        payload[1] ^= 0x01;
+        assert!(!verify_dump_payload(&payload));
+        assert!(!verify_dump_payload(&payload[..4]));
+    }
+
+    #[test]
+    fn test_read_compact_encodings() {
+        let mut listpack = vec![0; 6];
+        listpack.extend([0x81, b'f', 0x02, 0x81, b'v', 0x02, 0xFF]);
+        let mut zset_listpack = vec![0; 6];
+        zset_listpack.extend([0x81, b'm', 0x02, 0x05, 0x01, 0xFF]);
+        let mut intset = Vec::new();
+        intset.extend(2u32.to_le_bytes());
+        intset.extend(1u32.to_le_bytes());
+        intset.extend(7i16.to_le_bytes());
+
+        let mut data = b"REDIS0003".to_vec();
+        data.extend([OPCODE_AUX, 0x01, b'k', 0x01, b'v']);
+        data.extend([OPCODE_SELECTDB, 0x03]);
+        data.extend([OPCODE_EXPIRETIME]);
+        data.extend(1_000u32.to_le_bytes());
+        data.extend([TYPE_STRING, 0x01, b'n', 0xC1, 0x39, 0x30]);
+        data.extend([
+            TYPE_STRING,
+            0x01,
+            b'z',
+            0xC3,
+            0x04,
+            0x05,
+            0x00,
+            b'a',
+            0x40,
+            0x00,
+        ]);
+        data.extend([TYPE_HASH_LISTPACK, 0x01, b'h', listpack.len() as u8]);
+        data.extend(&listpack);
+        data.extend([
+            TYPE_ZSET_LISTPACK,
+            0x02,
+            b'z',
+            b's',
+            zset_listpack.len() as u8,
+        ]);
+        data.extend(&zset_listpack);
+        data.extend([TYPE_SET_INTSET, 0x01, b'i', intset.len() as u8]);
+        data.extend(&intset);
+        data.extend([
+            TYPE_ZSET, 0x02, b'z', b'o', 0x01, 0x01, b'a', 0x03, b'2', b'.', b'5',
+        ]);
+        data.push(OPCODE_EOF);
+
+        let databases = read_rdb(&data).unwrap();
+        assert_eq!(
+            databases,
+            vec![(
+                3,
+                vec![
+                    entry("n", RdbValue::String(bytes("12345")), Some(1_000_000)),
+                    entry("z", RdbValue::String(bytes("aaaaa")), None),
+                    entry("h", RdbValue::Hash(vec![(bytes("f"), bytes("v"))]), None),
+                    entry("zs", RdbValue::SortedSet(vec![(bytes("m"), 5.0)]), None),
+                    entry("i", RdbValue::Set(vec![bytes("7")]), None),
+                    entry("zo", RdbValue::SortedSet(vec![(bytes("a"), 2.5)]), None),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_types_conversion() {
+        let hash = Types::Map(BTreeMap::from([(
+            Types::BulkString("f".to_string()),
+            Types::Integer(1),
+        )]));
+        assert_eq!(
+            RdbValue::from_types(&hash),
+            RdbValue::Hash(vec![(bytes("f"), bytes("1"))])
+        );
+
+        let zset = RdbValue::SortedSet(vec![(bytes("m"), 2.0)]);
+        assert_eq!(RdbValue::from_types(&zset.clone().into_types()), zset);
+
+        let nested = Types::Array(vec![Types::Array(vec![])]);
+        assert_eq!(
+            RdbValue::from_types(&nested),
+            RdbValue::String(nested.to_bytes())
+        );
+        assert_eq!(
+            RdbValue::String(vec![0xFF]).into_types(),
+            Types::BulkBytes(vec![0xFF])
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Decoders for the compact encodings Redis stores small collections in:
+//! ziplists, listpacks and intsets, plus LZF compressed strings.
+
+use super::{Cursor, invalid};
+use std::io::Result;
+
+const ZIPLIST_HEADER_LEN: usize = 10;
+const LISTPACK_HEADER_LEN: usize = 6;
+const INTSET_HEADER_LEN: usize = 8;
+const END: u8 = 0xFF;
+
+/// Decompresses an LZF block that expands to `len` bytes.
+pub(super) fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let corrupt = || invalid("corrupt LZF data");
+    let mut output = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < input.len() {
+        let ctrl = input[pos] as usize;
+        pos += 1;
+
+        if ctrl < 32 {
+            let literal = input.get(pos..pos + ctrl + 1).ok_or_else(corrupt)?;
+            output.extend_from_slice(literal);
+            pos += ctrl + 1;
+            continue;
+        }
+
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += *input.get(pos).ok_or_else(corrupt)? as usize;
+            pos += 1;
+        }
+        let offset = ((ctrl & 0x1F) << 8) + *input.get(pos).ok_or_else(corrupt)? as usize + 1;
+        pos += 1;
+        let start = output.len().checked_sub(offset).ok_or_else(corrupt)?;
+        // The copied range may overlap the bytes being written, so go one by one.
+        for i in 0..run + 2 {
+            output.push(output[start + i]);
+        }
+    }
+
+    if output.len() != len {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+/// Elements of a ziplist, with integers rendered as decimal strings.
+pub(super) fn ziplist_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut cursor = Cursor::new(blob);
+    cursor.take(ZIPLIST_HEADER_LEN)?;
+
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = cursor.u8()?;
+        if prevlen == END {
+            return Ok(entries);
+        }
+        if prevlen == 0xFE {
+            cursor.take(4)?;
+        }
+
+        let encoding = cursor.u8()?;
+        let entry = match encoding >> 6 {
+            0b00 => cursor.take((encoding & 0x3F) as usize)?.to_vec(),
+            0b01 => {
+                let len = ((encoding & 0x3F) as usize) << 8 | cursor.u8()? as usize;
+                cursor.take(len)?.to_vec()
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(cursor.array()?) as usize;
+                cursor.take(len)?.to_vec()
+            }
+            _ => decimal(match encoding {
+                0xC0 => i16::from_le_bytes(cursor.array()?) as i64,
+                0xD0 => i32::from_le_bytes(cursor.array()?) as i64,
+                0xE0 => i64::from_le_bytes(cursor.array()?),
+                0xF0 => int24(cursor.array()?),
+                0xFE => cursor.u8()? as i8 as i64,
+                0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                _ => return Err(invalid(format!("unknown ziplist encoding {encoding:#04x}"))),
+            }),
+        };
+        entries.push(entry);
+    }
+}
+
+/// Elements of a listpack, with integers rendered as decimal strings.
+pub(super) fn listpack_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut cursor = Cursor::new(blob);
+    cursor.take(LISTPACK_HEADER_LEN)?;
+
+    let mut entries = Vec::new();
+    loop {
+        let start = cursor.pos;
+        let encoding = cursor.u8()?;
+        if encoding == END {
+            return Ok(entries);
+        }
+
+        let entry = if encoding & 0x80 == 0 {
+            decimal(encoding as i64)
+        } else if encoding & 0xC0 == 0x80 {
+            cursor.take((encoding & 0x3F) as usize)?.to_vec()
+        } else if encoding & 0xE0 == 0xC0 {
+            let value = ((encoding & 0x1F) as i64) << 8 | cursor.u8()? as i64;
+            // 13 bit two's complement.
+            decimal(if value >= 1 << 12 {
+                value - (1 << 13)
+            } else {
+                value
+            })
+        } else if encoding & 0xF0 == 0xE0 {
+            let len = ((encoding & 0x0F) as usize) << 8 | cursor.u8()? as usize;
+            cursor.take(len)?.to_vec()
+        } else {
+            match encoding {
+                0xF0 => {
+                    let len = u32::from_le_bytes(cursor.array()?) as usize;
+                    cursor.take(len)?.to_vec()
+                }
+                0xF1 => decimal(i16::from_le_bytes(cursor.array()?) as i64),
+                0xF2 => decimal(int24(cursor.array()?)),
+                0xF3 => decimal(i32::from_le_bytes(cursor.array()?) as i64),
+                0xF4 => decimal(i64::from_le_bytes(cursor.array()?)),
+                _ => {
+                    return Err(invalid(format!(
+                        "unknown listpack encoding {encoding:#04x}"
+                    )));
+                }
+            }
+        };
+        entries.push(entry);
+        cursor.take(backlen_size(cursor.pos - start))?;
+    }
+}
+
+/// Members of an intset as decimal strings.
+pub(super) fn intset_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut cursor = Cursor::new(blob);
+    let width = u32::from_le_bytes(cursor.array()?);
+    let len = u32::from_le_bytes(cursor.array()?) as usize;
+    if blob.len() != INTSET_HEADER_LEN + len * width as usize {
+        return Err(invalid("intset length does not match its contents"));
+    }
+
+    (0..len)
+        .map(|_| {
+            let value = match width {
+                2 => i16::from_le_bytes(cursor.array()?) as i64,
+                4 => i32::from_le_bytes(cursor.array()?) as i64,
+                8 => i64::from_le_bytes(cursor.array()?),
+                _ => return Err(invalid(format!("unknown intset encoding {width}"))),
+            };
+            Ok(decimal(value))
+        })
+        .collect()
+}
+
+fn decimal(value: i64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+fn int24(bytes: [u8; 3]) -> i64 {
+    (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as i64
+}
+
+/// Size of the length that trails every listpack entry of `entry_len` bytes.
+fn backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..16383 => 2,
+        16383..2097151 => 3,
+        2097151..268435455 => 4,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| item.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        let compressed = [0x01, b'a', b'b', 0xE0, 0x00, 0x01];
+        assert_eq!(lzf_decompress(&compressed, 11).unwrap(), b"abababababa");
+        assert!(lzf_decompress(&compressed, 12).is_err());
+        assert!(lzf_decompress(&[0xE0, 0x00, 0x05], 9).is_err());
+    }
+
+    #[test]
+    fn test_ziplist_entries() {
+        let mut blob = vec![0; ZIPLIST_HEADER_LEN];
+        blob.extend([0x00, 0x03, b'f', b'o', b'o']);
+        blob.extend([0x05, 0xF4]);
+        blob.extend([0x02, 0xFE, 0x9C]);
+        blob.extend([0x03, 0xC0, 0x39, 0x30]);
+        blob.extend([0x04, 0xF0, 0xFF, 0xFF, 0xFF]);
+        blob.push(END);
+
+        assert_eq!(
+            ziplist_entries(&blob).unwrap(),
+            strings(&["foo", "3", "-100", "12345", "-1"])
+        );
+        assert!(ziplist_entries(&blob[..blob.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_listpack_entries() {
+        let mut blob = vec![0; LISTPACK_HEADER_LEN];
+        blob.extend([0x83, b'b', b'a', b'r', 0x04]);
+        blob.extend([0x07, 0x01]);
+        blob.extend([0xDF, 0xFF, 0x02]);
+        blob.extend([0xF3, 0x40, 0x42, 0x0F, 0x00, 0x05]);
+        blob.push(END);
+
+        assert_eq!(
+            listpack_entries(&blob).unwrap(),
+            strings(&["bar", "7", "-1", "1000000"])
+        );
+    }
+
+    #[test]
+    fn test_intset_entries() {
+        let mut blob = Vec::new();
+        blob.extend(2u32.to_le_bytes());
+        blob.extend(2u32.to_le_bytes());
+        blob.extend((-5i16).to_le_bytes());
+        blob.extend(300i16.to_le_bytes());
+
+        assert_eq!(intset_entries(&blob).unwrap(), strings(&["-5", "300"]));
+        assert!(intset_entries(&blob[..blob.len() - 1]).is_err());
+    }
+}
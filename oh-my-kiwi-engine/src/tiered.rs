@@ -0,0 +1,982 @@
+//! An engine keeping hot keys in memory and cold ones on disk. `maxmemory`
+//! bounds the memory tier: once it's exceeded, the values of the least
+//! recently used keys are spilled to a file instead of being evicted, with
+//! only their key and place in the file left in memory. Reading a spilled
+//! key brings it back into memory. The spill file is scratch space, emptied
+//! on every start.
+//!
+//! Values are read from the file on a blocking thread without the lock,
+//! then checked to still be the ones the key points to. Spilling works the
+//! other way around: the values stay readable in memory while a copy of them
+//! is written out without the lock, and only the keys still holding them are
+//! moved to disk. Garbage is reclaimed the same way: the live values are
+//! copied to a fresh file, and only the ones spilled meanwhile are copied
+//! with the lock held.
+
+mod spill;
+
+use crate::config::KiwiConfig;
+use crate::encoding::{Codec, Encoding, Value};
+use crate::eviction::{Limit, MaxmemoryPolicy};
+use crate::in_memory::{Database, Lookup, Usage, active_expire, eviction_candidate, move_entry};
+use crate::lazy_free::{free, release};
+use crate::time::now_ms;
+use async_trait::async_trait;
+use oh_my_kiwi_domain::{
+    AccessHistory, Engine, Entry, MemoryStats, Snapshot, TierStats, ValueType,
+};
+use spill::{Compaction, Slot, SpillFile};
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::{RwLock, RwLockWriteGuard};
+use tracing::error;
+
+pub const DEFAULT_TIERED_FILE: &str = "cold.kiwi";
+
+/// Bookkeeping kept in memory for every spilled key on top of the key.
+const COLD_OVERHEAD: usize = size_of::<(u64, Vec<u8>, Cold)>();
+
+/// A key whose value was spilled, encoded as it was in memory.
+#[derive(Clone)]
+struct Cold {
+    slot: Slot,
+    expires_at: Option<u64>,
+    encoding: Encoding,
+    value_type: ValueType,
+}
+
+impl Cold {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The spilled keys of one database.
+#[derive(Default)]
+struct ColdTier {
+    keys: HashMap<Vec<u8>, Cold>,
+    memory: usize,
+}
+
+impl ColdTier {
+    fn insert(&mut self, key: Vec<u8>, cold: Cold) {
+        self.memory += COLD_OVERHEAD + key.len();
+        self.keys.insert(key, cold);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<(Vec<u8>, Cold)> {
+        let (key, cold) = self.keys.remove_entry(key)?;
+        self.memory -= COLD_OVERHEAD + key.len();
+        Some((key, cold))
+    }
+
+    fn contains(&self, key: &[u8], now: u64) -> bool {
+        self.keys.get(key).is_some_and(|cold| !cold.is_expired(now))
+    }
+}
+
+/// Every key lives in exactly one of the tiers.
+struct State {
+    hot: Vec<Database>,
+    cold: Vec<ColdTier>,
+    spill: SpillFile,
+}
+
+impl State {
+    fn memory(&self) -> usize {
+        let hot: usize = self.hot.iter().map(Database::memory).sum();
+        hot + self.cold.iter().map(|tier| tier.memory).sum::<usize>()
+    }
+
+    /// Removes the spilled `key` of `db`, returning whether it was live.
+    fn forget_cold(&mut self, db: usize, key: &[u8], now: u64) -> bool {
+        match self.cold[db].remove(key) {
+            Some((_, cold)) => {
+                self.spill.free(cold.slot);
+                !cold.is_expired(now)
+            }
+            None => false,
+        }
+    }
+
+    fn remove_if_expired(&mut self, db: usize, key: &[u8], now: u64) {
+        self.hot[db].remove_if_expired(key, now);
+        if self.cold[db]
+            .keys
+            .get(key)
+            .is_some_and(|cold| cold.is_expired(now))
+        {
+            self.forget_cold(db, key, now);
+        }
+    }
+
+    /// Where the live spilled `key` of `db` can be read from.
+    fn locate(&self, db: usize, key: &[u8], now: u64) -> Option<Located> {
+        let cold = self.cold[db].keys.get(key)?;
+        (!cold.is_expired(now)).then(|| Located {
+            file: self.spill.file().clone(),
+            slot: cold.slot,
+            encoding: cold.encoding,
+            value_type: cold.value_type,
+        })
+    }
+
+    /// Whether the spilled `key` of `db` still holds the value at `located`.
+    fn still_at(&self, db: usize, key: &[u8], located: &Located) -> bool {
+        Arc::ptr_eq(self.spill.file(), &located.file)
+            && self.cold[db]
+                .keys
+                .get(key)
+                .is_some_and(|cold| cold.slot == located.slot)
+    }
+
+    /// Brings the spilled `key` of `db` back into memory, `value` being what
+    /// was read from its slot.
+    fn fault_in(&mut self, db: usize, key: &[u8], value: Value, now: u64) {
+        if let Some((key, cold)) = self.cold[db].remove(key) {
+            self.spill.free(cold.slot);
+            self.hot[db].insert_value(key, value, cold.expires_at, now);
+        }
+    }
+
+    /// Empties the spill file once no key is left in it.
+    fn clear_spill(&mut self) {
+        if let Err(err) = self.spill.clear() {
+            error!("Clearing {} failed: {err}", self.spill.path().display());
+        }
+    }
+}
+
+/// A spilled value, with the file it was spilled to.
+struct Located {
+    file: Arc<File>,
+    slot: Slot,
+    encoding: Encoding,
+    value_type: ValueType,
+}
+
+impl Located {
+    /// Reads the value on a blocking thread.
+    async fn read(&self) -> std::io::Result<Value> {
+        let (file, slot) = (self.file.clone(), self.slot);
+        let bytes = tokio::task::spawn_blocking(move || slot.read(&file))
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)))?;
+        Ok(Value::from_stored(bytes, self.encoding, self.value_type))
+    }
+}
+
+/// A copy of a value being spilled, and its slot in the spill file.
+struct Spilled {
+    db: usize,
+    key: Vec<u8>,
+    value: Value,
+    slot: Slot,
+}
+
+pub struct TieredEngine {
+    databases: usize,
+    state: RwLock<State>,
+    limit: Limit,
+    codec: Codec,
+    /// Usage of the memory tier's values.
+    hot: Usage,
+    used_memory: AtomicUsize,
+    memory_keys: AtomicUsize,
+    disk_keys: AtomicUsize,
+    disk_bytes: AtomicU64,
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    /// Set while values are written to the spill file, one batch at a time.
+    spilling: AtomicBool,
+    compacting: AtomicBool,
+}
+
+impl TieredEngine {
+    /// An engine spilling to the configured file, with `maxmemory` as the
+    /// size of its memory tier.
+    pub fn from_config(config: &KiwiConfig) -> std::io::Result<Self> {
+        let databases = config.databases().max(1);
+        let state = State {
+            hot: vec![Database::default(); databases],
+            cold: (0..databases).map(|_| ColdTier::default()).collect(),
+            spill: SpillFile::create(config.tiered_path())?,
+        };
+        Ok(Self {
+            databases,
+            state: RwLock::new(state),
+            limit: Limit::from_config(config),
+            codec: Codec::from_config(config),
+            hot: Usage::default(),
+            used_memory: AtomicUsize::new(0),
+            memory_keys: AtomicUsize::new(0),
+            disk_keys: AtomicUsize::new(0),
+            disk_bytes: AtomicU64::new(0),
+            memory_hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            spilling: AtomicBool::new(false),
+            compacting: AtomicBool::new(false),
+        })
+    }
+
+    /// Publishes the statistics after a write and releases the lock, then
+    /// spills the least recently used values until the memory tier fits under
+    /// `maxmemory` and tidies the spill file.
+    async fn settle(&self, mut state: RwLockWriteGuard<'_, State>) {
+        let spill = self.pick_spills(&mut state);
+        let compact = state.spill.needs_compaction();
+        if compact && state.cold.iter().all(|tier| tier.keys.is_empty()) {
+            state.clear_spill();
+        }
+        self.publish(&state);
+        drop(state);
+
+        if let Some((file, spilled)) = spill {
+            self.spill(file, spilled).await;
+        }
+        if compact {
+            self.compact().await;
+        }
+    }
+
+    /// Picks the values to spill for the memory tier to fit under
+    /// `maxmemory` and reserves their slots, unless a spill is already being
+    /// written. The values stay in memory until they are on disk, so the
+    /// memory tier goes over `maxmemory` by a batch meanwhile.
+    fn pick_spills(&self, state: &mut State) -> Option<(Arc<File>, Vec<Spilled>)> {
+        // Spilling follows recency whatever the eviction policy, as no key
+        // is ever evicted.
+        let limit = Limit {
+            policy: MaxmemoryPolicy::AllKeysLru,
+            ..self.limit
+        };
+        if !limit.exceeded(state.memory()) || self.spilling.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        let now = now_ms();
+        let mut picked = Vec::new();
+        let mut cold_memory = 0;
+        while limit.exceeded(state.memory() + cold_memory) {
+            let Some(candidate) = eviction_candidate(&state.hot, &limit, now) else {
+                break;
+            };
+            let Some((key, stored)) = state.hot[candidate.db].remove(&candidate.key) else {
+                continue;
+            };
+            if stored.is_expired(now) {
+                continue;
+            }
+            cold_memory += COLD_OVERHEAD + key.len();
+            let slot = state.spill.reserve(stored.value().stored().len());
+            picked.push((candidate.db, key, stored, slot));
+        }
+        // Taken out only so the next candidate is another key.
+        let spilled: Vec<Spilled> = picked
+            .into_iter()
+            .map(|(db, key, stored, slot)| {
+                let value = stored.value().clone();
+                state.hot[db].insert(key.clone(), stored);
+                Spilled {
+                    db,
+                    key,
+                    value,
+                    slot,
+                }
+            })
+            .collect();
+        if spilled.is_empty() {
+            self.spilling.store(false, Ordering::Release);
+            return None;
+        }
+        Some((state.spill.file().clone(), spilled))
+    }
+
+    /// Writes the `spilled` values to their slots of `file` on a blocking
+    /// thread without the lock, then moves the keys still holding them to the
+    /// cold tier. A key written meanwhile keeps its new value in memory, and
+    /// so do all of them should the write fail or a clear or compaction
+    /// replace the file meanwhile.
+    async fn spill(&self, file: Arc<File>, spilled: Vec<Spilled>) {
+        let (file, spilled, written) = tokio::task::spawn_blocking(move || {
+            let written = spilled
+                .iter()
+                .try_for_each(|spilled| spilled.slot.write(&file, spilled.value.stored()));
+            (file, spilled, written)
+        })
+        .await
+        .expect("writing to the spill file panicked");
+
+        let mut state = self.state.write().await;
+        self.spilling.store(false, Ordering::Release);
+        if let Err(err) = &written {
+            error!("Spilling to {} failed: {err}", state.spill.path().display());
+        }
+        let current = Arc::ptr_eq(state.spill.file(), &file);
+        for Spilled {
+            db,
+            key,
+            value,
+            slot,
+        } in spilled
+        {
+            if written.is_err() || !current || !state.hot[db].holds(&key, &value) {
+                if current {
+                    state.spill.free(slot);
+                }
+                continue;
+            }
+            let Some((key, stored)) = state.hot[db].remove(&key) else {
+                continue;
+            };
+            let (value, expires_at) = stored.into_parts();
+            let cold = Cold {
+                slot,
+                expires_at,
+                encoding: value.encoding(),
+                value_type: value.value_type(),
+            };
+            state.cold[db].insert(key, cold);
+        }
+        self.publish(&state);
+    }
+
+    /// Rewrites the spill file without its garbage, unless that's already
+    /// being done. The live values are copied on a blocking thread without
+    /// the lock, then the ones spilled meanwhile with it, before the new file
+    /// takes over.
+    async fn compact(&self) {
+        if self.compacting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let (compaction, slots) = {
+            let state = self.state.read().await;
+            let slots: Vec<Slot> = state
+                .cold
+                .iter()
+                .flat_map(|tier| tier.keys.values())
+                .map(|cold| cold.slot)
+                .collect();
+            (state.spill.compaction(), slots)
+        };
+        let compaction = match compaction {
+            Ok(compaction) => compaction,
+            Err(err) => {
+                error!("Compacting the spill file failed: {err}");
+                self.compacting.store(false, Ordering::Release);
+                return;
+            }
+        };
+        let (compaction, copied) = copy_slots(compaction, slots, HashMap::new()).await;
+
+        let mut state = self.state.write().await;
+        let (compaction, copied) = match copied {
+            Ok(copied) if state.spill.is_compacted_by(&compaction) => {
+                let spilled_meanwhile = state
+                    .cold
+                    .iter()
+                    .flat_map(|tier| tier.keys.values())
+                    .map(|cold| cold.slot)
+                    .filter(|slot| !copied.contains_key(slot))
+                    .collect();
+                copy_slots(compaction, spilled_meanwhile, copied).await
+            }
+            copied => (compaction, copied),
+        };
+        let finished = match copied {
+            // A clear started a new file meanwhile.
+            Ok(_) if !state.spill.is_compacted_by(&compaction) => {
+                compaction.discard();
+                Ok(())
+            }
+            Ok(copied) => {
+                let live = state
+                    .cold
+                    .iter()
+                    .flat_map(|tier| tier.keys.values())
+                    .map(|cold| cold.slot.len() as u64)
+                    .sum();
+                let finished = state.spill.finish_compaction(compaction, live);
+                if finished.is_ok() {
+                    for cold in state
+                        .cold
+                        .iter_mut()
+                        .flat_map(|tier| tier.keys.values_mut())
+                    {
+                        cold.slot = copied[&cold.slot];
+                    }
+                }
+                finished
+            }
+            Err(err) => {
+                compaction.discard();
+                Err(err)
+            }
+        };
+        if let Err(err) = finished {
+            error!("Compacting {} failed: {err}", state.spill.path().display());
+        }
+        self.publish(&state);
+        drop(state);
+        self.compacting.store(false, Ordering::Release);
+    }
+
+    fn publish(&self, state: &State) {
+        self.hot.update(&state.hot);
+        self.used_memory.store(state.memory(), Ordering::Relaxed);
+        let hot = state.hot.iter().map(Database::len).sum();
+        self.memory_keys.store(hot, Ordering::Relaxed);
+        let cold = state.cold.iter().map(|tier| tier.keys.len()).sum();
+        self.disk_keys.store(cold, Ordering::Relaxed);
+        self.disk_bytes.store(state.spill.size(), Ordering::Relaxed);
+    }
+
+    /// Moves a resize of the memory tier along after a lookup, if the write
+    /// lock is free right away.
+    fn rehash_after_lookup(&self, db: usize, rehashing: bool) {
+        if rehashing && let Ok(mut state) = self.state.try_write() {
+            state.hot[db].rehash_step();
+        }
+    }
+
+    fn count(&self, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl Engine for TieredEngine {
+    fn databases(&self) -> usize {
+        self.databases
+    }
+
+    /// A spilled value is read without the lock, and brought back into
+    /// memory unless the key changed meanwhile, in which case it's looked up
+    /// again. A failed read is logged, and answered as a missing key.
+    async fn get(&self, db: usize, key: &[u8]) -> Option<Vec<u8>> {
+        let now = now_ms();
+        loop {
+            let state = self.state.read().await;
+            let rehashing = state.hot[db].is_rehashing();
+            match state.hot[db].get(key, now) {
+                Lookup::Found(value) => {
+                    drop(state);
+                    self.rehash_after_lookup(db, rehashing);
+                    self.count(&self.memory_hits);
+                    return Some(value.into_bytes());
+                }
+                Lookup::Missing if !state.cold[db].keys.contains_key(key) => {
+                    drop(state);
+                    self.rehash_after_lookup(db, rehashing);
+                    self.count(&self.misses);
+                    return None;
+                }
+                Lookup::Expired | Lookup::Missing => {}
+            }
+            let located = state.locate(db, key, now);
+            drop(state);
+            let read = match &located {
+                Some(located) => Some(located.read().await),
+                None => None,
+            };
+
+            let mut state = self.state.write().await;
+            if let Lookup::Found(value) = state.hot[db].get(key, now) {
+                self.count(&self.memory_hits);
+                return Some(value.into_bytes());
+            }
+            state.remove_if_expired(db, key, now);
+            let value = match (located, read) {
+                (Some(located), Some(read)) if state.still_at(db, key, &located) => match read {
+                    Ok(value) => {
+                        state.fault_in(db, key, value.clone(), now);
+                        Some(value)
+                    }
+                    Err(err) => {
+                        error!("Reading {} failed: {err}", state.spill.path().display());
+                        None
+                    }
+                },
+                _ if state.locate(db, key, now).is_some() => continue,
+                _ => None,
+            };
+            self.count(match value {
+                Some(_) => &self.disk_hits,
+                None => &self.misses,
+            });
+            self.settle(state).await;
+            return value.map(Value::into_bytes);
+        }
+    }
+
+    async fn set(&self, db: usize, key: Vec<u8>, value: Vec<u8>) {
+        let now = now_ms();
+        let value = self.codec.encode(value);
+        let mut state = self.state.write().await;
+        state.forget_cold(db, &key, now);
+        state.hot[db].set(key, value, now);
+        self.settle(state).await;
+    }
+
+    async fn delete(&self, db: usize, key: &[u8], lazy: bool) -> bool {
+        let now = now_ms();
+        let mut state = self.state.write().await;
+        let hot = state.hot[db].delete(key, now);
+        let deleted = hot.is_some() || state.forget_cold(db, key, now);
+        self.settle(state).await;
+
+        if let Some(value) = hot {
+            release(value, lazy);
+        }
+        deleted
+    }
+
+    async fn move_key(&self, db: usize, target: usize, key: &[u8]) -> bool {
+        let now = now_ms();
+        let mut state = self.state.write().await;
+        state.remove_if_expired(db, key, now);
+        state.remove_if_expired(target, key, now);
+        let moved = if state.hot[target].contains(key, now) || state.cold[target].contains(key, now)
+        {
+            false
+        } else if let Some((key, cold)) = state.cold[db].remove(key) {
+            state.cold[target].insert(key, cold);
+            true
+        } else {
+            move_entry(&mut state.hot, db, target, key)
+        };
+        self.settle(state).await;
+        moved
+    }
+
+    async fn swap(&self, first: usize, second: usize) {
+        let mut state = self.state.write().await;
+        state.hot.swap(first, second);
+        state.cold.swap(first, second);
+    }
+
+    async fn size(&self, db: usize) -> usize {
+        let state = self.state.read().await;
+        state.hot[db].len() + state.cold[db].keys.len()
+    }
+
+    async fn flush(&self, db: usize, lazy: bool) {
+        let mut state = self.state.write().await;
+        let database = std::mem::take(&mut state.hot[db]);
+        let cold = std::mem::take(&mut state.cold[db]);
+        for (_, cold) in cold.keys {
+            state.spill.free(cold.slot);
+        }
+        self.settle(state).await;
+
+        free(database, lazy);
+    }
+
+    async fn flush_all(&self, lazy: bool) {
+        let mut state = self.state.write().await;
+        let databases: Vec<Database> = state.hot.iter_mut().map(std::mem::take).collect();
+        state
+            .cold
+            .iter_mut()
+            .for_each(|tier| *tier = ColdTier::default());
+        state.clear_spill();
+        self.settle(state).await;
+
+        free(databases, lazy);
+    }
+
+    async fn set_expiry(&self, db: usize, key: &[u8], expires_at: Option<u64>) -> bool {
+        let now = now_ms();
+        let mut state = self.state.write().await;
+        state.remove_if_expired(db, key, now);
+        let found = match state.cold[db].keys.get_mut(key) {
+            Some(_) if expires_at.is_some_and(|expires_at| expires_at <= now) => {
+                state.forget_cold(db, key, now)
+            }
+            Some(cold) => {
+                cold.expires_at = expires_at;
+                true
+            }
+            None => state.hot[db].expire(key, expires_at, now),
+        };
+        self.settle(state).await;
+        found
+    }
+
+    async fn expiry(&self, db: usize, key: &[u8]) -> Option<Option<u64>> {
+        let now = now_ms();
+        let state = self.state.read().await;
+        match state.cold[db].keys.get(key) {
+            Some(cold) => (!cold.is_expired(now)).then_some(cold.expires_at),
+            None => state.hot[db].expiry(key, now),
+        }
+    }
+
+    async fn encoding(&self, db: usize, key: &[u8]) -> Option<&'static str> {
+        let now = now_ms();
+        let state = self.state.read().await;
+        if !state.cold[db].keys.contains_key(key) {
+            return state.hot[db].encoding(key, now);
+        }
+        let located = state.locate(db, key, now)?;
+        drop(state);
+        match located.read().await {
+            Ok(value) => Some(value.name()),
+            Err(err) => {
+                error!("Reading the spill file failed: {err}");
+                None
+            }
+        }
+    }
+
+    /// A spilled key only takes memory for its bookkeeping; its value is on
+    /// disk.
+    async fn memory_usage(&self, db: usize, key: &[u8]) -> Option<usize> {
+        let now = now_ms();
+        let state = self.state.read().await;
+        match state.cold[db].keys.get(key) {
+            Some(cold) => (!cold.is_expired(now)).then_some(COLD_OVERHEAD + key.len()),
+            None => state.hot[db].memory_usage(key, now),
+        }
+    }
+
+    /// Spilled values are read from disk as the snapshot is, from the file
+    /// they were spilled to, which clearing or compacting never changes.
+    async fn snapshot(&self) -> Box<dyn Snapshot> {
+        let now = now_ms();
+        let state = self.state.read().await;
+        let hot = state.hot.iter().map(|db| db.live_entries(now)).collect();
+        let cold = state
+            .cold
+            .iter()
+            .map(|tier| {
+                tier.keys
+                    .iter()
+                    .filter(|(_, cold)| !cold.is_expired(now))
+                    .map(|(key, cold)| (key.clone(), cold.clone()))
+                    .collect()
+            })
+            .collect();
+        Box::new(TieredSnapshot {
+            hot,
+            cold,
+            file: state.spill.file().clone(),
+        })
+    }
+
+    async fn restore(
+        &self,
+        db: usize,
+        entry: Entry,
+        history: AccessHistory,
+        replace: bool,
+    ) -> bool {
+        let now = now_ms();
+        let (key, value, expires_at) = self.codec.encode_entry(entry);
+        let mut state = self.state.write().await;
+        state.remove_if_expired(db, &key, now);
+        if !replace && state.cold[db].contains(&key, now) {
+            return false;
+        }
+        state.forget_cold(db, &key, now);
+        let restored = state.hot[db].restore(key, value, expires_at, history, replace, now);
+        self.settle(state).await;
+        restored
+    }
+
+    async fn insert_entries(&self, db: usize, entries: Vec<Entry>) {
+        let now = now_ms();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|entry| self.codec.encode_entry(entry))
+            .collect();
+        let mut state = self.state.write().await;
+        for (key, value, expires_at) in entries {
+            state.forget_cold(db, &key, now);
+            state.hot[db].insert_value(key, value, expires_at, now);
+        }
+        self.settle(state).await;
+    }
+
+    fn memory(&self) -> MemoryStats {
+        MemoryStats {
+            used: self.used_memory.load(Ordering::Relaxed),
+            overhead: self.hot.overhead() + self.disk_keys.load(Ordering::Relaxed) * COLD_OVERHEAD,
+            max: self.limit.maxmemory,
+            policy: self.limit.policy.name(),
+            evicted_keys: 0,
+            compression: self.hot.compression(),
+        }
+    }
+
+    /// Cold values are spilled by every write, so there's never anything
+    /// left to evict.
+    async fn evict(&self) -> Vec<(usize, Vec<u8>)> {
+        Vec::new()
+    }
+
+    /// Only the memory tier is swept: spilled keys take little memory and are
+    /// forgotten when next looked up.
+    async fn expire_cycle(&self) -> Vec<(usize, Vec<u8>)> {
+        let mut state = self.state.write().await;
+        let expired = active_expire(&mut state.hot, now_ms());
+        self.settle(state).await;
+        expired
+    }
+
+    fn tiers(&self) -> Option<TierStats> {
+        Some(TierStats {
+            memory_hits: self.memory_hits.load(Ordering::Relaxed),
+            disk_hits: self.disk_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            memory_keys: self.memory_keys.load(Ordering::Relaxed),
+            disk_keys: self.disk_keys.load(Ordering::Relaxed),
+            disk_bytes: self.disk_bytes.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Copies `slots` into `compaction` on a blocking thread, adding where they
+/// went to `copied`.
+async fn copy_slots(
+    mut compaction: Compaction,
+    slots: Vec<Slot>,
+    mut copied: HashMap<Slot, Slot>,
+) -> (Compaction, std::io::Result<HashMap<Slot, Slot>>) {
+    tokio::task::spawn_blocking(move || {
+        let result = slots.iter().try_for_each(|slot| {
+            copied.insert(*slot, compaction.copy(slot)?);
+            Ok(())
+        });
+        (compaction, result.map(|()| copied))
+    })
+    .await
+    .expect("compacting the spill file panicked")
+}
+
+/// The memory tier copied out, and where the spilled values lie in the file
+/// they were spilled to, read from it as the entries are.
+struct TieredSnapshot {
+    hot: Vec<Vec<Entry>>,
+    cold: Vec<Vec<(Vec<u8>, Cold)>>,
+    file: Arc<File>,
+}
+
+impl Snapshot for TieredSnapshot {
+    fn databases(&self) -> usize {
+        self.hot.len()
+    }
+
+    fn size_hint(&self, db: usize) -> (usize, usize) {
+        let volatile = self.hot[db]
+            .iter()
+            .filter(|entry| entry.expires_at.is_some())
+            .count()
+            + self.cold[db]
+                .iter()
+                .filter(|(_, cold)| cold.expires_at.is_some())
+                .count();
+        (self.hot[db].len() + self.cold[db].len(), volatile)
+    }
+
+    fn entries(&self, db: usize) -> Box<dyn Iterator<Item = std::io::Result<Entry>> + '_> {
+        let cold = self.cold[db].iter().map(|(key, cold)| {
+            let bytes = cold.slot.read(&self.file)?;
+            Ok(Entry {
+                key: key.clone(),
+                value: Value::from_stored(bytes, cold.encoding, cold.value_type).into_bytes(),
+                expires_at: cold.expires_at,
+                value_type: cold.value_type,
+            })
+        });
+        Box::new(self.hot[db].iter().cloned().map(Ok).chain(cold))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn engine(name: &str, maxmemory: usize) -> TieredEngine {
+        let config = KiwiConfig::new()
+            .with_databases(2)
+            .with_dir(std::env::temp_dir())
+            .with_tiered_file(format!("kiwi-tiered-{name}-{}.cold", std::process::id()))
+            .with_maxmemory(maxmemory);
+        TieredEngine::from_config(&config).unwrap()
+    }
+
+    async fn remove(engine: TieredEngine) {
+        let state = engine.state.read().await;
+        std::fs::remove_file(state.spill.path()).unwrap();
+    }
+
+    fn value(i: usize) -> Vec<u8> {
+        format!("{i:0>1000}").into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_cold_keys_spill_and_fault_in() {
+        let engine = engine("spill", 128 * 1024);
+        for i in 0..1_000 {
+            engine
+                .set(0, format!("key{i}").into_bytes(), value(i))
+                .await;
+        }
+        let tiers = engine.tiers().unwrap();
+        assert!(engine.memory().used <= 128 * 1024);
+        assert!(tiers.disk_keys > 900 && tiers.disk_bytes > 900_000);
+        assert_eq!(tiers.memory_keys + tiers.disk_keys, 1_000);
+        assert_eq!(engine.size(0).await, 1_000);
+        assert!(engine.evict().await.is_empty());
+
+        let state = engine.state.read().await;
+        let cold = state.cold[0].keys.keys().next().unwrap().clone();
+        let hot = state.hot[0].live_entries(now_ms()).swap_remove(0);
+        drop(state);
+        let usage = engine.memory_usage(0, &cold).await;
+        assert_eq!(usage, Some(COLD_OVERHEAD + cold.len()));
+        assert!(engine.memory_usage(0, &hot.key).await.unwrap() > hot.value.len());
+        assert!(engine.memory().overhead >= tiers.disk_keys * COLD_OVERHEAD);
+        assert_eq!(engine.get(0, &hot.key).await, Some(hot.value));
+        let expected = engine
+            .snapshot()
+            .await
+            .entries(0)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        let expected = expected.iter().find(|entry| entry.key == cold).unwrap();
+        assert_eq!(engine.get(0, &cold).await, Some(expected.value.clone()));
+        assert_eq!(engine.get(0, &cold).await, Some(expected.value.clone()));
+        assert_eq!(engine.get(0, b"missing").await, None);
+        let tiers = engine.tiers().unwrap();
+        assert_eq!(
+            (tiers.memory_hits, tiers.disk_hits, tiers.misses),
+            (2, 1, 1)
+        );
+
+        let entries = engine
+            .snapshot()
+            .await
+            .entries(0)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 1_000);
+        assert!(entries.contains(&Entry {
+            key: b"key500".to_vec(),
+            value: value(500),
+            expires_at: None,
+            value_type: ValueType::String,
+        }));
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_commands_on_cold_keys() {
+        let engine = engine("commands", 4 * 1024);
+        engine.set(0, b"cold".to_vec(), b"value".to_vec()).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        for i in 0..100 {
+            engine
+                .set(1, format!("key{i}").into_bytes(), value(i))
+                .await;
+        }
+        let later = now_ms() + 60_000;
+        assert!(
+            engine.state.read().await.cold[0]
+                .keys
+                .contains_key(&b"cold"[..])
+        );
+
+        assert!(engine.set_expiry(0, b"cold", Some(later)).await);
+        assert_eq!(engine.expiry(0, b"cold").await, Some(Some(later)));
+        assert!(!engine.move_key(0, 1, b"missing").await);
+        assert!(engine.move_key(0, 1, b"cold").await);
+        assert_eq!(engine.size(0).await, 0);
+        engine.swap(0, 1).await;
+        assert!(engine.delete(0, b"cold", false).await);
+        assert!(!engine.delete(0, b"cold", false).await);
+
+        engine.set(0, b"cold".to_vec(), b"new".to_vec()).await;
+        assert_eq!(engine.get(0, b"cold").await, Some(b"new".to_vec()));
+        assert!(engine.set_expiry(0, b"key1", Some(now_ms() - 1)).await);
+        assert_eq!(engine.get(0, b"key1").await, None);
+        assert_eq!(engine.size(0).await, 100);
+
+        engine.flush_all(false).await;
+        assert_eq!(engine.tiers().unwrap().disk_bytes, 0);
+        assert_eq!(engine.memory().used, 0);
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_spill_keeps_values() {
+        let engine = engine("failed-spill", 4 * 1024);
+        engine.state.write().await.spill.make_read_only();
+        for i in 0..10 {
+            engine
+                .set(0, format!("key{i}").into_bytes(), value(i))
+                .await;
+        }
+        let tiers = engine.tiers().unwrap();
+        assert_eq!((tiers.memory_keys, tiers.disk_keys), (10, 0));
+        assert_eq!(engine.get(0, b"key3").await, Some(value(3)));
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_spill_keeps_keys_written_meanwhile() {
+        let engine = engine("written-meanwhile", 4 * 1024);
+        for i in 0..10 {
+            engine.state.write().await.hot[0].insert_value(
+                format!("key{i}").into_bytes(),
+                Value::from_stored(value(i), Encoding::Plain, ValueType::String),
+                None,
+                now_ms(),
+            );
+        }
+        let (file, spilled) = engine
+            .pick_spills(&mut *engine.state.write().await)
+            .unwrap();
+        let overwritten = spilled[0].key.clone();
+        // Picked values stay readable, and another write doesn't spill them
+        // twice.
+        assert_eq!(engine.tiers().unwrap().disk_keys, 0);
+        engine.set(0, overwritten.clone(), b"new".to_vec()).await;
+        assert!(engine.spilling.load(Ordering::Acquire));
+
+        engine.spill(file, spilled).await;
+        let state = engine.state.read().await;
+        assert!(state.hot[0].contains(&overwritten, now_ms()));
+        assert!(!state.cold[0].keys.contains_key(&overwritten));
+        assert!(!state.cold[0].keys.is_empty());
+        drop(state);
+        assert_eq!(engine.get(0, &overwritten).await, Some(b"new".to_vec()));
+        assert_eq!(engine.size(0).await, 10);
+        remove(engine).await;
+    }
+
+    #[tokio::test]
+    async fn test_spill_file_is_compacted() {
+        let engine = engine("compaction", 4 * 1024);
+        let big = vec![1; 64 * 1024];
+        for round in 0..40 {
+            for i in 0..10 {
+                let key = format!("key{i}").into_bytes();
+                engine
+                    .set(0, key, [big.as_slice(), &[round]].concat())
+                    .await;
+            }
+        }
+        let tiers = engine.tiers().unwrap();
+        // Without compaction the file would hold every round's values.
+        assert!(tiers.disk_bytes < 10 * 64 * 1024 * 4);
+        let expected = [big.as_slice(), &[39]].concat();
+        assert_eq!(engine.get(0, b"key3").await, Some(expected));
+        remove(engine).await;
+    }
+}
@@ -0,0 +1,209 @@
+//! The file cold values are spilled to. Values are appended; a value that's
+//! read back, overwritten or deleted leaves garbage behind, reclaimed by
+//! rewriting the live values into a fresh file once garbage dominates.
+
+use std::fs::File;
+use std::io::Result;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Garbage below this many bytes is never worth a rewrite.
+const COMPACTION_MIN_GARBAGE: u64 = 1024 * 1024;
+
+/// Where a value lies in the spill file. The bytes of a slot never change
+/// once written: a cleared or compacted file is a new one, so a slot can be
+/// read without the lock through the file it was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) struct Slot {
+    offset: u64,
+    len: usize,
+}
+
+impl Slot {
+    pub(super) fn read(&self, file: &File) -> Result<Vec<u8>> {
+        let mut value = vec![0; self.len];
+        file.read_exact_at(&mut value, self.offset)?;
+        Ok(value)
+    }
+
+    pub(super) fn write(&self, file: &File, value: &[u8]) -> Result<()> {
+        file.write_all_at(value, self.offset)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+pub(super) struct SpillFile {
+    path: PathBuf,
+    file: Arc<File>,
+    end: u64,
+    /// Bytes of values no longer referenced.
+    garbage: u64,
+}
+
+impl SpillFile {
+    /// An empty spill file at `path`, replacing the one of any earlier run.
+    pub(super) fn create(path: PathBuf) -> Result<Self> {
+        Ok(Self {
+            file: Arc::new(open(&path)?),
+            path,
+            end: 0,
+            garbage: 0,
+        })
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file the slots handed out point into.
+    pub(super) fn file(&self) -> &Arc<File> {
+        &self.file
+    }
+
+    pub(super) fn size(&self) -> u64 {
+        self.end
+    }
+
+    /// A slot at the end of the file for a value of `len` bytes, to be
+    /// written with `Slot::write`.
+    pub(super) fn reserve(&mut self, len: usize) -> Slot {
+        let slot = Slot {
+            offset: self.end,
+            len,
+        };
+        self.end += len as u64;
+        slot
+    }
+
+    /// Marks the value in `slot` as garbage.
+    pub(super) fn free(&mut self, slot: Slot) {
+        self.garbage += slot.len as u64;
+    }
+
+    /// Starts a new file once nothing references the current one anymore.
+    pub(super) fn clear(&mut self) -> Result<()> {
+        std::fs::remove_file(&self.path)?;
+        self.file = Arc::new(open(&self.path)?);
+        self.end = 0;
+        self.garbage = 0;
+        Ok(())
+    }
+
+    /// Swaps in a handle writes fail through, as they would on a full disk.
+    #[cfg(test)]
+    pub(super) fn make_read_only(&mut self) {
+        self.file = Arc::new(File::open(&self.path).unwrap());
+    }
+
+    pub(super) fn needs_compaction(&self) -> bool {
+        self.garbage >= COMPACTION_MIN_GARBAGE && self.garbage > self.end - self.garbage
+    }
+
+    /// A fresh file to copy the live values of this one into.
+    pub(super) fn compaction(&self) -> Result<Compaction> {
+        let path = self.path.with_extension("compacting");
+        Ok(Compaction {
+            file: open(&path)?,
+            path,
+            source: self.file.clone(),
+            end: 0,
+        })
+    }
+
+    /// Whether `compaction` copies the current file, which a clear replaces.
+    pub(super) fn is_compacted_by(&self, compaction: &Compaction) -> bool {
+        Arc::ptr_eq(&self.file, &compaction.source)
+    }
+
+    /// Puts the compacted file in place of this one, of which `live` bytes
+    /// are still referenced. The slots copied must be pointed to their new
+    /// place.
+    pub(super) fn finish_compaction(&mut self, compaction: Compaction, live: u64) -> Result<()> {
+        if let Err(err) = std::fs::rename(&compaction.path, &self.path) {
+            compaction.discard();
+            return Err(err);
+        }
+        self.file = Arc::new(compaction.file);
+        self.end = compaction.end;
+        self.garbage = compaction.end - live;
+        Ok(())
+    }
+}
+
+/// The live values of a spill file being copied into a fresh one.
+pub(super) struct Compaction {
+    path: PathBuf,
+    file: File,
+    source: Arc<File>,
+    end: u64,
+}
+
+impl Compaction {
+    /// Copies the value in `slot` of the file being compacted, returning its
+    /// place in the new one.
+    pub(super) fn copy(&mut self, slot: &Slot) -> Result<Slot> {
+        let value = slot.read(&self.source)?;
+        let copied = Slot {
+            offset: self.end,
+            len: value.len(),
+        };
+        copied.write(&self.file, &value)?;
+        self.end += value.len() as u64;
+        Ok(copied)
+    }
+
+    /// Gives up on the compaction.
+    pub(super) fn discard(self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill_file() {
+        let path = std::env::temp_dir().join(format!("kiwi-spill-{}.cold", std::process::id()));
+        let mut spill = SpillFile::create(path.clone()).unwrap();
+        let big = vec![7; COMPACTION_MIN_GARBAGE as usize];
+        let freed = spill.reserve(big.len());
+        freed.write(spill.file(), &big).unwrap();
+        let kept = spill.reserve(5);
+        kept.write(spill.file(), b"value").unwrap();
+        assert_eq!(kept.read(spill.file()).unwrap(), b"value");
+        assert!(!spill.needs_compaction());
+
+        spill.free(freed);
+        assert!(spill.needs_compaction());
+        let old = spill.file().clone();
+        let mut compaction = spill.compaction().unwrap();
+        let copied = compaction.copy(&kept).unwrap();
+        assert!(spill.is_compacted_by(&compaction));
+        spill.finish_compaction(compaction, 5).unwrap();
+        assert_eq!(spill.size(), 5);
+        assert!(!spill.needs_compaction());
+        assert_eq!(copied.read(spill.file()).unwrap(), b"value");
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 5);
+        // The slots taken from the old file can still be read through it.
+        assert_eq!(kept.read(&old).unwrap(), b"value");
+
+        spill.clear().unwrap();
+        assert_eq!(spill.size(), 0);
+        assert_eq!(kept.read(&old).unwrap(), b"value");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+use crate::config::KiwiConfig;
+use oh_my_kiwi_domain::command::TrackingOptions;
+use oh_my_kiwi_domain::types::Types;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+pub type ClientId = u64;
+
+pub const DEFAULT_TRACKING_TABLE_MAX_KEYS: usize = 1_000_000;
+
+/// Bytes of push messages waiting in the queues of all connections.
+static QUEUED_PUSHES: AtomicUsize = AtomicUsize::new(0);
+
+/// The queue of push messages of a connection, which counts the bytes
+/// waiting in it toward [`queued_pushes`].
+pub fn push_channel() -> (PushSender, PushReceiver) {
+    let (sender, receiver) = unbounded_channel();
+    (PushSender { sender }, PushReceiver { receiver })
+}
+
+/// Bytes of push messages queued for the connected clients, not yet written.
+pub fn queued_pushes() -> usize {
+    QUEUED_PUSHES.load(Ordering::Relaxed)
+}
+
+#[derive(Clone)]
+pub struct PushSender {
+    sender: UnboundedSender<(Types, usize)>,
+}
+
+impl PushSender {
+    /// Queues `message`, failing once the connection is gone.
+    pub fn send(&self, message: Types) -> Result<(), Types> {
+        let len = message.to_bytes().len();
+        QUEUED_PUSHES.fetch_add(len, Ordering::Relaxed);
+        self.sender.send((message, len)).map_err(|err| {
+            QUEUED_PUSHES.fetch_sub(len, Ordering::Relaxed);
+            err.0.0
+        })
+    }
+}
+
+pub struct PushReceiver {
+    receiver: UnboundedReceiver<(Types, usize)>,
+}
+
+impl PushReceiver {
+    pub async fn recv(&mut self) -> Option<Types> {
+        let (message, len) = self.receiver.recv().await?;
+        QUEUED_PUSHES.fetch_sub(len, Ordering::Relaxed);
+        Some(message)
+    }
+
+    pub fn try_recv(&mut self) -> Option<Types> {
+        let (message, len) = self.receiver.try_recv().ok()?;
+        QUEUED_PUSHES.fetch_sub(len, Ordering::Relaxed);
+        Some(message)
+    }
+}
+
+impl Drop for PushReceiver {
+    /// Messages left in the queue go with the connection.
+    fn drop(&mut self) {
+        self.receiver.close();
+        while self.try_recv().is_some() {}
+    }
+}
+
+/// Server-wide table of clients with CLIENT TRACKING enabled.
+///
+/// In the default mode the table remembers which keys every client has read and
+/// sends one invalidation push per key once it is modified; the client has to read
+/// the key again to be notified next time. In BCAST mode clients are notified about
+/// every modified key matching one of their prefixes, whether they read it or not.
+///
+/// At most `tracking-table-max-keys` keys are remembered, 0 meaning no limit. Past
+/// that, keys are dropped from the table, and their clients told they're invalid
+/// as if they had been modified.
+pub struct TrackingTable {
+    max_keys: usize,
+    state: Mutex<TrackingState>,
+}
+
+#[derive(Default)]
+struct TrackingState {
+    clients: HashMap<ClientId, TrackedClient>,
+    keys: HashMap<Arc<[u8]>, HashSet<ClientId>>,
+    prefixes: HashMap<Vec<u8>, HashSet<ClientId>>,
+}
+
+struct TrackedClient {
+    sender: PushSender,
+    noloop: bool,
+    prefixes: Vec<Vec<u8>>,
+    /// The keys of the table the client is remembered under.
+    keys: HashSet<Arc<[u8]>>,
+}
+
+impl TrackingTable {
+    pub fn new(config: &KiwiConfig) -> Self {
+        Self {
+            max_keys: config.tracking_table_max_keys(),
+            state: Mutex::new(TrackingState::default()),
+        }
+    }
+
+    pub fn enable(&self, client: ClientId, sender: PushSender, options: &TrackingOptions) {
+        let mut state = self.state.lock().unwrap();
+        state.remove_client(client);
+
+        let mut prefixes: Vec<Vec<u8>> = Vec::new();
+        if options.bcast {
+            if options.prefixes.is_empty() {
+                prefixes.push(Vec::new());
+            } else {
+                prefixes.extend(
+                    options
+                        .prefixes
+                        .iter()
+                        .map(|prefix| prefix.as_bytes().to_vec()),
+                );
+            }
+        }
+        for prefix in &prefixes {
+            state
+                .prefixes
+                .entry(prefix.clone())
+                .or_default()
+                .insert(client);
+        }
+
+        state.clients.insert(
+            client,
+            TrackedClient {
+                sender,
+                noloop: options.noloop,
+                prefixes,
+                keys: HashSet::new(),
+            },
+        );
+    }
+
+    pub fn disable(&self, client: ClientId) {
+        self.state.lock().unwrap().remove_client(client);
+    }
+
+    /// Remembers that `client` has read `key`, so it is invalidated on the next write.
+    /// Past `tracking-table-max-keys`, another key is dropped and invalidated.
+    pub fn remember(&self, client: ClientId, key: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(tracked) = state.clients.get_mut(&client) else {
+            return;
+        };
+        let key: Arc<[u8]> = match state.keys.get_key_value(key) {
+            Some((key, _)) => key.clone(),
+            None => key.into(),
+        };
+        tracked.keys.insert(key.clone());
+        state.keys.entry(key.clone()).or_default().insert(client);
+
+        if self.max_keys > 0 && state.keys.len() > self.max_keys {
+            let evicted = state.keys.keys().find(|evicted| **evicted != key).cloned();
+            if let Some(evicted) = evicted {
+                let targets = state.forget_key(&evicted);
+                state.send(targets, None, invalidation_message(key_array(&evicted)));
+            }
+        }
+    }
+
+    /// Notifies every client tracking `key` that it has been modified by `origin`.
+    pub fn invalidate(&self, key: &[u8], origin: Option<ClientId>) {
+        let mut state = self.state.lock().unwrap();
+        let mut targets = state.forget_key(key);
+        for (prefix, clients) in &state.prefixes {
+            if key.starts_with(prefix) {
+                targets.extend(clients);
+            }
+        }
+
+        state.send(targets, origin, invalidation_message(key_array(key)));
+    }
+
+    /// Notifies every tracking client that the whole keyspace has been flushed.
+    pub fn invalidate_all(&self, origin: Option<ClientId>) {
+        let mut state = self.state.lock().unwrap();
+        state.keys.clear();
+        for tracked in state.clients.values_mut() {
+            tracked.keys.clear();
+        }
+
+        let targets = state.clients.keys().copied().collect();
+        state.send(targets, origin, invalidation_message(Types::Null));
+    }
+}
+
+impl Default for TrackingTable {
+    fn default() -> Self {
+        Self::new(&KiwiConfig::default())
+    }
+}
+
+impl TrackingState {
+    /// Drops the client along with the keys it read and its BCAST prefixes, so
+    /// tracking it again starts afresh.
+    fn remove_client(&mut self, client: ClientId) {
+        let Some(tracked) = self.clients.remove(&client) else {
+            return;
+        };
+        for key in tracked.keys {
+            if let Some(clients) = self.keys.get_mut(&key) {
+                clients.remove(&client);
+                if clients.is_empty() {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+        for prefix in tracked.prefixes {
+            if let Some(clients) = self.prefixes.get_mut(&prefix) {
+                clients.remove(&client);
+                if clients.is_empty() {
+                    self.prefixes.remove(&prefix);
+                }
+            }
+        }
+    }
+
+    /// Removes `key` from the table, returning the clients that read it.
+    fn forget_key(&mut self, key: &[u8]) -> HashSet<ClientId> {
+        let clients = self.keys.remove(key).unwrap_or_default();
+        for client in &clients {
+            if let Some(tracked) = self.clients.get_mut(client) {
+                tracked.keys.remove(key);
+            }
+        }
+        clients
+    }
+
+    fn send(&mut self, targets: HashSet<ClientId>, origin: Option<ClientId>, message: Types) {
+        let mut disconnected = Vec::new();
+        for client in targets {
+            let Some(tracked) = self.clients.get(&client) else {
+                continue;
+            };
+            if tracked.noloop && origin == Some(client) {
+                continue;
+            }
+            if tracked.sender.send(message.clone()).is_err() {
+                disconnected.push(client);
+            }
+        }
+
+        for client in disconnected {
+            self.remove_client(client);
+        }
+    }
+}
+
+fn key_array(key: &[u8]) -> Types {
+    Types::Array(vec![Types::BulkString(
+        String::from_utf8_lossy(key).into_owned(),
+    )])
+}
+
+fn invalidation_message(keys: Types) -> Types {
+    Types::Push(vec![Types::BulkString("invalidate".to_string()), keys])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(table: &TrackingTable, client: ClientId, options: TrackingOptions) -> PushReceiver {
+        let (sender, receiver) = push_channel();
+        table.enable(
+            client,
+            sender,
+            &TrackingOptions {
+                enabled: true,
+                ..options
+            },
+        );
+        receiver
+    }
+
+    fn invalidated_key(key: &str) -> Types {
+        invalidation_message(key_array(key.as_bytes()))
+    }
+
+    #[test]
+    fn test_default_mode_invalidates_read_keys_once() {
+        let table = TrackingTable::default();
+        let mut receiver = track(&table, 1, TrackingOptions::default());
+
+        table.remember(1, b"foo");
+        table.invalidate(b"bar", Some(2));
+        table.invalidate(b"foo", Some(2));
+        table.invalidate(b"foo", Some(2));
+
+        assert!(queued_pushes() >= invalidated_key("foo").to_bytes().len());
+        assert_eq!(receiver.try_recv().unwrap(), invalidated_key("foo"));
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_bcast_mode_matches_prefixes() {
+        let table = TrackingTable::default();
+        let options = TrackingOptions {
+            bcast: true,
+            prefixes: vec!["user:".to_string()],
+            ..TrackingOptions::default()
+        };
+        let mut receiver = track(&table, 1, options);
+
+        table.invalidate(b"user:1", Some(2));
+        table.invalidate(b"session:1", Some(2));
+        table.invalidate(b"user:2", Some(2));
+
+        assert_eq!(receiver.try_recv().unwrap(), invalidated_key("user:1"));
+        assert_eq!(receiver.try_recv().unwrap(), invalidated_key("user:2"));
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_noloop_skips_own_writes() {
+        let table = TrackingTable::default();
+        let options = TrackingOptions {
+            bcast: true,
+            noloop: true,
+            ..TrackingOptions::default()
+        };
+        let mut receiver = track(&table, 1, options);
+
+        table.invalidate(b"foo", Some(1));
+        table.invalidate(b"bar", Some(2));
+
+        assert_eq!(receiver.try_recv().unwrap(), invalidated_key("bar"));
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_disable_stops_notifications() {
+        let table = TrackingTable::default();
+        let mut receiver = track(&table, 1, TrackingOptions::default());
+
+        table.remember(1, b"foo");
+        table.disable(1);
+        table.invalidate(b"foo", Some(2));
+        assert!(receiver.try_recv().is_none());
+
+        // Tracking again doesn't bring back the keys read before.
+        let mut receiver = track(&table, 1, TrackingOptions::default());
+        table.remember(1, b"bar");
+        table.disable(1);
+        let mut receiver_again = track(&table, 1, TrackingOptions::default());
+        table.invalidate(b"bar", Some(2));
+        assert!(receiver.try_recv().is_none());
+        assert!(receiver_again.try_recv().is_none());
+        assert!(table.state.lock().unwrap().keys.is_empty());
+    }
+
+    #[test]
+    fn test_max_keys_evicts_and_invalidates() {
+        let table = TrackingTable::new(&KiwiConfig::new().with_tracking_table_max_keys(2));
+        let mut receiver = track(&table, 1, TrackingOptions::default());
+
+        table.remember(1, b"a");
+        table.remember(1, b"b");
+        assert!(receiver.try_recv().is_none());
+        table.remember(1, b"c");
+
+        let Some(Types::Push(message)) = receiver.try_recv() else {
+            panic!("expected an invalidation");
+        };
+        assert!(message[1] == key_array(b"a") || message[1] == key_array(b"b"));
+        let state = table.state.lock().unwrap();
+        assert_eq!(state.keys.len(), 2);
+        assert!(state.keys.contains_key(b"c".as_slice()));
+        assert_eq!(state.clients[&1].keys.len(), 2);
+    }
+
+    #[test]
+    fn test_invalidate_all_sends_null() {
+        let table = TrackingTable::default();
+        let mut receiver = track(&table, 1, TrackingOptions::default());
+
+        table.remember(1, b"foo");
+        table.invalidate_all(None);
+        table.invalidate(b"foo", None);
+
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            invalidation_message(Types::Null)
+        );
+        assert!(receiver.try_recv().is_none());
+    }
+}
@@ -2,6 +2,7 @@ pub mod services;
 
 use crate::services::CommandParser;
 use oh_my_kiwi_domain::error::KiwiError;
+use oh_my_kiwi_domain::response::Response;
 use oh_my_kiwi_domain::{CommandProcessor, ErrorHandler, ResponseWriter};
 use tracing::error;
 
@@ -62,8 +63,28 @@ where
     }
 
     async fn run_once(&mut self) -> Result<(), KiwiError> {
-        let command = self.parser.parse_next_command().await?;
+        // The parse future is kept alive while pushes are written, so a partially
+        // received command is never dropped in the middle of a frame.
+        let mut parse = self.parser.parse_next_command();
+        let command = loop {
+            tokio::select! {
+                command = &mut parse => break command?,
+                Some(push) = self.processor.next_push() => {
+                    if matches!(push, Response::Close) {
+                        return Err(KiwiError::ConnectionClosed);
+                    }
+                    self.writer.write(push).await?
+                }
+            }
+        };
+
         let response = self.processor.process(command).await?;
-        self.writer.write(response).await
+        let quit = matches!(response, Response::Quit);
+        self.writer.write(response).await?;
+
+        if quit {
+            return Err(KiwiError::ConnectionClosed);
+        }
+        Ok(())
     }
 }
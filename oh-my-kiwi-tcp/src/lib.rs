@@ -4,6 +4,9 @@ use crate::server::TcpServer;
 use crate::writer::TcpBytesWriter;
 use oh_my_kiwi_domain::{CommandProcessor, ErrorHandler, ResponseWriter};
 use oh_my_kiwi_server::services::CommandParser;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::runtime::Handle;
 
 pub mod config;
 pub mod reader;
@@ -11,6 +14,7 @@ pub(crate) mod server;
 pub mod writer;
 
 pub async fn start_tcp_server<P, PF, CP, CPF, R, RF, EH, EHF>(
+    config: TcpConfig,
     processor_factory: PF,
     parser_factory: CPF,
     response_writer_factory: RF,
@@ -18,7 +22,7 @@ pub async fn start_tcp_server<P, PF, CP, CPF, R, RF, EH, EHF>(
 ) -> std::io::Result<()>
 where
     P: CommandProcessor + Send + Sync + 'static,
-    PF: Fn() -> P + Send + Sync + 'static,
+    PF: Fn(SocketAddr) -> P + Send + Sync + 'static,
     CP: CommandParser + Send + Sync + 'static,
     CPF: Fn(TcpBufferedReader) -> CP + Send + Sync + 'static,
     R: ResponseWriter + Send + Sync + 'static,
@@ -26,7 +30,6 @@ where
     EH: ErrorHandler<R> + Send + Sync + 'static,
     EHF: Fn() -> EH + Send + Sync + 'static,
 {
-    let config = TcpConfig::default();
     let tcp = TcpServer::new(
         config,
         processor_factory,
@@ -36,3 +39,33 @@ where
     );
     tcp.run().await
 }
+
+/// Like [`start_tcp_server`], with every connection served on one of the
+/// `cores` runtimes, handed out in turn.
+pub async fn start_tcp_server_on_cores<P, PF, CP, CPF, R, RF, EH, EHF>(
+    config: TcpConfig,
+    cores: Vec<Handle>,
+    processor_factory: PF,
+    parser_factory: CPF,
+    response_writer_factory: RF,
+    error_handler_factory: EHF,
+) -> std::io::Result<()>
+where
+    P: CommandProcessor + Send + Sync + 'static,
+    PF: Fn(SocketAddr) -> P + Send + Sync + 'static,
+    CP: CommandParser + Send + Sync + 'static,
+    CPF: Fn(TcpBufferedReader) -> CP + Send + Sync + 'static,
+    R: ResponseWriter + Send + Sync + 'static,
+    RF: Fn(TcpBytesWriter) -> R + Send + Sync + 'static,
+    EH: ErrorHandler<R> + Send + Sync + 'static,
+    EHF: Fn() -> EH + Send + Sync + 'static,
+{
+    let tcp = TcpServer::new(
+        config,
+        processor_factory,
+        parser_factory,
+        response_writer_factory,
+        error_handler_factory,
+    );
+    Arc::new(tcp).run_on_cores(cores).await
+}
@@ -2,7 +2,7 @@ use oh_my_kiwi_domain::error::ParseError;
 use async_trait::async_trait;
 use tokio::io::{AsyncReadExt, ReadHalf};
 use tokio::net::TcpStream;
-use oh_my_kiwi_domain::BytesReader;
+use oh_my_kiwi_domain::{BytesReader, READ_BUFFER_SIZE};
 
 pub struct TcpBufferedReader {
     reader: ReadHalf<TcpStream>,
@@ -13,7 +13,7 @@ impl TcpBufferedReader {
     pub fn new(reader: ReadHalf<TcpStream>) -> Self {
         Self {
             reader,
-            buffer: Vec::with_capacity(1024 * 1024), // 1MB buffer
+            buffer: Vec::with_capacity(READ_BUFFER_SIZE),
         }
     }
 
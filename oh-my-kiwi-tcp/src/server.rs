@@ -4,8 +4,11 @@ use crate::writer::TcpBytesWriter;
 use oh_my_kiwi_domain::{CommandProcessor, ErrorHandler, ResponseWriter};
 use oh_my_kiwi_server::services::CommandParser;
 use oh_my_kiwi_server::RESP3Server;
-use tokio::net::TcpListener;
-use tracing::{info, info_span, Instrument};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+use tracing::{info, info_span, warn, Instrument};
 
 pub(crate) struct TcpServer<PF, CPF, RF, EHF> {
     tcp_config: TcpConfig,
@@ -35,7 +38,7 @@ impl<PF, CPF, RF, EHF> TcpServer<PF, CPF, RF, EHF> {
     pub(crate) async fn run<P, CP, R, EH>(&self) -> std::io::Result<()>
     where
         P: CommandProcessor + Send + Sync + 'static,
-        PF: Fn() -> P + Send + Sync + 'static,
+        PF: Fn(SocketAddr) -> P + Send + Sync + 'static,
         CP: CommandParser + Send + Sync + 'static,
         CPF: Fn(TcpBufferedReader) -> CP + Send + Sync + 'static,
         R: ResponseWriter + Send + Sync + 'static,
@@ -48,27 +51,76 @@ impl<PF, CPF, RF, EHF> TcpServer<PF, CPF, RF, EHF> {
 
         loop {
             let (stream, addr) = listener.accept().await?;
+            self.serve(stream, addr);
+        }
+    }
+
+    /// Accepts connections on the current runtime and hands them to the
+    /// `cores` in turn, each serving its connections on its own thread.
+    pub(crate) async fn run_on_cores<P, CP, R, EH>(
+        self: Arc<Self>,
+        cores: Vec<Handle>,
+    ) -> std::io::Result<()>
+    where
+        P: CommandProcessor + Send + Sync + 'static,
+        PF: Fn(SocketAddr) -> P + Send + Sync + 'static,
+        CP: CommandParser + Send + Sync + 'static,
+        CPF: Fn(TcpBufferedReader) -> CP + Send + Sync + 'static,
+        R: ResponseWriter + Send + Sync + 'static,
+        RF: Fn(TcpBytesWriter) -> R + Send + Sync + 'static,
+        EH: ErrorHandler<R> + Send + Sync + 'static,
+        EHF: Fn() -> EH + Send + Sync + 'static,
+    {
+        let socket_addr = self.tcp_config.socket_addr()?;
+        let listener = TcpListener::bind(socket_addr).await?;
+
+        for core in cores.iter().cycle() {
+            let (stream, addr) = listener.accept().await?;
+            // Moved to the core's own reactor, which it is registered with
+            // again there.
+            let stream = stream.into_std()?;
+            let server = self.clone();
+            core.spawn(async move {
+                match TcpStream::from_std(stream) {
+                    Ok(stream) => server.serve(stream, addr),
+                    Err(err) => warn!("Handing over the connection from {addr} failed: {err}"),
+                }
+            });
+        }
+        Ok(())
+    }
 
-            let info_span = info_span!("connection", addr = %addr);
-            info!("New connection from: ${addr}");
+    /// Serves the connection on a task of the current runtime.
+    fn serve<P, CP, R, EH>(&self, stream: TcpStream, addr: SocketAddr)
+    where
+        P: CommandProcessor + Send + Sync + 'static,
+        PF: Fn(SocketAddr) -> P + Send + Sync + 'static,
+        CP: CommandParser + Send + Sync + 'static,
+        CPF: Fn(TcpBufferedReader) -> CP + Send + Sync + 'static,
+        R: ResponseWriter + Send + Sync + 'static,
+        RF: Fn(TcpBytesWriter) -> R + Send + Sync + 'static,
+        EH: ErrorHandler<R> + Send + Sync + 'static,
+        EHF: Fn() -> EH + Send + Sync + 'static,
+    {
+        let info_span = info_span!("connection", addr = %addr);
+        info!("New connection from: ${addr}");
 
-            let (read_half, write_half) = tokio::io::split(stream);
+        let (read_half, write_half) = tokio::io::split(stream);
 
-            let bytes_reader = TcpBufferedReader::new(read_half);
-            let bytes_writer = TcpBytesWriter::new(write_half);
+        let bytes_reader = TcpBufferedReader::new(read_half);
+        let bytes_writer = TcpBytesWriter::new(write_half);
 
-            let processor = (self.processor_factory)();
-            let parser = (self.parser_factory)(bytes_reader);
-            let response_writer = (self.response_write_factory)(bytes_writer);
-            let error_handler = (self.error_handler_factory)();
+        let processor = (self.processor_factory)(addr);
+        let parser = (self.parser_factory)(bytes_reader);
+        let response_writer = (self.response_write_factory)(bytes_writer);
+        let error_handler = (self.error_handler_factory)();
 
-            let mut server = RESP3Server::new(parser, processor, response_writer, error_handler);
-            tokio::spawn(
-                async move {
-                    server.run().await;
-                }
-                .instrument(info_span),
-            );
-        }
+        let mut server = RESP3Server::new(parser, processor, response_writer, error_handler);
+        tokio::spawn(
+            async move {
+                server.run().await;
+            }
+            .instrument(info_span),
+        );
     }
 }
@@ -17,7 +17,7 @@ impl TcpBytesWriter {
 #[async_trait]
 impl BytesWriter for TcpBytesWriter {
     async fn write_all(&mut self, bytes: &[u8]) -> Result<(), KiwiError> {
-        self.writer.write_all(&bytes).await?;
+        self.writer.write_all(bytes).await?;
         Ok(self.writer.flush().await?)
     }
 }